@@ -0,0 +1,1674 @@
+//! Running AML.
+//!
+//! The code is run straight from the bytes of the tables, one term at a time, without being
+//! parsed into a tree first. Loading a table is running its code in the root scope, and
+//! invoking a method is running its body in a [`Frame`] of its own. Names a method defines go
+//! away again when it returns.
+
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, vec, vec::Vec};
+use core::{cmp::Ordering, mem};
+
+use super::{
+    name::{is_lead_char, is_segment, NameString, Path, Segment},
+    object::{
+        get_bits, set_bits, Access, BufferField, FieldKind, FieldUnit, Method, MethodBody, Object,
+        Reference, Region, UpdateRule,
+    },
+    opcodes as op, AmlError,
+};
+use crate::{
+    acpi::{self, AddressSpace},
+    debug,
+    drivers::pit,
+    intrinsics::io_wait,
+    warn,
+};
+
+/// How deep methods can call each other.
+const MAX_DEPTH: usize = 32;
+/// How many times a `While` can go round before it is taken to be stuck.
+const MAX_ITERATIONS: usize = 1 << 20;
+/// How long a chain of aliases can be.
+const MAX_ALIASES: usize = 8;
+/// What `Revision` gives.
+const REVISION: u64 = 1;
+
+const ARGS: usize = 7;
+const LOCALS: usize = 8;
+
+/// A position in some code.
+#[derive(Copy, Clone)]
+pub(super) struct Stream {
+    code: &'static [u8],
+    pos: usize,
+}
+
+impl Stream {
+    pub(super) fn new(code: &'static [u8]) -> Stream {
+        Stream { code, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.code.len()
+    }
+
+    fn peek(&self) -> Result<u8, AmlError> {
+        self.code
+            .get(self.pos)
+            .copied()
+            .ok_or(AmlError::UnexpectedEnd)
+    }
+
+    fn peek_at(&self, ahead: usize) -> Option<u8> {
+        self.code.get(self.pos + ahead).copied()
+    }
+
+    fn byte(&mut self) -> Result<u8, AmlError> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'static [u8], AmlError> {
+        let bytes = self
+            .code
+            .get(self.pos..self.pos + len)
+            .ok_or(AmlError::UnexpectedEnd)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> Result<u16, AmlError> {
+        Ok(u16::from_le_bytes(
+            self.bytes(2)?
+                .try_into()
+                .map_err(|_| AmlError::UnexpectedEnd)?,
+        ))
+    }
+
+    fn dword(&mut self) -> Result<u32, AmlError> {
+        Ok(u32::from_le_bytes(
+            self.bytes(4)?
+                .try_into()
+                .map_err(|_| AmlError::UnexpectedEnd)?,
+        ))
+    }
+
+    fn qword(&mut self) -> Result<u64, AmlError> {
+        Ok(u64::from_le_bytes(
+            self.bytes(8)?
+                .try_into()
+                .map_err(|_| AmlError::UnexpectedEnd)?,
+        ))
+    }
+
+    /// Returns what is left of the code.
+    fn rest(&self) -> &'static [u8] {
+        &self.code[self.pos.min(self.code.len())..]
+    }
+
+    /// Reads a package length. The top two bits of the first byte say how many more bytes
+    /// follow it, and those hold the rest of the length above the first byte's low 4 bits.
+    fn pkg_length(&mut self) -> Result<usize, AmlError> {
+        let lead = self.byte()?;
+        let extra = lead >> 6;
+        if extra == 0 {
+            return Ok(usize::from(lead & 0x3F));
+        }
+        let mut len = usize::from(lead & 0x0F);
+        for i in 0..extra {
+            len |= usize::from(self.byte()?) << (4 + 8 * i);
+        }
+        Ok(len)
+    }
+
+    /// Reads a package length and returns the code it covers, moving past it. The length
+    /// counts itself.
+    fn package(&mut self) -> Result<Stream, AmlError> {
+        let start = self.pos;
+        let end = start + self.pkg_length()?;
+        if end > self.code.len() || end < self.pos {
+            return Err(AmlError::UnexpectedEnd);
+        }
+        let package = Stream {
+            code: &self.code[..end],
+            pos: self.pos,
+        };
+        self.pos = end;
+        Ok(package)
+    }
+
+    /// Returns whether a name starts here.
+    fn at_name(&self) -> bool {
+        self.peek().is_ok_and(|byte| {
+            is_lead_char(byte)
+                || matches!(
+                    byte,
+                    op::ROOT_CHAR
+                        | op::PARENT_PREFIX
+                        | op::DUAL_NAME_PREFIX
+                        | op::MULTI_NAME_PREFIX
+                )
+        })
+    }
+
+    fn name_segment(&mut self) -> Result<Segment, AmlError> {
+        let segment: Segment = self
+            .bytes(4)?
+            .try_into()
+            .map_err(|_| AmlError::UnexpectedEnd)?;
+        if !is_segment(&segment) {
+            return Err(AmlError::InvalidName);
+        }
+        Ok(segment)
+    }
+
+    fn name_string(&mut self) -> Result<NameString, AmlError> {
+        let mut name = NameString {
+            root: false,
+            parents: 0,
+            segments: Vec::new(),
+        };
+        if self.peek()? == op::ROOT_CHAR {
+            self.byte()?;
+            name.root = true;
+        } else {
+            while self.peek()? == op::PARENT_PREFIX {
+                self.byte()?;
+                name.parents += 1;
+            }
+        }
+        let count = match self.peek()? {
+            op::DUAL_NAME_PREFIX => {
+                self.byte()?;
+                2
+            }
+            op::MULTI_NAME_PREFIX => {
+                self.byte()?;
+                usize::from(self.byte()?)
+            }
+            op::ZERO => {
+                self.byte()?;
+                0
+            }
+            _ => 1,
+        };
+        for _ in 0..count {
+            name.segments.push(self.name_segment()?);
+        }
+        Ok(name)
+    }
+}
+
+/// What the code running says to do next.
+enum Flow {
+    Normal,
+    Return(Object),
+    Break,
+    Continue,
+}
+
+/// The state of the method running, or of the table being loaded.
+pub(super) struct Frame {
+    scope: Path,
+    args: Vec<Object>,
+    locals: Vec<Object>,
+    /// The names the method defined, which go away when it returns. `None` while loading a
+    /// table, whose names stay.
+    created: Option<Vec<Path>>,
+}
+
+impl Frame {
+    fn new(scope: Path, args: Vec<Object>, created: Option<Vec<Path>>) -> Frame {
+        let mut frame = Frame {
+            scope,
+            args,
+            locals: Vec::new(),
+            created,
+        };
+        frame.args.resize_with(ARGS, || Object::Uninitialized);
+        frame.locals.resize_with(LOCALS, || Object::Uninitialized);
+        frame
+    }
+
+    /// Returns the frame a table is loaded in.
+    fn load() -> Frame {
+        Frame::new(Path::root(), Vec::new(), None)
+    }
+
+    fn is_loading(&self) -> bool {
+        self.created.is_none()
+    }
+}
+
+fn access(flags: u8) -> Access {
+    match flags & 0xF {
+        1 => Access::Byte,
+        2 => Access::Word,
+        3 => Access::DWord,
+        4 => Access::QWord,
+        5 => Access::Buffer,
+        _ => Access::Any,
+    }
+}
+
+fn bool_object(value: bool) -> Object {
+    Object::Integer(if value { u64::MAX } else { 0 })
+}
+
+/// The namespace, and what runs the code in it.
+pub struct Interpreter {
+    namespace: BTreeMap<Path, Object>,
+    /// Integers are 32 bits wide for DSDTs older than revision 2, and 64 bits wide otherwise.
+    ones: u64,
+    depth: usize,
+}
+
+impl Interpreter {
+    /// Creates an empty namespace for a DSDT of the given revision.
+    pub fn new(dsdt_revision: u8) -> Interpreter {
+        let mut namespace = BTreeMap::new();
+        namespace.insert(Path::root(), Object::Scope);
+        Interpreter {
+            namespace,
+            ones: if dsdt_revision < 2 {
+                u64::from(u32::MAX)
+            } else {
+                u64::MAX
+            },
+            depth: 0,
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&Object> {
+        self.namespace.get(path)
+    }
+
+    /// Returns every object, parents before their children.
+    pub fn objects(&self) -> impl Iterator<Item = (&Path, &Object)> {
+        self.namespace.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.namespace.len()
+    }
+
+    /// Returns the objects right below `path`.
+    pub fn children(&self, path: &Path) -> Vec<Path> {
+        self.namespace
+            .range(path.clone()..)
+            .skip(1)
+            .take_while(|(child, _)| child.starts_with(path))
+            .filter(|(child, _)| child.depth() == path.depth() + 1)
+            .map(|(child, _)| child.clone())
+            .collect()
+    }
+
+    /// Adds an object that is not defined by any table, like `\_OSI`.
+    pub fn insert(&mut self, path: Path, object: Object) {
+        self.namespace.insert(path, object);
+    }
+
+    /// Runs the code of a DSDT or SSDT, adding what it defines to the namespace.
+    pub fn load(&mut self, code: &'static [u8]) -> Result<(), AmlError> {
+        self.term_list(&mut Frame::load(), Stream::new(code))
+            .map(|_| ())
+    }
+
+    /// Evaluates the object at `path`, invoking it if it is a method.
+    pub fn evaluate(&mut self, path: &Path, args: Vec<Object>) -> Result<Object, AmlError> {
+        let path = self.resolve_alias(path);
+        match self.namespace.get(&path) {
+            Some(Object::Method(method)) => {
+                let method = *method;
+                self.invoke(&path, method, args)
+            }
+            Some(_) => self.read_named(&mut Frame::load(), &path),
+            None => Err(AmlError::UndefinedName(format!("{}", path))),
+        }
+    }
+
+    /// Evaluates `name` below `scope` as an integer, if it is there.
+    pub fn evaluate_integer(
+        &mut self,
+        scope: &Path,
+        name: &Segment,
+    ) -> Result<Option<u64>, AmlError> {
+        let path = scope.join(*name);
+        if !self.namespace.contains_key(&path) {
+            return Ok(None);
+        }
+        Ok(Some(self.evaluate(&path, Vec::new())?.as_integer()?))
+    }
+
+    /// Finds the object a name refers to in `scope`, searching the scopes above it for names
+    /// of one segment.
+    pub fn lookup(&self, name: &NameString, scope: &Path) -> Option<Path> {
+        if name.searches_parents() {
+            let mut scope = scope.clone();
+            loop {
+                let path = scope.join(name.segments[0]);
+                if self.namespace.contains_key(&path) {
+                    return Some(self.resolve_alias(&path));
+                }
+                scope = scope.parent()?;
+            }
+        }
+        let path = name.resolve(scope)?;
+        self.namespace
+            .contains_key(&path)
+            .then(|| self.resolve_alias(&path))
+    }
+
+    fn resolve_alias(&self, path: &Path) -> Path {
+        let mut path = path.clone();
+        for _ in 0..MAX_ALIASES {
+            match self.namespace.get(&path) {
+                Some(Object::Alias(target)) => path = target.clone(),
+                _ => break,
+            }
+        }
+        path
+    }
+
+    pub(super) fn truncate(&self, value: u64) -> u64 {
+        value & self.ones
+    }
+
+    pub(super) fn integer_bits(&self) -> u64 {
+        u64::from(self.ones.count_ones())
+    }
+
+    pub(super) fn integer_bytes(&self) -> usize {
+        self.ones.count_ones() as usize / 8
+    }
+
+    fn define(&mut self, frame: &mut Frame, path: Path, object: Object) -> Result<(), AmlError> {
+        if self.namespace.contains_key(&path) {
+            if frame.is_loading() {
+                // Firmware does this more often than it should, and the first one wins.
+                warn!("acpi: {} is defined more than once", path);
+                return Ok(());
+            }
+            return Err(AmlError::AlreadyDefined(format!("{}", path)));
+        }
+        self.namespace.insert(path.clone(), object);
+        if let Some(created) = &mut frame.created {
+            created.push(path);
+        }
+        Ok(())
+    }
+
+    /// Removes the object at `path` and everything below it.
+    fn remove(&mut self, path: &Path) {
+        self.namespace.retain(|other, _| !other.starts_with(path));
+    }
+
+    /// Returns the path a name being defined gets.
+    fn new_path(&self, frame: &Frame, name: &NameString) -> Result<Path, AmlError> {
+        match name.resolve(&frame.scope) {
+            Some(path) if !path.is_root() => Ok(path),
+            _ => Err(AmlError::InvalidName),
+        }
+    }
+
+    fn invoke(
+        &mut self,
+        path: &Path,
+        method: Method,
+        args: Vec<Object>,
+    ) -> Result<Object, AmlError> {
+        let code = match method.body {
+            MethodBody::Native(handler) => return handler(&args),
+            MethodBody::Aml(code) => code,
+        };
+        if self.depth >= MAX_DEPTH {
+            return Err(AmlError::TooDeep);
+        }
+        let mut frame = Frame::new(path.clone(), args, Some(Vec::new()));
+        self.depth += 1;
+        let result = self.term_list(&mut frame, Stream::new(code));
+        self.depth -= 1;
+        for created in frame.created.iter().flatten().rev() {
+            self.remove(created);
+        }
+        match result? {
+            Flow::Return(value) => Ok(value),
+            Flow::Normal => Ok(Object::Uninitialized),
+            Flow::Break | Flow::Continue => Err(AmlError::MisplacedBreak),
+        }
+    }
+
+    fn term_list(&mut self, frame: &mut Frame, mut s: Stream) -> Result<Flow, AmlError> {
+        while !s.is_empty() {
+            match self.term(frame, &mut s)? {
+                Flow::Normal => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Runs the body of a scope, device or the like with `path` as the scope. A mistake in
+    /// the body of one while loading is logged, and the rest of the table is still loaded.
+    fn with_scope(&mut self, frame: &mut Frame, path: Path, body: Stream) -> Result<(), AmlError> {
+        let outer = mem::replace(&mut frame.scope, path);
+        let result = self.term_list(frame, body);
+        let path = mem::replace(&mut frame.scope, outer);
+        match result {
+            Err(error) if frame.is_loading() => {
+                warn!("acpi: skipped the rest of {}: {}", path, error);
+                Ok(())
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Runs a term, which can be a statement, a definition or an expression whose value is
+    /// thrown away.
+    fn term(&mut self, frame: &mut Frame, s: &mut Stream) -> Result<Flow, AmlError> {
+        match s.peek()? {
+            op::IF => {
+                s.byte()?;
+                let mut body = s.package()?;
+                let predicate = self.integer_arg(frame, &mut body)?;
+                let otherwise = if s.peek_at(0) == Some(op::ELSE) {
+                    s.byte()?;
+                    Some(s.package()?)
+                } else {
+                    None
+                };
+                if predicate != 0 {
+                    return self.term_list(frame, body);
+                } else if let Some(otherwise) = otherwise {
+                    return self.term_list(frame, otherwise);
+                }
+            }
+            op::ELSE => {
+                // An Else without an If before it.
+                s.byte()?;
+                s.package()?;
+            }
+            op::WHILE => {
+                s.byte()?;
+                let body = s.package()?;
+                for _ in 0..MAX_ITERATIONS {
+                    let mut body = body;
+                    if self.integer_arg(frame, &mut body)? == 0 {
+                        return Ok(Flow::Normal);
+                    }
+                    match self.term_list(frame, body)? {
+                        Flow::Break => return Ok(Flow::Normal),
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Normal | Flow::Continue => {}
+                    }
+                }
+                return Err(AmlError::Timeout);
+            }
+            op::RETURN => {
+                s.byte()?;
+                let value = self.term_arg(frame, s)?;
+                return Ok(Flow::Return(value));
+            }
+            op::BREAK => {
+                s.byte()?;
+                return Ok(Flow::Break);
+            }
+            op::CONTINUE => {
+                s.byte()?;
+                return Ok(Flow::Continue);
+            }
+            op::NOOP | op::BREAK_POINT => {
+                s.byte()?;
+            }
+            op::NAME => {
+                s.byte()?;
+                let name = s.name_string()?;
+                let value = self.term_arg(frame, s)?;
+                let path = self.new_path(frame, &name)?;
+                self.define(frame, path, value)?;
+            }
+            op::ALIAS => {
+                s.byte()?;
+                let source = s.name_string()?;
+                let alias = s.name_string()?;
+                let source = self
+                    .lookup(&source, &frame.scope)
+                    .ok_or_else(|| AmlError::UndefinedName(format!("{}", source)))?;
+                let path = self.new_path(frame, &alias)?;
+                self.define(frame, path, Object::Alias(source))?;
+            }
+            op::SCOPE => {
+                s.byte()?;
+                let mut body = s.package()?;
+                let name = body.name_string()?;
+                let path = match self.lookup(&name, &frame.scope) {
+                    Some(path) => path,
+                    None => {
+                        // The scope may be declared External, in a table yet to be loaded.
+                        let path = name.resolve(&frame.scope).ok_or(AmlError::InvalidName)?;
+                        self.define(frame, path.clone(), Object::Scope)?;
+                        path
+                    }
+                };
+                self.with_scope(frame, path, body)?;
+            }
+            op::METHOD => {
+                s.byte()?;
+                let mut body = s.package()?;
+                let name = body.name_string()?;
+                let flags = body.byte()?;
+                let method = Method {
+                    args: flags & 0x7,
+                    serialized: flags & 0x8 != 0,
+                    body: MethodBody::Aml(body.rest()),
+                };
+                let path = self.new_path(frame, &name)?;
+                self.define(frame, path, Object::Method(method))?;
+            }
+            op::EXTERNAL => {
+                // Only says what another table defines.
+                s.byte()?;
+                s.name_string()?;
+                s.bytes(2)?;
+            }
+            op::CREATE_BIT_FIELD => {
+                s.byte()?;
+                self.create_field(frame, s, Some(1), false)?;
+            }
+            op::CREATE_BYTE_FIELD => {
+                s.byte()?;
+                self.create_field(frame, s, Some(8), true)?;
+            }
+            op::CREATE_WORD_FIELD => {
+                s.byte()?;
+                self.create_field(frame, s, Some(16), true)?;
+            }
+            op::CREATE_DWORD_FIELD => {
+                s.byte()?;
+                self.create_field(frame, s, Some(32), true)?;
+            }
+            op::CREATE_QWORD_FIELD => {
+                s.byte()?;
+                self.create_field(frame, s, Some(64), true)?;
+            }
+            op::NOTIFY => {
+                s.byte()?;
+                let target = self.super_name(frame, s)?;
+                let value = self.integer_arg(frame, s)?;
+                debug!("acpi: notify {:?} of {:#x}", target, value);
+            }
+            op::EXT_PREFIX => return self.extended_term(frame, s),
+            _ => {
+                self.term_arg(frame, s)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn extended_term(&mut self, frame: &mut Frame, s: &mut Stream) -> Result<Flow, AmlError> {
+        let Some(opcode) = s.peek_at(1) else {
+            return Err(AmlError::UnexpectedEnd);
+        };
+        match opcode {
+            op::MUTEX => {
+                s.bytes(2)?;
+                let name = s.name_string()?;
+                let sync_level = s.byte()? & 0xF;
+                let path = self.new_path(frame, &name)?;
+                self.define(frame, path, Object::Mutex { sync_level })?;
+            }
+            op::EVENT => {
+                s.bytes(2)?;
+                let name = s.name_string()?;
+                let path = self.new_path(frame, &name)?;
+                self.define(frame, path, Object::Event { signals: 0 })?;
+            }
+            op::OP_REGION => {
+                s.bytes(2)?;
+                let name = s.name_string()?;
+                let space = AddressSpace::from(s.byte()?);
+                let offset = self.integer_arg(frame, s)?;
+                let length = self.integer_arg(frame, s)?;
+                let path = self.new_path(frame, &name)?;
+                let region = Region {
+                    space,
+                    offset,
+                    length,
+                    scope: frame.scope.clone(),
+                };
+                self.define(frame, path, Object::OperationRegion(region))?;
+            }
+            op::DATA_REGION => {
+                s.bytes(2)?;
+                let name = s.name_string()?;
+                let signature = self.term_arg(frame, s)?.as_string()?;
+                let oem_id = self.term_arg(frame, s)?.as_string()?;
+                let oem_table_id = self.term_arg(frame, s)?.as_string()?;
+                let table = acpi::get()
+                    .and_then(|acpi| {
+                        acpi.tables.iter().find(|table| {
+                            table.signature() == signature
+                                && (oem_id.is_empty()
+                                    || table.oem_id.starts_with(oem_id.as_bytes()))
+                                && (oem_table_id.is_empty()
+                                    || table.oem_table_id.starts_with(oem_table_id.as_bytes()))
+                        })
+                    })
+                    .ok_or(AmlError::Unsupported("a data region for a missing table"))?;
+                let region = Region {
+                    space: AddressSpace::Memory,
+                    offset: table.address as u64,
+                    length: table.bytes.len() as u64,
+                    scope: frame.scope.clone(),
+                };
+                let path = self.new_path(frame, &name)?;
+                self.define(frame, path, Object::OperationRegion(region))?;
+            }
+            op::FIELD => {
+                s.bytes(2)?;
+                let mut body = s.package()?;
+                let region = self.region_name(frame, &mut body)?;
+                let flags = body.byte()?;
+                self.field_list(frame, body, FieldKind::Region(region), flags)?;
+            }
+            op::INDEX_FIELD => {
+                s.bytes(2)?;
+                let mut body = s.package()?;
+                let index = self.region_name(frame, &mut body)?;
+                let data = self.region_name(frame, &mut body)?;
+                let flags = body.byte()?;
+                self.field_list(frame, body, FieldKind::Index { index, data }, flags)?;
+            }
+            op::BANK_FIELD => {
+                s.bytes(2)?;
+                let mut body = s.package()?;
+                let region = self.region_name(frame, &mut body)?;
+                let bank = self.region_name(frame, &mut body)?;
+                let value = self.integer_arg(frame, &mut body)?;
+                let flags = body.byte()?;
+                let kind = FieldKind::Bank {
+                    region,
+                    bank,
+                    value,
+                };
+                self.field_list(frame, body, kind, flags)?;
+            }
+            op::DEVICE | op::THERMAL_ZONE => {
+                s.bytes(2)?;
+                let mut body = s.package()?;
+                let name = body.name_string()?;
+                let path = self.new_path(frame, &name)?;
+                let object = if opcode == op::DEVICE {
+                    Object::Device
+                } else {
+                    Object::ThermalZone
+                };
+                self.define(frame, path.clone(), object)?;
+                self.with_scope(frame, path, body)?;
+            }
+            op::PROCESSOR => {
+                s.bytes(2)?;
+                let mut body = s.package()?;
+                let name = body.name_string()?;
+                let processor = Object::Processor {
+                    id: body.byte()?,
+                    block_address: body.dword()?,
+                    block_length: body.byte()?,
+                };
+                let path = self.new_path(frame, &name)?;
+                self.define(frame, path.clone(), processor)?;
+                self.with_scope(frame, path, body)?;
+            }
+            op::POWER_RES => {
+                s.bytes(2)?;
+                let mut body = s.package()?;
+                let name = body.name_string()?;
+                let resource = Object::PowerResource {
+                    system_level: body.byte()?,
+                    order: body.word()?,
+                };
+                let path = self.new_path(frame, &name)?;
+                self.define(frame, path.clone(), resource)?;
+                self.with_scope(frame, path, body)?;
+            }
+            op::CREATE_FIELD => {
+                s.bytes(2)?;
+                self.create_field(frame, s, None, false)?;
+            }
+            op::RELEASE | op::RESET => {
+                s.bytes(2)?;
+                let target = self.super_name(frame, s)?;
+                if opcode == op::RESET {
+                    if let Reference::Named(path) = target {
+                        if let Some(Object::Event { signals }) = self.namespace.get_mut(&path) {
+                            *signals = 0;
+                        }
+                    }
+                }
+            }
+            op::SIGNAL => {
+                s.bytes(2)?;
+                if let Reference::Named(path) = self.super_name(frame, s)? {
+                    if let Some(Object::Event { signals }) = self.namespace.get_mut(&path) {
+                        *signals = signals.saturating_add(1);
+                    }
+                }
+            }
+            op::STALL => {
+                s.bytes(2)?;
+                let microseconds = self.integer_arg(frame, s)?;
+                for _ in 0..microseconds.min(100) {
+                    io_wait();
+                }
+            }
+            op::SLEEP => {
+                s.bytes(2)?;
+                let milliseconds = self.integer_arg(frame, s)?;
+                for _ in 0..milliseconds.min(10_000) * 1000 {
+                    io_wait();
+                }
+            }
+            op::FATAL => {
+                s.bytes(2)?;
+                let kind = s.byte()?;
+                let code = s.dword()?;
+                let argument = self.integer_arg(frame, s)?;
+                return Err(AmlError::Fatal {
+                    kind,
+                    code,
+                    argument,
+                });
+            }
+            op::LOAD | op::LOAD_TABLE | op::UNLOAD => {
+                return Err(AmlError::Unsupported("loading tables from AML"));
+            }
+            _ => {
+                self.term_arg(frame, s)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Reads the name of an object a field refers to, which has to be defined already.
+    fn region_name(&self, frame: &Frame, s: &mut Stream) -> Result<Path, AmlError> {
+        let name = s.name_string()?;
+        self.lookup(&name, &frame.scope)
+            .ok_or_else(|| AmlError::UndefinedName(format!("{}", name)))
+    }
+
+    /// Defines the fields in a field list, which lays them out one after another.
+    fn field_list(
+        &mut self,
+        frame: &mut Frame,
+        mut s: Stream,
+        kind: FieldKind,
+        flags: u8,
+    ) -> Result<(), AmlError> {
+        let mut access = access(flags);
+        let update = match (flags >> 5) & 0x3 {
+            0 => UpdateRule::Preserve,
+            1 => UpdateRule::WriteAsOnes,
+            _ => UpdateRule::WriteAsZeros,
+        };
+        let mut offset = 0;
+        while !s.is_empty() {
+            match s.peek()? {
+                op::RESERVED_FIELD => {
+                    s.byte()?;
+                    offset += s.pkg_length()? as u64;
+                }
+                op::ACCESS_FIELD => {
+                    s.byte()?;
+                    access = self::access(s.byte()?);
+                    s.byte()?;
+                }
+                op::EXTENDED_ACCESS_FIELD => {
+                    s.byte()?;
+                    access = self::access(s.byte()?);
+                    s.bytes(2)?;
+                }
+                op::CONNECT_FIELD => {
+                    // Only matters for GPIO and serial bus regions, which are not supported.
+                    s.byte()?;
+                    if s.at_name() {
+                        s.name_string()?;
+                    } else {
+                        self.term_arg(frame, &mut s)?;
+                    }
+                }
+                _ => {
+                    let name = s.name_segment()?;
+                    let bit_length = s.pkg_length()? as u64;
+                    let field = FieldUnit {
+                        kind: kind.clone(),
+                        bit_offset: offset,
+                        bit_length,
+                        access,
+                        update,
+                    };
+                    let path = frame.scope.join(name);
+                    self.define(frame, path, Object::FieldUnit(field))?;
+                    offset += bit_length;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Defines a field in a buffer, `bit_length` bits long if it is known from the opcode, at
+    /// an index that counts bytes if `byte_index` is set and bits otherwise.
+    fn create_field(
+        &mut self,
+        frame: &mut Frame,
+        s: &mut Stream,
+        bit_length: Option<usize>,
+        byte_index: bool,
+    ) -> Result<(), AmlError> {
+        let source = self.source_reference(frame, s)?;
+        let index = self.integer_arg(frame, s)? as usize;
+        let bit_offset = if byte_index { index * 8 } else { index };
+        let bit_length = match bit_length {
+            Some(bit_length) => bit_length,
+            None => self.integer_arg(frame, s)? as usize,
+        };
+        let name = s.name_string()?;
+        let path = self.new_path(frame, &name)?;
+        let field = BufferField {
+            source,
+            bit_offset,
+            bit_length,
+        };
+        self.define(frame, path, Object::BufferField(field))
+    }
+
+    fn integer_arg(&mut self, frame: &mut Frame, s: &mut Stream) -> Result<u64, AmlError> {
+        let value = self.term_arg(frame, s)?.as_integer()?;
+        Ok(self.truncate(value))
+    }
+
+    /// Reads the target at the end of an operator and stores its result there.
+    fn result(
+        &mut self,
+        frame: &mut Frame,
+        s: &mut Stream,
+        value: Object,
+    ) -> Result<Object, AmlError> {
+        let target = self.super_name(frame, s)?;
+        self.store(frame, value.clone(), &target)?;
+        Ok(value)
+    }
+
+    /// Evaluates an expression.
+    fn term_arg(&mut self, frame: &mut Frame, s: &mut Stream) -> Result<Object, AmlError> {
+        if s.at_name() {
+            return self.name_term(frame, s);
+        }
+        let opcode = s.byte()?;
+        Ok(match opcode {
+            op::ZERO => Object::Integer(0),
+            op::ONE => Object::Integer(1),
+            op::ONES => Object::Integer(self.ones),
+            op::BYTE_PREFIX => Object::Integer(u64::from(s.byte()?)),
+            op::WORD_PREFIX => Object::Integer(u64::from(s.word()?)),
+            op::DWORD_PREFIX => Object::Integer(u64::from(s.dword()?)),
+            op::QWORD_PREFIX => Object::Integer(self.truncate(s.qword()?)),
+            op::STRING_PREFIX => {
+                let len = s
+                    .rest()
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .ok_or(AmlError::UnexpectedEnd)?;
+                let string = String::from_utf8_lossy(s.bytes(len)?).into_owned();
+                s.byte()?;
+                Object::String(string)
+            }
+            op::BUFFER => {
+                let mut body = s.package()?;
+                let size = self.integer_arg(frame, &mut body)? as usize;
+                let mut bytes = body.rest().to_vec();
+                if bytes.len() < size {
+                    bytes.resize(size, 0);
+                }
+                Object::Buffer(bytes)
+            }
+            op::PACKAGE => {
+                let mut body = s.package()?;
+                let count = usize::from(body.byte()?);
+                self.package(frame, body, count)?
+            }
+            op::VAR_PACKAGE => {
+                let mut body = s.package()?;
+                let count = self.integer_arg(frame, &mut body)? as usize;
+                self.package(frame, body, count)?
+            }
+            op::LOCAL0..=op::LOCAL7 => frame.locals[usize::from(opcode - op::LOCAL0)].clone(),
+            op::ARG0..=op::ARG6 => frame.args[usize::from(opcode - op::ARG0)].clone(),
+            op::STORE => {
+                let value = self.term_arg(frame, s)?;
+                let target = self.super_name(frame, s)?;
+                self.store(frame, value.clone(), &target)?;
+                value
+            }
+            op::COPY_OBJECT => {
+                let value = self.term_arg(frame, s)?;
+                let target = self.super_name(frame, s)?;
+                self.copy_object(frame, value.clone(), &target)?;
+                value
+            }
+            op::REF_OF => Object::Reference(self.super_name(frame, s)?),
+            op::ADD
+            | op::SUBTRACT
+            | op::MULTIPLY
+            | op::SHIFT_LEFT
+            | op::SHIFT_RIGHT
+            | op::AND
+            | op::NAND
+            | op::OR
+            | op::NOR
+            | op::XOR
+            | op::MOD => {
+                let a = self.integer_arg(frame, s)?;
+                let b = self.integer_arg(frame, s)?;
+                let value = match opcode {
+                    op::ADD => a.wrapping_add(b),
+                    op::SUBTRACT => a.wrapping_sub(b),
+                    op::MULTIPLY => a.wrapping_mul(b),
+                    op::SHIFT_LEFT => a.checked_shl(b as u32).filter(|_| b < 64).unwrap_or(0),
+                    op::SHIFT_RIGHT => a.checked_shr(b as u32).filter(|_| b < 64).unwrap_or(0),
+                    op::AND => a & b,
+                    op::NAND => !(a & b),
+                    op::OR => a | b,
+                    op::NOR => !(a | b),
+                    op::XOR => a ^ b,
+                    _ => a.checked_rem(b).ok_or(AmlError::DivideByZero)?,
+                };
+                let value = Object::Integer(self.truncate(value));
+                self.result(frame, s, value)?
+            }
+            op::DIVIDE => {
+                let dividend = self.integer_arg(frame, s)?;
+                let divisor = self.integer_arg(frame, s)?;
+                let quotient = dividend
+                    .checked_div(divisor)
+                    .ok_or(AmlError::DivideByZero)?;
+                self.result(frame, s, Object::Integer(dividend % divisor))?;
+                self.result(frame, s, Object::Integer(quotient))?
+            }
+            op::NOT => {
+                let value = !self.integer_arg(frame, s)?;
+                let value = Object::Integer(self.truncate(value));
+                self.result(frame, s, value)?
+            }
+            op::FIND_SET_LEFT_BIT => {
+                let value = self.integer_arg(frame, s)?;
+                let bit = if value == 0 {
+                    0
+                } else {
+                    64 - value.leading_zeros()
+                };
+                self.result(frame, s, Object::Integer(u64::from(bit)))?
+            }
+            op::FIND_SET_RIGHT_BIT => {
+                let value = self.integer_arg(frame, s)?;
+                let bit = if value == 0 {
+                    0
+                } else {
+                    value.trailing_zeros() + 1
+                };
+                self.result(frame, s, Object::Integer(u64::from(bit)))?
+            }
+            op::INCREMENT | op::DECREMENT => {
+                let target = self.super_name(frame, s)?;
+                let value = self.read(frame, &target)?.as_integer()?;
+                let value = if opcode == op::INCREMENT {
+                    value.wrapping_add(1)
+                } else {
+                    value.wrapping_sub(1)
+                };
+                let value = Object::Integer(self.truncate(value));
+                self.store(frame, value.clone(), &target)?;
+                value
+            }
+            op::CONCAT => {
+                let a = self.term_arg(frame, s)?;
+                let b = self.term_arg(frame, s)?;
+                let value = match a {
+                    Object::String(mut a) => {
+                        a.push_str(&b.as_string()?);
+                        Object::String(a)
+                    }
+                    Object::Buffer(mut a) => {
+                        a.extend(b.as_buffer(self.integer_bytes())?);
+                        Object::Buffer(a)
+                    }
+                    a => {
+                        let mut bytes = a.as_buffer(self.integer_bytes())?;
+                        let b = Object::Integer(b.as_integer()?);
+                        bytes.extend(b.as_buffer(self.integer_bytes())?);
+                        Object::Buffer(bytes)
+                    }
+                };
+                self.result(frame, s, value)?
+            }
+            op::CONCAT_RES => {
+                // Joins two resource templates, dropping the end tag of the first.
+                let mut a = self.term_arg(frame, s)?.as_buffer(self.integer_bytes())?;
+                let b = self.term_arg(frame, s)?.as_buffer(self.integer_bytes())?;
+                if a.len() >= 2 && a[a.len() - 2] == 0x79 {
+                    a.truncate(a.len() - 2);
+                }
+                a.extend(b);
+                self.result(frame, s, Object::Buffer(a))?
+            }
+            op::DEREF_OF => match self.term_arg(frame, s)? {
+                Object::Reference(reference) => self.read(frame, &reference)?,
+                Object::String(path) => {
+                    let path = Path::parse(&path).ok_or(AmlError::InvalidName)?;
+                    self.read_named(frame, &path)?
+                }
+                _ => return Err(AmlError::TypeMismatch("a reference")),
+            },
+            op::SIZE_OF => {
+                let target = self.super_name(frame, s)?;
+                let len = match self.read(frame, &target)? {
+                    Object::Buffer(bytes) => bytes.len(),
+                    Object::String(string) => string.len(),
+                    Object::Package(elements) => elements.len(),
+                    _ => return Err(AmlError::TypeMismatch("a buffer, string or package")),
+                };
+                Object::Integer(len as u64)
+            }
+            op::INDEX => {
+                let source = self.source_reference(frame, s)?;
+                let index = self.integer_arg(frame, s)? as usize;
+                let reference = Object::Reference(Reference::Element(Box::new(source), index));
+                self.result(frame, s, reference)?
+            }
+            op::MATCH => {
+                let package = self.term_arg(frame, s)?;
+                let first = (s.byte()?, self.term_arg(frame, s)?);
+                let second = (s.byte()?, self.term_arg(frame, s)?);
+                let start = self.integer_arg(frame, s)? as usize;
+                let Object::Package(elements) = package else {
+                    return Err(AmlError::TypeMismatch("a package"));
+                };
+                let found = elements
+                    .iter()
+                    .enumerate()
+                    .skip(start)
+                    .find(|(_, element)| {
+                        [&first, &second].into_iter().all(|(operator, operand)| {
+                            let ordering = self.compare(element, operand).ok();
+                            match operator {
+                                0 => true,
+                                1 => ordering == Some(Ordering::Equal),
+                                2 => ordering.is_some_and(|ordering| ordering != Ordering::Greater),
+                                3 => ordering == Some(Ordering::Less),
+                                4 => ordering.is_some_and(|ordering| ordering != Ordering::Less),
+                                5 => ordering == Some(Ordering::Greater),
+                                _ => false,
+                            }
+                        })
+                    });
+                Object::Integer(found.map_or(self.ones, |(index, _)| index as u64))
+            }
+            op::OBJECT_TYPE => {
+                let target = self.super_name(frame, s)?;
+                let code = match &target {
+                    Reference::Named(path) => self
+                        .namespace
+                        .get(path)
+                        .map_or(0, |object| object.type_code()),
+                    Reference::Debug => 16,
+                    _ => self.read(frame, &target)?.type_code(),
+                };
+                Object::Integer(code)
+            }
+            op::LAND | op::LOR => {
+                let a = self.integer_arg(frame, s)? != 0;
+                let b = self.integer_arg(frame, s)? != 0;
+                bool_object(if opcode == op::LAND { a && b } else { a || b })
+            }
+            op::LNOT => bool_object(self.integer_arg(frame, s)? == 0),
+            op::LEQUAL | op::LGREATER | op::LLESS => {
+                let a = self.term_arg(frame, s)?;
+                let b = self.term_arg(frame, s)?;
+                let ordering = self.compare(&a, &b)?;
+                bool_object(
+                    ordering
+                        == match opcode {
+                            op::LEQUAL => Ordering::Equal,
+                            op::LGREATER => Ordering::Greater,
+                            _ => Ordering::Less,
+                        },
+                )
+            }
+            op::TO_BUFFER => {
+                let value = self.term_arg(frame, s)?.as_buffer(self.integer_bytes())?;
+                self.result(frame, s, Object::Buffer(value))?
+            }
+            op::TO_DECIMAL_STRING | op::TO_HEX_STRING => {
+                let hex = opcode == op::TO_HEX_STRING;
+                let string = match self.term_arg(frame, s)? {
+                    Object::Integer(value) if hex => format!("{:#X}", value),
+                    Object::Integer(value) => format!("{}", value),
+                    Object::Buffer(bytes) => bytes
+                        .iter()
+                        .map(|byte| {
+                            if hex {
+                                format!("{:#04X}", byte)
+                            } else {
+                                format!("{}", byte)
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    Object::String(string) => string,
+                    _ => return Err(AmlError::TypeMismatch("an integer, buffer or string")),
+                };
+                self.result(frame, s, Object::String(string))?
+            }
+            op::TO_INTEGER => {
+                let value = match self.term_arg(frame, s)? {
+                    Object::String(string) => {
+                        let string = string.trim();
+                        match string.strip_prefix("0x").or(string.strip_prefix("0X")) {
+                            Some(hex) => u64::from_str_radix(hex, 16),
+                            None => string.parse(),
+                        }
+                        .map_err(|_| AmlError::TypeMismatch("a number"))?
+                    }
+                    other => other.as_integer()?,
+                };
+                let value = Object::Integer(self.truncate(value));
+                self.result(frame, s, value)?
+            }
+            op::TO_STRING => {
+                let bytes = self.term_arg(frame, s)?.as_buffer(self.integer_bytes())?;
+                let len = self.integer_arg(frame, s)? as usize;
+                let end = bytes
+                    .iter()
+                    .take(len)
+                    .position(|&byte| byte == 0)
+                    .unwrap_or(len.min(bytes.len()));
+                let string = String::from_utf8_lossy(&bytes[..end]).into_owned();
+                self.result(frame, s, Object::String(string))?
+            }
+            op::MID => {
+                let source = self.term_arg(frame, s)?;
+                let index = self.integer_arg(frame, s)? as usize;
+                let len = self.integer_arg(frame, s)? as usize;
+                let value = match source {
+                    Object::String(string) => {
+                        let bytes = string.as_bytes();
+                        let start = index.min(bytes.len());
+                        let end = start.saturating_add(len).min(bytes.len());
+                        Object::String(String::from_utf8_lossy(&bytes[start..end]).into_owned())
+                    }
+                    other => {
+                        let bytes = other.as_buffer(self.integer_bytes())?;
+                        let start = index.min(bytes.len());
+                        let end = start.saturating_add(len).min(bytes.len());
+                        Object::Buffer(bytes[start..end].to_vec())
+                    }
+                };
+                self.result(frame, s, value)?
+            }
+            op::EXT_PREFIX => self.extended_term_arg(frame, s)?,
+            _ => return Err(AmlError::UnknownOpcode(opcode)),
+        })
+    }
+
+    fn extended_term_arg(&mut self, frame: &mut Frame, s: &mut Stream) -> Result<Object, AmlError> {
+        let opcode = s.byte()?;
+        Ok(match opcode {
+            op::COND_REF_OF => {
+                let found = self.optional_name(frame, s)?;
+                let target = self.super_name(frame, s)?;
+                match found {
+                    Some(reference) => {
+                        self.store(frame, Object::Reference(reference), &target)?;
+                        Object::Integer(self.ones)
+                    }
+                    None => Object::Integer(0),
+                }
+            }
+            op::ACQUIRE => {
+                // There is only ever the one thread running AML, so it always gets the mutex.
+                self.super_name(frame, s)?;
+                s.word()?;
+                Object::Integer(0)
+            }
+            op::WAIT => {
+                let target = self.super_name(frame, s)?;
+                self.integer_arg(frame, s)?;
+                let signalled = match target {
+                    Reference::Named(path) => match self.namespace.get_mut(&path) {
+                        Some(Object::Event { signals }) if *signals > 0 => {
+                            *signals -= 1;
+                            true
+                        }
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                // Nothing else could signal it while waiting, so it times out right away.
+                Object::Integer(if signalled { 0 } else { self.ones })
+            }
+            op::FROM_BCD => {
+                let bcd = self.integer_arg(frame, s)?;
+                let value = (0..16)
+                    .rev()
+                    .fold(0, |value, digit| value * 10 + (bcd >> (digit * 4) & 0xF));
+                self.result(frame, s, Object::Integer(value))?
+            }
+            op::TO_BCD => {
+                let mut value = self.integer_arg(frame, s)?;
+                let mut bcd = 0;
+                for digit in 0..16 {
+                    bcd |= (value % 10) << (digit * 4);
+                    value /= 10;
+                }
+                let bcd = Object::Integer(self.truncate(bcd));
+                self.result(frame, s, bcd)?
+            }
+            op::REVISION => Object::Integer(REVISION),
+            op::TIMER => {
+                // Counts in units of 100 ns.
+                let per_tick = 10_000_000 / u64::from(pit::HZ);
+                Object::Integer(u64::from(pit::ticks()) * per_tick)
+            }
+            op::DEBUG => Object::Reference(Reference::Debug),
+            _ => return Err(AmlError::UnknownExtendedOpcode(opcode)),
+        })
+    }
+
+    /// Evaluates a name in an expression, invoking it if it is a method.
+    fn name_term(&mut self, frame: &mut Frame, s: &mut Stream) -> Result<Object, AmlError> {
+        let name = s.name_string()?;
+        let path = self
+            .lookup(&name, &frame.scope)
+            .ok_or_else(|| AmlError::UndefinedName(format!("{}", name)))?;
+        if let Some(Object::Method(method)) = self.namespace.get(&path) {
+            let method = *method;
+            let mut args = Vec::with_capacity(usize::from(method.args));
+            for _ in 0..method.args {
+                args.push(self.term_arg(frame, s)?);
+            }
+            return self.invoke(&path, method, args);
+        }
+        self.read_named(frame, &path)
+    }
+
+    /// Builds a package out of its elements. Names in it are kept as references, and may be
+    /// to objects that are not defined yet.
+    fn package(
+        &mut self,
+        frame: &mut Frame,
+        mut s: Stream,
+        count: usize,
+    ) -> Result<Object, AmlError> {
+        let mut elements = Vec::with_capacity(count);
+        while !s.is_empty() {
+            let element = if s.at_name() {
+                let name = s.name_string()?;
+                let path = self
+                    .lookup(&name, &frame.scope)
+                    .or_else(|| name.resolve(&frame.scope))
+                    .ok_or(AmlError::InvalidName)?;
+                Object::Reference(Reference::Named(path))
+            } else {
+                self.term_arg(frame, &mut s)?
+            };
+            elements.push(element);
+        }
+        if elements.len() < count {
+            elements.resize_with(count, || Object::Uninitialized);
+        }
+        Ok(Object::Package(elements))
+    }
+
+    /// Reads where the result of an operator goes, or the object it works on.
+    fn super_name(&mut self, frame: &mut Frame, s: &mut Stream) -> Result<Reference, AmlError> {
+        if s.at_name() {
+            let name = s.name_string()?;
+            let path = self
+                .lookup(&name, &frame.scope)
+                .ok_or_else(|| AmlError::UndefinedName(format!("{}", name)))?;
+            return Ok(Reference::Named(path));
+        }
+        let opcode = s.peek()?;
+        match opcode {
+            op::ZERO => {
+                s.byte()?;
+                Ok(Reference::Null)
+            }
+            op::LOCAL0..=op::LOCAL7 => {
+                s.byte()?;
+                Ok(Reference::Local(usize::from(opcode - op::LOCAL0)))
+            }
+            op::ARG0..=op::ARG6 => {
+                s.byte()?;
+                Ok(Reference::Arg(usize::from(opcode - op::ARG0)))
+            }
+            op::EXT_PREFIX if s.peek_at(1) == Some(op::DEBUG) => {
+                s.bytes(2)?;
+                Ok(Reference::Debug)
+            }
+            op::DEREF_OF => {
+                // The target is what the operand refers to, rather than its value.
+                s.byte()?;
+                match self.term_arg(frame, s)? {
+                    Object::Reference(reference) => Ok(reference),
+                    Object::String(path) => Path::parse(&path)
+                        .map(Reference::Named)
+                        .ok_or(AmlError::InvalidName),
+                    _ => Err(AmlError::TypeMismatch("a reference")),
+                }
+            }
+            op::REF_OF | op::INDEX => match self.term_arg(frame, s)? {
+                Object::Reference(reference) => Ok(reference),
+                _ => Err(AmlError::TypeMismatch("a reference")),
+            },
+            _ => Err(AmlError::UnknownOpcode(opcode)),
+        }
+    }
+
+    /// Reads a super name that may be a name nothing is defined at, for `CondRefOf`.
+    fn optional_name(
+        &mut self,
+        frame: &mut Frame,
+        s: &mut Stream,
+    ) -> Result<Option<Reference>, AmlError> {
+        if s.at_name() {
+            let name = s.name_string()?;
+            return Ok(self.lookup(&name, &frame.scope).map(Reference::Named));
+        }
+        self.super_name(frame, s).map(Some)
+    }
+
+    /// Reads the buffer, string or package an operator picks parts out of. Writes through
+    /// the parts reach the original if it is a local, an argument or a named object.
+    fn source_reference(
+        &mut self,
+        frame: &mut Frame,
+        s: &mut Stream,
+    ) -> Result<Reference, AmlError> {
+        match s.peek()? {
+            op::LOCAL0..=op::LOCAL7 | op::ARG0..=op::ARG6 => return self.super_name(frame, s),
+            _ if s.at_name() => {
+                let mut probe = *s;
+                let name = probe.name_string()?;
+                if let Some(path) = self.lookup(&name, &frame.scope) {
+                    if self.namespace.get(&path).is_some_and(Object::is_data) {
+                        *s = probe;
+                        return Ok(Reference::Named(path));
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(match self.term_arg(frame, s)? {
+            Object::Reference(reference) => reference,
+            value => Reference::Value(Box::new(value)),
+        })
+    }
+
+    fn read(&mut self, frame: &mut Frame, reference: &Reference) -> Result<Object, AmlError> {
+        match reference {
+            Reference::Null => Ok(Object::Uninitialized),
+            Reference::Debug => Ok(Object::Reference(Reference::Debug)),
+            Reference::Local(local) => Ok(frame.locals[*local].clone()),
+            Reference::Arg(arg) => Ok(frame.args[*arg].clone()),
+            Reference::Named(path) => self.read_named(frame, path),
+            Reference::Element(container, index) => match self.read(frame, container)? {
+                Object::Package(mut elements) => {
+                    if *index >= elements.len() {
+                        return Err(AmlError::IndexOutOfRange);
+                    }
+                    Ok(elements.swap_remove(*index))
+                }
+                Object::Buffer(bytes) => bytes
+                    .get(*index)
+                    .map(|&byte| Object::Integer(u64::from(byte)))
+                    .ok_or(AmlError::IndexOutOfRange),
+                Object::String(string) => string
+                    .as_bytes()
+                    .get(*index)
+                    .map(|&byte| Object::Integer(u64::from(byte)))
+                    .ok_or(AmlError::IndexOutOfRange),
+                Object::Reference(inner) => {
+                    self.read(frame, &Reference::Element(Box::new(inner), *index))
+                }
+                _ => Err(AmlError::TypeMismatch("a package, buffer or string")),
+            },
+            Reference::Value(value) => Ok((**value).clone()),
+        }
+    }
+
+    /// Reads a named object. Fields are read from the hardware, and objects that are not data
+    /// give a reference to themselves.
+    fn read_named(&mut self, frame: &mut Frame, path: &Path) -> Result<Object, AmlError> {
+        let path = self.resolve_alias(path);
+        match self.namespace.get(&path) {
+            None => Err(AmlError::UndefinedName(format!("{}", path))),
+            Some(Object::FieldUnit(field)) => {
+                let field = field.clone();
+                self.read_field(&field)
+            }
+            Some(Object::BufferField(field)) => {
+                let field = field.clone();
+                let bytes = self
+                    .read(frame, &field.source)?
+                    .as_buffer(self.integer_bytes())?;
+                if field.bit_length as u64 <= self.integer_bits() {
+                    return Ok(Object::Integer(get_bits(
+                        &bytes,
+                        field.bit_offset,
+                        field.bit_length,
+                    )));
+                }
+                let mut value = vec![0; field.bit_length.div_ceil(8)];
+                for chunk in (0..field.bit_length).step_by(64) {
+                    let count = (field.bit_length - chunk).min(64);
+                    let bits = get_bits(&bytes, field.bit_offset + chunk, count);
+                    set_bits(&mut value, chunk, count, bits);
+                }
+                Ok(Object::Buffer(value))
+            }
+            Some(object) if object.is_data() => Ok(object.clone()),
+            Some(_) => Ok(Object::Reference(Reference::Named(path))),
+        }
+    }
+
+    /// Returns the object a reference points at, for writing to.
+    fn object_mut<'a>(
+        &'a mut self,
+        frame: &'a mut Frame,
+        reference: &Reference,
+    ) -> Result<&'a mut Object, AmlError> {
+        match reference {
+            Reference::Local(local) => Ok(&mut frame.locals[*local]),
+            Reference::Arg(arg) => Ok(&mut frame.args[*arg]),
+            Reference::Named(path) => {
+                let path = self.resolve_alias(path);
+                self.namespace
+                    .get_mut(&path)
+                    .ok_or_else(|| AmlError::UndefinedName(format!("{}", path)))
+            }
+            Reference::Element(container, index) => {
+                let inner = match self.object_mut(frame, container)? {
+                    Object::Reference(inner) => Some(inner.clone()),
+                    _ => None,
+                };
+                if let Some(inner) = inner {
+                    return self.object_mut(frame, &Reference::Element(Box::new(inner), *index));
+                }
+                match self.object_mut(frame, container)? {
+                    Object::Package(elements) => {
+                        elements.get_mut(*index).ok_or(AmlError::IndexOutOfRange)
+                    }
+                    _ => Err(AmlError::TypeMismatch("a package")),
+                }
+            }
+            Reference::Null | Reference::Debug | Reference::Value(_) => {
+                Err(AmlError::TypeMismatch("an object that can be written"))
+            }
+        }
+    }
+
+    /// Stores a value, converting it to the type of a named target.
+    fn store(
+        &mut self,
+        frame: &mut Frame,
+        value: Object,
+        target: &Reference,
+    ) -> Result<(), AmlError> {
+        match target {
+            Reference::Null | Reference::Value(_) => Ok(()),
+            Reference::Debug => {
+                debug!("acpi: debug: {}", value);
+                Ok(())
+            }
+            Reference::Local(local) => {
+                frame.locals[*local] = value;
+                Ok(())
+            }
+            Reference::Arg(arg) => {
+                // An argument passed by reference is written through.
+                if let Object::Reference(reference @ Reference::Named(_)) = &frame.args[*arg] {
+                    let reference = reference.clone();
+                    return self.store(frame, value, &reference);
+                }
+                frame.args[*arg] = value;
+                Ok(())
+            }
+            Reference::Named(path) => self.write_named(frame, path, value),
+            Reference::Element(container, index) => {
+                let byte = match &value {
+                    Object::Integer(_) | Object::Buffer(_) | Object::String(_) => {
+                        Some(value.as_integer()? as u8)
+                    }
+                    _ => None,
+                };
+                let inner = match self.object_mut(frame, container)? {
+                    Object::Reference(inner) => Some(inner.clone()),
+                    _ => None,
+                };
+                if let Some(inner) = inner {
+                    let target = Reference::Element(Box::new(inner), *index);
+                    return self.store(frame, value, &target);
+                }
+                match self.object_mut(frame, container)? {
+                    Object::Package(elements) => {
+                        *elements.get_mut(*index).ok_or(AmlError::IndexOutOfRange)? = value;
+                    }
+                    Object::Buffer(bytes) => {
+                        let byte = byte.ok_or(AmlError::TypeMismatch("an integer"))?;
+                        *bytes.get_mut(*index).ok_or(AmlError::IndexOutOfRange)? = byte;
+                    }
+                    Object::String(string) => {
+                        let byte = byte
+                            .filter(u8::is_ascii)
+                            .ok_or(AmlError::TypeMismatch("a character"))?;
+                        let mut bytes = mem::take(string).into_bytes();
+                        let result = match bytes.get_mut(*index) {
+                            Some(slot) => {
+                                *slot = byte;
+                                Ok(())
+                            }
+                            None => Err(AmlError::IndexOutOfRange),
+                        };
+                        *string = String::from_utf8(bytes).unwrap_or_default();
+                        result?;
+                    }
+                    _ => return Err(AmlError::TypeMismatch("a package, buffer or string")),
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn write_named(
+        &mut self,
+        frame: &mut Frame,
+        path: &Path,
+        value: Object,
+    ) -> Result<(), AmlError> {
+        let path = self.resolve_alias(path);
+        let integer_bytes = self.integer_bytes();
+        let value = match self.namespace.get(&path) {
+            None => return Err(AmlError::UndefinedName(format!("{}", path))),
+            Some(Object::FieldUnit(field)) => {
+                let field = field.clone();
+                return self.write_field(&field, &value);
+            }
+            Some(Object::BufferField(field)) => {
+                let field = field.clone();
+                let mut bytes = self.read(frame, &field.source)?.as_buffer(integer_bytes)?;
+                let data = value.as_buffer(integer_bytes)?;
+                for chunk in (0..field.bit_length).step_by(64) {
+                    let count = (field.bit_length - chunk).min(64);
+                    set_bits(
+                        &mut bytes,
+                        field.bit_offset + chunk,
+                        count,
+                        get_bits(&data, chunk, count),
+                    );
+                }
+                return self.store(frame, Object::Buffer(bytes), &field.source);
+            }
+            Some(Object::Integer(_)) => Object::Integer(self.truncate(value.as_integer()?)),
+            Some(Object::String(_)) => Object::String(value.as_string()?),
+            Some(Object::Buffer(old)) => {
+                // A buffer keeps its length.
+                let len = old.len();
+                let mut bytes = value.as_buffer(integer_bytes)?;
+                bytes.resize(len, 0);
+                Object::Buffer(bytes)
+            }
+            Some(object) if object.is_data() => value,
+            Some(_) => return Err(AmlError::TypeMismatch("a data object")),
+        };
+        self.namespace.insert(path, value);
+        Ok(())
+    }
+
+    /// Replaces the target with the value as it is, without converting it.
+    fn copy_object(
+        &mut self,
+        frame: &mut Frame,
+        value: Object,
+        target: &Reference,
+    ) -> Result<(), AmlError> {
+        match target {
+            Reference::Local(local) => frame.locals[*local] = value,
+            Reference::Arg(arg) => frame.args[*arg] = value,
+            Reference::Named(path) => {
+                let path = self.resolve_alias(path);
+                match self.namespace.get(&path) {
+                    Some(Object::FieldUnit(_) | Object::BufferField(_)) => {
+                        return self.store(frame, value, target)
+                    }
+                    _ => {
+                        self.namespace.insert(path, value);
+                    }
+                }
+            }
+            _ => return self.store(frame, value, target),
+        }
+        Ok(())
+    }
+
+    fn compare(&self, a: &Object, b: &Object) -> Result<Ordering, AmlError> {
+        Ok(match a {
+            Object::Integer(a) => a.cmp(&self.truncate(b.as_integer()?)),
+            Object::String(a) => a.as_bytes().cmp(b.as_string()?.as_bytes()),
+            Object::Buffer(a) => a
+                .as_slice()
+                .cmp(b.as_buffer(self.integer_bytes())?.as_slice()),
+            _ => return Err(AmlError::TypeMismatch("an integer, string or buffer")),
+        })
+    }
+}
@@ -0,0 +1,374 @@
+//! This module runs the AML code in the DSDT and SSDTs, which describes the devices ACPI knows
+//! about and has the methods that manage them.
+//!
+//! Loading a table runs its code in the root scope, which builds the namespace: the devices,
+//! the methods, the data and the fields that make up the machine's description. Method bodies
+//! are kept as bytes and only run when they are invoked, with [`evaluate`]. Fields in system
+//! memory, I/O ports and PCI configuration space can be read and written, and the other
+//! address spaces are not supported.
+//!
+//! There is only ever one thread running AML, so mutexes are always acquired at once and
+//! waiting on an event that has not been signalled times out right away.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+mod interpreter;
+mod name;
+mod object;
+mod opcodes;
+mod region;
+
+pub use interpreter::Interpreter;
+pub use name::Path;
+pub use object::{Method, MethodBody, Object, Reference};
+
+use super::AddressSpace;
+use crate::{info, println, shell::CommandError, shell_command, sync::Mutex, warn};
+
+/// What `\_OS` says the operating system is. Firmware is only tested with Windows, so this is
+/// what gets the best behaved code paths.
+const OS_NAME: &str = "Microsoft Windows NT";
+/// What `\_REV` says the ACPI revision is.
+const OS_REVISION: u64 = 2;
+
+/// The interfaces `\_OSI` says are supported, which are the Windows versions since XP.
+const INTERFACES: [&str; 12] = [
+    "Windows 2001",
+    "Windows 2001 SP1",
+    "Windows 2001 SP2",
+    "Windows 2006",
+    "Windows 2006 SP1",
+    "Windows 2009",
+    "Windows 2012",
+    "Windows 2013",
+    "Windows 2015",
+    "Module Device",
+    "Processor Device",
+    "3.0 Thermal Model",
+];
+
+#[derive(Clone, Debug)]
+pub enum AmlError {
+    /// The code ends in the middle of a term.
+    UnexpectedEnd,
+    UnknownOpcode(u8),
+    UnknownExtendedOpcode(u8),
+    InvalidName,
+    UndefinedName(String),
+    /// A method defined a name that already exists.
+    AlreadyDefined(String),
+    /// An operand is not what the operator needs.
+    TypeMismatch(&'static str),
+    IndexOutOfRange,
+    DivideByZero,
+    /// A field reaches past the end of its region.
+    OutOfRange,
+    UnsupportedSpace(AddressSpace),
+    Unsupported(&'static str),
+    /// Methods called each other too deeply.
+    TooDeep,
+    /// A loop went round too many times.
+    Timeout,
+    /// A `Break` or `Continue` outside of a loop.
+    MisplacedBreak,
+    /// The firmware reported an error it cannot recover from.
+    Fatal {
+        kind: u8,
+        code: u32,
+        argument: u64,
+    },
+    /// There are no tables loaded.
+    NotLoaded,
+}
+
+impl fmt::Display for AmlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmlError::UnexpectedEnd => f.write_str("unexpected end of code"),
+            AmlError::UnknownOpcode(opcode) => write!(f, "unknown opcode {:#04x}", opcode),
+            AmlError::UnknownExtendedOpcode(opcode) => {
+                write!(f, "unknown opcode 0x5b {:#04x}", opcode)
+            }
+            AmlError::InvalidName => f.write_str("invalid name"),
+            AmlError::UndefinedName(name) => write!(f, "{} is not defined", name),
+            AmlError::AlreadyDefined(name) => write!(f, "{} is already defined", name),
+            AmlError::TypeMismatch(expected) => write!(f, "expected {}", expected),
+            AmlError::IndexOutOfRange => f.write_str("index out of range"),
+            AmlError::DivideByZero => f.write_str("division by zero"),
+            AmlError::OutOfRange => f.write_str("field out of its region"),
+            AmlError::UnsupportedSpace(space) => {
+                write!(f, "unsupported address space {:?}", space)
+            }
+            AmlError::Unsupported(what) => write!(f, "{} is not supported", what),
+            AmlError::TooDeep => f.write_str("methods nested too deeply"),
+            AmlError::Timeout => f.write_str("loop did not finish"),
+            AmlError::MisplacedBreak => f.write_str("break outside of a loop"),
+            AmlError::Fatal {
+                kind,
+                code,
+                argument,
+            } => write!(
+                f,
+                "fatal error type {:#x}, code {:#x}, argument {:#x}",
+                kind, code, argument
+            ),
+            AmlError::NotLoaded => f.write_str("no AML loaded"),
+        }
+    }
+}
+
+/// What `_STA` says about a device.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub present: bool,
+    pub enabled: bool,
+    pub shown: bool,
+    pub functioning: bool,
+}
+
+impl Status {
+    fn from_bits(bits: u64) -> Status {
+        Status {
+            present: bits & 1 << 0 != 0,
+            enabled: bits & 1 << 1 != 0,
+            shown: bits & 1 << 2 != 0,
+            functioning: bits & 1 << 3 != 0,
+        }
+    }
+}
+
+/// Where the interrupt of a PCI slot's pin goes, from a `_PRT` entry.
+#[derive(Clone, Debug)]
+pub struct PciRoute {
+    pub device: u8,
+    /// The pin, from 0 for INTA to 3 for INTD.
+    pub pin: u8,
+    /// The link device the pin is wired to, or `None` if it is wired straight to a GSI.
+    pub source: Option<Path>,
+    /// The GSI if there is no link device, and which of its interrupts otherwise.
+    pub index: u32,
+}
+
+/// Held for as long as a method runs, which can be a while, so it is a sleeping lock.
+static INTERPRETER: Mutex<Option<Interpreter>> = Mutex::named("aml", None);
+
+fn osi(args: &[Object]) -> Result<Object, AmlError> {
+    let interface = args
+        .first()
+        .ok_or(AmlError::TypeMismatch("a string"))?
+        .as_string()?;
+    let supported = INTERFACES.contains(&interface.as_str());
+    Ok(Object::Integer(if supported { u64::MAX } else { 0 }))
+}
+
+/// Adds the objects every namespace starts with.
+fn add_predefined(interpreter: &mut Interpreter) {
+    for scope in [b"_GPE", b"_PR_", b"_SB_", b"_SI_", b"_TZ_"] {
+        interpreter.insert(Path::root().join(*scope), Object::Scope);
+    }
+    interpreter.insert(
+        Path::root().join(*b"_OS_"),
+        Object::String(String::from(OS_NAME)),
+    );
+    interpreter.insert(Path::root().join(*b"_REV"), Object::Integer(OS_REVISION));
+    interpreter.insert(Path::root().join(*b"_GL_"), Object::Mutex { sync_level: 0 });
+    let osi = Method {
+        args: 1,
+        serialized: false,
+        body: MethodBody::Native(osi),
+    };
+    interpreter.insert(Path::root().join(*b"_OSI"), Object::Method(osi));
+}
+
+fn status_of(interpreter: &mut Interpreter, device: &Path) -> Result<Status, AmlError> {
+    // Devices without `_STA` are there and working.
+    let bits = interpreter
+        .evaluate_integer(device, b"_STA")?
+        .unwrap_or(0xF);
+    Ok(Status::from_bits(bits))
+}
+
+/// Runs `_INI` below `scope`, if there is one.
+fn run_ini(interpreter: &mut Interpreter, scope: &Path) {
+    let ini = scope.join(*b"_INI");
+    if interpreter.get(&ini).is_some() {
+        if let Err(error) = interpreter.evaluate(&ini, Vec::new()) {
+            warn!("acpi: {} failed: {}", ini, error);
+        }
+    }
+}
+
+/// Runs `_INI` on every device below `scope` that is present. The children of a device that
+/// is not present are skipped too, unless it says it is functioning anyway.
+fn initialize_devices(interpreter: &mut Interpreter, scope: &Path) {
+    for child in interpreter.children(scope) {
+        match interpreter.get(&child) {
+            Some(Object::Device | Object::Processor { .. }) => {
+                let status = match status_of(interpreter, &child) {
+                    Ok(status) => status,
+                    Err(error) => {
+                        warn!("acpi: {}._STA failed: {}", child, error);
+                        continue;
+                    }
+                };
+                if status.present {
+                    run_ini(interpreter, &child);
+                }
+                if status.present || status.functioning {
+                    initialize_devices(interpreter, &child);
+                }
+            }
+            Some(Object::Scope | Object::ThermalZone | Object::PowerResource { .. }) => {
+                initialize_devices(interpreter, &child);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Loads the DSDT and the SSDTs, and initializes the devices they describe.
+pub fn initialize() {
+    let Some(acpi) = super::get() else {
+        return;
+    };
+    let Some(dsdt) = acpi.table(b"DSDT") else {
+        warn!("acpi: no DSDT");
+        return;
+    };
+    let mut interpreter = Interpreter::new(dsdt.revision);
+    add_predefined(&mut interpreter);
+    let ssdts = acpi
+        .tables
+        .iter()
+        .filter(|table| &table.signature == b"SSDT");
+    for table in [dsdt].into_iter().chain(ssdts) {
+        if let Err(error) = interpreter.load(table.body()) {
+            warn!(
+                "acpi: error loading the {} at {:#x}: {}",
+                table.signature(),
+                table.address,
+                error
+            );
+        }
+    }
+    let sb = Path::root().join(*b"_SB_");
+    run_ini(&mut interpreter, &sb);
+    initialize_devices(&mut interpreter, &Path::root());
+    info!("acpi: {} objects in the namespace", interpreter.len());
+    *INTERPRETER.lock() = Some(interpreter);
+}
+
+/// Evaluates the object at an absolute path like `\_SB.PCI0._PRT`, invoking it with `args` if
+/// it is a method.
+pub fn evaluate(path: &str, args: Vec<Object>) -> Result<Object, AmlError> {
+    let path = Path::parse(path).ok_or(AmlError::InvalidName)?;
+    INTERPRETER
+        .lock()
+        .as_mut()
+        .ok_or(AmlError::NotLoaded)?
+        .evaluate(&path, args)
+}
+
+/// Returns the status of the device at `path`.
+pub fn status(path: &str) -> Result<Status, AmlError> {
+    let path = Path::parse(path).ok_or(AmlError::InvalidName)?;
+    status_of(
+        INTERPRETER.lock().as_mut().ok_or(AmlError::NotLoaded)?,
+        &path,
+    )
+}
+
+/// Returns where the interrupts of the slots behind the PCI bridge at `bridge` are routed to,
+/// from its `_PRT`.
+pub fn pci_routing(bridge: &str) -> Result<Vec<PciRoute>, AmlError> {
+    let prt = alloc::format!("{}._PRT", bridge);
+    let Object::Package(entries) = evaluate(&prt, Vec::new())? else {
+        return Err(AmlError::TypeMismatch("a package"));
+    };
+    entries
+        .iter()
+        .map(|entry| {
+            let Object::Package(fields) = entry else {
+                return Err(AmlError::TypeMismatch("a package"));
+            };
+            let [address, pin, source, index] = fields.as_slice() else {
+                return Err(AmlError::IndexOutOfRange);
+            };
+            let source = match source {
+                Object::Reference(Reference::Named(path)) => Some(path.clone()),
+                Object::String(path) => Some(Path::parse(path).ok_or(AmlError::InvalidName)?),
+                _ => None,
+            };
+            Ok(PciRoute {
+                device: (address.as_integer()? >> 16) as u8,
+                pin: pin.as_integer()? as u8,
+                source,
+                index: index.as_integer()? as u32,
+            })
+        })
+        .collect()
+}
+
+/// Prints the namespace below `root`, one object per line.
+fn print_tree(root: &Path) -> Result<(), CommandError> {
+    let interpreter = INTERPRETER.lock();
+    let interpreter = interpreter
+        .as_ref()
+        .ok_or(CommandError::Failed("no AML loaded"))?;
+    if interpreter.get(root).is_none() {
+        return Err(CommandError::Failed("no such object"));
+    }
+    for (path, object) in interpreter
+        .objects()
+        .filter(|(path, _)| path.starts_with(root) && !path.is_root())
+    {
+        let indent = 2 * (path.depth() - root.depth());
+        let name = path.name().unwrap_or(*b"____");
+        let name = core::str::from_utf8(&name).unwrap_or("????");
+        match object {
+            Object::Integer(_) | Object::String(_) | Object::Alias(_) | Object::Method(_) => {
+                println!("{:indent$}{} {}", "", name, object)
+            }
+            _ => println!("{:indent$}{} {}", "", name, object.type_name()),
+        }
+    }
+    Ok(())
+}
+
+fn aml_command(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        ["tree"] => print_tree(&Path::root()),
+        ["tree", path] => print_tree(&Path::parse(path).ok_or(CommandError::Usage)?),
+        ["eval", path, args @ ..] => {
+            // Arguments that look like numbers are passed as integers, and the rest as strings.
+            let args = args
+                .iter()
+                .map(|arg| match crate::shell::parse_number(arg) {
+                    Ok(value) => Object::Integer(value as u64),
+                    Err(_) => Object::String(String::from(*arg)),
+                })
+                .collect();
+            match evaluate(path, args) {
+                Ok(Object::Uninitialized) => Ok(()),
+                Ok(value) => {
+                    println!("{}", value);
+                    Ok(())
+                }
+                Err(error) => {
+                    println!("{}", error);
+                    Err(CommandError::Failed("evaluation failed"))
+                }
+            }
+        }
+        _ => Err(CommandError::Usage),
+    }
+}
+
+shell_command!(
+    AML_COMMAND,
+    "aml",
+    "tree [path] | eval <path> [args...]",
+    "Show the ACPI namespace or evaluate an object in it",
+    aml_command
+);
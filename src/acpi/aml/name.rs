@@ -0,0 +1,156 @@
+//! Names and paths in the namespace.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A name segment, four characters padded with underscores.
+pub type Segment = [u8; 4];
+
+/// Returns whether `byte` can start a name segment.
+pub fn is_lead_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte == b'_'
+}
+
+fn is_name_char(byte: u8) -> bool {
+    is_lead_char(byte) || byte.is_ascii_digit()
+}
+
+/// Returns whether `segment` is made of characters a name segment can have.
+pub fn is_segment(segment: &Segment) -> bool {
+    is_lead_char(segment[0]) && segment[1..].iter().all(|&byte| is_name_char(byte))
+}
+
+/// An absolute path in the namespace. The root is the empty path.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Path(Vec<Segment>);
+
+impl Path {
+    pub const fn root() -> Path {
+        Path(Vec::new())
+    }
+
+    /// Parses a path like `\_SB.PCI0`, where the leading backslash is optional and short
+    /// segments are padded with underscores.
+    pub fn parse(path: &str) -> Option<Path> {
+        let path = path.strip_prefix('\\').unwrap_or(path);
+        if path.is_empty() {
+            return Some(Path::root());
+        }
+        path.split('.')
+            .map(|name| {
+                let name = name.as_bytes();
+                if name.is_empty() || name.len() > 4 {
+                    return None;
+                }
+                let mut segment = *b"____";
+                segment[..name.len()].copy_from_slice(name);
+                is_segment(&segment).then_some(segment)
+            })
+            .collect::<Option<_>>()
+            .map(Path)
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.0
+    }
+
+    pub fn depth(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the last segment, or `None` for the root.
+    pub fn name(&self) -> Option<Segment> {
+        self.0.last().copied()
+    }
+
+    pub fn parent(&self) -> Option<Path> {
+        let (_, parent) = self.0.split_last()?;
+        Some(Path(parent.to_vec()))
+    }
+
+    pub fn join(&self, segment: Segment) -> Path {
+        let mut path = self.clone();
+        path.0.push(segment);
+        path
+    }
+
+    /// Returns whether `self` is `ancestor` or lies below it.
+    pub fn starts_with(&self, ancestor: &Path) -> bool {
+        self.0.starts_with(&ancestor.0)
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\\")?;
+        for (i, segment) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            for &byte in segment {
+                write!(f, "{}", char::from(byte))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// A name as the AML spells it, which may be relative to the scope it is used in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameString {
+    /// Whether the name starts at the root.
+    pub root: bool,
+    /// How many scopes to go up before following the segments.
+    pub parents: usize,
+    pub segments: Vec<Segment>,
+}
+
+impl NameString {
+    /// Returns whether looking the name up searches the enclosing scopes as well, which is
+    /// the case for a single segment with no prefix.
+    pub fn searches_parents(&self) -> bool {
+        !self.root && self.parents == 0 && self.segments.len() == 1
+    }
+
+    /// Returns the path the name stands for in `scope`, without searching the scopes above.
+    pub fn resolve(&self, scope: &Path) -> Option<Path> {
+        let mut segments = if self.root {
+            Vec::new()
+        } else {
+            let depth = scope.depth().checked_sub(self.parents)?;
+            scope.0[..depth].to_vec()
+        };
+        segments.extend_from_slice(&self.segments);
+        Some(Path(segments))
+    }
+}
+
+impl fmt::Display for NameString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.root {
+            f.write_str("\\")?;
+        }
+        for _ in 0..self.parents {
+            f.write_str("^")?;
+        }
+        for (i, segment) in self.segments.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            for &byte in segment {
+                write!(f, "{}", char::from(byte))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+//! The objects in the namespace and the values AML computes with.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt;
+
+use super::{name::Path, AmlError};
+use crate::acpi::AddressSpace;
+
+/// What a method runs when it is invoked.
+#[derive(Copy, Clone, Debug)]
+pub enum MethodBody {
+    Aml(&'static [u8]),
+    /// A method the kernel provides itself, like `\_OSI`.
+    Native(fn(&[Object]) -> Result<Object, AmlError>),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Method {
+    pub args: u8,
+    pub serialized: bool,
+    pub body: MethodBody,
+}
+
+/// Where a value is read from or written to.
+#[derive(Clone, Debug)]
+pub enum Reference {
+    /// Discards what is written to it.
+    Null,
+    /// Logs what is written to it.
+    Debug,
+    /// A local of the method running.
+    Local(usize),
+    /// An argument of the method running.
+    Arg(usize),
+    Named(Path),
+    /// An element of a package, or a byte of a buffer or string.
+    Element(Box<Reference>, usize),
+    /// A value with no name, whose changes go nowhere.
+    Value(Box<Object>),
+}
+
+/// The granularity a field is accessed with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Any,
+    Byte,
+    Word,
+    DWord,
+    QWord,
+    Buffer,
+}
+
+/// What the bits of a unit that lie outside a field are written as.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UpdateRule {
+    Preserve,
+    WriteAsOnes,
+    WriteAsZeros,
+}
+
+/// A range of an address space that fields are laid out in.
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub space: AddressSpace,
+    pub offset: u64,
+    pub length: u64,
+    /// The scope the region was declared in, whose `_ADR` says which function a region in PCI
+    /// configuration space belongs to.
+    pub scope: Path,
+}
+
+#[derive(Clone, Debug)]
+pub enum FieldKind {
+    Region(Path),
+    /// Selected by writing `value` to the `bank` field first.
+    Bank {
+        region: Path,
+        bank: Path,
+        value: u64,
+    },
+    /// Reached by writing the offset to the `index` field and going through the `data` one.
+    Index {
+        index: Path,
+        data: Path,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct FieldUnit {
+    pub kind: FieldKind,
+    pub bit_offset: u64,
+    pub bit_length: u64,
+    pub access: Access,
+    pub update: UpdateRule,
+}
+
+impl FieldUnit {
+    /// Returns how many bits each access to the field takes.
+    pub fn access_width(&self) -> u64 {
+        match self.access {
+            Access::Byte => 8,
+            Access::Word => 16,
+            Access::DWord => 32,
+            Access::QWord => 64,
+            // The narrowest access that reaches the whole field at once, if there is one.
+            Access::Any | Access::Buffer => [8, 16, 32]
+                .into_iter()
+                .find(|width| {
+                    self.bit_offset / width
+                        == (self.bit_offset + self.bit_length.max(1) - 1) / width
+                })
+                .unwrap_or(8),
+        }
+    }
+}
+
+/// Bits of a buffer given a name with `CreateField` and friends.
+#[derive(Clone, Debug)]
+pub struct BufferField {
+    pub source: Reference,
+    pub bit_offset: usize,
+    pub bit_length: usize,
+}
+
+#[derive(Clone, Debug)]
+pub enum Object {
+    Uninitialized,
+    Integer(u64),
+    String(String),
+    Buffer(Vec<u8>),
+    Package(Vec<Object>),
+    Reference(Reference),
+    Method(Method),
+    /// A scope that is not a device, like `\_SB`.
+    Scope,
+    Device,
+    Processor {
+        id: u8,
+        block_address: u32,
+        block_length: u8,
+    },
+    PowerResource {
+        system_level: u8,
+        order: u16,
+    },
+    ThermalZone,
+    Mutex {
+        sync_level: u8,
+    },
+    Event {
+        signals: u32,
+    },
+    OperationRegion(Region),
+    FieldUnit(FieldUnit),
+    BufferField(BufferField),
+    /// Another name for the object at the path.
+    Alias(Path),
+}
+
+impl Object {
+    /// Returns the number `ObjectType` gives for the object.
+    pub fn type_code(&self) -> u64 {
+        match self {
+            Object::Uninitialized | Object::Scope | Object::Alias(_) => 0,
+            Object::Integer(_) => 1,
+            Object::String(_) => 2,
+            Object::Buffer(_) => 3,
+            Object::Package(_) => 4,
+            Object::FieldUnit(_) => 5,
+            Object::Device => 6,
+            Object::Event { .. } => 7,
+            Object::Method(_) => 8,
+            Object::Mutex { .. } => 9,
+            Object::OperationRegion(_) => 10,
+            Object::PowerResource { .. } => 11,
+            Object::Processor { .. } => 12,
+            Object::ThermalZone => 13,
+            Object::BufferField(_) => 14,
+            Object::Reference(Reference::Debug) => 16,
+            Object::Reference(_) => 20,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::Uninitialized => "Uninitialized",
+            Object::Integer(_) => "Integer",
+            Object::String(_) => "String",
+            Object::Buffer(_) => "Buffer",
+            Object::Package(_) => "Package",
+            Object::Reference(_) => "Reference",
+            Object::Method(_) => "Method",
+            Object::Scope => "Scope",
+            Object::Device => "Device",
+            Object::Processor { .. } => "Processor",
+            Object::PowerResource { .. } => "PowerResource",
+            Object::ThermalZone => "ThermalZone",
+            Object::Mutex { .. } => "Mutex",
+            Object::Event { .. } => "Event",
+            Object::OperationRegion(_) => "OperationRegion",
+            Object::FieldUnit(_) => "FieldUnit",
+            Object::BufferField(_) => "BufferField",
+            Object::Alias(_) => "Alias",
+        }
+    }
+
+    /// Returns whether the object holds data, as opposed to being a device, a method or the
+    /// like.
+    pub fn is_data(&self) -> bool {
+        matches!(
+            self,
+            Object::Uninitialized
+                | Object::Integer(_)
+                | Object::String(_)
+                | Object::Buffer(_)
+                | Object::Package(_)
+                | Object::Reference(_)
+        )
+    }
+
+    /// Converts the object to an integer the way operators do with their operands.
+    pub fn as_integer(&self) -> Result<u64, AmlError> {
+        match self {
+            Object::Integer(value) => Ok(*value),
+            Object::Buffer(bytes) => Ok(get_bits(bytes, 0, (bytes.len() * 8).min(64))),
+            // Strings are taken to be hexadecimal, up to the first character that is not.
+            Object::String(string) => Ok(string
+                .trim_start_matches("0x")
+                .chars()
+                .map_while(|c| c.to_digit(16))
+                .take(16)
+                .fold(0, |value, digit| value << 4 | u64::from(digit))),
+            _ => Err(AmlError::TypeMismatch("an integer")),
+        }
+    }
+
+    /// Converts the object to a string the way operators do with their operands.
+    pub fn as_string(&self) -> Result<String, AmlError> {
+        use core::fmt::Write;
+        match self {
+            Object::String(string) => Ok(string.clone()),
+            Object::Integer(value) => Ok(alloc::format!("{:X}", value)),
+            Object::Buffer(bytes) => {
+                let mut string = String::new();
+                for (i, byte) in bytes.iter().enumerate() {
+                    let separator = if i > 0 { " " } else { "" };
+                    let _ = write!(string, "{}{:02X}", separator, byte);
+                }
+                Ok(string)
+            }
+            _ => Err(AmlError::TypeMismatch("a string")),
+        }
+    }
+
+    /// Converts the object to a buffer the way operators do with their operands, with
+    /// integers taking `integer_bytes` bytes.
+    pub fn as_buffer(&self, integer_bytes: usize) -> Result<Vec<u8>, AmlError> {
+        match self {
+            Object::Buffer(bytes) => Ok(bytes.clone()),
+            Object::Integer(value) => Ok(value.to_le_bytes()[..integer_bytes].to_vec()),
+            Object::String(string) => {
+                let mut bytes = string.as_bytes().to_vec();
+                bytes.push(0);
+                Ok(bytes)
+            }
+            _ => Err(AmlError::TypeMismatch("a buffer")),
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Object::Integer(value) => write!(f, "{:#x}", value),
+            Object::String(string) => write!(f, "{:?}", string),
+            Object::Buffer(bytes) => {
+                f.write_str("Buffer {")?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    write!(f, "{}{:02x}", if i > 0 { " " } else { "" }, byte)?;
+                }
+                f.write_str("}")
+            }
+            Object::Package(elements) => {
+                f.write_str("Package {")?;
+                for (i, element) in elements.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { ", " } else { "" }, element)?;
+                }
+                f.write_str("}")
+            }
+            Object::Reference(Reference::Named(path)) => write!(f, "{}", path),
+            Object::Method(method) => write!(f, "Method({})", method.args),
+            Object::Alias(path) => write!(f, "Alias({})", path),
+            other => f.write_str(other.type_name()),
+        }
+    }
+}
+
+pub fn mask(bits: u64) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+/// Reads up to 64 bits out of a little-endian bit string. Bits past its end read as zero.
+pub fn get_bits(bytes: &[u8], offset: usize, count: usize) -> u64 {
+    (0..count).fold(0, |value, i| {
+        let bit = offset + i;
+        let set = bytes
+            .get(bit / 8)
+            .is_some_and(|byte| byte >> (bit % 8) & 1 != 0);
+        value | u64::from(set) << i
+    })
+}
+
+/// Writes up to 64 bits into a little-endian bit string. Bits past its end are dropped.
+pub fn set_bits(bytes: &mut [u8], offset: usize, count: usize, value: u64) {
+    for i in 0..count {
+        let bit = offset + i;
+        if let Some(byte) = bytes.get_mut(bit / 8) {
+            if value >> i & 1 != 0 {
+                *byte |= 1 << (bit % 8);
+            } else {
+                *byte &= !(1 << (bit % 8));
+            }
+        }
+    }
+}
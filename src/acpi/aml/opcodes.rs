@@ -0,0 +1,117 @@
+//! The opcodes and prefixes of the AML encoding.
+
+pub const ZERO: u8 = 0x00;
+pub const ONE: u8 = 0x01;
+pub const ALIAS: u8 = 0x06;
+pub const NAME: u8 = 0x08;
+pub const BYTE_PREFIX: u8 = 0x0A;
+pub const WORD_PREFIX: u8 = 0x0B;
+pub const DWORD_PREFIX: u8 = 0x0C;
+pub const STRING_PREFIX: u8 = 0x0D;
+pub const QWORD_PREFIX: u8 = 0x0E;
+pub const SCOPE: u8 = 0x10;
+pub const BUFFER: u8 = 0x11;
+pub const PACKAGE: u8 = 0x12;
+pub const VAR_PACKAGE: u8 = 0x13;
+pub const METHOD: u8 = 0x14;
+pub const EXTERNAL: u8 = 0x15;
+pub const DUAL_NAME_PREFIX: u8 = 0x2E;
+pub const MULTI_NAME_PREFIX: u8 = 0x2F;
+pub const EXT_PREFIX: u8 = 0x5B;
+pub const ROOT_CHAR: u8 = b'\\';
+pub const PARENT_PREFIX: u8 = b'^';
+pub const LOCAL0: u8 = 0x60;
+pub const LOCAL7: u8 = 0x67;
+pub const ARG0: u8 = 0x68;
+pub const ARG6: u8 = 0x6E;
+pub const STORE: u8 = 0x70;
+pub const REF_OF: u8 = 0x71;
+pub const ADD: u8 = 0x72;
+pub const CONCAT: u8 = 0x73;
+pub const SUBTRACT: u8 = 0x74;
+pub const INCREMENT: u8 = 0x75;
+pub const DECREMENT: u8 = 0x76;
+pub const MULTIPLY: u8 = 0x77;
+pub const DIVIDE: u8 = 0x78;
+pub const SHIFT_LEFT: u8 = 0x79;
+pub const SHIFT_RIGHT: u8 = 0x7A;
+pub const AND: u8 = 0x7B;
+pub const NAND: u8 = 0x7C;
+pub const OR: u8 = 0x7D;
+pub const NOR: u8 = 0x7E;
+pub const XOR: u8 = 0x7F;
+pub const NOT: u8 = 0x80;
+pub const FIND_SET_LEFT_BIT: u8 = 0x81;
+pub const FIND_SET_RIGHT_BIT: u8 = 0x82;
+pub const DEREF_OF: u8 = 0x83;
+pub const CONCAT_RES: u8 = 0x84;
+pub const MOD: u8 = 0x85;
+pub const NOTIFY: u8 = 0x86;
+pub const SIZE_OF: u8 = 0x87;
+pub const INDEX: u8 = 0x88;
+pub const MATCH: u8 = 0x89;
+pub const CREATE_DWORD_FIELD: u8 = 0x8A;
+pub const CREATE_WORD_FIELD: u8 = 0x8B;
+pub const CREATE_BYTE_FIELD: u8 = 0x8C;
+pub const CREATE_BIT_FIELD: u8 = 0x8D;
+pub const OBJECT_TYPE: u8 = 0x8E;
+pub const CREATE_QWORD_FIELD: u8 = 0x8F;
+pub const LAND: u8 = 0x90;
+pub const LOR: u8 = 0x91;
+pub const LNOT: u8 = 0x92;
+pub const LEQUAL: u8 = 0x93;
+pub const LGREATER: u8 = 0x94;
+pub const LLESS: u8 = 0x95;
+pub const TO_BUFFER: u8 = 0x96;
+pub const TO_DECIMAL_STRING: u8 = 0x97;
+pub const TO_HEX_STRING: u8 = 0x98;
+pub const TO_INTEGER: u8 = 0x99;
+pub const TO_STRING: u8 = 0x9C;
+pub const COPY_OBJECT: u8 = 0x9D;
+pub const MID: u8 = 0x9E;
+pub const CONTINUE: u8 = 0x9F;
+pub const IF: u8 = 0xA0;
+pub const ELSE: u8 = 0xA1;
+pub const WHILE: u8 = 0xA2;
+pub const NOOP: u8 = 0xA3;
+pub const RETURN: u8 = 0xA4;
+pub const BREAK: u8 = 0xA5;
+pub const BREAK_POINT: u8 = 0xCC;
+pub const ONES: u8 = 0xFF;
+
+// These follow `EXT_PREFIX`.
+pub const MUTEX: u8 = 0x01;
+pub const EVENT: u8 = 0x02;
+pub const COND_REF_OF: u8 = 0x12;
+pub const CREATE_FIELD: u8 = 0x13;
+pub const LOAD_TABLE: u8 = 0x1F;
+pub const LOAD: u8 = 0x20;
+pub const STALL: u8 = 0x21;
+pub const SLEEP: u8 = 0x22;
+pub const ACQUIRE: u8 = 0x23;
+pub const SIGNAL: u8 = 0x24;
+pub const WAIT: u8 = 0x25;
+pub const RESET: u8 = 0x26;
+pub const RELEASE: u8 = 0x27;
+pub const FROM_BCD: u8 = 0x28;
+pub const TO_BCD: u8 = 0x29;
+pub const UNLOAD: u8 = 0x2A;
+pub const REVISION: u8 = 0x30;
+pub const DEBUG: u8 = 0x31;
+pub const FATAL: u8 = 0x32;
+pub const TIMER: u8 = 0x33;
+pub const OP_REGION: u8 = 0x80;
+pub const FIELD: u8 = 0x81;
+pub const DEVICE: u8 = 0x82;
+pub const PROCESSOR: u8 = 0x83;
+pub const POWER_RES: u8 = 0x84;
+pub const THERMAL_ZONE: u8 = 0x85;
+pub const INDEX_FIELD: u8 = 0x86;
+pub const BANK_FIELD: u8 = 0x87;
+pub const DATA_REGION: u8 = 0x88;
+
+// These start the entries of a field list.
+pub const RESERVED_FIELD: u8 = 0x00;
+pub const ACCESS_FIELD: u8 = 0x01;
+pub const CONNECT_FIELD: u8 = 0x02;
+pub const EXTENDED_ACCESS_FIELD: u8 = 0x03;
@@ -0,0 +1,277 @@
+//! Reading and writing fields, which goes through their operation regions to the hardware.
+//!
+//! A field is accessed one unit at a time, each as wide as its access type says and aligned to
+//! that width. The bits of a unit that lie outside the field are kept, or written as ones or
+//! zeros, as the field's update rule says.
+
+use alloc::{format, vec};
+use core::ptr;
+
+use super::{
+    interpreter::Interpreter,
+    name::Path,
+    object::{get_bits, mask, set_bits, FieldKind, FieldUnit, Object, Region, UpdateRule},
+    AmlError,
+};
+use crate::{
+    acpi::AddressSpace,
+    drivers::pci::{self, config},
+    intrinsics::{inb, inl, inw, outb, outl, outw},
+};
+
+impl Interpreter {
+    pub(super) fn read_field(&mut self, field: &FieldUnit) -> Result<Object, AmlError> {
+        let width = field.access_width();
+        let mut bytes = vec![0; field.bit_length.div_ceil(8) as usize];
+        let mut bit = 0;
+        while bit < field.bit_length {
+            let position = field.bit_offset + bit;
+            let unit = position / width * width;
+            let shift = position - unit;
+            let count = (width - shift).min(field.bit_length - bit);
+            let value = self.read_unit(field, unit / 8, width)? >> shift & mask(count);
+            set_bits(&mut bytes, bit as usize, count as usize, value);
+            bit += count;
+        }
+        if field.bit_length <= self.integer_bits() {
+            Ok(Object::Integer(get_bits(
+                &bytes,
+                0,
+                field.bit_length as usize,
+            )))
+        } else {
+            Ok(Object::Buffer(bytes))
+        }
+    }
+
+    pub(super) fn write_field(
+        &mut self,
+        field: &FieldUnit,
+        value: &Object,
+    ) -> Result<(), AmlError> {
+        let width = field.access_width();
+        let bytes = value.as_buffer(self.integer_bytes())?;
+        let mut bit = 0;
+        while bit < field.bit_length {
+            let position = field.bit_offset + bit;
+            let unit = position / width * width;
+            let shift = position - unit;
+            let count = (width - shift).min(field.bit_length - bit);
+            let bits = get_bits(&bytes, bit as usize, count as usize);
+            let others = if count == width {
+                0
+            } else {
+                match field.update {
+                    UpdateRule::Preserve => self.read_unit(field, unit / 8, width)?,
+                    UpdateRule::WriteAsOnes => u64::MAX,
+                    UpdateRule::WriteAsZeros => 0,
+                }
+            };
+            let value = others & !(mask(count) << shift) | bits << shift;
+            self.write_unit(field, unit / 8, width, value & mask(width))?;
+            bit += count;
+        }
+        Ok(())
+    }
+
+    fn named_field(&self, path: &Path) -> Result<FieldUnit, AmlError> {
+        match self.get(path) {
+            Some(Object::FieldUnit(field)) => Ok(field.clone()),
+            Some(_) => Err(AmlError::TypeMismatch("a field")),
+            None => Err(AmlError::UndefinedName(format!("{}", path))),
+        }
+    }
+
+    fn region(&self, path: &Path) -> Result<Region, AmlError> {
+        match self.get(path) {
+            Some(Object::OperationRegion(region)) => Ok(region.clone()),
+            Some(_) => Err(AmlError::TypeMismatch("an operation region")),
+            None => Err(AmlError::UndefinedName(format!("{}", path))),
+        }
+    }
+
+    /// Reads the unit `offset` bytes into a field's region.
+    fn read_unit(&mut self, field: &FieldUnit, offset: u64, width: u64) -> Result<u64, AmlError> {
+        match &field.kind {
+            FieldKind::Region(region) => {
+                let region = self.region(region)?;
+                self.read_region(&region, offset, width)
+            }
+            FieldKind::Bank {
+                region,
+                bank,
+                value,
+            } => {
+                let bank = self.named_field(bank)?;
+                self.write_field(&bank, &Object::Integer(*value))?;
+                let region = self.region(region)?;
+                self.read_region(&region, offset, width)
+            }
+            FieldKind::Index { index, data } => {
+                let (index, data) = (self.named_field(index)?, self.named_field(data)?);
+                self.write_field(&index, &Object::Integer(offset))?;
+                self.read_field(&data)?.as_integer()
+            }
+        }
+    }
+
+    fn write_unit(
+        &mut self,
+        field: &FieldUnit,
+        offset: u64,
+        width: u64,
+        value: u64,
+    ) -> Result<(), AmlError> {
+        match &field.kind {
+            FieldKind::Region(region) => {
+                let region = self.region(region)?;
+                self.write_region(&region, offset, width, value)
+            }
+            FieldKind::Bank {
+                region,
+                bank,
+                value: bank_value,
+            } => {
+                let bank = self.named_field(bank)?;
+                self.write_field(&bank, &Object::Integer(*bank_value))?;
+                let region = self.region(region)?;
+                self.write_region(&region, offset, width, value)
+            }
+            FieldKind::Index { index, data } => {
+                let (index, data) = (self.named_field(index)?, self.named_field(data)?);
+                self.write_field(&index, &Object::Integer(offset))?;
+                self.write_field(&data, &Object::Integer(value))
+            }
+        }
+    }
+
+    /// Returns the address of a unit in a region, checking that the unit lies inside it.
+    fn unit_address(region: &Region, offset: u64, width: u64) -> Result<u64, AmlError> {
+        if offset + width / 8 > region.length {
+            return Err(AmlError::OutOfRange);
+        }
+        Ok(region.offset + offset)
+    }
+
+    fn read_region(&mut self, region: &Region, offset: u64, width: u64) -> Result<u64, AmlError> {
+        let address = Self::unit_address(region, offset, width)?;
+        match region.space {
+            AddressSpace::Memory => {
+                let address = usize::try_from(address).map_err(|_| AmlError::OutOfRange)?;
+                // SAFETY: The firmware says this memory belongs to the device, and AML is
+                // trusted with it as much as the firmware is.
+                Ok(unsafe {
+                    match width {
+                        8 => u64::from(ptr::read_volatile(address as *const u8)),
+                        16 => u64::from(ptr::read_volatile(address as *const u16)),
+                        32 => u64::from(ptr::read_volatile(address as *const u32)),
+                        _ => ptr::read_volatile(address as *const u64),
+                    }
+                })
+            }
+            AddressSpace::Io => {
+                let port = u16::try_from(address).map_err(|_| AmlError::OutOfRange)?;
+                // SAFETY: See above.
+                Ok(unsafe {
+                    match width {
+                        8 => u64::from(inb(port)),
+                        16 => u64::from(inw(port)),
+                        32 => u64::from(inl(port)),
+                        _ => u64::from(inl(port)) | u64::from(inl(port + 4)) << 32,
+                    }
+                })
+            }
+            AddressSpace::PciConfig => {
+                let function = self.pci_function(region)?;
+                let offset = u16::try_from(address).map_err(|_| AmlError::OutOfRange)?;
+                Ok(match width {
+                    8 => u64::from(config::read_u8(function, offset)),
+                    16 => u64::from(config::read_u16(function, offset)),
+                    32 => u64::from(config::read(function, offset)),
+                    _ => {
+                        u64::from(config::read(function, offset))
+                            | u64::from(config::read(function, offset + 4)) << 32
+                    }
+                })
+            }
+            space => Err(AmlError::UnsupportedSpace(space)),
+        }
+    }
+
+    fn write_region(
+        &mut self,
+        region: &Region,
+        offset: u64,
+        width: u64,
+        value: u64,
+    ) -> Result<(), AmlError> {
+        let address = Self::unit_address(region, offset, width)?;
+        match region.space {
+            AddressSpace::Memory => {
+                let address = usize::try_from(address).map_err(|_| AmlError::OutOfRange)?;
+                // SAFETY: See `read_region`.
+                unsafe {
+                    match width {
+                        8 => ptr::write_volatile(address as *mut u8, value as u8),
+                        16 => ptr::write_volatile(address as *mut u16, value as u16),
+                        32 => ptr::write_volatile(address as *mut u32, value as u32),
+                        _ => ptr::write_volatile(address as *mut u64, value),
+                    }
+                }
+            }
+            AddressSpace::Io => {
+                let port = u16::try_from(address).map_err(|_| AmlError::OutOfRange)?;
+                // SAFETY: See `read_region`.
+                unsafe {
+                    match width {
+                        8 => outb(port, value as u8),
+                        16 => outw(port, value as u16),
+                        32 => outl(port, value as u32),
+                        _ => {
+                            outl(port, value as u32);
+                            outl(port + 4, (value >> 32) as u32);
+                        }
+                    }
+                }
+            }
+            AddressSpace::PciConfig => {
+                let function = self.pci_function(region)?;
+                let offset = u16::try_from(address).map_err(|_| AmlError::OutOfRange)?;
+                match width {
+                    8 => config::write_u8(function, offset, value as u8),
+                    16 => config::write_u16(function, offset, value as u16),
+                    32 => config::write(function, offset, value as u32),
+                    _ => {
+                        config::write(function, offset, value as u32);
+                        config::write(function, offset + 4, (value >> 32) as u32);
+                    }
+                }
+            }
+            space => return Err(AmlError::UnsupportedSpace(space)),
+        }
+        Ok(())
+    }
+
+    /// Returns the PCI function a region in configuration space belongs to. Its device is the
+    /// one the region is declared in, and its bus and segment are those of the root bridge
+    /// above that, which are 0 unless `_BBN` and `_SEG` say otherwise.
+    fn pci_function(&mut self, region: &Region) -> Result<pci::Address, AmlError> {
+        let adr = self.evaluate_integer(&region.scope, b"_ADR")?.unwrap_or(0);
+        let (mut bus, mut segment) = (0, 0);
+        let mut scope = Some(region.scope.clone());
+        while let Some(path) = scope {
+            if let Some(number) = self.evaluate_integer(&path, b"_BBN")? {
+                bus = number;
+                segment = self.evaluate_integer(&path, b"_SEG")?.unwrap_or(0);
+                break;
+            }
+            scope = path.parent();
+        }
+        Ok(pci::Address {
+            segment: segment as u16,
+            bus: bus as u8,
+            device: (adr >> 16) as u8,
+            function: adr as u8,
+        })
+    }
+}
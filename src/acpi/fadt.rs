@@ -0,0 +1,110 @@
+//! The Fixed ACPI Description Table, which holds the power management registers and points to
+//! the DSDT.
+//!
+//! The offsets below are from the start of the table, header included, as the specification
+//! gives them. Fields added by later revisions are only read when the table is long enough.
+
+use super::{u16_at, u32_at, u64_at, u8_at, GenericAddress, Signature, Table};
+
+pub const SIGNATURE: &Signature = b"FACP";
+
+/// Set in the boot architecture flags when there is an 8042 keyboard controller.
+const BOOT_8042: u16 = 1 << 1;
+/// Set in the flags when the reset register can be used.
+const RESET_REG_SUP: u32 = 1 << 10;
+/// Set in the flags on machines without the fixed hardware, where the PM1 registers are
+/// missing.
+const HW_REDUCED_ACPI: u32 = 1 << 20;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Fadt {
+    pub revision: u8,
+    /// The physical address of the FACS.
+    pub facs: u64,
+    /// The physical address of the DSDT.
+    pub dsdt: u64,
+    /// The ISA IRQ the SCI arrives on.
+    pub sci_interrupt: u16,
+    /// The port commands to hand over the hardware are written to, or 0 if it is always in
+    /// ACPI mode.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event: Option<GenericAddress>,
+    pub pm1b_event: Option<GenericAddress>,
+    pub pm1a_control: Option<GenericAddress>,
+    pub pm1b_control: Option<GenericAddress>,
+    pub pm_timer: Option<GenericAddress>,
+    /// The RTC register holding the century, or 0 if there is none.
+    pub century: u8,
+    pub boot_flags: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+/// Takes the extended register at `extended` if the table has one, and the port at `legacy`
+/// otherwise.
+fn register(
+    bytes: &[u8],
+    extended: usize,
+    legacy: usize,
+    len: Option<u8>,
+) -> Option<GenericAddress> {
+    GenericAddress::parse(bytes, extended)
+        .or_else(|| GenericAddress::io(u32_at(bytes, legacy)?, len?))
+}
+
+impl Fadt {
+    pub fn parse(table: &Table) -> Option<Fadt> {
+        let bytes = table.bytes;
+        let pm1_event_length = u8_at(bytes, 88);
+        let pm1_control_length = u8_at(bytes, 89);
+        let pm_timer_length = u8_at(bytes, 91);
+
+        let facs = u64_at(bytes, 132)
+            .filter(|&facs| facs != 0)
+            .unwrap_or(u64::from(u32_at(bytes, 36)?));
+        let dsdt = u64_at(bytes, 140)
+            .filter(|&dsdt| dsdt != 0)
+            .unwrap_or(u64::from(u32_at(bytes, 40)?));
+        let flags = u32_at(bytes, 112).unwrap_or(0);
+
+        Some(Fadt {
+            revision: table.revision,
+            facs,
+            dsdt,
+            sci_interrupt: u16_at(bytes, 46)?,
+            smi_command: u32_at(bytes, 48)?,
+            acpi_enable: u8_at(bytes, 52)?,
+            acpi_disable: u8_at(bytes, 53)?,
+            // The event blocks are split in half, with the status registers first.
+            pm1a_event: register(bytes, 148, 56, pm1_event_length),
+            pm1b_event: register(bytes, 160, 60, pm1_event_length),
+            pm1a_control: register(bytes, 172, 64, pm1_control_length),
+            pm1b_control: register(bytes, 184, 68, pm1_control_length),
+            pm_timer: register(bytes, 208, 76, pm_timer_length),
+            century: u8_at(bytes, 108).unwrap_or(0),
+            boot_flags: u16_at(bytes, 109).unwrap_or(0),
+            flags,
+            reset_register: if flags & RESET_REG_SUP != 0 {
+                GenericAddress::parse(bytes, 116)
+            } else {
+                None
+            },
+            reset_value: u8_at(bytes, 128).unwrap_or(0),
+        })
+    }
+
+    /// Returns whether there is an 8042. ACPI 1.0 tables do not say, so there is taken to be
+    /// one.
+    pub fn has_8042(&self) -> bool {
+        self.revision < 2 || self.boot_flags & BOOT_8042 != 0
+    }
+
+    /// Returns whether the machine lacks the fixed hardware, and has to be driven through AML
+    /// alone.
+    pub fn hardware_reduced(&self) -> bool {
+        self.flags & HW_REDUCED_ACPI != 0
+    }
+}
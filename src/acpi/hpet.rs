@@ -0,0 +1,42 @@
+//! The HPET description table, which says where the high precision event timer is.
+
+use super::{u16_at, u32_at, u8_at, GenericAddress, Signature, Table};
+
+pub const SIGNATURE: &Signature = b"HPET";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparators: u8,
+    /// Whether the main counter is 64 bits wide rather than 32.
+    pub wide_counter: bool,
+    /// Whether the timer can take over the PIT and RTC interrupts.
+    pub legacy_replacement: bool,
+    pub vendor_id: u16,
+    /// Where the registers are, which is always memory.
+    pub base: GenericAddress,
+    /// Which HPET this is, on machines with more than one.
+    pub number: u8,
+    /// The smallest period in main counter ticks that periodic mode can be used with without
+    /// losing interrupts.
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+impl Hpet {
+    pub fn parse(table: &Table) -> Option<Hpet> {
+        let bytes = table.bytes;
+        let id = u32_at(bytes, 36)?;
+        Some(Hpet {
+            hardware_revision: id as u8,
+            comparators: ((id >> 8) & 0x1F) as u8 + 1,
+            wide_counter: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            vendor_id: (id >> 16) as u16,
+            base: GenericAddress::parse(bytes, 40)?,
+            number: u8_at(bytes, 52)?,
+            minimum_tick: u16_at(bytes, 53)?,
+            page_protection: u8_at(bytes, 55)?,
+        })
+    }
+}
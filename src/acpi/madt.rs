@@ -0,0 +1,201 @@
+//! The Multiple APIC Description Table, which lists the processors and interrupt controllers.
+
+use alloc::vec::Vec;
+
+use super::{u16_at, u32_at, u64_at, u8_at, Signature, Table};
+
+pub const SIGNATURE: &Signature = b"APIC";
+
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_OVERRIDE: u8 = 2;
+const LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const LOCAL_X2APIC: u8 = 9;
+const LOCAL_X2APIC_NMI: u8 = 0xA;
+
+const ENABLED: u32 = 1 << 0;
+const ONLINE_CAPABLE: u32 = 1 << 1;
+/// Set in the MADT flags when there are 8259s as well, which have to be masked to use the
+/// APICs.
+const PCAT_COMPAT: u32 = 1 << 0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    /// Whether the processor can be used right away.
+    pub enabled: bool,
+    /// Whether a disabled processor can be brought online later.
+    pub online_capable: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// The first global system interrupt the I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    /// As the bus specifies, which is active high for ISA.
+    Conforming,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// As the bus specifies, which is edge for ISA.
+    Conforming,
+    Edge,
+    Level,
+}
+
+fn polarity(flags: u16) -> Polarity {
+    match flags & 0x3 {
+        1 => Polarity::ActiveHigh,
+        3 => Polarity::ActiveLow,
+        _ => Polarity::Conforming,
+    }
+}
+
+fn trigger(flags: u16) -> Trigger {
+    match (flags >> 2) & 0x3 {
+        1 => Trigger::Edge,
+        3 => Trigger::Level,
+        _ => Trigger::Conforming,
+    }
+}
+
+/// An ISA IRQ that is wired to a different global system interrupt than its number.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    /// The ISA IRQ.
+    pub source: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+/// A local APIC input wired to the NMI.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// The processor it applies to, or `None` for all of them.
+    pub processor_id: Option<u32>,
+    /// LINT0 or LINT1.
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    /// Whether the machine has 8259 PICs as well.
+    pub has_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    pub fn parse(table: &Table) -> Option<Madt> {
+        let body = table.body();
+        let mut madt = Madt {
+            local_apic_address: u64::from(u32_at(body, 0)?),
+            has_pics: u32_at(body, 4)? & PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut entries = &body[8..];
+        while let [kind, len, ..] = *entries {
+            let len = usize::from(len);
+            if len < 2 || len > entries.len() {
+                break;
+            }
+            let entry = &entries[..len];
+            madt.add(kind, entry);
+            entries = &entries[len..];
+        }
+
+        Some(madt)
+    }
+
+    /// Records an entry, skipping it if it is too short.
+    fn add(&mut self, kind: u8, entry: &[u8]) -> Option<()> {
+        match kind {
+            LOCAL_APIC => {
+                let flags = u32_at(entry, 4)?;
+                self.processors.push(Processor {
+                    processor_id: u32::from(u8_at(entry, 2)?),
+                    apic_id: u32::from(u8_at(entry, 3)?),
+                    enabled: flags & ENABLED != 0,
+                    online_capable: flags & ONLINE_CAPABLE != 0,
+                });
+            }
+            LOCAL_X2APIC => {
+                let flags = u32_at(entry, 8)?;
+                self.processors.push(Processor {
+                    processor_id: u32_at(entry, 12)?,
+                    apic_id: u32_at(entry, 4)?,
+                    enabled: flags & ENABLED != 0,
+                    online_capable: flags & ONLINE_CAPABLE != 0,
+                });
+            }
+            IO_APIC => self.io_apics.push(IoApic {
+                id: u8_at(entry, 2)?,
+                address: u32_at(entry, 4)?,
+                gsi_base: u32_at(entry, 8)?,
+            }),
+            INTERRUPT_OVERRIDE => {
+                let flags = u16_at(entry, 8)?;
+                self.overrides.push(InterruptOverride {
+                    bus: u8_at(entry, 2)?,
+                    source: u8_at(entry, 3)?,
+                    gsi: u32_at(entry, 4)?,
+                    polarity: polarity(flags),
+                    trigger: trigger(flags),
+                });
+            }
+            LOCAL_APIC_NMI => {
+                let flags = u16_at(entry, 3)?;
+                let processor_id = u8_at(entry, 2)?;
+                self.nmis.push(LocalApicNmi {
+                    processor_id: (processor_id != 0xFF).then_some(u32::from(processor_id)),
+                    lint: u8_at(entry, 5)?,
+                    polarity: polarity(flags),
+                    trigger: trigger(flags),
+                });
+            }
+            LOCAL_X2APIC_NMI => {
+                let flags = u16_at(entry, 2)?;
+                let processor_id = u32_at(entry, 4)?;
+                self.nmis.push(LocalApicNmi {
+                    processor_id: (processor_id != u32::MAX).then_some(processor_id),
+                    lint: u8_at(entry, 8)?,
+                    polarity: polarity(flags),
+                    trigger: trigger(flags),
+                });
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE => self.local_apic_address = u64_at(entry, 4)?,
+            _ => {}
+        }
+        Some(())
+    }
+
+    /// Returns the global system interrupt an ISA IRQ arrives on.
+    pub fn isa_gsi(&self, irq: u8) -> u32 {
+        self.overrides
+            .iter()
+            .find(|over| over.bus == 0 && over.source == irq)
+            .map_or(u32::from(irq), |over| over.gsi)
+    }
+}
@@ -0,0 +1,37 @@
+//! The MCFG table, which lists the memory-mapped PCI configuration space.
+
+use alloc::vec::Vec;
+
+use super::{u16_at, u64_at, u8_at, Signature, Table};
+use crate::drivers::pci::config::EcamRegion;
+
+pub const SIGNATURE: &Signature = b"MCFG";
+
+/// Where the entries start, after the header and 8 reserved bytes.
+const ENTRIES: usize = 44;
+const ENTRY_SIZE: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mcfg {
+    pub regions: Vec<EcamRegion>,
+}
+
+impl Mcfg {
+    pub fn parse(table: &Table) -> Option<Mcfg> {
+        let regions = table
+            .bytes
+            .get(ENTRIES..)?
+            .chunks_exact(ENTRY_SIZE)
+            .filter_map(|entry| {
+                Some(EcamRegion {
+                    base: u64_at(entry, 0)?,
+                    segment: u16_at(entry, 8)?,
+                    start_bus: u8_at(entry, 10)?,
+                    end_bus: u8_at(entry, 11)?,
+                })
+            })
+            .filter(|region| region.start_bus <= region.end_bus)
+            .collect();
+        Some(Mcfg { regions })
+    }
+}
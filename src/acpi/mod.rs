@@ -0,0 +1,579 @@
+//! This module finds the ACPI tables the firmware left in memory and parses the ones the
+//! kernel cares about.
+//!
+//! The RSDP comes from the multiboot2 information if the bootloader passed it on, and is
+//! searched for in the EBDA and the BIOS area otherwise. It leads to the XSDT, or the RSDT on
+//! ACPI 1.0 machines, which lists every other table apart from the DSDT, which the FADT points
+//! to. Every table is checked against its checksum before it is used.
+//!
+//! The [`madt`], [`fadt`], [`hpet`] and [`mcfg`] tables are decoded into their own types, the
+//! code in the DSDT and SSDTs is run by [`aml`] to build the namespace, and [`pm`] turns the
+//! machine off and resets it through the registers the FADT describes.
+
+use alloc::vec::Vec;
+use core::str;
+
+use multiboot2::BootInformation;
+use spin::Once;
+
+pub mod aml;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+pub mod pm;
+
+use crate::{
+    drivers::pci,
+    info,
+    intrinsics::{inb, inl, inw, outb, outl, outw},
+    println,
+    shell::CommandError,
+    shell_command, warn,
+};
+
+/// The size of the header every system description table starts with.
+pub const HEADER_SIZE: usize = 36;
+/// Tables claiming to be larger than this are taken to be garbage.
+const MAX_TABLE_SIZE: usize = 16 << 20;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+/// Where the real-mode segment of the EBDA is kept in the BIOS data area.
+const EBDA_POINTER: usize = 0x40E;
+const BIOS_AREA: (usize, usize) = (0xE0000, 0x100000);
+
+/// A 4-character table signature, like `APIC` for the MADT.
+pub type Signature = [u8; 4];
+
+/// Reads a little-endian field out of a table, or `None` if the table is too short for it.
+pub(crate) fn field<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    bytes.get(offset..offset + N)?.try_into().ok()
+}
+
+pub(crate) fn u8_at(bytes: &[u8], offset: usize) -> Option<u8> {
+    bytes.get(offset).copied()
+}
+
+pub(crate) fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    field(bytes, offset).map(u16::from_le_bytes)
+}
+
+pub(crate) fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    field(bytes, offset).map(u32::from_le_bytes)
+}
+
+pub(crate) fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    field(bytes, offset).map(u64::from_le_bytes)
+}
+
+fn checksum_is_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Returns the bytes at a physical address. Paging is off, so it is used as it is.
+///
+/// # Safety
+/// The range has to be memory, left alone by everything else for as long as the slice lives.
+unsafe fn physical(address: usize, len: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(address as *const u8, len)
+}
+
+/// Where the register a [`GenericAddress`] describes lives.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    PciConfig,
+    EmbeddedController,
+    SmBus,
+    FunctionalFixed,
+    Other(u8),
+}
+
+/// The same numbers are used by AML operation regions.
+impl From<u8> for AddressSpace {
+    fn from(space: u8) -> AddressSpace {
+        match space {
+            0 => AddressSpace::Memory,
+            1 => AddressSpace::Io,
+            2 => AddressSpace::PciConfig,
+            3 => AddressSpace::EmbeddedController,
+            4 => AddressSpace::SmBus,
+            0x7F => AddressSpace::FunctionalFixed,
+            other => AddressSpace::Other(other),
+        }
+    }
+}
+
+/// A register described in the Generic Address Structure format.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 1 to 4 for byte to quad word accesses, or 0 if the register does not say.
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// The size of the structure in a table.
+    pub const SIZE: usize = 12;
+
+    /// Reads the structure at `offset`. An address of zero means the register is not there.
+    pub fn parse(bytes: &[u8], offset: usize) -> Option<GenericAddress> {
+        let address = u64_at(bytes, offset + 4)?;
+        if address == 0 {
+            return None;
+        }
+        Some(GenericAddress {
+            space: AddressSpace::from(u8_at(bytes, offset)?),
+            bit_width: u8_at(bytes, offset + 1)?,
+            bit_offset: u8_at(bytes, offset + 2)?,
+            access_size: u8_at(bytes, offset + 3)?,
+            address,
+        })
+    }
+
+    /// Describes a block of I/O ports given the old way, as a port and a length in bytes.
+    pub fn io(port: u32, len: u8) -> Option<GenericAddress> {
+        (port != 0).then_some(GenericAddress {
+            space: AddressSpace::Io,
+            bit_width: len.saturating_mul(8),
+            bit_offset: 0,
+            access_size: 0,
+            address: u64::from(port),
+        })
+    }
+
+    /// Returns how many bits wide an access to the register is.
+    fn access_width(&self) -> u32 {
+        match self.access_size {
+            1..=4 => 8 << (self.access_size - 1),
+            _ => match u32::from(self.bit_width) + u32::from(self.bit_offset) {
+                0..=8 => 8,
+                9..=16 => 16,
+                17..=32 => 32,
+                _ => 64,
+            },
+        }
+    }
+
+    /// Reads the register, or returns `None` if it is in an address space that is not
+    /// supported.
+    ///
+    /// # Safety
+    /// Reading a register can have arbitrary side effects on the hardware behind it.
+    pub unsafe fn read(&self) -> Option<u64> {
+        let address = usize::try_from(self.address).ok()?;
+        Some(match (self.space, self.access_width()) {
+            (AddressSpace::Memory, 8) => u64::from((address as *const u8).read_volatile()),
+            (AddressSpace::Memory, 16) => u64::from((address as *const u16).read_volatile()),
+            (AddressSpace::Memory, 32) => u64::from((address as *const u32).read_volatile()),
+            (AddressSpace::Memory, _) => (address as *const u64).read_volatile(),
+            (AddressSpace::Io, width) => {
+                let port = u16::try_from(address).ok()?;
+                match width {
+                    8 => u64::from(inb(port)),
+                    16 => u64::from(inw(port)),
+                    _ => u64::from(inl(port)),
+                }
+            }
+            (AddressSpace::PciConfig, _) => {
+                let (address, offset) = pci_config_address(self.address);
+                u64::from(pci::config::read(address, offset)) >> ((offset & 3) * 8)
+            }
+            _ => return None,
+        })
+    }
+
+    /// Writes the register, or returns `None` if it is in an address space that is not
+    /// supported.
+    ///
+    /// # Safety
+    /// Writing a register can have arbitrary side effects on the hardware behind it.
+    pub unsafe fn write(&self, value: u64) -> Option<()> {
+        let address = usize::try_from(self.address).ok()?;
+        match (self.space, self.access_width()) {
+            (AddressSpace::Memory, 8) => (address as *mut u8).write_volatile(value as u8),
+            (AddressSpace::Memory, 16) => (address as *mut u16).write_volatile(value as u16),
+            (AddressSpace::Memory, 32) => (address as *mut u32).write_volatile(value as u32),
+            (AddressSpace::Memory, _) => (address as *mut u64).write_volatile(value),
+            (AddressSpace::Io, width) => {
+                let port = u16::try_from(address).ok()?;
+                match width {
+                    8 => outb(port, value as u8),
+                    16 => outw(port, value as u16),
+                    _ => outl(port, value as u32),
+                }
+            }
+            (AddressSpace::PciConfig, width) => {
+                let (address, offset) = pci_config_address(self.address);
+                let shift = (offset & 3) * 8;
+                let mask = match width {
+                    8 => 0xFF,
+                    16 => 0xFFFF,
+                    _ => u32::MAX,
+                } << shift;
+                let old = pci::config::read(address, offset);
+                let new = (old & !mask) | ((value as u32) << shift & mask);
+                pci::config::write(address, offset, new);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// Splits a register address in PCI configuration space into the function on segment 0, bus 0
+/// and the offset within it.
+fn pci_config_address(address: u64) -> (pci::Address, u16) {
+    let function = pci::Address {
+        segment: 0,
+        bus: 0,
+        device: (address >> 32) as u8,
+        function: (address >> 16) as u8,
+    };
+    (function, address as u16)
+}
+
+/// A system description table whose checksum was correct.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Table {
+    pub signature: Signature,
+    pub address: usize,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    /// The whole table, header included.
+    pub bytes: &'static [u8],
+}
+
+impl Table {
+    /// Checks the table at `address` and returns it if it is sound.
+    fn load(address: u64) -> Option<Table> {
+        let address = usize::try_from(address)
+            .ok()
+            .filter(|&address| address != 0)?;
+        // SAFETY: The firmware put a table here, and the memory it sits in is never handed out.
+        let header = unsafe { physical(address, HEADER_SIZE) };
+        let len = u32_at(header, 4)? as usize;
+        if !(HEADER_SIZE..=MAX_TABLE_SIZE).contains(&len) {
+            return None;
+        }
+        // SAFETY: See above.
+        let bytes = unsafe { physical(address, len) };
+        let signature = field(bytes, 0)?;
+        if !checksum_is_valid(bytes) {
+            warn!(
+                "acpi: {} at {:#x} has a bad checksum",
+                signature_str(&signature),
+                address
+            );
+            return None;
+        }
+        Some(Table {
+            signature,
+            address,
+            revision: bytes[8],
+            oem_id: field(bytes, 10)?,
+            oem_table_id: field(bytes, 16)?,
+            bytes,
+        })
+    }
+
+    /// Returns what follows the header.
+    pub fn body(&self) -> &'static [u8] {
+        &self.bytes[HEADER_SIZE..]
+    }
+
+    pub fn signature(&self) -> &str {
+        signature_str(&self.signature)
+    }
+}
+
+fn signature_str(signature: &Signature) -> &str {
+    str::from_utf8(signature).unwrap_or("????")
+}
+
+/// Where the system description tables are listed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Rsdp {
+    revision: u8,
+    oem_id: [u8; 6],
+    rsdt: u32,
+    /// Only there from ACPI 2.0 on.
+    xsdt: Option<u64>,
+}
+
+impl Rsdp {
+    /// Parses and checks an RSDP.
+    fn parse(bytes: &[u8]) -> Option<Rsdp> {
+        if field::<8>(bytes, 0)? != *RSDP_SIGNATURE
+            || !checksum_is_valid(bytes.get(..RSDP_V1_SIZE)?)
+        {
+            return None;
+        }
+        let revision = u8_at(bytes, 15)?;
+        let xsdt = if revision >= 2 {
+            let len = u32_at(bytes, 20)? as usize;
+            if len < RSDP_V2_SIZE || !checksum_is_valid(bytes.get(..len)?) {
+                return None;
+            }
+            Some(u64_at(bytes, 24)?)
+        } else {
+            None
+        };
+        Some(Rsdp {
+            revision,
+            oem_id: field(bytes, 9)?,
+            rsdt: u32_at(bytes, 16)?,
+            xsdt,
+        })
+    }
+
+    /// Looks for the RSDP on a 16-byte boundary within the range.
+    fn scan(start: usize, end: usize) -> Option<Rsdp> {
+        (start..end.saturating_sub(RSDP_V2_SIZE))
+            .step_by(16)
+            .find_map(|address| {
+                // SAFETY: The range is the EBDA or the BIOS area, which are always readable.
+                let bytes = unsafe { physical(address, RSDP_V2_SIZE) };
+                if bytes.starts_with(RSDP_SIGNATURE) {
+                    // A v1 RSDP may be this close to the end of the range.
+                    let len = if bytes[15] >= 2 {
+                        u32_at(bytes, 20)? as usize
+                    } else {
+                        RSDP_V1_SIZE
+                    };
+                    // SAFETY: See above.
+                    Rsdp::parse(unsafe { physical(address, len.min(end - address)) })
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Takes the RSDP from the bootloader, or goes looking for it.
+    fn find(boot_info: &BootInformation) -> Option<Rsdp> {
+        if let Some(tag) = boot_info
+            .rsdp_v2_tag()
+            .filter(|tag| tag.checksum_is_valid())
+        {
+            return Some(Rsdp {
+                revision: tag.revision(),
+                oem_id: field(tag.oem_id().unwrap_or("").as_bytes(), 0).unwrap_or([b' '; 6]),
+                rsdt: 0,
+                xsdt: Some(tag.xsdt_address() as u64),
+            });
+        }
+        if let Some(tag) = boot_info
+            .rsdp_v1_tag()
+            .filter(|tag| tag.checksum_is_valid())
+        {
+            return Some(Rsdp {
+                revision: tag.revision(),
+                oem_id: field(tag.oem_id().unwrap_or("").as_bytes(), 0).unwrap_or([b' '; 6]),
+                rsdt: tag.rsdt_address() as u32,
+                xsdt: None,
+            });
+        }
+
+        // SAFETY: The BIOS data area is always there.
+        let ebda = usize::from(u16_at(unsafe { physical(EBDA_POINTER, 2) }, 0)?) << 4;
+        let from_ebda = if ebda != 0 {
+            Rsdp::scan(ebda, ebda + 1024)
+        } else {
+            None
+        };
+        from_ebda.or_else(|| Rsdp::scan(BIOS_AREA.0, BIOS_AREA.1))
+    }
+}
+
+/// Everything found in the tables.
+pub struct Acpi {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// Every sound table, the DSDT included.
+    pub tables: Vec<Table>,
+    pub madt: Option<madt::Madt>,
+    pub fadt: Option<fadt::Fadt>,
+    pub hpet: Option<hpet::Hpet>,
+    pub mcfg: Option<mcfg::Mcfg>,
+}
+
+impl Acpi {
+    pub fn table(&self, signature: &Signature) -> Option<&Table> {
+        self.tables
+            .iter()
+            .find(|table| &table.signature == signature)
+    }
+}
+
+static ACPI: Once<Acpi> = Once::new();
+
+/// Returns the tables, if any were found.
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.get()
+}
+
+pub fn table(signature: &Signature) -> Option<&'static Table> {
+    get()?.table(signature)
+}
+
+pub fn madt() -> Option<&'static madt::Madt> {
+    get()?.madt.as_ref()
+}
+
+pub fn fadt() -> Option<&'static fadt::Fadt> {
+    get()?.fadt.as_ref()
+}
+
+pub fn hpet() -> Option<&'static hpet::Hpet> {
+    get()?.hpet.as_ref()
+}
+
+pub fn mcfg() -> Option<&'static mcfg::Mcfg> {
+    get()?.mcfg.as_ref()
+}
+
+/// Reads the table addresses out of the XSDT or the RSDT.
+fn root_entries(rsdp: &Rsdp) -> Option<Vec<u64>> {
+    if let Some(xsdt) = rsdp.xsdt.and_then(Table::load) {
+        if &xsdt.signature == b"XSDT" {
+            return Some(
+                xsdt.body()
+                    .chunks_exact(8)
+                    .map(|entry| u64_at(entry, 0).unwrap_or(0))
+                    .collect(),
+            );
+        }
+    }
+    let rsdt = Table::load(u64::from(rsdp.rsdt)).filter(|rsdt| &rsdt.signature == b"RSDT")?;
+    Some(
+        rsdt.body()
+            .chunks_exact(4)
+            .map(|entry| u64::from(u32_at(entry, 0).unwrap_or(0)))
+            .collect(),
+    )
+}
+
+/// Finds and parses the tables. Needs the heap.
+pub fn initialize(boot_info: &BootInformation) {
+    let Some(rsdp) = Rsdp::find(boot_info) else {
+        warn!("acpi: no RSDP found");
+        return;
+    };
+    let Some(entries) = root_entries(&rsdp) else {
+        warn!("acpi: neither the XSDT nor the RSDT is usable");
+        return;
+    };
+
+    let mut tables: Vec<Table> = entries.into_iter().filter_map(Table::load).collect();
+    let fadt = tables
+        .iter()
+        .find(|table| &table.signature == fadt::SIGNATURE)
+        .and_then(fadt::Fadt::parse);
+    if let Some(dsdt) = fadt
+        .as_ref()
+        .and_then(|fadt| Table::load(fadt.dsdt))
+        .filter(|dsdt| &dsdt.signature == b"DSDT")
+    {
+        tables.push(dsdt);
+    }
+    let find = |signature: &Signature| tables.iter().find(|table| &table.signature == signature);
+    let madt = find(madt::SIGNATURE).and_then(madt::Madt::parse);
+    let hpet = find(hpet::SIGNATURE).and_then(hpet::Hpet::parse);
+    let mcfg = find(mcfg::SIGNATURE).and_then(mcfg::Mcfg::parse);
+
+    ACPI.call_once(|| Acpi {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        tables,
+        madt,
+        fadt,
+        hpet,
+        mcfg,
+    });
+    aml::initialize();
+}
+
+/// Logs the tables that were found and the highlights of the parsed ones.
+pub fn report() {
+    let Some(acpi) = get() else {
+        return;
+    };
+    info!(
+        "acpi: revision {} from {}",
+        acpi.revision,
+        str::from_utf8(&acpi.oem_id).unwrap_or("?").trim_end()
+    );
+    for table in &acpi.tables {
+        info!(
+            "acpi: {} at {:#010x} ({} bytes)",
+            table.signature(),
+            table.address,
+            table.bytes.len()
+        );
+    }
+    if let Some(madt) = &acpi.madt {
+        info!(
+            "acpi: {} CPUs, {} I/O APICs, {} interrupt overrides",
+            madt.processors.len(),
+            madt.io_apics.len(),
+            madt.overrides.len()
+        );
+    }
+}
+
+fn acpi_command(args: &[&str]) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+    let acpi = get().ok_or(CommandError::Failed("no ACPI tables"))?;
+    for table in &acpi.tables {
+        println!(
+            "{} {:#010x} {:>6} rev {} {} {}",
+            table.signature(),
+            table.address,
+            table.bytes.len(),
+            table.revision,
+            str::from_utf8(&table.oem_id).unwrap_or("?"),
+            str::from_utf8(&table.oem_table_id).unwrap_or("?")
+        );
+    }
+    if let Some(madt) = &acpi.madt {
+        for processor in &madt.processors {
+            println!(
+                "CPU {} APIC {}{}",
+                processor.processor_id,
+                processor.apic_id,
+                if processor.enabled { "" } else { " (disabled)" }
+            );
+        }
+        for io_apic in &madt.io_apics {
+            println!(
+                "I/O APIC {} at {:#x}, GSI base {}",
+                io_apic.id, io_apic.address, io_apic.gsi_base
+            );
+        }
+        for over in &madt.overrides {
+            println!(
+                "IRQ {} -> GSI {} ({:?}, {:?})",
+                over.source, over.gsi, over.polarity, over.trigger
+            );
+        }
+    }
+    Ok(())
+}
+
+shell_command!(
+    ACPI_COMMAND,
+    "acpi",
+    "",
+    "List the ACPI tables and the interrupt setup",
+    acpi_command
+);
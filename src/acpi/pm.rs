@@ -0,0 +1,148 @@
+//! Power management through the fixed hardware the FADT describes.
+//!
+//! Entering a sleep state takes writing its sleep type and the sleep enable bit to the PM1
+//! control registers. The sleep types come from the `\_Sx` packages in the namespace, which
+//! are evaluated by the [AML interpreter](super::aml).
+
+use alloc::vec::Vec;
+
+use super::{
+    aml::{self, Object},
+    fadt::Fadt,
+    GenericAddress,
+};
+use crate::{
+    intrinsics::{io_wait, outb},
+    warn,
+};
+
+/// Set in the PM1 control register once the hardware is in ACPI mode.
+const SCI_EN: u64 = 1 << 0;
+const SLP_TYP_SHIFT: u32 = 10;
+const SLP_TYP_MASK: u64 = 0x7 << SLP_TYP_SHIFT;
+const SLP_EN: u64 = 1 << 13;
+
+/// How many microseconds to give the firmware to switch to ACPI mode.
+const ENABLE_TIMEOUT: usize = 3_000_000;
+/// How many microseconds to give the machine to go down once it was told to.
+const SLEEP_TIMEOUT: usize = 1_000_000;
+
+/// Why the machine could not be put to sleep or reset.
+pub type Error = &'static str;
+
+/// The values to write to the PM1a and PM1b control registers to enter a sleep state.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// Returns the sleep type of the sleep state `state`, from S0 to S5.
+pub fn sleep_type(state: u8) -> Option<SleepType> {
+    let name = alloc::format!("\\_S{}_", state);
+    let Object::Package(elements) = aml::evaluate(&name, Vec::new()).ok()? else {
+        return None;
+    };
+    let mut types = elements.iter().map(|element| element.as_integer().ok());
+    let a = types.next()??;
+    let b = types.next().flatten().unwrap_or(0);
+    Some(SleepType {
+        a: a as u8,
+        b: b as u8,
+    })
+}
+
+/// Reads the PM1 control register, both halves of it together.
+fn read_control(fadt: &Fadt) -> Option<u64> {
+    // SAFETY: Reading the control registers has no side effects.
+    let a = unsafe { fadt.pm1a_control?.read()? };
+    // SAFETY: See above.
+    let b = fadt.pm1b_control.and_then(|b| unsafe { b.read() });
+    Some(a | b.unwrap_or(0))
+}
+
+/// Hands the power management hardware over from the firmware, if it has not been already.
+pub fn enable() -> Result<(), Error> {
+    let fadt = super::fadt().ok_or("there is no FADT")?;
+    if fadt.hardware_reduced() {
+        return Ok(());
+    }
+    let control = read_control(fadt).ok_or("the PM1 control register is unusable")?;
+    if control & SCI_EN != 0 {
+        return Ok(());
+    }
+    if fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return Err("the firmware cannot be asked to enter ACPI mode");
+    }
+    let port = u16::try_from(fadt.smi_command).map_err(|_| "the SMI command port is invalid")?;
+    // SAFETY: The FADT says this is what switches the hardware over.
+    unsafe { outb(port, fadt.acpi_enable) };
+    for _ in 0..ENABLE_TIMEOUT {
+        if read_control(fadt).is_some_and(|control| control & SCI_EN != 0) {
+            return Ok(());
+        }
+        io_wait();
+    }
+    Err("the firmware did not enter ACPI mode")
+}
+
+/// Writes a sleep type to a PM1 control register, keeping its other bits, and sets the sleep
+/// enable bit if `sleep` is set.
+///
+/// # Safety
+/// The machine goes to sleep once both halves have been written with `sleep` set.
+unsafe fn write_sleep(register: GenericAddress, sleep_type: u8, sleep: bool) -> Result<(), Error> {
+    let control = register
+        .read()
+        .ok_or("the PM1 control register is unusable")?;
+    let mut control = (control & !(SLP_TYP_MASK | SLP_EN)) | u64::from(sleep_type) << SLP_TYP_SHIFT;
+    if sleep {
+        control |= SLP_EN;
+    }
+    register
+        .write(control)
+        .ok_or("the PM1 control register is unusable")
+}
+
+/// Enters sleep state `state`, returning only if that failed. Interrupts have to be disabled,
+/// and S5 is the only state that is safe to enter, since nothing is saved to resume from.
+pub fn enter_sleep_state(state: u8) -> Result<(), Error> {
+    let fadt = super::fadt().ok_or("there is no FADT")?;
+    if fadt.hardware_reduced() {
+        return Err("the machine has no PM1 control registers");
+    }
+    let control = fadt
+        .pm1a_control
+        .ok_or("there is no PM1a control register")?;
+    let sleep_type = sleep_type(state).ok_or("the DSDT does not say how to enter the state")?;
+    if let Err(error) = enable() {
+        warn!("acpi: {}", error);
+    }
+    // The sleep types go in first, and the sleep enable bits after them.
+    for sleep in [false, true] {
+        // SAFETY: This is the whole point.
+        unsafe {
+            write_sleep(control, sleep_type.a, sleep)?;
+            if let Some(control) = fadt.pm1b_control {
+                write_sleep(control, sleep_type.b, sleep)?;
+            }
+        }
+    }
+    for _ in 0..SLEEP_TIMEOUT {
+        io_wait();
+    }
+    Err("the machine did not go to sleep")
+}
+
+/// Resets the machine through the reset register, returning only if that failed.
+pub fn reset() -> Result<(), Error> {
+    let fadt = super::fadt().ok_or("there is no FADT")?;
+    let register = fadt.reset_register.ok_or("there is no reset register")?;
+    // SAFETY: The machine is meant to go down here.
+    unsafe { register.write(u64::from(fadt.reset_value)) }
+        .ok_or("the reset register is in an unsupported address space")?;
+    for _ in 0..SLEEP_TIMEOUT {
+        io_wait();
+    }
+    Err("the machine did not reset")
+}
@@ -0,0 +1,168 @@
+//! This module exposes the files GRUB loaded next to the kernel through `module2` lines in
+//! `grub.cfg`:
+//! ```text
+//! module2 /boot/initrd.tar initrd
+//! ```
+//! The first word after the path names the module and anything after it is kept as its
+//! arguments. Modules without a name are called `module0`, `module1` and so on.
+//!
+//! The frames backing each module are reserved by [`memory::initialize`](crate::memory::initialize),
+//! so the contents stay valid for as long as the kernel runs.
+
+use core::fmt::Write;
+
+use multiboot2::BootInformation;
+use spin::Once;
+
+use crate::info;
+
+/// The most boot modules that are recorded.
+pub const MAX_MODULES: usize = 16;
+
+/// The longest module name or argument string that is kept, longer ones are truncated.
+const MAX_STRING: usize = 64;
+
+/// A file loaded into memory by the bootloader.
+#[derive(Copy, Clone)]
+pub struct BootModule {
+    start: usize,
+    end: usize,
+    name: [u8; MAX_STRING],
+    name_len: usize,
+    args: [u8; MAX_STRING],
+    args_len: usize,
+}
+
+impl BootModule {
+    const EMPTY: BootModule = BootModule {
+        start: 0,
+        end: 0,
+        name: [0; MAX_STRING],
+        name_len: 0,
+        args: [0; MAX_STRING],
+        args_len: 0,
+    };
+
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    /// Returns whatever followed the name on the `module2` line.
+    pub fn args(&self) -> &str {
+        core::str::from_utf8(&self.args[..self.args_len]).unwrap_or("")
+    }
+
+    /// The physical address the module was loaded at.
+    pub fn start_address(&self) -> usize {
+        self.start
+    }
+
+    pub fn end_address(&self) -> usize {
+        self.end
+    }
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns the contents of the module.
+    pub fn data(&self) -> &'static [u8] {
+        // SAFETY: The bootloader placed the module at this range and its frames are reserved,
+        // so nothing else will ever be allocated over it.
+        unsafe { core::slice::from_raw_parts(self.start as *const u8, self.size()) }
+    }
+}
+
+struct ModuleTable {
+    modules: [BootModule; MAX_MODULES],
+    len: usize,
+}
+
+static MODULES: Once<ModuleTable> = Once::new();
+
+/// Copies `source` into `buffer`, cutting it at a character boundary if it does not fit.
+fn copy_str(buffer: &mut [u8; MAX_STRING], source: &str) -> usize {
+    let mut len = source.len().min(MAX_STRING);
+    while !source.is_char_boundary(len) {
+        len -= 1;
+    }
+    buffer[..len].copy_from_slice(&source.as_bytes()[..len]);
+    len
+}
+
+/// A `fmt::Write` sink over a fixed buffer, used to generate default module names.
+struct NameWriter<'a> {
+    buffer: &'a mut [u8; MAX_STRING],
+    len: usize,
+}
+
+impl Write for NameWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > MAX_STRING {
+            return Err(core::fmt::Error);
+        }
+        self.buffer[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Records the module tags of the multiboot2 information.
+pub fn initialize(boot_info: &BootInformation) {
+    MODULES.call_once(|| {
+        let mut table = ModuleTable {
+            modules: [BootModule::EMPTY; MAX_MODULES],
+            len: 0,
+        };
+        for (index, tag) in boot_info.module_tags().take(MAX_MODULES).enumerate() {
+            let module = &mut table.modules[index];
+            module.start = tag.start_address() as usize;
+            module.end = tag.end_address() as usize;
+
+            let cmdline = tag.cmdline().unwrap_or("").trim_end_matches('\0').trim();
+            let (name, args) = cmdline
+                .split_once(char::is_whitespace)
+                .unwrap_or((cmdline, ""));
+            if name.is_empty() {
+                let mut writer = NameWriter {
+                    buffer: &mut module.name,
+                    len: 0,
+                };
+                write!(writer, "module{}", index).ok();
+                module.name_len = writer.len;
+            } else {
+                module.name_len = copy_str(&mut module.name, name);
+            }
+            module.args_len = copy_str(&mut module.args, args.trim_start());
+            table.len += 1;
+        }
+        table
+    });
+}
+
+/// Returns every module loaded by the bootloader.
+pub fn modules() -> &'static [BootModule] {
+    MODULES
+        .get()
+        .map_or(&[], |table| &table.modules[..table.len])
+}
+
+/// Looks up a module by name.
+pub fn find(name: &str) -> Option<&'static BootModule> {
+    modules().iter().find(|module| module.name() == name)
+}
+
+/// Logs every module that was loaded.
+pub fn report() {
+    for module in modules() {
+        info!(
+            "module: {} at [{:#010x}-{:#010x}] ({} bytes) {}",
+            module.name(),
+            module.start_address(),
+            module.end_address(),
+            module.size(),
+            module.args()
+        );
+    }
+}
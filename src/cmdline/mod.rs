@@ -0,0 +1,277 @@
+//! This module parses the kernel command line handed over by the bootloader and dispatches each
+//! argument to the boot parameter registered for it.
+//!
+//! The command line is a whitespace separated list of `key=value` pairs and bare flags. Values
+//! may be wrapped in double quotes to include spaces, and everything after a lone `--` is left
+//! alone for the init process:
+//! ```text
+//! console=serial,vga loglevel=debug nosmp init="/bin/sh" -- single
+//! ```
+//!
+//! Parameters are registered with [`boot_param!`](crate::boot_param), which places a
+//! [`BootParam`] in the `.boot_params` link section, so the subsystem that owns a setting can
+//! declare it next to the code that uses it.
+
+use core::{fmt, str::FromStr};
+
+use multiboot2::BootInformation;
+use spin::Once;
+
+pub mod params;
+
+use crate::{info, sync::SpinLock, warn};
+
+/// The longest command line that is kept around after boot, longer ones are truncated.
+pub const COMMAND_LINE_MAX: usize = 1024;
+
+/// The most rejected arguments that are remembered for [`report`].
+const MAX_REJECTED: usize = 8;
+
+/// A kernel parameter that can be set from the command line.
+pub struct BootParam {
+    /// The key this parameter answers to. `-` and `_` are treated as the same character.
+    pub name: &'static str,
+    /// Invoked with the value of the argument, or `None` if it was given as a bare flag.
+    pub handler: fn(Option<Value<'static>>) -> Result<(), ParamError>,
+}
+
+/// Registers a [`BootParam`](crate::cmdline::BootParam) under the given static name.
+///
+/// ```ignore
+/// fn set_quiet(value: Option<Value<'static>>) -> Result<(), ParamError> { ... }
+/// boot_param!(QUIET_PARAM, "quiet", set_quiet);
+/// ```
+#[macro_export]
+macro_rules! boot_param {
+    ($ident:ident, $name:literal, $handler:expr) => {
+        #[used(linker)]
+        #[link_section = ".boot_params"]
+        static $ident: $crate::cmdline::BootParam = $crate::cmdline::BootParam {
+            name: $name,
+            handler: $handler,
+        };
+    };
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParamError {
+    /// No parameter with this name has been registered.
+    Unknown,
+    /// The parameter needs a value but was given as a flag.
+    MissingValue,
+    /// The parameter is a flag but was given a value.
+    UnexpectedValue,
+    /// The value could not be parsed.
+    Invalid,
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParamError::Unknown => "unknown parameter",
+            ParamError::MissingValue => "missing value",
+            ParamError::UnexpectedValue => "unexpected value",
+            ParamError::Invalid => "invalid value",
+        })
+    }
+}
+
+/// The value half of a `key=value` argument.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Value<'a>(&'a str);
+
+impl<'a> Value<'a> {
+    pub fn as_str(&self) -> &'a str {
+        self.0
+    }
+
+    /// Parses the value as any [`FromStr`] type.
+    pub fn parse<T: FromStr>(&self) -> Result<T, ParamError> {
+        self.0.parse().map_err(|_| ParamError::Invalid)
+    }
+
+    /// Parses the value as a boolean, accepting `1`/`0`, `y`/`n`, `yes`/`no`, `on`/`off` and
+    /// `true`/`false`.
+    pub fn as_bool(&self) -> Result<bool, ParamError> {
+        match self.0 {
+            "1" | "y" | "yes" | "on" | "true" => Ok(true),
+            "0" | "n" | "no" | "off" | "false" => Ok(false),
+            _ => Err(ParamError::Invalid),
+        }
+    }
+
+    /// Splits a comma separated value into its items.
+    pub fn list(&self) -> impl Iterator<Item = &'a str> {
+        self.0.split(',').filter(|item| !item.is_empty())
+    }
+}
+
+/// A single argument from the command line.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Arg<'a> {
+    pub key: &'a str,
+    pub value: Option<Value<'a>>,
+}
+
+/// Iterates over the kernel arguments of a command line, stopping at `--`.
+#[derive(Clone)]
+pub struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(command_line: &'a str) -> Self {
+        Args { rest: command_line }
+    }
+
+    /// Returns whatever follows the `--` separator, if the iterator has reached it.
+    fn remainder(mut self) -> Option<&'a str> {
+        while self.next().is_some() {}
+        let rest = self.rest.trim_start();
+        rest.strip_prefix("--").map(str::trim_start)
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = Arg<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.trim_start();
+        self.rest = rest;
+        if rest.is_empty() || rest == "--" || rest.starts_with("-- ") {
+            return None;
+        }
+
+        // Find the end of the argument, skipping over whitespace inside of quotes.
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                !quoted && c.is_whitespace()
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (arg, rest) = rest.split_at(end);
+        self.rest = rest;
+
+        Some(match arg.split_once('=') {
+            Some((key, value)) => {
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                Arg {
+                    key,
+                    value: Some(Value(value)),
+                }
+            }
+            None => Arg {
+                key: arg,
+                value: None,
+            },
+        })
+    }
+}
+
+static COMMAND_LINE: Once<CommandLine> = Once::new();
+
+static REJECTED: SpinLock<[Option<(&'static str, ParamError)>; MAX_REJECTED]> =
+    SpinLock::new([None; MAX_REJECTED]);
+
+struct CommandLine {
+    buffer: [u8; COMMAND_LINE_MAX],
+    len: usize,
+}
+
+impl CommandLine {
+    fn as_str(&self) -> &str {
+        // The buffer was copied from a `str` and only ever cut at a character boundary.
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+}
+
+/// Returns every registered boot parameter.
+pub fn registered() -> &'static [BootParam] {
+    extern "C" {
+        static __boot_params_start: u8;
+        static __boot_params_end: u8;
+    }
+
+    // SAFETY: The linker script gathers every `BootParam` emitted by `boot_param!` between
+    // these two symbols.
+    unsafe {
+        let start = core::ptr::addr_of!(__boot_params_start) as *const BootParam;
+        let end = core::ptr::addr_of!(__boot_params_end) as *const BootParam;
+        let len = (end as usize - start as usize) / core::mem::size_of::<BootParam>();
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+fn key_matches(name: &str, key: &str) -> bool {
+    name.len() == key.len()
+        && name
+            .bytes()
+            .zip(key.bytes())
+            .all(|(a, b)| a == b || (matches!(a, b'-' | b'_') && matches!(b, b'-' | b'_')))
+}
+
+/// Looks up the boot parameter registered under `key`.
+pub fn lookup(key: &str) -> Option<&'static BootParam> {
+    registered()
+        .iter()
+        .find(|param| key_matches(param.name, key))
+}
+
+/// Returns the command line the kernel was booted with.
+pub fn raw() -> &'static str {
+    COMMAND_LINE.get().map_or("", CommandLine::as_str)
+}
+
+/// Copies the command line out of the multiboot2 information and applies every argument to
+/// its boot parameter. This runs before any output is set up, so failures are held back for
+/// [`report`].
+pub fn initialize(boot_info: &BootInformation) {
+    let command_line = COMMAND_LINE.call_once(|| {
+        let source = boot_info
+            .command_line_tag()
+            .and_then(|tag| tag.command_line().ok())
+            .unwrap_or("");
+        let mut len = source.len().min(COMMAND_LINE_MAX);
+        while !source.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        let mut buffer = [0; COMMAND_LINE_MAX];
+        buffer[..len].copy_from_slice(&source.as_bytes()[..len]);
+        CommandLine { buffer, len }
+    });
+
+    let mut rejected = REJECTED.lock();
+    let mut slots = rejected.iter_mut();
+    for arg in Args::new(command_line.as_str()) {
+        let result = match lookup(arg.key) {
+            Some(param) => (param.handler)(arg.value),
+            None => Err(ParamError::Unknown),
+        };
+        if let Err(error) = result {
+            if let Some(slot) = slots.next() {
+                *slot = Some((arg.key, error));
+            }
+        }
+    }
+}
+
+/// Logs the command line and any arguments that were rejected by [`initialize`].
+pub fn report() {
+    info!("command line: {}", raw());
+    for (key, error) in REJECTED.lock().iter().flatten() {
+        warn!("cmdline: ignoring `{}`: {}", key, error);
+    }
+}
+
+/// Returns whatever follows `--` on the command line.
+fn trailing() -> &'static str {
+    Args::new(raw()).remainder().unwrap_or("")
+}
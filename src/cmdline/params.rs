@@ -0,0 +1,50 @@
+//! General kernel parameters that do not belong to a particular subsystem.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::{ParamError, Value};
+use crate::{boot_param, sync::SpinLock};
+
+/// The program started once the kernel has finished booting, unless overridden by `init=`.
+pub const DEFAULT_INIT: &str = "/sbin/init";
+
+static SMP: AtomicBool = AtomicBool::new(true);
+static INIT: SpinLock<&'static str> = SpinLock::new(DEFAULT_INIT);
+
+fn set_nosmp(value: Option<Value<'static>>) -> Result<(), ParamError> {
+    match value {
+        None => {
+            SMP.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+        Some(_) => Err(ParamError::UnexpectedValue),
+    }
+}
+
+fn set_init(value: Option<Value<'static>>) -> Result<(), ParamError> {
+    let path = value.ok_or(ParamError::MissingValue)?.as_str();
+    if !path.starts_with('/') {
+        return Err(ParamError::Invalid);
+    }
+    *INIT.lock() = path;
+    Ok(())
+}
+
+boot_param!(NOSMP_PARAM, "nosmp", set_nosmp);
+boot_param!(INIT_PARAM, "init", set_init);
+
+/// Returns `false` if the kernel was booted with `nosmp`, meaning only the bootstrap processor
+/// should be used.
+pub fn smp_enabled() -> bool {
+    SMP.load(Ordering::Relaxed)
+}
+
+/// Returns the path of the first program to run, as set by `init=`.
+pub fn init_path() -> &'static str {
+    *INIT.lock()
+}
+
+/// Returns the arguments following `--`, which are meant for the init process.
+pub fn init_args() -> impl Iterator<Item = &'static str> {
+    super::trailing().split_whitespace()
+}
@@ -0,0 +1,107 @@
+//! Identification of the processor through CPUID.
+
+use alloc::{string::String, vec::Vec};
+use core::arch::x86::__cpuid as cpuid;
+
+/// The feature flags of leaf 1 worth mentioning, as (bit, name) pairs for EDX and ECX.
+const EDX_FEATURES: [(u32, &str); 15] = [
+    (0, "fpu"),
+    (4, "tsc"),
+    (5, "msr"),
+    (6, "pae"),
+    (9, "apic"),
+    (11, "sep"),
+    (13, "pge"),
+    (15, "cmov"),
+    (16, "pat"),
+    (19, "clflush"),
+    (23, "mmx"),
+    (24, "fxsr"),
+    (25, "sse"),
+    (26, "sse2"),
+    (28, "htt"),
+];
+const ECX_FEATURES: [(u32, &str); 15] = [
+    (0, "sse3"),
+    (1, "pclmulqdq"),
+    (9, "ssse3"),
+    (12, "fma"),
+    (13, "cx16"),
+    (19, "sse4_1"),
+    (20, "sse4_2"),
+    (21, "x2apic"),
+    (22, "movbe"),
+    (23, "popcnt"),
+    (25, "aes"),
+    (26, "xsave"),
+    (28, "avx"),
+    (30, "rdrand"),
+    (31, "hypervisor"),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuInfo {
+    /// The vendor string, like `GenuineIntel`.
+    pub vendor: String,
+    /// The marketing name, on processors that have one.
+    pub brand: Option<String>,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: Vec<&'static str>,
+}
+
+/// Appends the bytes of `registers` to `out`, stopping at the first NUL.
+fn push_ascii(out: &mut String, registers: &[u32]) {
+    let bytes = registers.iter().flat_map(|register| register.to_le_bytes());
+    out.extend(bytes.take_while(|&byte| byte != 0).map(char::from));
+}
+
+/// Asks the processor what it is.
+pub fn identify() -> CpuInfo {
+    let leaf0 = cpuid(0);
+    let mut vendor = String::new();
+    push_ascii(&mut vendor, &[leaf0.ebx, leaf0.edx, leaf0.ecx]);
+
+    let leaf1 = cpuid(1);
+    let base_family = (leaf1.eax >> 8) & 0xF;
+    let mut family = base_family;
+    let mut model = (leaf1.eax >> 4) & 0xF;
+    if base_family == 0xF {
+        family += (leaf1.eax >> 20) & 0xFF;
+    }
+    if base_family == 0x6 || base_family == 0xF {
+        model |= ((leaf1.eax >> 16) & 0xF) << 4;
+    }
+    let features = EDX_FEATURES
+        .iter()
+        .filter(|(bit, _)| leaf1.edx & 1 << bit != 0)
+        .chain(
+            ECX_FEATURES
+                .iter()
+                .filter(|(bit, _)| leaf1.ecx & 1 << bit != 0),
+        )
+        .map(|&(_, name)| name)
+        .collect();
+
+    let brand = (cpuid(0x8000_0000).eax >= 0x8000_0004).then(|| {
+        let mut brand = String::new();
+        for leaf in 0x8000_0002..=0x8000_0004 {
+            let result = cpuid(leaf);
+            push_ascii(
+                &mut brand,
+                &[result.eax, result.ebx, result.ecx, result.edx],
+            );
+        }
+        String::from(brand.trim())
+    });
+
+    CpuInfo {
+        vendor,
+        brand,
+        family,
+        model,
+        stepping: leaf1.eax & 0xF,
+        features,
+    }
+}
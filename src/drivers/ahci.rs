@@ -0,0 +1,765 @@
+//! SATA hard disks on AHCI controllers, which move their data by DMA.
+//!
+//! The controller's registers are in memory behind BAR 5, with a block of them for each of
+//! its up to 32 ports. Commands are handed over through a command list in memory that the
+//! controller fetches from on its own: each of its slots points at a command table holding
+//! the command, as the FIS a SATA drive would receive, and a list of the buffers to transfer
+//! to or from. Paging is off, so those buffers are simply the ones the caller passed in.
+//!
+//! Drives that support native command queuing get their reads and writes queued, one slot
+//! per command, so that they can be worked on in whatever order suits the drive. Everything
+//! else waits for the drive to be idle and runs alone.
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    ptr,
+    sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering},
+};
+
+use super::{
+    ata::{self, FLUSH_CACHE, FLUSH_CACHE_EXT, IDENTIFY},
+    block::{self, BlockDevice, BlockError},
+    pci::{self, Bar, Match},
+    pit,
+};
+use crate::{
+    debug, info, interrupts,
+    intrinsics::io_wait,
+    memory::{FRAME_ALLOCATOR, PAGE_SIZE},
+    pci_driver,
+    sync::Mutex,
+    warn,
+};
+
+// Registers of the whole controller.
+const CAP: usize = 0x00;
+const GHC: usize = 0x04;
+const IS: usize = 0x08;
+const PI: usize = 0x0C;
+const VS: usize = 0x10;
+const CAP2: usize = 0x24;
+const BOHC: usize = 0x28;
+
+const CAP_SSS: u32 = 1 << 27;
+const CAP_SNCQ: u32 = 1 << 30;
+const GHC_HR: u32 = 1 << 0;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+const CAP2_BOH: u32 = 1 << 0;
+const BOHC_BOS: u32 = 1 << 0;
+const BOHC_OOS: u32 = 1 << 1;
+
+// Registers of each port, as offsets from the start of its block.
+const PORTS: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0C;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SCTL: usize = 0x2C;
+const PX_SERR: usize = 0x30;
+const PX_SACT: usize = 0x34;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_POD: u32 = 1 << 2;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_DF: u32 = 1 << 5;
+const TFD_BSY: u32 = 1 << 7;
+
+/// The interrupts a port raises: when a command finishes, queued or not, and when it fails.
+const IE_ENABLED: u32 = 1 << 0 | 1 << 1 | 1 << 3 | 1 << 5 | 0xF << 27;
+
+/// The device detection field of PxSSTS, once a device is there and talking.
+const SSTS_DET_PRESENT: u32 = 3;
+const SCTL_DET_RESET: u32 = 1;
+
+/// What PxSIG holds once an ATA drive has said hello. ATAPI drives, enclosure management
+/// bridges and port multipliers leave something else.
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+const SIGNATURE_ATAPI: u32 = 0xEB14_0101;
+
+const FIS_REGISTER_H2D: u8 = 0x27;
+/// Set in the second byte of a Register FIS that carries a command.
+const FIS_COMMAND: u8 = 1 << 7;
+const DEVICE_LBA: u8 = 1 << 6;
+
+const READ_DMA: u8 = 0xC8;
+const READ_DMA_EXT: u8 = 0x25;
+const WRITE_DMA: u8 = 0xCA;
+const WRITE_DMA_EXT: u8 = 0x35;
+const READ_FPDMA_QUEUED: u8 = 0x60;
+const WRITE_FPDMA_QUEUED: u8 = 0x61;
+
+// Where the command list, the received FISes and the command tables of a port go in the
+// frames allocated for it, and how large each part is.
+const COMMAND_LIST: usize = 0;
+const COMMAND_HEADER_SIZE: usize = 32;
+const RECEIVED_FIS: usize = 0x400;
+const COMMAND_TABLES: usize = PAGE_SIZE;
+/// A command table with room for a single PRD entry, rounded up to the alignment of the next.
+const COMMAND_TABLE_SIZE: usize = 0x100;
+const PRDT: usize = 0x80;
+const MAX_SLOTS: usize = 32;
+const PORT_FRAMES: usize = (COMMAND_TABLES + MAX_SLOTS * COMMAND_TABLE_SIZE) / PAGE_SIZE;
+
+const HEADER_WRITE: u32 = 1 << 6;
+/// The length of a Register Host to Device FIS in double words.
+const HEADER_FIS_LENGTH: u32 = 5;
+
+/// The most one PRD entry, and so one command, transfers.
+const MAX_BYTES: usize = 4 << 20;
+const LBA28_LIMIT: u64 = 1 << 28;
+const LBA28_MAX_SECTORS: u64 = 256;
+
+/// How long to wait for a command, in ticks.
+const COMMAND_TIMEOUT: u32 = 5 * pit::HZ;
+/// How many times to check on a command before giving up, when ticks cannot be counted.
+const POLL_TIMEOUT: usize = 5_000_000;
+
+/// The controllers with an interrupt handler, whose registers it reads to acknowledge the
+/// interrupt.
+const IRQ_SLOTS: usize = 4;
+static IRQ_REGISTERS: [AtomicUsize; IRQ_SLOTS] = [const { AtomicUsize::new(0) }; IRQ_SLOTS];
+static CONTROLLERS: AtomicUsize = AtomicUsize::new(0);
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+
+fn handle_irq(slot: usize) {
+    let hba = Registers(IRQ_REGISTERS[slot].load(Ordering::Relaxed));
+    let pending = hba.read(IS);
+    for port in (0..32).filter(|port| pending & (1 << port) != 0) {
+        let port = hba.port(port);
+        port.write(PX_IS, port.read(PX_IS));
+    }
+    hba.write(IS, pending);
+}
+
+fn handle_irq_0() {
+    handle_irq(0);
+}
+
+fn handle_irq_1() {
+    handle_irq(1);
+}
+
+fn handle_irq_2() {
+    handle_irq(2);
+}
+
+fn handle_irq_3() {
+    handle_irq(3);
+}
+
+const IRQ_HANDLERS: [fn(); IRQ_SLOTS] = [handle_irq_0, handle_irq_1, handle_irq_2, handle_irq_3];
+
+/// A block of memory-mapped registers, either the controller's or a port's.
+#[derive(Copy, Clone, Debug)]
+struct Registers(usize);
+
+impl Registers {
+    fn read(self, offset: usize) -> u32 {
+        // SAFETY: The registers belong to the controller, whose BAR says where they are.
+        unsafe { ptr::read_volatile((self.0 + offset) as *const u32) }
+    }
+
+    fn write(self, offset: usize, value: u32) {
+        // SAFETY: See `read`.
+        unsafe { ptr::write_volatile((self.0 + offset) as *mut u32, value) }
+    }
+
+    fn port(self, number: usize) -> Registers {
+        Registers(self.0 + PORTS + number * PORT_SIZE)
+    }
+
+    /// Waits up to `micros` microseconds for the bits in `mask` to read as `expected`.
+    fn wait(self, offset: usize, mask: u32, expected: u32, micros: usize) -> bool {
+        for _ in 0..micros {
+            if self.read(offset) & mask == expected {
+                return true;
+            }
+            io_wait();
+        }
+        self.read(offset) & mask == expected
+    }
+}
+
+/// The registers of a Register Host to Device FIS that commands fill in.
+#[derive(Copy, Clone, Debug, Default)]
+struct Request {
+    command: u8,
+    features: u16,
+    lba: u64,
+    count: u16,
+    device: u8,
+}
+
+impl Request {
+    fn fis(&self) -> [u8; 20] {
+        let lba = self.lba.to_le_bytes();
+        let [features_low, features_high] = self.features.to_le_bytes();
+        let [count_low, count_high] = self.count.to_le_bytes();
+        let mut fis = [0; 20];
+        fis[..4].copy_from_slice(&[FIS_REGISTER_H2D, FIS_COMMAND, self.command, features_low]);
+        fis[4..8].copy_from_slice(&[lba[0], lba[1], lba[2], self.device]);
+        fis[8..12].copy_from_slice(&[lba[3], lba[4], lba[5], features_high]);
+        fis[12..14].copy_from_slice(&[count_low, count_high]);
+        fis
+    }
+}
+
+/// Where the data of a command goes or comes from, as a physical address and a length.
+#[derive(Copy, Clone, Debug)]
+enum Data {
+    None,
+    Read(usize, usize),
+    Write(usize, usize),
+}
+
+/// A port with a drive on it, and the memory its commands are handed over in.
+struct Port {
+    number: usize,
+    registers: Registers,
+    memory: usize,
+    /// How many command slots the controller has.
+    slots: usize,
+    /// The slots in use.
+    busy: AtomicU32,
+    /// Held while issuing a queued command, and for the whole of any other.
+    issue: Mutex<()>,
+    /// Counts the times the port has been restarted after an error, which throws away every
+    /// command in flight. Only changes while `issue` is held.
+    restarts: AtomicU32,
+    use_irq: bool,
+}
+
+impl Port {
+    /// Stops the port from processing commands and receiving FISes.
+    fn stop(&self) -> bool {
+        let registers = self.registers;
+        registers.write(PX_CMD, registers.read(PX_CMD) & !CMD_ST);
+        if !registers.wait(PX_CMD, CMD_CR, 0, 500_000) {
+            return false;
+        }
+        registers.write(PX_CMD, registers.read(PX_CMD) & !CMD_FRE);
+        registers.wait(PX_CMD, CMD_FR, 0, 500_000)
+    }
+
+    fn start(&self) {
+        let registers = self.registers;
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_FRE);
+        registers.wait(PX_TFD, TFD_BSY | TFD_DRQ, 0, 1_000_000);
+        registers.write(PX_SERR, u32::MAX);
+        registers.write(PX_IS, u32::MAX);
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_ST);
+    }
+
+    /// Points the port at its command list and received FIS area.
+    fn set_up(&self) {
+        // SAFETY: The frames were allocated for the port and nothing else uses them.
+        unsafe { ptr::write_bytes(self.memory as *mut u8, 0, PORT_FRAMES * PAGE_SIZE) };
+        let registers = self.registers;
+        registers.write(PX_CLB, (self.memory + COMMAND_LIST) as u32);
+        registers.write(PX_CLBU, 0);
+        registers.write(PX_FB, (self.memory + RECEIVED_FIS) as u32);
+        registers.write(PX_FBU, 0);
+        registers.write(PX_IE, if self.use_irq { IE_ENABLED } else { 0 });
+    }
+
+    /// Gets the port going again after a command failed, which cancels everything in flight.
+    /// A drive that is stuck busy gets a COMRESET.
+    ///
+    /// `seen` is the count of restarts from when the failed command was issued. When several
+    /// queued commands fail together, each of their waiters gets here, and only the first
+    /// finds the count unchanged and restarts the port. Must be called with `issue` held.
+    fn restart(&self, seen: u32) {
+        if self.restarts.load(Ordering::Relaxed) != seen {
+            return;
+        }
+        let registers = self.registers;
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+        self.stop();
+        if registers.read(PX_TFD) & (TFD_BSY | TFD_DRQ) != 0 {
+            let control = registers.read(PX_SCTL) & !0xF;
+            registers.write(PX_SCTL, control | SCTL_DET_RESET);
+            for _ in 0..1000 {
+                io_wait();
+            }
+            registers.write(PX_SCTL, control);
+            registers.wait(PX_SSTS, 0xF, SSTS_DET_PRESENT, 10_000);
+        }
+        self.start();
+    }
+
+    /// Takes a free slot among the first `limit`.
+    fn take_slot(&self, limit: usize) -> usize {
+        loop {
+            let busy = self.busy.load(Ordering::Relaxed);
+            let slot = busy.trailing_ones() as usize;
+            if slot < limit
+                && self
+                    .busy
+                    .compare_exchange(busy, busy | 1 << slot, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return slot;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Fills in a slot's command header and table.
+    fn prepare(&self, slot: usize, request: &Request, data: Data) {
+        let table = self.memory + COMMAND_TABLES + slot * COMMAND_TABLE_SIZE;
+        let (flags, prd) = match data {
+            Data::None => (0, None),
+            Data::Read(address, len) => (0, Some((address, len))),
+            Data::Write(address, len) => (HEADER_WRITE, Some((address, len))),
+        };
+        let header = (self.memory + COMMAND_LIST + slot * COMMAND_HEADER_SIZE) as *mut u32;
+        let fis = request.fis();
+        // SAFETY: The slot is ours, and so are its header and table until it is issued.
+        unsafe {
+            ptr::write_bytes(table as *mut u8, 0, COMMAND_TABLE_SIZE);
+            ptr::copy_nonoverlapping(fis.as_ptr(), table as *mut u8, fis.len());
+            if let Some((address, len)) = prd {
+                let entry = (table + PRDT) as *mut u32;
+                entry.write_volatile(address as u32);
+                entry.add(1).write_volatile(0);
+                // The byte count is stored less one.
+                entry.add(3).write_volatile(len as u32 - 1);
+            }
+            let entries = u32::from(prd.is_some());
+            header.write_volatile(HEADER_FIS_LENGTH | flags | entries << 16);
+            header.add(1).write_volatile(0);
+            header.add(2).write_volatile(table as u32);
+            header.add(3).write_volatile(0);
+        }
+    }
+
+    /// Runs a command and waits for it to finish. Queued commands have the slot they are in
+    /// put into their sector count as their tag.
+    fn execute(
+        &self,
+        mut request: Request,
+        data: Data,
+        queue_depth: Option<usize>,
+    ) -> Result<(), BlockError> {
+        let slot = self.take_slot(queue_depth.unwrap_or(self.slots));
+        let bit = 1 << slot;
+        let result = if queue_depth.is_some() {
+            request.count = (slot as u16) << 3;
+            self.prepare(slot, &request, data);
+            let restarts = {
+                let _guard = self.issue.lock();
+                let restarts = self.restarts.load(Ordering::Relaxed);
+                fence(Ordering::SeqCst);
+                self.registers.write(PX_SACT, bit);
+                self.registers.write(PX_CI, bit);
+                restarts
+            };
+            self.wait(|| (self.registers.read(PX_SACT) | self.registers.read(PX_CI)) & bit != 0)
+                .inspect_err(|_| {
+                    let _guard = self.issue.lock();
+                    self.restart(restarts);
+                })
+                .and_then(|()| {
+                    // Another command failing cancels this one along with it.
+                    if self.restarts.load(Ordering::Relaxed) == restarts {
+                        Ok(())
+                    } else {
+                        Err(BlockError::Failed("command aborted"))
+                    }
+                })
+        } else {
+            self.prepare(slot, &request, data);
+            let _guard = self.issue.lock();
+            let restarts = self.restarts.load(Ordering::Relaxed);
+            self.wait(|| self.registers.read(PX_SACT) != 0)
+                .and_then(|()| {
+                    fence(Ordering::SeqCst);
+                    self.registers.write(PX_CI, bit);
+                    self.wait(|| self.registers.read(PX_CI) & bit != 0)
+                })
+                .inspect_err(|_| self.restart(restarts))
+        };
+        fence(Ordering::SeqCst);
+        self.busy.fetch_and(!bit, Ordering::Release);
+        result
+    }
+
+    /// Waits until `pending` returns `false`, sleeping until the next interrupt in between if
+    /// interrupts are on, and fails if the drive reports an error. The port is then left for
+    /// the caller to restart.
+    fn wait(&self, pending: impl Fn() -> bool) -> Result<(), BlockError> {
+        let sleep = self.use_irq && interrupts::are_enabled();
+        let start = pit::ticks();
+        let mut polls = 0;
+        loop {
+            if sleep {
+                interrupts::disable();
+            }
+            let status = self.registers.read(PX_TFD);
+            if status & (TFD_ERR | TFD_DF) != 0 {
+                if sleep {
+                    interrupts::enable();
+                }
+                let error = (status >> 8) as u8;
+                return Err(BlockError::Failed(if status & TFD_DF != 0 {
+                    "drive fault"
+                } else {
+                    ata::error_message(error)
+                }));
+            }
+            if !pending() {
+                if sleep {
+                    interrupts::enable();
+                }
+                return Ok(());
+            }
+            let timed_out = if sleep {
+                pit::ticks().wrapping_sub(start) > COMMAND_TIMEOUT
+            } else {
+                polls += 1;
+                polls > POLL_TIMEOUT
+            };
+            if timed_out {
+                if sleep {
+                    interrupts::enable();
+                }
+                warn!("ahci: port {} timed out", self.number);
+                return Err(BlockError::Timeout);
+            }
+            if sleep {
+                interrupts::enable_and_wait();
+            } else {
+                io_wait();
+            }
+        }
+    }
+
+    fn identify(&self) -> Result<[u16; 256], BlockError> {
+        let mut identify = [0u16; 256];
+        let request = Request {
+            command: IDENTIFY,
+            ..Request::default()
+        };
+        self.execute(
+            request,
+            Data::Read(identify.as_mut_ptr() as usize, 512),
+            None,
+        )?;
+        Ok(identify)
+    }
+}
+
+pub struct Disk {
+    name: String,
+    port: Port,
+    lba48: bool,
+    /// How many commands the drive takes at once, if it supports native command queuing.
+    queue_depth: Option<usize>,
+    sector_size: usize,
+    sector_count: u64,
+    model: String,
+}
+
+impl Disk {
+    /// The most sectors one command transfers.
+    fn max_sectors(&self) -> u64 {
+        let sectors = (MAX_BYTES / self.sector_size) as u64;
+        if self.lba48 {
+            sectors
+        } else {
+            sectors.min(LBA28_MAX_SECTORS)
+        }
+    }
+
+    /// Reads or writes up to [`Disk::max_sectors`] sectors with one command. The buffer has
+    /// to start on an even address.
+    fn transfer(&self, sector: u64, count: u64, data: Data) -> Result<(), BlockError> {
+        let write = matches!(data, Data::Write(..));
+        let request = if self.queue_depth.is_some() {
+            Request {
+                command: if write {
+                    WRITE_FPDMA_QUEUED
+                } else {
+                    READ_FPDMA_QUEUED
+                },
+                // Queued commands take the count in the features and the tag in its place.
+                features: count as u16,
+                lba: sector,
+                device: DEVICE_LBA,
+                ..Request::default()
+            }
+        } else if self.lba48 {
+            Request {
+                command: if write { WRITE_DMA_EXT } else { READ_DMA_EXT },
+                lba: sector,
+                // A count of 0 means 65536.
+                count: count as u16,
+                device: DEVICE_LBA,
+                ..Request::default()
+            }
+        } else {
+            if sector + count > LBA28_LIMIT {
+                return Err(BlockError::OutOfRange);
+            }
+            Request {
+                command: if write { WRITE_DMA } else { READ_DMA },
+                lba: sector & 0xFF_FFFF,
+                // A count of 0 means 256.
+                count: count as u16 & 0xFF,
+                device: DEVICE_LBA | (sector >> 24) as u8 & 0xF,
+                ..Request::default()
+            }
+        };
+        self.port.execute(request, data, self.queue_depth)
+    }
+}
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.model
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_transfer(self, sector, buffer.len())?;
+        let max_sectors = self.max_sectors();
+        for (i, chunk) in buffer
+            .chunks_mut(max_sectors as usize * self.sector_size)
+            .enumerate()
+        {
+            let start = sector + i as u64 * max_sectors;
+            let count = (chunk.len() / self.sector_size) as u64;
+            if (chunk.as_ptr() as usize).is_multiple_of(2) {
+                let data = Data::Read(chunk.as_mut_ptr() as usize, chunk.len());
+                self.transfer(start, count, data)?;
+            } else {
+                // DMA needs the buffer to start on a word.
+                let mut bounce = vec![0u16; chunk.len() / 2];
+                let data = Data::Read(bounce.as_mut_ptr() as usize, chunk.len());
+                self.transfer(start, count, data)?;
+                for (bytes, word) in chunk.chunks_exact_mut(2).zip(&bounce) {
+                    bytes.copy_from_slice(&word.to_ne_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_transfer(self, sector, buffer.len())?;
+        let max_sectors = self.max_sectors();
+        for (i, chunk) in buffer
+            .chunks(max_sectors as usize * self.sector_size)
+            .enumerate()
+        {
+            let start = sector + i as u64 * max_sectors;
+            let count = (chunk.len() / self.sector_size) as u64;
+            if (chunk.as_ptr() as usize).is_multiple_of(2) {
+                let data = Data::Write(chunk.as_ptr() as usize, chunk.len());
+                self.transfer(start, count, data)?;
+            } else {
+                let bounce: Vec<u16> = chunk
+                    .chunks_exact(2)
+                    .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+                    .collect();
+                let data = Data::Write(bounce.as_ptr() as usize, chunk.len());
+                self.transfer(start, count, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let request = Request {
+            command: if self.lba48 {
+                FLUSH_CACHE_EXT
+            } else {
+                FLUSH_CACHE
+            },
+            device: DEVICE_LBA,
+            ..Request::default()
+        };
+        self.port.execute(request, Data::None, None)
+    }
+}
+
+/// Brings up a port, and registers the drive on it if it is a hard disk.
+fn probe_port(hba: Registers, number: usize, use_irq: bool) {
+    let capabilities = hba.read(CAP);
+    let registers = hba.port(number);
+    if capabilities & CAP_SSS != 0 {
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_SUD | CMD_POD);
+    }
+    // The link comes up within 10 ms of the drive spinning up, if there is one.
+    if !registers.wait(PX_SSTS, 0xF, SSTS_DET_PRESENT, 10_000) {
+        debug!("ahci: nothing on port {}", number);
+        return;
+    }
+    let Some(memory) = FRAME_ALLOCATOR
+        .lock()
+        .allocate_contiguous(PORT_FRAMES, PAGE_SIZE)
+    else {
+        warn!("ahci: no memory for port {}", number);
+        return;
+    };
+    let port = Port {
+        number,
+        registers,
+        memory,
+        slots: ((capabilities >> 8 & 0x1F) + 1) as usize,
+        busy: AtomicU32::new(0),
+        issue: Mutex::named("ahci", ()),
+        restarts: AtomicU32::new(0),
+        use_irq,
+    };
+    if !port.stop() {
+        warn!("ahci: port {} does not stop", number);
+        FRAME_ALLOCATOR
+            .lock()
+            .deallocate_contiguous(memory, PORT_FRAMES);
+        return;
+    }
+    port.set_up();
+    port.start();
+
+    let identify = match registers.read(PX_SIG) {
+        SIGNATURE_ATA => port.identify(),
+        SIGNATURE_ATAPI => Err(BlockError::Failed("ATAPI drives are not supported")),
+        _ => Err(BlockError::Failed("unknown kind of device")),
+    };
+    let identify = match identify {
+        Ok(identify) => identify,
+        Err(error) => {
+            info!("ahci: port {}: {}", number, error);
+            port.stop();
+            FRAME_ALLOCATOR
+                .lock()
+                .deallocate_contiguous(memory, PORT_FRAMES);
+            return;
+        }
+    };
+
+    let (lba48, sector_size, sector_count) = ata::identify_capacity(&identify);
+    // Word 76 says whether the drive queues commands, and word 75 how many, less one.
+    let queue_depth = (capabilities & CAP_SNCQ != 0 && identify[76] & (1 << 8) != 0)
+        .then(|| (usize::from(identify[75] & 0x1F) + 1).min(port.slots));
+    let disk = Disk {
+        name: format!(
+            "sd{}",
+            (b'a' + DISKS.fetch_add(1, Ordering::Relaxed) as u8) as char
+        ),
+        port,
+        lba48,
+        queue_depth,
+        sector_size,
+        sector_count,
+        model: ata::identify_string(&identify[27..47]),
+    };
+    debug!(
+        "ahci: port {} is {}, queue depth {}",
+        number,
+        disk.name,
+        queue_depth.unwrap_or(1)
+    );
+    if block::register(Arc::new(disk)).is_err() {
+        warn!("ahci: a block device with the same name already exists");
+    }
+}
+
+/// Takes the controller from the firmware, resets it and switches it into AHCI mode.
+fn reset(hba: Registers) -> bool {
+    if hba.read(CAP2) & CAP2_BOH != 0 {
+        hba.write(BOHC, hba.read(BOHC) | BOHC_OOS);
+        if !hba.wait(BOHC, BOHC_BOS, 0, 2_000_000) {
+            warn!("ahci: the firmware does not let go of the controller");
+        }
+    }
+    hba.write(GHC, hba.read(GHC) | GHC_AE);
+    hba.write(GHC, hba.read(GHC) | GHC_HR);
+    if !hba.wait(GHC, GHC_HR, 0, 1_000_000) {
+        return false;
+    }
+    hba.write(GHC, hba.read(GHC) | GHC_AE);
+    true
+}
+
+fn probe(device: &pci::Device) -> Result<(), ()> {
+    let Some(Bar::Memory { address, .. }) = device.bars[5] else {
+        return Err(());
+    };
+    let hba = Registers(usize::try_from(address).map_err(|_| ())?);
+    device.enable();
+    if !reset(hba) {
+        warn!("ahci: {} does not come out of reset", device.address);
+        return Err(());
+    }
+
+    let capabilities = hba.read(CAP);
+    let version = hba.read(VS);
+    info!(
+        "ahci: {} version {}.{}, {} ports, {} command slots{}",
+        device.address,
+        version >> 16,
+        version >> 8 & 0xFF,
+        (capabilities & 0x1F) + 1,
+        (capabilities >> 8 & 0x1F) + 1,
+        if capabilities & CAP_SNCQ != 0 {
+            ", NCQ"
+        } else {
+            ""
+        }
+    );
+
+    let number = CONTROLLERS.fetch_add(1, Ordering::Relaxed);
+    let use_irq = number < IRQ_SLOTS
+        && device.interrupt_line.is_some_and(|irq| {
+            IRQ_REGISTERS[number].store(hba.0, Ordering::Relaxed);
+            interrupts::register_irq(irq, IRQ_HANDLERS[number]).is_ok()
+        });
+    if use_irq {
+        hba.write(IS, u32::MAX);
+        hba.write(GHC, hba.read(GHC) | GHC_IE);
+        device.set_interrupts(true);
+    } else {
+        warn!("ahci: {} cannot use its IRQ, polling it", device.address);
+    }
+
+    let implemented = hba.read(PI);
+    for port in (0..32).filter(|port| implemented & (1 << port) != 0) {
+        probe_port(hba, port, use_irq);
+    }
+    Ok(())
+}
+
+pci_driver!(
+    AHCI_DRIVER,
+    "ahci",
+    &[Match::class(0x01, 0x06, Some(0x01))],
+    probe
+);
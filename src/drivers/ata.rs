@@ -0,0 +1,628 @@
+//! Parallel ATA and ATAPI drives on IDE controllers, driven in PIO mode.
+//!
+//! Each controller has two channels with up to two drives each. The channels sit on the
+//! legacy ports and IRQs 14 and 15 unless the controller has been switched to native mode, in
+//! which case they are wherever its BARs say. Hard disks are read and written with LBA28
+//! commands, or LBA48 ones for sectors past the first 128 GiB on drives that support them.
+//! CD and DVD drives are read with SCSI commands sent in ATAPI packets.
+//!
+//! The drive raises an interrupt whenever a sector is ready or a command has finished, which
+//! the driver sleeps until. A channel whose interrupts do not arrive is polled instead.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+
+use super::{
+    block::{self, BlockDevice, BlockError},
+    pci::{self, Bar, Match},
+    pit,
+};
+use crate::{
+    debug, info, interrupts,
+    intrinsics::{inb, inw, io_wait, outb, outw},
+    pci_driver,
+    sync::Mutex,
+    warn,
+};
+
+// Registers, as offsets from the base port of a channel.
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const FEATURES: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const ERROR_ABRT: u8 = 1 << 2;
+const ERROR_IDNF: u8 = 1 << 4;
+const ERROR_UNC: u8 = 1 << 6;
+
+const CONTROL_SRST: u8 = 1 << 2;
+
+const DRIVE_LBA: u8 = 0xE0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const PACKET: u8 = 0xA0;
+const IDENTIFY_PACKET: u8 = 0xA1;
+pub(super) const FLUSH_CACHE: u8 = 0xE7;
+pub(super) const FLUSH_CACHE_EXT: u8 = 0xEA;
+pub(super) const IDENTIFY: u8 = 0xEC;
+
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_12: u8 = 0xA8;
+/// The sense key an ATAPI drive reports when it has no disc in it, among other things.
+const SENSE_NOT_READY: u8 = 0x2;
+
+/// What ATAPI drives leave in the LBA mid and high registers after a reset.
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xEB);
+
+const LEGACY_CHANNELS: [(u16, u16, u8); 2] = [(0x1F0, 0x3F6, 14), (0x170, 0x376, 15)];
+/// Set in the programming interface for each channel in native mode.
+const NATIVE_MODE: [u8; 2] = [1 << 0, 1 << 2];
+
+const SECTOR_SIZE: usize = 512;
+const ATAPI_SECTOR_SIZE: usize = 2048;
+/// The most sectors one command transfers, which is all the LBA28 sector count can say.
+const MAX_SECTORS: u64 = 256;
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// How many times to check the status before giving up on a drive, about a microsecond each.
+const POLL_TIMEOUT: usize = 5_000_000;
+/// How many ticks to wait for an interrupt before polling instead.
+const IRQ_TIMEOUT: u32 = pit::HZ;
+
+/// The channels with an interrupt handler, whose status port it reads to acknowledge the
+/// interrupt and whose flag it sets.
+const IRQ_SLOTS: usize = 2;
+static IRQ_PORTS: [AtomicU16; IRQ_SLOTS] = [const { AtomicU16::new(0) }; IRQ_SLOTS];
+static IRQ_RECEIVED: [AtomicBool; IRQ_SLOTS] = [const { AtomicBool::new(false) }; IRQ_SLOTS];
+static CHANNELS: AtomicUsize = AtomicUsize::new(0);
+
+fn handle_irq(slot: usize) {
+    let port = IRQ_PORTS[slot].load(Ordering::Relaxed);
+    // SAFETY: Reading the status register acknowledges the interrupt.
+    unsafe { inb(port + STATUS) };
+    IRQ_RECEIVED[slot].store(true, Ordering::Release);
+}
+
+fn handle_irq_0() {
+    handle_irq(0);
+}
+
+fn handle_irq_1() {
+    handle_irq(1);
+}
+
+const IRQ_HANDLERS: [fn(); IRQ_SLOTS] = [handle_irq_0, handle_irq_1];
+
+/// Turns the error register into what went wrong.
+pub(super) fn error_message(error: u8) -> &'static str {
+    if error & ERROR_UNC != 0 {
+        "uncorrectable data error"
+    } else if error & ERROR_IDNF != 0 {
+        "sector not found"
+    } else if error & ERROR_ABRT != 0 {
+        "command aborted"
+    } else {
+        "drive error"
+    }
+}
+
+/// One of the two channels of an IDE controller.
+struct Channel {
+    /// Which channel this is, counting across controllers.
+    number: usize,
+    base: u16,
+    control: u16,
+    /// Whether the channel's interrupt handler is installed and its interrupts arrive.
+    use_irq: AtomicBool,
+    /// Held for the whole of each command.
+    lock: Mutex<()>,
+}
+
+impl Channel {
+    fn new(base: u16, control: u16, irq: u8) -> Channel {
+        let number = CHANNELS.fetch_add(1, Ordering::Relaxed);
+        let use_irq = number < IRQ_SLOTS && {
+            IRQ_PORTS[number].store(base, Ordering::Relaxed);
+            interrupts::register_irq(irq, IRQ_HANDLERS[number]).is_ok()
+        };
+        if !use_irq {
+            warn!("ata: channel {} cannot use IRQ {}, polling it", number, irq);
+        }
+        Channel {
+            number,
+            base,
+            control,
+            use_irq: AtomicBool::new(use_irq),
+            lock: Mutex::named("ata", ()),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        // SAFETY: The ports belong to the channel.
+        unsafe { inb(self.base + register) }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        // SAFETY: See `read`.
+        unsafe { outb(self.base + register, value) }
+    }
+
+    /// Reads the status without acknowledging an interrupt.
+    fn alternate_status(&self) -> u8 {
+        // SAFETY: See `read`.
+        unsafe { inb(self.control) }
+    }
+
+    /// Gives the drive the 400 ns it needs before its status means anything.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn read_data(&self, buffer: &mut [u8]) {
+        for word in buffer.chunks_exact_mut(2) {
+            // SAFETY: See `read`.
+            word.copy_from_slice(&unsafe { inw(self.base + DATA) }.to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, buffer: &[u8]) {
+        for word in buffer.chunks_exact(2) {
+            // SAFETY: See `read`.
+            unsafe { outw(self.base + DATA, u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    /// Resets both drives. Returns `false` if there is nothing on the channel.
+    fn reset(&self) -> bool {
+        // A floating bus reads as all ones.
+        if self.alternate_status() == 0xFF {
+            return false;
+        }
+        // SAFETY: See `read`.
+        unsafe {
+            outb(self.control, CONTROL_SRST);
+            for _ in 0..5 {
+                io_wait();
+            }
+            outb(self.control, 0);
+        }
+        for _ in 0..2000 {
+            io_wait();
+        }
+        self.wait_not_busy().is_ok()
+    }
+
+    fn select(&self, slave: bool, high_bits: u8) {
+        let slave = if slave { DRIVE_SLAVE } else { 0 };
+        self.write(DRIVE, DRIVE_LBA | slave | high_bits & 0xF);
+        self.delay();
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_TIMEOUT {
+            let status = self.alternate_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            io_wait();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Waits for the drive to finish what it is doing and checks that it did not fail.
+    fn poll(&self) -> Result<u8, BlockError> {
+        let status = self.wait_not_busy()?;
+        if status & STATUS_DF != 0 {
+            return Err(BlockError::Failed("drive fault"));
+        }
+        if status & STATUS_ERR != 0 {
+            return Err(BlockError::Failed(error_message(self.read(ERROR))));
+        }
+        Ok(status)
+    }
+
+    /// Waits for the drive to raise its interrupt, or polls it if interrupts cannot be used.
+    fn wait(&self) -> Result<u8, BlockError> {
+        if self.use_irq.load(Ordering::Relaxed) && interrupts::are_enabled() {
+            let start = pit::ticks();
+            loop {
+                interrupts::disable();
+                if IRQ_RECEIVED[self.number].swap(false, Ordering::Acquire) {
+                    interrupts::enable();
+                    break;
+                }
+                if pit::ticks().wrapping_sub(start) > IRQ_TIMEOUT {
+                    interrupts::enable();
+                    warn!("ata: no interrupt from channel {}, polling it", self.number);
+                    self.use_irq.store(false, Ordering::Relaxed);
+                    break;
+                }
+                interrupts::enable_and_wait();
+            }
+        }
+        self.poll()
+    }
+
+    /// Writes the command registers and issues `command`.
+    fn command(&self, command: u8) {
+        if self.number < IRQ_SLOTS {
+            IRQ_RECEIVED[self.number].store(false, Ordering::Relaxed);
+        }
+        self.write(COMMAND, command);
+        self.delay();
+    }
+
+    /// Runs IDENTIFY or IDENTIFY PACKET on a drive, returning the kind of drive and what it
+    /// says about itself, or `None` if there is no drive.
+    fn identify(&self, slave: bool) -> Option<(Kind, [u16; 256])> {
+        self.select(slave, 0);
+        for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            self.write(register, 0);
+        }
+        self.command(IDENTIFY);
+        if self.alternate_status() == 0 {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        let kind = if (self.read(LBA_MID), self.read(LBA_HIGH)) == ATAPI_SIGNATURE {
+            self.command(IDENTIFY_PACKET);
+            Kind::Atapi
+        } else {
+            Kind::Ata
+        };
+        let status = self.poll().ok()?;
+        if status & STATUS_DRQ == 0 {
+            return None;
+        }
+        let mut bytes = [0; 512];
+        self.read_data(&mut bytes);
+        Some((
+            kind,
+            core::array::from_fn(|i| u16::from_le_bytes([bytes[2 * i], bytes[2 * i + 1]])),
+        ))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Ata,
+    Atapi,
+}
+
+/// Reads a string out of IDENTIFY data, which keeps the two characters of each word swapped.
+pub(super) fn identify_string(words: &[u16]) -> String {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+/// Reads what IDENTIFY data says about the size of a hard disk: whether it supports LBA48,
+/// how large its sectors are and how many of them it has.
+pub(super) fn identify_capacity(identify: &[u16; 256]) -> (bool, usize, u64) {
+    let lba48 = identify[83] & (1 << 10) != 0;
+    let sector_count = if lba48 {
+        (0..4).fold(0, |count, i| {
+            count | u64::from(identify[100 + i]) << (16 * i)
+        })
+    } else {
+        u64::from(identify[60]) | u64::from(identify[61]) << 16
+    };
+    // Word 106 says whether word 117 and 118 hold a logical sector size other than 512.
+    let sector_size = if identify[106] & 0xC000 == 0x4000 && identify[106] & (1 << 12) != 0 {
+        2 * (usize::from(identify[117]) | usize::from(identify[118]) << 16)
+    } else {
+        SECTOR_SIZE
+    };
+    (lba48, sector_size, sector_count)
+}
+
+pub struct Drive {
+    name: String,
+    channel: Arc<Channel>,
+    slave: bool,
+    kind: Kind,
+    lba48: bool,
+    sector_size: usize,
+    sector_count: u64,
+    model: String,
+}
+
+impl Drive {
+    fn new(channel: Arc<Channel>, slave: bool, kind: Kind, identify: &[u16; 256]) -> Drive {
+        let name = format!(
+            "hd{}",
+            (b'a' + (channel.number * 2 + slave as usize) as u8) as char
+        );
+        let (lba48, sector_size, sector_count) = identify_capacity(identify);
+        let mut drive = Drive {
+            name,
+            channel,
+            slave,
+            kind,
+            lba48,
+            sector_size,
+            sector_count,
+            model: identify_string(&identify[27..47]),
+        };
+        if kind == Kind::Atapi {
+            drive.sector_size = ATAPI_SECTOR_SIZE;
+            drive.sector_count = match drive.read_capacity() {
+                Ok(count) => count,
+                Err(error) => {
+                    debug!("ata: {}: {}", drive.name, error);
+                    0
+                }
+            };
+        }
+        drive
+    }
+
+    /// Reads or writes up to [`MAX_SECTORS`] sectors of a hard disk with one command.
+    fn transfer(&self, sector: u64, count: u64, buffer: Transfer) -> Result<(), BlockError> {
+        let channel = &self.channel;
+        let _guard = channel.lock.lock();
+        let extended = sector + count > LBA28_LIMIT;
+        if extended && !self.lba48 {
+            return Err(BlockError::OutOfRange);
+        }
+        if extended {
+            self.channel.select(self.slave, 0);
+            channel.write(SECTOR_COUNT, (count >> 8) as u8);
+            channel.write(LBA_LOW, (sector >> 24) as u8);
+            channel.write(LBA_MID, (sector >> 32) as u8);
+            channel.write(LBA_HIGH, (sector >> 40) as u8);
+        } else {
+            self.channel.select(self.slave, (sector >> 24) as u8);
+        }
+        // A count of 0 means 256, or 65536 for LBA48.
+        channel.write(SECTOR_COUNT, count as u8);
+        channel.write(LBA_LOW, sector as u8);
+        channel.write(LBA_MID, (sector >> 8) as u8);
+        channel.write(LBA_HIGH, (sector >> 16) as u8);
+
+        match buffer {
+            Transfer::Read(buffer) => {
+                channel.command(if extended {
+                    READ_SECTORS_EXT
+                } else {
+                    READ_SECTORS
+                });
+                for chunk in buffer.chunks_exact_mut(self.sector_size) {
+                    if channel.wait()? & STATUS_DRQ == 0 {
+                        return Err(BlockError::Failed("drive has no data"));
+                    }
+                    channel.read_data(chunk);
+                }
+            }
+            Transfer::Write(buffer) => {
+                channel.command(if extended {
+                    WRITE_SECTORS_EXT
+                } else {
+                    WRITE_SECTORS
+                });
+                // The drive asks for the first sector without an interrupt.
+                let mut status = channel.poll()?;
+                for chunk in buffer.chunks_exact(self.sector_size) {
+                    if status & STATUS_DRQ == 0 {
+                        return Err(BlockError::Failed("drive does not take data"));
+                    }
+                    channel.write_data(chunk);
+                    status = channel.wait()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a SCSI command to an ATAPI drive and reads what it returns into `buffer`,
+    /// returning how many bytes that was.
+    fn packet(&self, packet: [u8; 12], buffer: &mut [u8]) -> Result<usize, BlockError> {
+        let channel = &self.channel;
+        let _guard = channel.lock.lock();
+        channel.select(self.slave, 0);
+        // PIO, with the most the drive should send per interrupt in the byte count registers.
+        let limit = buffer.len().min(0xFFFE) as u16;
+        channel.write(FEATURES, 0);
+        channel.write(LBA_MID, limit as u8);
+        channel.write(LBA_HIGH, (limit >> 8) as u8);
+        channel.command(PACKET);
+        if channel.poll()? & STATUS_DRQ == 0 {
+            return Err(BlockError::Failed("drive does not take the packet"));
+        }
+        channel.write_data(&packet);
+
+        let mut received = 0;
+        loop {
+            let status = match channel.wait() {
+                Ok(status) => status,
+                // The top half of the error register holds the sense key.
+                Err(BlockError::Failed(_)) if channel.read(ERROR) >> 4 == SENSE_NOT_READY => {
+                    return Err(BlockError::NoMedium)
+                }
+                Err(error) => return Err(error),
+            };
+            if status & STATUS_DRQ == 0 {
+                return Ok(received);
+            }
+            let len = usize::from(channel.read(LBA_MID)) | usize::from(channel.read(LBA_HIGH)) << 8;
+            let end = (received + len).min(buffer.len());
+            channel.read_data(&mut buffer[received..end]);
+            // Drain whatever did not fit.
+            for _ in (end - received..len).step_by(2) {
+                // SAFETY: See `Channel::read`.
+                unsafe { inw(channel.base + DATA) };
+            }
+            received = end;
+        }
+    }
+
+    /// Asks an ATAPI drive how many sectors the disc in it has.
+    fn read_capacity(&self) -> Result<u64, BlockError> {
+        let mut packet = [0; 12];
+        packet[0] = SCSI_READ_CAPACITY;
+        let mut data = [0; 8];
+        if self.packet(packet, &mut data)? < data.len() {
+            return Err(BlockError::Failed("short capacity data"));
+        }
+        let last = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        Ok(u64::from(last) + 1)
+    }
+}
+
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl BlockDevice for Drive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.model
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.kind == Kind::Atapi
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        if self.kind == Kind::Atapi && self.sector_count == 0 {
+            return Err(BlockError::NoMedium);
+        }
+        block::check_transfer(self, sector, buffer.len())?;
+        for (i, chunk) in buffer
+            .chunks_mut(MAX_SECTORS as usize * self.sector_size)
+            .enumerate()
+        {
+            let start = sector + i as u64 * MAX_SECTORS;
+            let sectors = (chunk.len() / self.sector_size) as u64;
+            match self.kind {
+                Kind::Ata => self.transfer(start, sectors, Transfer::Read(chunk))?,
+                Kind::Atapi => {
+                    let mut packet = [0; 12];
+                    packet[0] = SCSI_READ_12;
+                    packet[2..6].copy_from_slice(&(start as u32).to_be_bytes());
+                    packet[6..10].copy_from_slice(&(sectors as u32).to_be_bytes());
+                    if self.packet(packet, chunk)? < chunk.len() {
+                        return Err(BlockError::Failed("short read"));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.kind == Kind::Atapi {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_transfer(self, sector, buffer.len())?;
+        for (i, chunk) in buffer
+            .chunks(MAX_SECTORS as usize * self.sector_size)
+            .enumerate()
+        {
+            let start = sector + i as u64 * MAX_SECTORS;
+            let sectors = (chunk.len() / self.sector_size) as u64;
+            self.transfer(start, sectors, Transfer::Write(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.kind == Kind::Atapi {
+            return Ok(());
+        }
+        let channel = &self.channel;
+        let _guard = channel.lock.lock();
+        channel.select(self.slave, 0);
+        channel.command(if self.lba48 {
+            FLUSH_CACHE_EXT
+        } else {
+            FLUSH_CACHE
+        });
+        channel.wait().map(|_| ())
+    }
+}
+
+/// Finds the drives on a channel and registers them.
+fn probe_channel(base: u16, control: u16, irq: u8) {
+    let channel = Arc::new(Channel::new(base, control, irq));
+    if !channel.reset() {
+        debug!("ata: nothing on channel {}", channel.number);
+        return;
+    }
+    for slave in [false, true] {
+        let identify = {
+            let _guard = channel.lock.lock();
+            channel.identify(slave)
+        };
+        let Some((kind, identify)) = identify else {
+            continue;
+        };
+        let drive = Drive::new(channel.clone(), slave, kind, &identify);
+        if block::register(Arc::new(drive)).is_err() {
+            warn!("ata: a block device with the same name already exists");
+        }
+    }
+}
+
+/// Returns the I/O port a BAR of a controller in native mode starts at.
+fn io_bar(device: &pci::Device, index: usize) -> Option<u16> {
+    match device.bars[index] {
+        Some(Bar::Io { port, .. }) => Some(port),
+        _ => None,
+    }
+}
+
+fn probe(device: &pci::Device) -> Result<(), ()> {
+    device.enable();
+    for (index, &(base, control, irq)) in LEGACY_CHANNELS.iter().enumerate() {
+        let (base, control, irq) = if device.prog_if & NATIVE_MODE[index] != 0 {
+            let base = io_bar(device, 2 * index).ok_or(())?;
+            // The control block BAR covers 4 ports, of which the alternate status is the third.
+            let control = io_bar(device, 2 * index + 1).ok_or(())? + 2;
+            (base, control, device.interrupt_line.ok_or(())?)
+        } else {
+            (base, control, irq)
+        };
+        info!(
+            "ata: {} channel {} at {:#x}, IRQ {}",
+            device.address, index, base, irq
+        );
+        probe_channel(base, control, irq);
+    }
+    Ok(())
+}
+
+pci_driver!(ATA_DRIVER, "ata", &[Match::class(0x01, 0x01, None)], probe);
@@ -0,0 +1,230 @@
+//! The buffer cache, which keeps recently used sectors of a disk in memory.
+//!
+//! Every disk a driver registers is wrapped in a [`CachedDevice`], so everything above the
+//! drivers, partitions included, goes through its cache. Reads are served from the cache
+//! where possible and the sectors that are missing are fetched through the disk's
+//! [`RequestQueue`]. Writes only land in the cache and mark the sectors dirty. They reach the
+//! disk when the least recently used sectors are evicted to make room, or when the device is
+//! flushed, which the VFS does every few seconds.
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+
+use super::{
+    check_transfer,
+    queue::{Request, RequestQueue},
+    BlockDevice, BlockError,
+};
+use crate::sync::Mutex;
+
+/// How much of each disk is kept in memory.
+const CACHE_BYTES: usize = 1024 * 1024;
+
+struct Block {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// When the sector was last used, by the cache's clock.
+    used: u64,
+}
+
+struct Cache {
+    blocks: BTreeMap<u64, Block>,
+    /// The cached sectors by when they were last used, oldest first.
+    lru: BTreeMap<u64, u64>,
+    /// Counts every use of a sector.
+    clock: u64,
+    capacity: usize,
+}
+
+impl Cache {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Copies a cached sector into `buffer` and marks it as just used. Returns `false` if
+    /// the sector is not cached.
+    fn read(&mut self, sector: u64, buffer: &mut [u8]) -> bool {
+        let now = self.tick();
+        let Some(block) = self.blocks.get_mut(&sector) else {
+            return false;
+        };
+        buffer.copy_from_slice(&block.data);
+        self.lru.remove(&block.used);
+        block.used = now;
+        self.lru.insert(now, sector);
+        true
+    }
+
+    /// Puts a sector into the cache, returning the dirty sectors evicted to make room for it,
+    /// which have to be written back.
+    fn insert(&mut self, sector: u64, data: &[u8], dirty: bool) -> Vec<(u64, Box<[u8]>)> {
+        let now = self.tick();
+        match self.blocks.get_mut(&sector) {
+            Some(block) => {
+                block.data.copy_from_slice(data);
+                block.dirty |= dirty;
+                self.lru.remove(&block.used);
+                block.used = now;
+            }
+            None => {
+                let block = Block {
+                    data: data.into(),
+                    dirty,
+                    used: now,
+                };
+                self.blocks.insert(sector, block);
+            }
+        }
+        self.lru.insert(now, sector);
+
+        let mut evicted = Vec::new();
+        while self.blocks.len() > self.capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(block) = self.blocks.remove(&oldest) {
+                if block.dirty {
+                    evicted.push((oldest, block.data));
+                }
+            }
+        }
+        evicted
+    }
+}
+
+pub struct CachedDevice {
+    queue: RequestQueue,
+    /// Held across the disk I/O of every read, write and flush.
+    cache: Mutex<Cache>,
+}
+
+impl CachedDevice {
+    pub fn new(device: Arc<dyn BlockDevice>) -> CachedDevice {
+        let capacity = (CACHE_BYTES / device.sector_size().max(1)).max(1);
+        CachedDevice {
+            queue: RequestQueue::new(device),
+            cache: Mutex::new(Cache {
+                blocks: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                capacity,
+            }),
+        }
+    }
+
+    /// Writes sectors back to the disk, failing with the first error.
+    fn write_back(&self, blocks: Vec<(u64, Box<[u8]>)>) -> Result<(), BlockError> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        for (sector, data) in blocks {
+            self.queue.submit(Request::write(sector, data.into_vec()));
+        }
+        self.queue
+            .run()
+            .into_iter()
+            .try_for_each(|request| request.result)
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn name(&self) -> &str {
+        self.queue.device().name()
+    }
+
+    fn description(&self) -> &str {
+        self.queue.device().description()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.queue.device().sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.queue.device().sector_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.queue.device().is_read_only()
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_transfer(self, sector, buffer.len())?;
+        let size = self.sector_size();
+        let mut cache = self.cache.lock();
+
+        // Serve what is cached, and gather what is not into runs of neighbouring sectors.
+        let mut missing: Vec<(u64, usize)> = Vec::new();
+        for (i, chunk) in buffer.chunks_exact_mut(size).enumerate() {
+            if cache.read(sector + i as u64, chunk) {
+                continue;
+            }
+            match missing.last_mut() {
+                Some((start, count)) if *start + *count as u64 == sector + i as u64 => *count += 1,
+                _ => missing.push((sector + i as u64, 1)),
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        for &(start, count) in &missing {
+            self.queue.submit(Request::read(start, count * size));
+        }
+        let (mut result, mut evicted) = (Ok(()), Vec::new());
+        for request in self.queue.run() {
+            if let Err(error) = request.result {
+                result = result.and(Err(error));
+                continue;
+            }
+            let offset = (request.sector - sector) as usize * size;
+            buffer[offset..offset + request.buffer.len()].copy_from_slice(&request.buffer);
+            for (i, data) in request.buffer.chunks_exact(size).enumerate() {
+                evicted.extend(cache.insert(request.sector + i as u64, data, false));
+            }
+        }
+        // Sectors evicted along the way have to reach the disk even if the read failed.
+        self.write_back(evicted)?;
+        result
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        check_transfer(self, sector, buffer.len())?;
+        let mut cache = self.cache.lock();
+        let mut evicted = Vec::new();
+        for (i, data) in buffer.chunks_exact(self.sector_size()).enumerate() {
+            evicted.extend(cache.insert(sector + i as u64, data, true));
+        }
+        self.write_back(evicted)
+    }
+
+    /// Writes every dirty sector back and then flushes the disk's own cache.
+    fn flush(&self) -> Result<(), BlockError> {
+        let mut cache = self.cache.lock();
+        for (&sector, block) in cache.blocks.iter().filter(|(_, block)| block.dirty) {
+            self.queue
+                .submit(Request::write(sector, block.data.to_vec()));
+        }
+        // Only what made it to the disk is clean now.
+        let mut result = Ok(());
+        for request in self.queue.run() {
+            match request.result {
+                Ok(()) => {
+                    let sectors = request.buffer.len() / self.sector_size();
+                    for sector in request.sector..request.sector + sectors as u64 {
+                        if let Some(block) = cache.blocks.get_mut(&sector) {
+                            block.dirty = false;
+                        }
+                    }
+                }
+                Err(error) => result = result.and(Err(error)),
+            }
+        }
+        drop(cache);
+        result?;
+        self.queue.device().flush()
+    }
+}
@@ -0,0 +1,309 @@
+//! This module is where disk drivers hand over the disks they find.
+//!
+//! Each disk is a [`BlockDevice`], which reads and writes whole sectors. Drivers name their
+//! disks after the kind of controller they sit on, like `hda` for the first IDE drive, and
+//! [`register`] them, after which they can be looked up with [`find`]. What is registered is
+//! the disk behind a buffer cache, followed by a device for each partition on it.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::fmt;
+
+mod cache;
+mod partition;
+mod queue;
+
+use cache::CachedDevice;
+
+use crate::{
+    info, println,
+    shell::{self, parse_number, CommandError},
+    shell_command,
+    sync::SpinLock,
+    warn,
+};
+
+/// How many sectors `disk read` shows if it is not told.
+const READ_DEFAULT: usize = 1;
+/// The most sectors `disk read` dumps at once.
+const READ_MAX: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    /// The transfer reaches past the last sector.
+    OutOfRange,
+    /// The buffer is not a whole number of sectors long.
+    UnalignedBuffer,
+    ReadOnly,
+    /// A removable drive has no disc or card in it.
+    NoMedium,
+    /// The device did not answer in time.
+    Timeout,
+    /// The device reported that the command failed.
+    Failed(&'static str),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => f.write_str("sector out of range"),
+            BlockError::UnalignedBuffer => f.write_str("buffer is not a whole number of sectors"),
+            BlockError::ReadOnly => f.write_str("device is read-only"),
+            BlockError::NoMedium => f.write_str("no medium"),
+            BlockError::Timeout => f.write_str("device timed out"),
+            BlockError::Failed(message) => f.write_str(message),
+        }
+    }
+}
+
+/// A disk, or anything else made of sectors that can be read and written in any order.
+pub trait BlockDevice: Send + Sync {
+    /// The name the device is known by, like `hda`.
+    fn name(&self) -> &str;
+
+    /// What the device says it is, like its model number.
+    fn description(&self) -> &str;
+
+    fn sector_size(&self) -> usize;
+
+    fn sector_count(&self) -> u64;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads the sectors starting at `sector` into `buffer`, which is a whole number of
+    /// sectors long.
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buffer`, which is a whole number of sectors long, to the sectors starting at
+    /// `sector`.
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure everything written so far has reached the medium.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// The disk the device is a partition of, if it is one.
+    fn disk(&self) -> Option<&Arc<dyn BlockDevice>> {
+        None
+    }
+}
+
+/// Returns whether `inner` is `outer` or lies within it, going by their names.
+fn within(inner: &dyn BlockDevice, outer: &dyn BlockDevice) -> bool {
+    let mut device = Some(inner);
+    while let Some(current) = device {
+        if current.name() == outer.name() {
+            return true;
+        }
+        device = current.disk().map(|disk| &**disk);
+    }
+    false
+}
+
+/// Returns whether two devices share sectors, because they are the same device or one is a
+/// partition of the other.
+pub fn overlap(a: &dyn BlockDevice, b: &dyn BlockDevice) -> bool {
+    within(a, b) || within(b, a)
+}
+
+/// Checks that a transfer of `len` bytes starting at `sector` fits on `device`, and returns
+/// how many sectors it covers.
+pub fn check_transfer(
+    device: &dyn BlockDevice,
+    sector: u64,
+    len: usize,
+) -> Result<u64, BlockError> {
+    if !len.is_multiple_of(device.sector_size()) {
+        return Err(BlockError::UnalignedBuffer);
+    }
+    let count = (len / device.sector_size()) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Reads `buffer.len()` bytes starting at byte `offset` of `device`, which need not line up
+/// with its sectors.
+pub fn read_bytes(
+    device: &dyn BlockDevice,
+    mut offset: u64,
+    mut buffer: &mut [u8],
+) -> Result<(), BlockError> {
+    let size = device.sector_size();
+    let within = (offset % size as u64) as usize;
+    if within != 0 || (!buffer.is_empty() && buffer.len() < size) {
+        let mut sector = vec![0; size];
+        device.read(offset / size as u64, &mut sector)?;
+        let count = (size - within).min(buffer.len());
+        buffer[..count].copy_from_slice(&sector[within..within + count]);
+        offset += count as u64;
+        buffer = &mut buffer[count..];
+    }
+    let whole = buffer.len() / size * size;
+    if whole > 0 {
+        device.read(offset / size as u64, &mut buffer[..whole])?;
+        offset += whole as u64;
+        buffer = &mut buffer[whole..];
+    }
+    if !buffer.is_empty() {
+        let mut sector = vec![0; size];
+        device.read(offset / size as u64, &mut sector)?;
+        buffer.copy_from_slice(&sector[..buffer.len()]);
+    }
+    Ok(())
+}
+
+/// Writes `data` starting at byte `offset` of `device`, keeping whatever else shares the
+/// sectors at either end.
+pub fn write_bytes(
+    device: &dyn BlockDevice,
+    mut offset: u64,
+    mut data: &[u8],
+) -> Result<(), BlockError> {
+    let size = device.sector_size();
+    let within = (offset % size as u64) as usize;
+    if within != 0 || (!data.is_empty() && data.len() < size) {
+        let mut sector = vec![0; size];
+        device.read(offset / size as u64, &mut sector)?;
+        let count = (size - within).min(data.len());
+        sector[within..within + count].copy_from_slice(&data[..count]);
+        device.write(offset / size as u64, &sector)?;
+        offset += count as u64;
+        data = &data[count..];
+    }
+    let whole = data.len() / size * size;
+    if whole > 0 {
+        device.write(offset / size as u64, &data[..whole])?;
+        offset += whole as u64;
+        data = &data[whole..];
+    }
+    if !data.is_empty() {
+        let mut sector = vec![0; size];
+        device.read(offset / size as u64, &mut sector)?;
+        sector[..data.len()].copy_from_slice(data);
+        device.write(offset / size as u64, &sector)?;
+    }
+    Ok(())
+}
+
+static DEVICES: SpinLock<Vec<Arc<dyn BlockDevice>>> = SpinLock::named("block devices", Vec::new());
+
+/// Makes a disk available to the rest of the kernel, along with its partitions. Fails if the
+/// name is taken.
+pub fn register(device: Arc<dyn BlockDevice>) -> Result<(), ()> {
+    let disk: Arc<dyn BlockDevice> = Arc::new(CachedDevice::new(device));
+    add(disk.clone())?;
+    for partition in partition::scan(&disk) {
+        if add(Arc::new(partition)).is_err() {
+            warn!("block: {}: a partition's name is taken", disk.name());
+        }
+    }
+    Ok(())
+}
+
+fn add(device: Arc<dyn BlockDevice>) -> Result<(), ()> {
+    {
+        let mut devices = DEVICES.lock();
+        if devices.iter().any(|other| other.name() == device.name()) {
+            return Err(());
+        }
+        devices.push(device.clone());
+    }
+    info!(
+        "block: {}: {} ({} sectors of {} bytes, {} MiB{})",
+        device.name(),
+        device.description(),
+        device.sector_count(),
+        device.sector_size(),
+        (device.sector_count() * device.sector_size() as u64) >> 20,
+        if device.is_read_only() {
+            ", read-only"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
+
+/// Returns every registered device, in the order they were found.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// Writes everything cached back to the disks.
+pub fn sync() -> Result<(), BlockError> {
+    devices()
+        .iter()
+        .map(|device| device.flush())
+        .fold(Ok(()), Result::and)
+}
+
+fn disk(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            for device in devices() {
+                println!(
+                    "{:<6} {:>10} x {:<5} {}{}",
+                    device.name(),
+                    device.sector_count(),
+                    device.sector_size(),
+                    device.description(),
+                    if device.is_read_only() {
+                        " (read-only)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            Ok(())
+        }
+        ["read", name, sector, rest @ ..] => {
+            let device = find(name).ok_or(CommandError::Failed("no such device"))?;
+            let sector = parse_number(sector)? as u64;
+            let count = match rest {
+                [] => READ_DEFAULT,
+                [count] => parse_number(count)?,
+                _ => return Err(CommandError::Usage),
+            };
+            if count > READ_MAX {
+                return Err(CommandError::Failed("too many sectors at once"));
+            }
+            if sector
+                .checked_add(count as u64)
+                .is_none_or(|end| end > device.sector_count())
+            {
+                return Err(CommandError::Failed("past the end of the device"));
+            }
+            let len = count
+                .checked_mul(device.sector_size())
+                .ok_or(CommandError::Failed("too many sectors at once"))?;
+            let mut buffer = vec![0; len];
+            if let Err(error) = device.read(sector, &mut buffer) {
+                println!("{}: {}", device.name(), error);
+                return Err(CommandError::Failed("read failed"));
+            }
+            shell::hexdump(sector * device.sector_size() as u64, &buffer);
+            Ok(())
+        }
+        _ => Err(CommandError::Usage),
+    }
+}
+
+shell_command!(
+    DISK_COMMAND,
+    "disk",
+    "[read <device> <sector> [count]]",
+    "List the disks or dump sectors of one",
+    disk
+);
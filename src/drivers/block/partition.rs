@@ -0,0 +1,358 @@
+//! Partitions, found in the MBR or GPT partition table of a disk.
+//!
+//! Each partition becomes a block device of its own, named after its disk with the number of
+//! the partition appended: `sda1`, or `nvme0n1p1` when the disk's name ends in a digit. MBR
+//! partitions are numbered 1 to 4 for the primary ones and from 5 for the logical ones inside
+//! an extended partition, and GPT partitions by their entry in the table.
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+
+use super::{check_transfer, BlockDevice, BlockError};
+use crate::{debug, warn};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+/// The boot indicators an entry may have: inactive and active.
+const MBR_BOOT_INDICATORS: [u8; 2] = [0x00, 0x80];
+/// The partition type of the single partition covering a GPT disk.
+const MBR_TYPE_GPT: u8 = 0xEE;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// How many logical partitions to follow the chain of before deciding it loops.
+const MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+/// The most partition entries a table may have, which is how many they usually have room
+/// for. Tables claiming more are taken to be damaged.
+const GPT_MAX_ENTRIES: u32 = 128;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+
+/// A partition of a disk, seen as a block device of its own.
+pub struct Partition {
+    name: String,
+    description: String,
+    disk: Arc<dyn BlockDevice>,
+    start: u64,
+    sector_count: u64,
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.disk.is_read_only()
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_transfer(self, sector, buffer.len())?;
+        self.disk.read(self.start + sector, buffer)
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_transfer(self, sector, buffer.len())?;
+        self.disk.write(self.start + sector, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+
+    fn disk(&self) -> Option<&Arc<dyn BlockDevice>> {
+        Some(&self.disk)
+    }
+}
+
+/// A partition as found in a partition table.
+struct Entry {
+    number: usize,
+    start: u64,
+    sector_count: u64,
+    kind: String,
+}
+
+fn read_sector(disk: &dyn BlockDevice, sector: u64) -> Result<Vec<u8>, BlockError> {
+    let mut buffer = vec![0; disk.sector_size()];
+    disk.read(sector, &mut buffer)?;
+    Ok(buffer)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Computes the CRC-32 that GPT protects its header and entries with.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(u32::MAX, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn mbr_type_name(kind: u8) -> &'static str {
+    match kind {
+        0x01 => "FAT12",
+        0x04 | 0x06 | 0x0E => "FAT16",
+        0x07 => "NTFS/exFAT",
+        0x0B | 0x0C => "FAT32",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0xEF => "EFI system",
+        _ => "unknown",
+    }
+}
+
+/// Returns whether the four primary entries look like a partition table rather than the boot
+/// code of a filesystem written to the whole disk, which also ends in the MBR signature.
+fn is_mbr(mbr: &[u8]) -> bool {
+    mbr[MBR_SIGNATURE_OFFSET..][..2] == MBR_SIGNATURE
+        && (0..4).all(|i| MBR_BOOT_INDICATORS.contains(&mbr[MBR_ENTRIES + i * MBR_ENTRY_SIZE]))
+}
+
+/// Reads the entries of an MBR partition table, following the chain of extended boot
+/// records for the logical partitions.
+fn read_mbr(disk: &dyn BlockDevice, mbr: &[u8]) -> Result<Vec<Entry>, BlockError> {
+    let mut entries = Vec::new();
+    let mut extended = None;
+    for number in 1..=4 {
+        let entry = &mbr[MBR_ENTRIES + (number - 1) * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        let (kind, start, count) = (entry[4], u32_at(entry, 8), u32_at(entry, 12));
+        if kind == 0 || count == 0 {
+            continue;
+        }
+        if MBR_EXTENDED.contains(&kind) {
+            extended.get_or_insert(u64::from(start));
+            continue;
+        }
+        entries.push(Entry {
+            number,
+            start: u64::from(start),
+            sector_count: u64::from(count),
+            kind: format!("{} ({:#04x})", mbr_type_name(kind), kind),
+        });
+    }
+
+    // Each extended boot record describes one logical partition, relative to itself, and
+    // where the next record is, relative to the start of the extended partition.
+    let Some(base) = extended else {
+        return Ok(entries);
+    };
+    let mut record = base;
+    for number in 5..5 + MAX_LOGICAL {
+        let ebr = read_sector(disk, record)?;
+        if ebr[MBR_SIGNATURE_OFFSET..][..2] != MBR_SIGNATURE {
+            break;
+        }
+        let logical = &ebr[MBR_ENTRIES..][..MBR_ENTRY_SIZE];
+        let next = &ebr[MBR_ENTRIES + MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        if logical[4] != 0 && u32_at(logical, 12) != 0 {
+            entries.push(Entry {
+                number,
+                start: record + u64::from(u32_at(logical, 8)),
+                sector_count: u64::from(u32_at(logical, 12)),
+                kind: format!("{} ({:#04x})", mbr_type_name(logical[4]), logical[4]),
+            });
+        }
+        if next[4] == 0 {
+            break;
+        }
+        record = base + u64::from(u32_at(next, 8));
+    }
+    Ok(entries)
+}
+
+/// Turns the mixed-endian bytes of a GUID into its usual text form.
+fn guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        u32_at(bytes, 0),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        bytes[8],
+        bytes[9],
+        bytes[10..16]
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<String>()
+    )
+}
+
+fn gpt_type_name(guid: &str) -> &'static str {
+    match guid {
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => "EFI system",
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "basic data",
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux",
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux swap",
+        "21686148-6449-6E6F-744E-656564454649" => "BIOS boot",
+        _ => "unknown",
+    }
+}
+
+/// Reads and checks the GPT header at `sector`, returning where the entries are, how many
+/// there are and how large each is.
+fn read_gpt_header(
+    disk: &dyn BlockDevice,
+    sector: u64,
+) -> Result<Option<(u64, u32, usize, u32)>, BlockError> {
+    let header = read_sector(disk, sector)?;
+    let size = u32_at(&header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE || !(GPT_HEADER_MIN_SIZE..=header.len()).contains(&size) {
+        return Ok(None);
+    }
+    let mut copy = header[..size].to_vec();
+    copy[16..20].fill(0);
+    if crc32(&copy) != u32_at(&header, 16) {
+        return Ok(None);
+    }
+    // Both come from the disk, and bound how much is read and allocated for the entries.
+    let count = u32_at(&header, 80);
+    if count == 0 || count > GPT_MAX_ENTRIES {
+        return Ok(None);
+    }
+    let entry_size = u32_at(&header, 84) as usize;
+    if !(GPT_ENTRY_MIN_SIZE..=header.len()).contains(&entry_size) || !entry_size.is_multiple_of(8) {
+        return Ok(None);
+    }
+    Ok(Some((
+        u64_at(&header, 72),
+        count,
+        entry_size,
+        u32_at(&header, 88),
+    )))
+}
+
+/// Reads the entries of a GPT partition table, from the backup at the end of the disk if the
+/// primary one is damaged.
+fn read_gpt(disk: &dyn BlockDevice) -> Result<Vec<Entry>, BlockError> {
+    let header = match read_gpt_header(disk, 1)? {
+        Some(header) => header,
+        None => {
+            warn!("block: {}: primary GPT is damaged", disk.name());
+            match read_gpt_header(disk, disk.sector_count() - 1)? {
+                Some(header) => header,
+                None => return Err(BlockError::Failed("no valid GPT")),
+            }
+        }
+    };
+    let (table, count, entry_size, checksum) = header;
+    let len = (count as usize)
+        .checked_mul(entry_size)
+        .ok_or(BlockError::Failed("GPT entries are too large"))?;
+    let sector_size = disk.sector_size();
+    let mut bytes = vec![0; len.div_ceil(sector_size) * sector_size];
+    disk.read(table, &mut bytes)?;
+    if crc32(&bytes[..len]) != checksum {
+        return Err(BlockError::Failed("GPT entries are damaged"));
+    }
+
+    let mut entries = Vec::new();
+    for (i, entry) in bytes[..len].chunks_exact(entry_size).enumerate() {
+        if entry[..16].iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        if last < first {
+            continue;
+        }
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        let name = String::from_utf16_lossy(&name);
+        let kind = gpt_type_name(&guid(&entry[..16]));
+        entries.push(Entry {
+            number: i + 1,
+            start: first,
+            sector_count: last - first + 1,
+            kind: if name.is_empty() {
+                String::from(kind)
+            } else {
+                format!("{} \"{}\"", kind, name)
+            },
+        });
+    }
+    Ok(entries)
+}
+
+/// Finds the partitions of a disk. A disk without a partition table has none.
+pub fn scan(disk: &Arc<dyn BlockDevice>) -> Vec<Partition> {
+    if disk.sector_count() == 0 {
+        return Vec::new();
+    }
+    let entries = read_sector(&**disk, 0).and_then(|mbr| {
+        if !is_mbr(&mbr) {
+            Ok(Vec::new())
+        } else if mbr[MBR_ENTRIES + 4] == MBR_TYPE_GPT {
+            read_gpt(&**disk)
+        } else {
+            read_mbr(&**disk, &mbr)
+        }
+    });
+    let entries = match entries {
+        Ok(entries) => entries,
+        Err(error) => {
+            debug!("block: {}: no partitions: {}", disk.name(), error);
+            return Vec::new();
+        }
+    };
+
+    let separator = if disk.name().ends_with(|c: char| c.is_ascii_digit()) {
+        "p"
+    } else {
+        ""
+    };
+    entries
+        .into_iter()
+        .filter(|entry| {
+            let fits = entry
+                .start
+                .checked_add(entry.sector_count)
+                .is_some_and(|end| end <= disk.sector_count());
+            if !fits {
+                warn!(
+                    "block: {}: partition {} reaches past the end",
+                    disk.name(),
+                    entry.number
+                );
+            }
+            fits
+        })
+        .map(|entry| Partition {
+            name: format!("{}{}{}", disk.name(), separator, entry.number),
+            description: format!(
+                "partition {} of {}, {}",
+                entry.number,
+                disk.name(),
+                entry.kind
+            ),
+            disk: disk.clone(),
+            start: entry.start,
+            sector_count: entry.sector_count,
+        })
+        .collect()
+}
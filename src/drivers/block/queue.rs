@@ -0,0 +1,132 @@
+//! The request queue in front of each disk.
+//!
+//! Requests are collected with [`RequestQueue::submit`] and sent off together by
+//! [`RequestQueue::run`], sorted by sector so the disk sweeps across once instead of seeking
+//! back and forth, and with neighbouring requests in the same direction merged into one
+//! transfer. The requests submitted together must not overlap, since they may be reordered.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use super::{BlockDevice, BlockError};
+use crate::sync::SpinLock;
+
+/// The largest transfer requests are merged into.
+const MAX_MERGED_BYTES: usize = 128 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+pub struct Request {
+    pub sector: u64,
+    pub operation: Operation,
+    /// The data to write, or the space to read into.
+    pub buffer: Vec<u8>,
+    /// How the request went, once it has been run.
+    pub result: Result<(), BlockError>,
+}
+
+impl Request {
+    pub fn read(sector: u64, len: usize) -> Request {
+        Request {
+            sector,
+            operation: Operation::Read,
+            buffer: vec![0; len],
+            result: Ok(()),
+        }
+    }
+
+    pub fn write(sector: u64, buffer: Vec<u8>) -> Request {
+        Request {
+            sector,
+            operation: Operation::Write,
+            buffer,
+            result: Ok(()),
+        }
+    }
+}
+
+pub struct RequestQueue {
+    device: Arc<dyn BlockDevice>,
+    pending: SpinLock<Vec<Request>>,
+}
+
+impl RequestQueue {
+    pub fn new(device: Arc<dyn BlockDevice>) -> RequestQueue {
+        RequestQueue {
+            device,
+            pending: SpinLock::new(Vec::new()),
+        }
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn submit(&self, request: Request) {
+        self.pending.lock().push(request);
+    }
+
+    /// Runs every request submitted so far and hands them back, in order of sector, with
+    /// their results filled in.
+    pub fn run(&self) -> Vec<Request> {
+        let mut requests = core::mem::take(&mut *self.pending.lock());
+        requests.sort_by_key(|request| request.sector);
+        let sector_size = self.device.sector_size() as u64;
+        let mut start = 0;
+        while start < requests.len() {
+            // Take in every following request that carries on where the last one ends.
+            let mut end = start + 1;
+            let mut len = requests[start].buffer.len();
+            while let Some(next) = requests.get(end) {
+                let previous = &requests[end - 1];
+                if next.operation != previous.operation
+                    || next.sector != previous.sector + previous.buffer.len() as u64 / sector_size
+                    || len + next.buffer.len() > MAX_MERGED_BYTES
+                {
+                    break;
+                }
+                len += next.buffer.len();
+                end += 1;
+            }
+            self.dispatch(&mut requests[start..end]);
+            start = end;
+        }
+        requests
+    }
+
+    /// Sends a run of neighbouring requests to the disk as one transfer.
+    fn dispatch(&self, requests: &mut [Request]) {
+        let sector = requests[0].sector;
+        let result = match requests {
+            [request] => match request.operation {
+                Operation::Read => self.device.read(sector, &mut request.buffer),
+                Operation::Write => self.device.write(sector, &request.buffer),
+            },
+            _ if requests[0].operation == Operation::Write => {
+                let buffer: Vec<u8> = requests
+                    .iter()
+                    .flat_map(|request| request.buffer.iter().copied())
+                    .collect();
+                self.device.write(sector, &buffer)
+            }
+            _ => {
+                let len = requests.iter().map(|request| request.buffer.len()).sum();
+                let mut buffer = vec![0; len];
+                let result = self.device.read(sector, &mut buffer);
+                let mut offset = 0;
+                for request in requests.iter_mut() {
+                    let end = offset + request.buffer.len();
+                    request.buffer.copy_from_slice(&buffer[offset..end]);
+                    offset = end;
+                }
+                result
+            }
+        };
+        for request in requests {
+            request.result = result;
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! The layouts that ship with the kernel.
+//!
+//! Each level is written out row by row, from the key left of `1` down to the key left of
+//! right shift, following [`KEYS`](super::KEYS).
+
+use super::{Keymap, Level};
+use crate::drivers::keyboard::KeyCode;
+
+pub(super) static US: Keymap = Keymap {
+    name: "us",
+    description: "English (US)",
+    levels: [
+        concat!(
+            "`1234567890-=",
+            "qwertyuiop[]\\",
+            "asdfghjkl;'",
+            "\\zxcvbnm,./"
+        ),
+        concat!(
+            "~!@#$%^&*()_+",
+            "QWERTYUIOP{}|",
+            "ASDFGHJKL:\"",
+            "|ZXCVBNM<>?"
+        ),
+        "",
+        "",
+    ],
+    dead: &[],
+};
+
+pub(super) static UK: Keymap = Keymap {
+    name: "uk",
+    description: "English (UK)",
+    levels: [
+        concat!(
+            "`1234567890-=",
+            "qwertyuiop[]#",
+            "asdfghjkl;'",
+            "\\zxcvbnm,./"
+        ),
+        concat!(
+            "¬!\"£$%^&*()_+",
+            "QWERTYUIOP{}~",
+            "ASDFGHJKL:@",
+            "|ZXCVBNM<>?"
+        ),
+        concat!(
+            "¦   €        ",
+            "  é   úíó    ",
+            "á          ",
+            "           "
+        ),
+        concat!(
+            "             ",
+            "  É   ÚÍÓ    ",
+            "Á          ",
+            "           "
+        ),
+    ],
+    dead: &[],
+};
+
+pub(super) static DE: Keymap = Keymap {
+    name: "de",
+    description: "German",
+    levels: [
+        concat!(
+            "^1234567890ß´",
+            "qwertzuiopü+#",
+            "asdfghjklöä",
+            "<yxcvbnm,.-"
+        ),
+        concat!(
+            "°!\"§$%&/()=?`",
+            "QWERTZUIOPÜ*'",
+            "ASDFGHJKLÖÄ",
+            ">YXCVBNM;:_"
+        ),
+        concat!(
+            "  ²³   {[]}\\ ",
+            "@ €        ~ ",
+            "           ",
+            "|      µ   "
+        ),
+        "",
+    ],
+    dead: &[
+        (KeyCode::Backtick, Level::Base),
+        (KeyCode::Equals, Level::Base),
+        (KeyCode::Equals, Level::Shift),
+    ],
+};
+
+pub(super) static FR: Keymap = Keymap {
+    name: "fr",
+    description: "French (AZERTY)",
+    levels: [
+        concat!(
+            "²&é\"'(-è_çà)=",
+            "azertyuiop^$*",
+            "qsdfghjklmù",
+            "<wxcvbn,;:!"
+        ),
+        concat!(
+            " 1234567890°+",
+            "AZERTYUIOP¨£µ",
+            "QSDFGHJKLM%",
+            ">WXCVBN?./§"
+        ),
+        concat!(
+            "  ~#{[|`\\^@]}",
+            "  €        ¤ ",
+            "           ",
+            "           "
+        ),
+        "",
+    ],
+    dead: &[
+        (KeyCode::LeftBracket, Level::Base),
+        (KeyCode::LeftBracket, Level::Shift),
+        (KeyCode::Key2, Level::AltGr),
+        (KeyCode::Key7, Level::AltGr),
+    ],
+};
+
+pub(super) static DVORAK: Keymap = Keymap {
+    name: "dvorak",
+    description: "English (Dvorak)",
+    levels: [
+        concat!(
+            "`1234567890[]",
+            "',.pyfgcrl/=\\",
+            "aoeuidhtns-",
+            "\\;qjkxbmwvz"
+        ),
+        concat!(
+            "~!@#$%^&*(){}",
+            "\"<>PYFGCRL?+|",
+            "AOEUIDHTNS_",
+            "|:QJKXBMWVZ"
+        ),
+        "",
+        "",
+    ],
+    dead: &[],
+};
@@ -0,0 +1,241 @@
+//! Keyboard layouts, which decide what each key types.
+//!
+//! A [`Keymap`] lists what the 48 keys of the main block type on each of four levels: plain,
+//! with shift, with AltGr and with both. Any of them can be a dead key, which types nothing
+//! by itself but puts an accent on the next letter. Keys that are the same everywhere, like
+//! enter or the keypad, are handled here rather than in every layout.
+//!
+//! The active layout is chosen with `keymap=` on the command line, the `keymap` shell command or
+//! [`set_active`] later on.
+
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{KeyCode, Modifiers};
+use crate::{
+    boot_param,
+    cmdline::{ParamError, Value},
+    println,
+    shell::CommandError,
+    shell_command,
+};
+
+mod layouts;
+
+/// The keys described by a layout's rows, in order.
+#[rustfmt::skip]
+const KEYS: [KeyCode; 48] = {
+    use KeyCode::*;
+    [
+        Backtick, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0, Minus, Equals,
+        Q, W, E, R, T, Y, U, I, O, P, LeftBracket, RightBracket, Backslash,
+        A, S, D, F, G, H, J, K, L, Semicolon, Apostrophe,
+        NonUsBackslash, Z, X, C, V, B, N, M, Comma, Period, Slash,
+    ]
+};
+
+/// A space in a level stands for a key that types nothing on that level.
+const NOTHING: char = ' ';
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Level {
+    Base,
+    Shift,
+    AltGr,
+    ShiftAltGr,
+}
+
+/// What pressing a key does.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Symbol {
+    Char(char),
+    /// Puts the accent on the next character typed.
+    Dead(char),
+}
+
+/// A keyboard layout.
+pub struct Keymap {
+    /// The name `keymap=` selects the layout by.
+    pub name: &'static str,
+    pub description: &'static str,
+    /// One string per level with a character for each of [`KEYS`]. Empty strings leave a level
+    /// out entirely.
+    levels: [&'static str; 4],
+    /// The keys that are dead on a given level. Their character on that level is the accent
+    /// they put on.
+    dead: &'static [(KeyCode, Level)],
+}
+
+impl Keymap {
+    /// Returns what `key` does on `level`, if anything.
+    pub fn symbol(&self, key: KeyCode, level: Level) -> Option<Symbol> {
+        let index = KEYS.iter().position(|&k| k == key)?;
+        let ch = self.levels[level as usize].chars().nth(index)?;
+        if ch == NOTHING {
+            None
+        } else if self.dead.contains(&(key, level)) {
+            Some(Symbol::Dead(ch))
+        } else {
+            Some(Symbol::Char(ch))
+        }
+    }
+
+    /// Returns what `key` does with the given modifiers held.
+    pub fn lookup(&self, key: KeyCode, modifiers: Modifiers) -> Option<Symbol> {
+        if let Some(ch) = common(key, modifiers) {
+            return Some(Symbol::Char(ch));
+        }
+        if key == KeyCode::Space {
+            return Some(Symbol::Char(' '));
+        }
+
+        let base = self.symbol(key, Level::Base);
+        let mut shift = modifiers.contains(Modifiers::SHIFT);
+        // Caps lock only affects keys whose shifted character is the capital of the plain one,
+        // and shift undoes it.
+        if let (Some(Symbol::Char(lower)), Some(Symbol::Char(upper))) =
+            (base, self.symbol(key, Level::Shift))
+        {
+            if modifiers.contains(Modifiers::CAPS_LOCK) && lower.to_uppercase().eq([upper]) {
+                shift = !shift;
+            }
+        }
+
+        let level = match (shift, modifiers.contains(Modifiers::ALT_GR)) {
+            (false, false) => Level::Base,
+            (true, false) => Level::Shift,
+            (false, true) => Level::AltGr,
+            (true, true) => Level::ShiftAltGr,
+        };
+        self.symbol(key, level)
+    }
+}
+
+/// Returns what a key that is the same on every layout types.
+fn common(key: KeyCode, modifiers: Modifiers) -> Option<char> {
+    let num_lock = modifiers.contains(Modifiers::NUM_LOCK);
+    Some(match key {
+        KeyCode::Escape => '\x1b',
+        KeyCode::Backspace => '\x08',
+        KeyCode::Tab => '\t',
+        KeyCode::Enter | KeyCode::KeypadEnter => '\n',
+        KeyCode::KeypadSlash => '/',
+        KeyCode::KeypadAsterisk => '*',
+        KeyCode::KeypadMinus => '-',
+        KeyCode::KeypadPlus => '+',
+        // Without num lock the rest of the keypad acts as the navigation keys.
+        KeyCode::KeypadPeriod if num_lock => '.',
+        KeyCode::Keypad0 if num_lock => '0',
+        KeyCode::Keypad1 if num_lock => '1',
+        KeyCode::Keypad2 if num_lock => '2',
+        KeyCode::Keypad3 if num_lock => '3',
+        KeyCode::Keypad4 if num_lock => '4',
+        KeyCode::Keypad5 if num_lock => '5',
+        KeyCode::Keypad6 if num_lock => '6',
+        KeyCode::Keypad7 if num_lock => '7',
+        KeyCode::Keypad8 if num_lock => '8',
+        KeyCode::Keypad9 if num_lock => '9',
+        _ => return None,
+    })
+}
+
+/// The plain and accented letters for each accent a dead key can put on.
+const ACCENTS: [(char, &str, &str); 5] = [
+    ('´', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    ('`', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('^', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ('¨', "aeiouyAEIOUY", "äëïöüÿÄËÏÖÜŸ"),
+    ('~', "anoANO", "ãñõÃÑÕ"),
+];
+
+/// Puts `accent` on `ch`. A space or the accent itself gives the accent alone, and `None`
+/// means the two do not combine.
+pub fn compose(accent: char, ch: char) -> Option<char> {
+    if ch == ' ' || ch == accent {
+        return Some(accent);
+    }
+    let (_, plain, accented) = ACCENTS.iter().find(|(a, _, _)| *a == accent)?;
+    let index = plain.chars().position(|c| c == ch)?;
+    accented.chars().nth(index)
+}
+
+/// Every layout that can be selected.
+static KEYMAPS: [&Keymap; 5] = [
+    &layouts::US,
+    &layouts::UK,
+    &layouts::DE,
+    &layouts::FR,
+    &layouts::DVORAK,
+];
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn keymaps() -> &'static [&'static Keymap] {
+    &KEYMAPS
+}
+
+/// Returns the layout keys are currently translated with.
+pub fn active() -> &'static Keymap {
+    KEYMAPS[ACTIVE.load(Ordering::Relaxed)]
+}
+
+/// Switches to the layout called `name`, returning `false` if there is none.
+pub fn set_active(name: &str) -> bool {
+    match KEYMAPS
+        .iter()
+        .position(|keymap| keymap.name.eq_ignore_ascii_case(name))
+    {
+        Some(index) => {
+            ACTIVE.store(index, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+fn set_keymap(value: Option<Value<'static>>) -> Result<(), ParamError> {
+    let name = value.ok_or(ParamError::MissingValue)?.as_str();
+    if set_active(name) {
+        Ok(())
+    } else {
+        Err(ParamError::Invalid)
+    }
+}
+
+boot_param!(KEYMAP_PARAM, "keymap", set_keymap);
+
+fn keymap_command(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            for keymap in keymaps() {
+                let marker = if keymap.name == active().name {
+                    '*'
+                } else {
+                    ' '
+                };
+                println!("{} {:<8} {}", marker, keymap.name, keymap.description);
+            }
+            Ok(())
+        }
+        [name] if set_active(name) => Ok(()),
+        [_] => Err(CommandError::Failed("no such keymap")),
+        _ => Err(CommandError::Usage),
+    }
+}
+
+fn complete_keymap(word: &str) -> Vec<String> {
+    keymaps()
+        .iter()
+        .filter(|keymap| keymap.name.starts_with(word))
+        .map(|keymap| String::from(keymap.name))
+        .collect()
+}
+
+shell_command!(
+    KEYMAP_COMMAND,
+    "keymap",
+    "[name]",
+    "List the keyboard layouts or switch to one",
+    keymap_command,
+    complete = complete_keymap
+);
@@ -0,0 +1,406 @@
+//! Keyboard handling that does not depend on how the keys are wired up.
+//!
+//! Drivers decode their hardware codes into [`KeyCode`]s and hand them to a [`Keyboard`],
+//! which tracks the modifiers and lock keys and turns each key into a [`KeyEvent`], including
+//! the character it types under the active [`keymap`].
+
+pub mod keymap;
+
+use keymap::Symbol;
+
+/// A physical key, named after what it is labelled on a US keyboard.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+
+    Backtick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Apostrophe,
+    Enter,
+
+    LeftShift,
+    /// The extra key next to left shift on ISO keyboards.
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+
+    LeftCtrl,
+    LeftMeta,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightMeta,
+    Menu,
+    RightCtrl,
+
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Up,
+    Down,
+    Left,
+    Right,
+
+    NumLock,
+    KeypadSlash,
+    KeypadAsterisk,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+}
+
+impl KeyCode {
+    pub const fn is_keypad(&self) -> bool {
+        matches!(
+            self,
+            KeyCode::KeypadSlash
+                | KeyCode::KeypadAsterisk
+                | KeyCode::KeypadMinus
+                | KeyCode::KeypadPlus
+                | KeyCode::KeypadEnter
+                | KeyCode::KeypadPeriod
+                | KeyCode::Keypad0
+                | KeyCode::Keypad1
+                | KeyCode::Keypad2
+                | KeyCode::Keypad3
+                | KeyCode::Keypad4
+                | KeyCode::Keypad5
+                | KeyCode::Keypad6
+                | KeyCode::Keypad7
+                | KeyCode::Keypad8
+                | KeyCode::Keypad9
+        )
+    }
+
+    pub const fn is_letter(&self) -> bool {
+        matches!(
+            self,
+            KeyCode::A
+                | KeyCode::B
+                | KeyCode::C
+                | KeyCode::D
+                | KeyCode::E
+                | KeyCode::F
+                | KeyCode::G
+                | KeyCode::H
+                | KeyCode::I
+                | KeyCode::J
+                | KeyCode::K
+                | KeyCode::L
+                | KeyCode::M
+                | KeyCode::N
+                | KeyCode::O
+                | KeyCode::P
+                | KeyCode::Q
+                | KeyCode::R
+                | KeyCode::S
+                | KeyCode::T
+                | KeyCode::U
+                | KeyCode::V
+                | KeyCode::W
+                | KeyCode::X
+                | KeyCode::Y
+                | KeyCode::Z
+        )
+    }
+}
+
+/// The modifiers and lock keys in effect, combined with `|`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const SHIFT: Modifiers = Modifiers(1 << 0);
+    pub const CTRL: Modifiers = Modifiers(1 << 1);
+    pub const ALT: Modifiers = Modifiers(1 << 2);
+    /// The right alt key, which selects the third level on many layouts.
+    pub const ALT_GR: Modifiers = Modifiers(1 << 3);
+    pub const META: Modifiers = Modifiers(1 << 4);
+    pub const CAPS_LOCK: Modifiers = Modifiers(1 << 5);
+    pub const NUM_LOCK: Modifiers = Modifiers(1 << 6);
+    pub const SCROLL_LOCK: Modifiers = Modifiers(1 << 7);
+
+    pub const fn contains(&self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+}
+
+impl core::ops::BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, rhs: Modifiers) -> Modifiers {
+        Modifiers(self.0 | rhs.0)
+    }
+}
+
+/// A key going down, repeating or coming back up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+    /// Whether this is a typematic repeat of a key that was already down.
+    pub repeat: bool,
+    /// The modifiers in effect once the event has been applied.
+    pub modifiers: Modifiers,
+    /// What the key types, for presses of keys that type something.
+    pub ch: Option<char>,
+}
+
+/// The lock key LEDs, in the bit order PS/2 keyboards use.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Leds(u8);
+
+impl Leds {
+    pub const SCROLL_LOCK: Leds = Leds(1 << 0);
+    pub const NUM_LOCK: Leds = Leds(1 << 1);
+    pub const CAPS_LOCK: Leds = Leds(1 << 2);
+
+    pub const fn contains(&self, other: Leds) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+}
+
+/// The modifier and lock state of one keyboard.
+#[derive(Default)]
+pub struct Keyboard {
+    /// One bit per [`KeyCode`] that is currently held down.
+    down: [u32; 4],
+    shift: u8,
+    ctrl: u8,
+    alt: bool,
+    alt_gr: bool,
+    meta: u8,
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool,
+    /// The accent of a dead key waiting for the next character.
+    dead: Option<char>,
+}
+
+impl Keyboard {
+    pub const fn new() -> Self {
+        Keyboard {
+            down: [0; 4],
+            shift: 0,
+            ctrl: 0,
+            alt: false,
+            alt_gr: false,
+            meta: 0,
+            caps_lock: false,
+            num_lock: false,
+            scroll_lock: false,
+            dead: None,
+        }
+    }
+
+    /// Returns whether `key` is currently held down.
+    pub fn is_down(&self, key: KeyCode) -> bool {
+        self.down[key as usize / 32] & 1 << (key as usize % 32) != 0
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        let mut modifiers = Modifiers::NONE;
+        for (active, modifier) in [
+            (self.shift != 0, Modifiers::SHIFT),
+            (self.ctrl != 0, Modifiers::CTRL),
+            (self.alt, Modifiers::ALT),
+            (self.alt_gr, Modifiers::ALT_GR),
+            (self.meta != 0, Modifiers::META),
+            (self.caps_lock, Modifiers::CAPS_LOCK),
+            (self.num_lock, Modifiers::NUM_LOCK),
+            (self.scroll_lock, Modifiers::SCROLL_LOCK),
+        ] {
+            if active {
+                modifiers = modifiers | modifier;
+            }
+        }
+        modifiers
+    }
+
+    pub fn leds(&self) -> Leds {
+        let mut leds = 0;
+        for (active, led) in [
+            (self.scroll_lock, Leds::SCROLL_LOCK),
+            (self.num_lock, Leds::NUM_LOCK),
+            (self.caps_lock, Leds::CAPS_LOCK),
+        ] {
+            if active {
+                leds |= led.0;
+            }
+        }
+        Leds(leds)
+    }
+
+    /// Applies a key going down or up. Returns the event along with whether the lock LEDs
+    /// changed.
+    pub fn process(&mut self, key: KeyCode, pressed: bool) -> (KeyEvent, bool) {
+        let leds = self.leds();
+        let (word, bit) = (key as usize / 32, 1 << (key as usize % 32));
+        let repeat = pressed && self.down[word] & bit != 0;
+        if pressed {
+            self.down[word] |= bit;
+        } else {
+            self.down[word] &= !bit;
+        }
+
+        // Both keys of a pair count separately, so releasing one leaves the other in effect.
+        let held = |keys: &mut u8, bit: u8| {
+            if pressed {
+                *keys |= bit;
+            } else {
+                *keys &= !bit;
+            }
+        };
+        match key {
+            KeyCode::LeftShift => held(&mut self.shift, 1),
+            KeyCode::RightShift => held(&mut self.shift, 2),
+            KeyCode::LeftCtrl => held(&mut self.ctrl, 1),
+            KeyCode::RightCtrl => held(&mut self.ctrl, 2),
+            KeyCode::LeftMeta => held(&mut self.meta, 1),
+            KeyCode::RightMeta => held(&mut self.meta, 2),
+            KeyCode::LeftAlt => self.alt = pressed,
+            KeyCode::RightAlt => self.alt_gr = pressed,
+            KeyCode::CapsLock if pressed && !repeat => self.caps_lock = !self.caps_lock,
+            KeyCode::NumLock if pressed && !repeat => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock if pressed && !repeat => self.scroll_lock = !self.scroll_lock,
+            _ => {}
+        }
+
+        let modifiers = self.modifiers();
+        let ch = if pressed {
+            self.translate(key, modifiers)
+        } else {
+            None
+        };
+        let event = KeyEvent {
+            key,
+            pressed,
+            repeat,
+            modifiers,
+            ch,
+        };
+        (event, self.leds() != leds)
+    }
+}
+
+impl Keyboard {
+    /// Works out what a key press types, taking any pending dead key into account.
+    fn translate(&mut self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        match keymap::active().lookup(key, modifiers)? {
+            Symbol::Dead(accent) => match self.dead.take() {
+                // Pressing a dead key twice types the accent.
+                Some(pending) => Some(keymap::compose(pending, accent).unwrap_or(accent)),
+                None => {
+                    self.dead = Some(accent);
+                    None
+                }
+            },
+            Symbol::Char(ch) => {
+                let ch = match self.dead.take() {
+                    Some(accent) if !ch.is_control() => keymap::compose(accent, ch).unwrap_or(ch),
+                    _ => ch,
+                };
+                Some(control_character(ch, modifiers))
+            }
+        }
+    }
+}
+
+/// Turns `ch` into the matching control character when ctrl is held, as in `^C`.
+fn control_character(ch: char, modifiers: Modifiers) -> char {
+    if !modifiers.contains(Modifiers::CTRL) {
+        return ch;
+    }
+    match ch {
+        'a'..='z' | 'A'..='Z' => (ch.to_ascii_uppercase() as u8 - b'@') as char,
+        '@' | '[' | '\\' | ']' | '^' | '_' => (ch as u8 - b'@') as char,
+        '?' => '\x7f',
+        _ => ch,
+    }
+}
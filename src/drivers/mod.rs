@@ -0,0 +1,18 @@
+//! This module contains the drivers for the hardware the kernel talks to directly.
+
+pub mod ahci;
+pub mod ata;
+pub mod block;
+pub mod keyboard;
+pub mod mouse;
+pub mod pci;
+pub mod pit;
+pub mod ps2;
+pub mod virtio;
+
+/// Probes for devices and starts their drivers.
+pub fn initialize() {
+    pit::initialize();
+    pci::initialize();
+    ps2::initialize();
+}
@@ -0,0 +1,40 @@
+//! Mouse state, independent of the hardware it comes from.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    /// The fourth button, usually on the side and mapped to "back".
+    Side,
+    /// The fifth button, usually on the side and mapped to "forward".
+    Extra,
+}
+
+impl MouseButton {
+    pub const ALL: [MouseButton; 5] = [
+        MouseButton::Left,
+        MouseButton::Right,
+        MouseButton::Middle,
+        MouseButton::Side,
+        MouseButton::Extra,
+    ];
+}
+
+/// The set of buttons held down, one bit per [`MouseButton`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Buttons(u8);
+
+impl Buttons {
+    pub const fn contains(&self, button: MouseButton) -> bool {
+        self.0 & 1 << button as u8 != 0
+    }
+
+    pub fn set(&mut self, button: MouseButton, pressed: bool) {
+        if pressed {
+            self.0 |= 1 << button as u8;
+        } else {
+            self.0 &= !(1 << button as u8);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Names for class codes and capability IDs.
+
+/// Returns a name for a class, subclass and programming interface, as specific as is known.
+pub fn name(class: u8, subclass: u8, prog_if: u8) -> &'static str {
+    match (class, subclass, prog_if) {
+        (0x00, 0x01, _) => "VGA-compatible device",
+        (0x00, _, _) => "Unclassified device",
+
+        (0x01, 0x00, _) => "SCSI controller",
+        (0x01, 0x01, _) => "IDE controller",
+        (0x01, 0x02, _) => "Floppy controller",
+        (0x01, 0x04, _) => "RAID controller",
+        (0x01, 0x05, _) => "ATA controller",
+        (0x01, 0x06, 0x01) => "SATA controller (AHCI)",
+        (0x01, 0x06, _) => "SATA controller",
+        (0x01, 0x07, _) => "SAS controller",
+        (0x01, 0x08, 0x02) => "NVM Express controller",
+        (0x01, 0x08, _) => "Non-volatile memory controller",
+        (0x01, _, _) => "Mass storage controller",
+
+        (0x02, 0x00, _) => "Ethernet controller",
+        (0x02, _, _) => "Network controller",
+
+        (0x03, 0x00, _) => "VGA controller",
+        (0x03, 0x01, _) => "XGA controller",
+        (0x03, 0x02, _) => "3D controller",
+        (0x03, _, _) => "Display controller",
+
+        (0x04, 0x00, _) => "Video device",
+        (0x04, 0x01, _) => "Audio device",
+        (0x04, 0x03, _) => "Audio device (HD Audio)",
+        (0x04, _, _) => "Multimedia controller",
+
+        (0x05, 0x00, _) => "RAM controller",
+        (0x05, _, _) => "Memory controller",
+
+        (0x06, 0x00, _) => "Host bridge",
+        (0x06, 0x01, _) => "ISA bridge",
+        (0x06, 0x02, _) => "EISA bridge",
+        (0x06, 0x04, _) => "PCI bridge",
+        (0x06, 0x05, _) => "PCMCIA bridge",
+        (0x06, 0x07, _) => "CardBus bridge",
+        (0x06, _, _) => "Bridge",
+
+        (0x07, 0x00, _) => "Serial controller",
+        (0x07, 0x01, _) => "Parallel controller",
+        (0x07, _, _) => "Communication controller",
+
+        (0x08, 0x00, 0x20) => "I/O APIC",
+        (0x08, 0x00, _) => "PIC",
+        (0x08, 0x01, _) => "DMA controller",
+        (0x08, 0x02, _) => "Timer",
+        (0x08, 0x03, _) => "RTC",
+        (0x08, 0x05, _) => "SD host controller",
+        (0x08, 0x06, _) => "IOMMU",
+        (0x08, _, _) => "System peripheral",
+
+        (0x09, 0x00, _) => "Keyboard controller",
+        (0x09, 0x02, _) => "Mouse controller",
+        (0x09, _, _) => "Input device controller",
+
+        (0x0A, _, _) => "Docking station",
+        (0x0B, _, _) => "Processor",
+
+        (0x0C, 0x00, _) => "FireWire controller",
+        (0x0C, 0x03, 0x00) => "USB controller (UHCI)",
+        (0x0C, 0x03, 0x10) => "USB controller (OHCI)",
+        (0x0C, 0x03, 0x20) => "USB controller (EHCI)",
+        (0x0C, 0x03, 0x30) => "USB controller (xHCI)",
+        (0x0C, 0x03, _) => "USB controller",
+        (0x0C, 0x05, _) => "SMBus controller",
+        (0x0C, _, _) => "Serial bus controller",
+
+        (0x0D, _, _) => "Wireless controller",
+        (0x0E, _, _) => "Intelligent controller",
+        (0x0F, _, _) => "Satellite controller",
+        (0x10, _, _) => "Encryption controller",
+        (0x11, _, _) => "Signal processing controller",
+        (0x12, _, _) => "Processing accelerator",
+        (0xFF, _, _) => "Unassigned class",
+        _ => "Unknown device",
+    }
+}
+
+/// Returns the name of a capability, if it is one of the common ones.
+pub fn capability_name(id: u8) -> Option<&'static str> {
+    Some(match id {
+        0x01 => "Power Management",
+        0x03 => "VPD",
+        0x05 => "MSI",
+        0x07 => "PCI-X",
+        0x09 => "Vendor Specific",
+        0x0A => "Debug port",
+        0x0D => "Subsystem ID",
+        0x10 => "PCI Express",
+        0x11 => "MSI-X",
+        0x12 => "SATA",
+        0x13 => "Advanced Features",
+        _ => return None,
+    })
+}
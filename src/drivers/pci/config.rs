@@ -0,0 +1,168 @@
+//! Access to configuration space.
+//!
+//! Configuration mechanism #1 goes through an address and a data port and reaches the 256
+//! bytes of every function on segment 0. ECAM maps the full 4 KiB of each function into memory
+//! and is used for the buses the firmware describes in the MCFG table.
+
+use alloc::vec::Vec;
+
+use super::Address;
+use crate::{
+    intrinsics::{inl, outb, outl, outw},
+    sync::{RwLock, TicketLock},
+    warn,
+};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+const ENABLE: u32 = 1 << 31;
+
+/// A range of buses whose configuration space is memory mapped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EcamRegion {
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl EcamRegion {
+    fn contains(&self, address: Address) -> bool {
+        address.segment == self.segment && (self.start_bus..=self.end_bus).contains(&address.bus)
+    }
+
+    fn pointer(&self, address: Address, offset: u16) -> *mut u32 {
+        let offset = usize::from(address.bus - self.start_bus) << 20
+            | usize::from(address.device) << 15
+            | usize::from(address.function) << 12
+            | usize::from(offset & 0xFFC);
+        (self.base as usize + offset) as *mut u32
+    }
+}
+
+static ECAM: RwLock<Vec<EcamRegion>> = RwLock::named("pci ecam", Vec::new());
+
+/// Serialises the two port accesses a read or write through mechanism #1 takes, and keeps
+/// interrupt handlers and other tasks from coming in between them.
+static PORTS: TicketLock<()> = TicketLock::named("pci config", ());
+
+/// Has the buses of `region` reached through ECAM from now on. Regions above 4 GiB cannot be
+/// reached without paging and are ignored.
+pub fn add_ecam(region: EcamRegion) {
+    if region.base + ((u64::from(region.end_bus - region.start_bus) + 1) << 20) > 1 << 32 {
+        warn!("pci: ECAM at {:#x} is out of reach", region.base);
+        return;
+    }
+    ECAM.write().push(region);
+}
+
+/// Returns the ECAM region covering `address`, if there is one.
+fn ecam(address: Address) -> Option<EcamRegion> {
+    ECAM.read()
+        .iter()
+        .find(|region| region.contains(address))
+        .copied()
+}
+
+/// Returns the segments configuration space can be reached on, along with their buses.
+pub fn segments() -> Vec<(u16, u8, u8)> {
+    let ecam = ECAM.read();
+    let mut segments: Vec<_> = ecam
+        .iter()
+        .map(|region| (region.segment, region.start_bus, region.end_bus))
+        .collect();
+    if !ecam.iter().any(|region| region.segment == 0) {
+        segments.insert(0, (0, 0, 255));
+    }
+    segments
+}
+
+fn port_address(address: Address, offset: u16) -> u32 {
+    ENABLE
+        | u32::from(address.bus) << 16
+        | u32::from(address.device) << 11
+        | u32::from(address.function) << 8
+        | u32::from(offset & 0xFC)
+}
+
+/// Reads the double word at `offset`, which is rounded down to a multiple of four.
+pub fn read(address: Address, offset: u16) -> u32 {
+    if let Some(region) = ecam(address) {
+        // SAFETY: The region was handed over by the firmware as configuration space.
+        return unsafe { region.pointer(address, offset).read_volatile() };
+    }
+    if address.segment != 0 || offset >= 0x100 {
+        return u32::MAX;
+    }
+    let _ports = PORTS.lock();
+    // SAFETY: These are the configuration mechanism #1 ports.
+    unsafe {
+        outl(CONFIG_ADDRESS, port_address(address, offset));
+        inl(CONFIG_DATA)
+    }
+}
+
+/// Writes the double word at `offset`, which is rounded down to a multiple of four.
+pub fn write(address: Address, offset: u16, value: u32) {
+    if let Some(region) = ecam(address) {
+        // SAFETY: See `read`.
+        unsafe { region.pointer(address, offset).write_volatile(value) };
+        return;
+    }
+    if address.segment != 0 || offset >= 0x100 {
+        return;
+    }
+    let _ports = PORTS.lock();
+    // SAFETY: See `read`.
+    unsafe {
+        outl(CONFIG_ADDRESS, port_address(address, offset));
+        outl(CONFIG_DATA, value);
+    }
+}
+
+pub fn read_u16(address: Address, offset: u16) -> u16 {
+    (read(address, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_u8(address: Address, offset: u16) -> u8 {
+    (read(address, offset) >> ((offset & 3) * 8)) as u8
+}
+
+/// Writes just the word at `offset`, leaving the other half of its double word alone. That
+/// matters next to registers like the status, whose bits are cleared by writing ones.
+pub fn write_u16(address: Address, offset: u16, value: u16) {
+    if let Some(region) = ecam(address) {
+        let pointer = region.pointer(address, offset) as usize + usize::from(offset & 2);
+        // SAFETY: See `read`.
+        unsafe { (pointer as *mut u16).write_volatile(value) };
+        return;
+    }
+    if address.segment != 0 || offset >= 0x100 {
+        return;
+    }
+    let _ports = PORTS.lock();
+    // SAFETY: See `read`.
+    unsafe {
+        outl(CONFIG_ADDRESS, port_address(address, offset));
+        outw(CONFIG_DATA + (offset & 2), value);
+    }
+}
+
+/// Writes just the byte at `offset`.
+pub fn write_u8(address: Address, offset: u16, value: u8) {
+    if let Some(region) = ecam(address) {
+        let pointer = region.pointer(address, offset) as usize + usize::from(offset & 3);
+        // SAFETY: See `read`.
+        unsafe { (pointer as *mut u8).write_volatile(value) };
+        return;
+    }
+    if address.segment != 0 || offset >= 0x100 {
+        return;
+    }
+    let _ports = PORTS.lock();
+    // SAFETY: See `read`.
+    unsafe {
+        outl(CONFIG_ADDRESS, port_address(address, offset));
+        outb(CONFIG_DATA + (offset & 3), value);
+    }
+}
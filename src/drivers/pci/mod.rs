@@ -0,0 +1,539 @@
+//! This module enumerates the devices on the PCI buses and hands them to their drivers.
+//!
+//! Configuration space is reached through mechanism #1 or, for the buses listed in the ACPI
+//! MCFG table, through ECAM (see [`config`]). Every function found is read into a [`Device`]
+//! with its class, BARs, capabilities and interrupt line.
+//!
+//! Drivers are registered with [`pci_driver!`](crate::pci_driver), which places a [`Driver`]
+//! in the `.pci_drivers` link section. Each device is offered to the drivers whose [`Match`]
+//! entries fit it until one of them takes it.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use spin::Once;
+
+pub mod class;
+pub mod config;
+
+use crate::{info, print, println, shell::CommandError, shell_command, warn};
+
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0E;
+const BAR0: u16 = 0x10;
+const SUBSYSTEM_VENDOR_ID: u16 = 0x2C;
+const SUBSYSTEM_ID: u16 = 0x2E;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3C;
+const INTERRUPT_PIN: u16 = 0x3D;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_GENERAL: u8 = 0x00;
+const HEADER_BRIDGE: u8 = 0x01;
+
+/// No device answers at an address whose vendor reads as this.
+const NO_VENDOR: u16 = 0xFFFF;
+/// Guards against capability lists that loop.
+const MAX_CAPABILITIES: usize = 48;
+
+/// The location of a function in configuration space.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// A base address register, sized by writing ones to it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// Whether the BAR takes up the next slot as well for the upper half of the address.
+        wide: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Where in configuration space the capability's registers start.
+    pub offset: u8,
+}
+
+impl Capability {
+    pub fn name(&self) -> Option<&'static str> {
+        class::capability_name(self.id)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// The layout of the rest of the header, without the multi-function bit.
+    pub header_type: u8,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    /// The legacy IRQ the firmware routed the device to, if it uses one.
+    pub interrupt_line: Option<u8>,
+    /// Which of INTA# to INTD# the device raises, or 0 for none.
+    pub interrupt_pin: u8,
+    /// The driver that took the device.
+    pub driver: Option<&'static str>,
+}
+
+impl Device {
+    pub fn class_name(&self) -> &'static str {
+        class::name(self.class, self.subclass, self.prog_if)
+    }
+
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities
+            .iter()
+            .find(|capability| capability.id == id)
+            .copied()
+    }
+
+    pub fn read_config(&self, offset: u16) -> u32 {
+        config::read(self.address, offset)
+    }
+
+    pub fn write_config(&self, offset: u16, value: u32) {
+        config::write(self.address, offset, value)
+    }
+
+    /// Turns on decoding of the device's I/O and memory BARs and lets it master the bus.
+    pub fn enable(&self) {
+        let command = config::read_u16(self.address, COMMAND);
+        config::write_u16(
+            self.address,
+            COMMAND,
+            command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+        );
+    }
+
+    /// Allows or masks the device's legacy interrupt.
+    pub fn set_interrupts(&self, enabled: bool) {
+        let command = config::read_u16(self.address, COMMAND);
+        let command = if enabled {
+            command & !COMMAND_INTERRUPT_DISABLE
+        } else {
+            command | COMMAND_INTERRUPT_DISABLE
+        };
+        config::write_u16(self.address, COMMAND, command);
+    }
+}
+
+/// Which devices a driver is for. Fields left as `None` match anything.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl Match {
+    /// Matches one particular device.
+    pub const fn id(vendor_id: u16, device_id: u16) -> Self {
+        Match {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    /// Matches every device of a class, optionally narrowed to one programming interface.
+    pub const fn class(class: u8, subclass: u8, prog_if: Option<u8>) -> Self {
+        Match {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if,
+        }
+    }
+
+    pub fn matches(&self, device: &Device) -> bool {
+        fn fits<T: PartialEq>(wanted: Option<T>, actual: T) -> bool {
+            wanted.is_none_or(|wanted| wanted == actual)
+        }
+        fits(self.vendor_id, device.vendor_id)
+            && fits(self.device_id, device.device_id)
+            && fits(self.class, device.class)
+            && fits(self.subclass, device.subclass)
+            && fits(self.prog_if, device.prog_if)
+    }
+}
+
+/// A driver for PCI devices.
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /// Invoked for each matching device that no other driver has taken. Returning `Err` leaves
+    /// the device for the next driver.
+    pub probe: fn(&Device) -> Result<(), ()>,
+}
+
+/// Registers a [`Driver`](crate::drivers::pci::Driver) under the given static name.
+///
+/// ```ignore
+/// fn probe(device: &Device) -> Result<(), ()> { ... }
+/// pci_driver!(AHCI_DRIVER, "ahci", &[Match::class(0x01, 0x06, Some(0x01))], probe);
+/// ```
+#[macro_export]
+macro_rules! pci_driver {
+    ($ident:ident, $name:literal, $matches:expr, $probe:expr) => {
+        #[used(linker)]
+        #[link_section = ".pci_drivers"]
+        static $ident: $crate::drivers::pci::Driver = $crate::drivers::pci::Driver {
+            name: $name,
+            matches: $matches,
+            probe: $probe,
+        };
+    };
+}
+
+/// Returns every registered driver.
+pub fn drivers() -> &'static [Driver] {
+    extern "C" {
+        static __pci_drivers_start: u8;
+        static __pci_drivers_end: u8;
+    }
+
+    // SAFETY: The linker script gathers every `Driver` emitted by `pci_driver!` between these
+    // two symbols.
+    unsafe {
+        let start = core::ptr::addr_of!(__pci_drivers_start) as *const Driver;
+        let end = core::ptr::addr_of!(__pci_drivers_end) as *const Driver;
+        let len = (end as usize - start as usize) / core::mem::size_of::<Driver>();
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+static DEVICES: Once<Vec<Device>> = Once::new();
+
+/// Returns every device found, in address order.
+pub fn devices() -> &'static [Device] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Returns the devices that match `matches`.
+pub fn find(matches: Match) -> impl Iterator<Item = &'static Device> {
+    devices()
+        .iter()
+        .filter(move |device| matches.matches(device))
+}
+
+/// Sizes the BAR at `index`. Decoding has to be off while this runs.
+fn probe_bar(address: Address, index: usize) -> Option<Bar> {
+    let offset = BAR0 + index as u16 * 4;
+    let original = config::read(address, offset);
+    config::write(address, offset, u32::MAX);
+    let mask = config::read(address, offset);
+    config::write(address, offset, original);
+
+    if original & 1 != 0 {
+        let mask = mask & !0x3 & 0xFFFF;
+        if mask == 0 {
+            return None;
+        }
+        return Some(Bar::Io {
+            port: (original & !0x3) as u16,
+            size: ((!mask & 0xFFFF) + 1) as u16,
+        });
+    }
+
+    let wide = (original >> 1) & 0x3 == 0x2;
+    let (address_bits, mask) = if wide && index < 5 {
+        let upper = config::read(address, offset + 4);
+        config::write(address, offset + 4, u32::MAX);
+        let upper_mask = config::read(address, offset + 4);
+        config::write(address, offset + 4, upper);
+        (
+            u64::from(upper) << 32 | u64::from(original),
+            u64::from(upper_mask) << 32 | u64::from(mask),
+        )
+    } else {
+        (u64::from(original), u64::from(mask) | 0xFFFF_FFFF_0000_0000)
+    };
+    let mask = mask & !0xF;
+    if mask == 0xFFFF_FFFF_0000_0000 || mask == 0 {
+        return None;
+    }
+    Some(Bar::Memory {
+        address: address_bits & !0xF,
+        size: (!mask).wrapping_add(1),
+        prefetchable: original & 0x8 != 0,
+        wide,
+    })
+}
+
+fn read_bars(address: Address, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let command = config::read_u16(address, COMMAND);
+    config::write_u16(address, COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+    let mut index = 0;
+    while index < count {
+        let bar = probe_bar(address, index);
+        bars[index] = bar;
+        index += if let Some(Bar::Memory { wide: true, .. }) = bar {
+            2
+        } else {
+            1
+        };
+    }
+    config::write_u16(address, COMMAND, command);
+    bars
+}
+
+fn read_capabilities(address: Address) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if config::read_u16(address, STATUS) & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+    let mut offset = config::read_u8(address, CAPABILITIES_POINTER) & 0xFC;
+    while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+        let header = config::read_u16(address, u16::from(offset));
+        capabilities.push(Capability {
+            id: header as u8,
+            offset,
+        });
+        offset = (header >> 8) as u8 & 0xFC;
+    }
+    capabilities
+}
+
+/// Reads the function at `address`, if there is one.
+fn read_device(address: Address) -> Option<Device> {
+    let vendor_id = config::read_u16(address, VENDOR_ID);
+    if vendor_id == NO_VENDOR {
+        return None;
+    }
+    let class = config::read(address, REVISION);
+    let header_type = config::read_u8(address, HEADER_TYPE) & !HEADER_MULTI_FUNCTION;
+    let (bars, subsystem) = match header_type {
+        HEADER_GENERAL => (
+            read_bars(address, 6),
+            (
+                config::read_u16(address, SUBSYSTEM_VENDOR_ID),
+                config::read_u16(address, SUBSYSTEM_ID),
+            ),
+        ),
+        HEADER_BRIDGE => (read_bars(address, 2), (0, 0)),
+        _ => ([None; 6], (0, 0)),
+    };
+    let interrupt_line = config::read_u8(address, INTERRUPT_LINE);
+    Some(Device {
+        address,
+        vendor_id,
+        device_id: config::read_u16(address, DEVICE_ID),
+        subsystem_vendor_id: subsystem.0,
+        subsystem_id: subsystem.1,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        header_type,
+        bars,
+        capabilities: read_capabilities(address),
+        // 0xFF means not connected, and nothing sane is routed above IRQ 15.
+        interrupt_line: (interrupt_line < 16).then_some(interrupt_line),
+        interrupt_pin: config::read_u8(address, INTERRUPT_PIN),
+        driver: None,
+    })
+}
+
+/// Walks every bus, device and function that can be reached.
+fn enumerate() -> Vec<Device> {
+    let mut devices = Vec::new();
+    for (segment, start_bus, end_bus) in config::segments() {
+        for bus in start_bus..=end_bus {
+            for device in 0..32 {
+                let address = Address {
+                    segment,
+                    bus,
+                    device,
+                    function: 0,
+                };
+                if config::read_u16(address, VENDOR_ID) == NO_VENDOR {
+                    continue;
+                }
+                let functions =
+                    if config::read_u8(address, HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {
+                        8
+                    } else {
+                        1
+                    };
+                devices.extend((0..functions).filter_map(|function| {
+                    read_device(Address {
+                        function,
+                        ..address
+                    })
+                }));
+            }
+        }
+    }
+    devices
+}
+
+/// Offers `device` to each matching driver until one takes it.
+fn bind(device: &mut Device) {
+    for driver in drivers() {
+        if !driver.matches.iter().any(|matches| matches.matches(device)) {
+            continue;
+        }
+        match (driver.probe)(device) {
+            Ok(()) => {
+                device.driver = Some(driver.name);
+                return;
+            }
+            Err(()) => warn!("pci: {} refused {}", driver.name, device.address),
+        }
+    }
+}
+
+/// Finds every device and starts the drivers for them, reaching the buses in the MCFG table
+/// through ECAM.
+pub fn initialize() {
+    for &region in crate::acpi::mcfg().map_or(&[][..], |mcfg| &mcfg.regions) {
+        config::add_ecam(region);
+    }
+    DEVICES.call_once(|| {
+        let mut devices = enumerate();
+        for device in &devices {
+            info!(
+                "pci: {} {:04x}:{:04x} {}",
+                device.address,
+                device.vendor_id,
+                device.device_id,
+                device.class_name()
+            );
+        }
+        for device in &mut devices {
+            bind(device);
+        }
+        devices
+    });
+}
+
+fn print_verbose(device: &Device) {
+    println!(
+        "    class {:02x}:{:02x}:{:02x}, revision {:#04x}, subsystem {:04x}:{:04x}",
+        device.class,
+        device.subclass,
+        device.prog_if,
+        device.revision,
+        device.subsystem_vendor_id,
+        device.subsystem_id
+    );
+    if let (Some(line), 1..=4) = (device.interrupt_line, device.interrupt_pin) {
+        println!(
+            "    IRQ {} on INT{}#",
+            line,
+            (b'@' + device.interrupt_pin) as char
+        );
+    }
+    for (index, bar) in device.bars.iter().enumerate() {
+        match bar {
+            Some(Bar::Memory {
+                address,
+                size,
+                prefetchable,
+                wide,
+            }) => println!(
+                "    BAR{}: memory at {:#x} ({} KiB, {}-bit{})",
+                index,
+                address,
+                size / 1024,
+                if *wide { 64 } else { 32 },
+                if *prefetchable { ", prefetchable" } else { "" }
+            ),
+            Some(Bar::Io { port, size }) => {
+                println!("    BAR{}: I/O at {:#x} ({} ports)", index, port, size)
+            }
+            None => {}
+        }
+    }
+    if !device.capabilities.is_empty() {
+        print!("    capabilities:");
+        for capability in &device.capabilities {
+            match capability.name() {
+                Some(name) => print!(" {}", name),
+                None => print!(" {:#04x}", capability.id),
+            }
+        }
+        println!();
+    }
+}
+
+fn lspci(args: &[&str]) -> Result<(), CommandError> {
+    let verbose = match args {
+        [] => false,
+        ["-v"] => true,
+        _ => return Err(CommandError::Usage),
+    };
+    for device in devices() {
+        print!(
+            "{} {:04x}:{:04x} {}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class_name()
+        );
+        match device.driver {
+            Some(driver) => println!(" [{}]", driver),
+            None => println!(),
+        }
+        if verbose {
+            print_verbose(device);
+        }
+    }
+    Ok(())
+}
+
+shell_command!(
+    LSPCI_COMMAND,
+    "lspci",
+    "[-v]",
+    "List the PCI devices",
+    lspci
+);
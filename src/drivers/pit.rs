@@ -0,0 +1,63 @@
+//! The 8253/8254 programmable interval timer, which keeps the time since boot.
+//!
+//! Channel 0 is set up as a rate generator raising IRQ 0 [`HZ`] times a second, and every
+//! interrupt counts as one tick. Every tick also drives the task scheduler.
+
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    info, interrupts,
+    intrinsics::{io_wait, outb},
+    task,
+};
+
+const CHANNEL_0: u16 = 0x40;
+const MODE_COMMAND: u16 = 0x43;
+
+/// Channel 0, low byte then high byte, mode 2 (rate generator), binary.
+const RATE_GENERATOR: u8 = 0b0011_0100;
+
+/// The frequency the timer counts down at.
+const BASE_FREQUENCY: u32 = 1_193_182;
+/// How often the timer ticks.
+pub const HZ: u32 = 100;
+
+const TIMER_IRQ: u8 = 0;
+
+static TICKS: AtomicU32 = AtomicU32::new(0);
+
+fn handle_irq() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    task::tick();
+}
+
+/// Returns the number of ticks since the timer was started.
+pub fn ticks() -> u32 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns how long ago the timer was started.
+pub fn uptime() -> Duration {
+    let ticks = ticks();
+    Duration::from_secs(u64::from(ticks / HZ))
+        + Duration::from_nanos(u64::from(ticks % HZ) * 1_000_000_000 / u64::from(HZ))
+}
+
+/// Programs channel 0 and starts counting ticks.
+pub fn initialize() {
+    let divisor = (BASE_FREQUENCY / HZ) as u16;
+    // SAFETY: Channel 0 is only used for the tick.
+    unsafe {
+        outb(MODE_COMMAND, RATE_GENERATOR);
+        io_wait();
+        outb(CHANNEL_0, divisor as u8);
+        io_wait();
+        outb(CHANNEL_0, (divisor >> 8) as u8);
+    }
+    if interrupts::register_irq(TIMER_IRQ, handle_irq).is_ok() {
+        info!("pit: ticking at {} Hz", HZ);
+    }
+}
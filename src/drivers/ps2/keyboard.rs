@@ -0,0 +1,417 @@
+//! The driver for a keyboard on one of the PS/2 ports.
+//!
+//! The keyboard is switched to scancode set 2 where it allows that, and otherwise left on set
+//! 1 through the controller's translation. Each byte arrives through the port's IRQ and is
+//! decoded into a key event, which is reported to the [`input`](crate::input) layer.
+
+use super::{Port, Ps2Error, ACK, RESEND};
+use crate::{
+    drivers::keyboard::{KeyCode, Keyboard},
+    info,
+    input::{self, Capabilities, DeviceId, EventKind},
+    sync::SpinLock,
+};
+
+const SET_LEDS: u8 = 0xED;
+const SCANCODE_SET: u8 = 0xF0;
+
+const EXTENDED: u8 = 0xE0;
+const PAUSE: u8 = 0xE1;
+const SET2_RELEASE: u8 = 0xF0;
+const SET2_SELF_TEST_PASSED: u8 = 0xAA;
+const ECHO: u8 = 0xEE;
+const KEY_ERROR: u8 = 0x00;
+const KEY_OVERRUN: u8 = 0xFF;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScancodeSet {
+    One,
+    Two,
+}
+
+/// Turns the byte stream of a keyboard into key presses and releases.
+struct Decoder {
+    set: ScancodeSet,
+    extended: bool,
+    release: bool,
+    /// How many bytes of a pause sequence are still to come.
+    pause: u8,
+}
+
+impl Decoder {
+    const fn new(set: ScancodeSet) -> Self {
+        Decoder {
+            set,
+            extended: false,
+            release: false,
+            pause: 0,
+        }
+    }
+
+    /// Feeds in the next byte, returning the key and whether it was pressed once a whole
+    /// scancode has been seen.
+    fn feed(&mut self, byte: u8) -> Option<(KeyCode, bool)> {
+        if self.pause > 0 {
+            self.pause -= 1;
+            return (self.pause == 0).then_some((KeyCode::Pause, true));
+        }
+        match byte {
+            EXTENDED => {
+                self.extended = true;
+                return None;
+            }
+            PAUSE => {
+                // The pause key sends its press and release back to back, and no break code.
+                self.pause = match self.set {
+                    ScancodeSet::One => 5,
+                    ScancodeSet::Two => 7,
+                };
+                return None;
+            }
+            SET2_RELEASE if self.set == ScancodeSet::Two => {
+                self.release = true;
+                return None;
+            }
+            _ => {}
+        }
+
+        let extended = core::mem::take(&mut self.extended);
+        match self.set {
+            ScancodeSet::One => set1(byte & 0x7F, extended).map(|key| (key, byte & 0x80 == 0)),
+            ScancodeSet::Two => {
+                let release = core::mem::take(&mut self.release);
+                set2(byte, extended).map(|key| (key, !release))
+            }
+        }
+    }
+}
+
+/// Decodes a scancode set 1 make code.
+fn set1(code: u8, extended: bool) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(if extended {
+        match code {
+            0x1C => KeypadEnter,
+            0x1D => RightCtrl,
+            0x35 => KeypadSlash,
+            0x37 => PrintScreen,
+            0x38 => RightAlt,
+            0x47 => Home,
+            0x48 => Up,
+            0x49 => PageUp,
+            0x4B => Left,
+            0x4D => Right,
+            0x4F => End,
+            0x50 => Down,
+            0x51 => PageDown,
+            0x52 => Insert,
+            0x53 => Delete,
+            0x5B => LeftMeta,
+            0x5C => RightMeta,
+            0x5D => Menu,
+            // Includes the fake shifts sent around the navigation keys.
+            _ => return None,
+        }
+    } else {
+        match code {
+            0x01 => Escape,
+            0x02 => Key1,
+            0x03 => Key2,
+            0x04 => Key3,
+            0x05 => Key4,
+            0x06 => Key5,
+            0x07 => Key6,
+            0x08 => Key7,
+            0x09 => Key8,
+            0x0A => Key9,
+            0x0B => Key0,
+            0x0C => Minus,
+            0x0D => Equals,
+            0x0E => Backspace,
+            0x0F => Tab,
+            0x10 => Q,
+            0x11 => W,
+            0x12 => E,
+            0x13 => R,
+            0x14 => T,
+            0x15 => Y,
+            0x16 => U,
+            0x17 => I,
+            0x18 => O,
+            0x19 => P,
+            0x1A => LeftBracket,
+            0x1B => RightBracket,
+            0x1C => Enter,
+            0x1D => LeftCtrl,
+            0x1E => A,
+            0x1F => S,
+            0x20 => D,
+            0x21 => F,
+            0x22 => G,
+            0x23 => H,
+            0x24 => J,
+            0x25 => K,
+            0x26 => L,
+            0x27 => Semicolon,
+            0x28 => Apostrophe,
+            0x29 => Backtick,
+            0x2A => LeftShift,
+            0x2B => Backslash,
+            0x2C => Z,
+            0x2D => X,
+            0x2E => C,
+            0x2F => V,
+            0x30 => B,
+            0x31 => N,
+            0x32 => M,
+            0x33 => Comma,
+            0x34 => Period,
+            0x35 => Slash,
+            0x36 => RightShift,
+            0x37 => KeypadAsterisk,
+            0x38 => LeftAlt,
+            0x39 => Space,
+            0x3A => CapsLock,
+            0x3B => F1,
+            0x3C => F2,
+            0x3D => F3,
+            0x3E => F4,
+            0x3F => F5,
+            0x40 => F6,
+            0x41 => F7,
+            0x42 => F8,
+            0x43 => F9,
+            0x44 => F10,
+            0x45 => NumLock,
+            0x46 => ScrollLock,
+            0x47 => Keypad7,
+            0x48 => Keypad8,
+            0x49 => Keypad9,
+            0x4A => KeypadMinus,
+            0x4B => Keypad4,
+            0x4C => Keypad5,
+            0x4D => Keypad6,
+            0x4E => KeypadPlus,
+            0x4F => Keypad1,
+            0x50 => Keypad2,
+            0x51 => Keypad3,
+            0x52 => Keypad0,
+            0x53 => KeypadPeriod,
+            0x56 => NonUsBackslash,
+            0x57 => F11,
+            0x58 => F12,
+            _ => return None,
+        }
+    })
+}
+
+/// Decodes a scancode set 2 make code.
+fn set2(code: u8, extended: bool) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(if extended {
+        match code {
+            0x11 => RightAlt,
+            0x14 => RightCtrl,
+            0x1F => LeftMeta,
+            0x27 => RightMeta,
+            0x2F => Menu,
+            0x4A => KeypadSlash,
+            0x5A => KeypadEnter,
+            0x69 => End,
+            0x6B => Left,
+            0x6C => Home,
+            0x70 => Insert,
+            0x71 => Delete,
+            0x72 => Down,
+            0x74 => Right,
+            0x75 => Up,
+            0x7A => PageDown,
+            0x7C => PrintScreen,
+            0x7D => PageUp,
+            // Includes the fake shifts sent around the navigation keys.
+            _ => return None,
+        }
+    } else {
+        match code {
+            0x01 => F9,
+            0x03 => F5,
+            0x04 => F3,
+            0x05 => F1,
+            0x06 => F2,
+            0x07 => F12,
+            0x09 => F10,
+            0x0A => F8,
+            0x0B => F6,
+            0x0C => F4,
+            0x0D => Tab,
+            0x0E => Backtick,
+            0x11 => LeftAlt,
+            0x12 => LeftShift,
+            0x14 => LeftCtrl,
+            0x15 => Q,
+            0x16 => Key1,
+            0x1A => Z,
+            0x1B => S,
+            0x1C => A,
+            0x1D => W,
+            0x1E => Key2,
+            0x21 => C,
+            0x22 => X,
+            0x23 => D,
+            0x24 => E,
+            0x25 => Key4,
+            0x26 => Key3,
+            0x29 => Space,
+            0x2A => V,
+            0x2B => F,
+            0x2C => T,
+            0x2D => R,
+            0x2E => Key5,
+            0x31 => N,
+            0x32 => B,
+            0x33 => H,
+            0x34 => G,
+            0x35 => Y,
+            0x36 => Key6,
+            0x3A => M,
+            0x3B => J,
+            0x3C => U,
+            0x3D => Key7,
+            0x3E => Key8,
+            0x41 => Comma,
+            0x42 => K,
+            0x43 => I,
+            0x44 => O,
+            0x45 => Key0,
+            0x46 => Key9,
+            0x49 => Period,
+            0x4A => Slash,
+            0x4B => L,
+            0x4C => Semicolon,
+            0x4D => P,
+            0x4E => Minus,
+            0x52 => Apostrophe,
+            0x54 => LeftBracket,
+            0x55 => Equals,
+            0x58 => CapsLock,
+            0x59 => RightShift,
+            0x5A => Enter,
+            0x5B => RightBracket,
+            0x5D => Backslash,
+            0x61 => NonUsBackslash,
+            0x66 => Backspace,
+            0x69 => Keypad1,
+            0x6B => Keypad4,
+            0x6C => Keypad7,
+            0x70 => Keypad0,
+            0x71 => KeypadPeriod,
+            0x72 => Keypad2,
+            0x73 => Keypad5,
+            0x74 => Keypad6,
+            0x75 => Keypad8,
+            0x76 => Escape,
+            0x77 => NumLock,
+            0x78 => F11,
+            0x79 => KeypadPlus,
+            0x7A => Keypad3,
+            0x7B => KeypadMinus,
+            0x7C => KeypadAsterisk,
+            0x7D => Keypad9,
+            0x7E => ScrollLock,
+            0x83 => F7,
+            _ => return None,
+        }
+    })
+}
+
+struct State {
+    port: Port,
+    device: DeviceId,
+    decoder: Decoder,
+    keyboard: Keyboard,
+    /// LED bits waiting for the keyboard to acknowledge the set LEDs command.
+    pending_leds: Option<u8>,
+}
+
+/// Only touched from the IRQ handler once the keyboard is running.
+static STATE: SpinLock<Option<State>> = SpinLock::named("ps2 keyboard", None);
+
+/// Returns the scancode set the keyboard is decoded with, if one is running.
+pub fn scancode_set() -> Option<ScancodeSet> {
+    STATE.lock().as_ref().map(|state| state.decoder.set)
+}
+
+/// Handles a byte that arrived from `port`, if it is the keyboard's.
+pub(super) fn receive(port: Port, byte: u8) {
+    let mut state = STATE.lock();
+    let Some(state) = state.as_mut().filter(|state| state.port == port) else {
+        return;
+    };
+
+    match byte {
+        ACK => {
+            if let Some(leds) = state.pending_leds.take() {
+                let _ = super::write(state.port, leds);
+            }
+            return;
+        }
+        RESEND | ECHO | KEY_ERROR | KEY_OVERRUN => return,
+        // The keyboard was unplugged and plugged back in.
+        SET2_SELF_TEST_PASSED if state.decoder.set == ScancodeSet::Two => return,
+        _ => {}
+    }
+
+    let Some((key, pressed)) = state.decoder.feed(byte) else {
+        return;
+    };
+    let (event, leds_changed) = state.keyboard.process(key, pressed);
+    input::report(state.device, event.into());
+    if key == KeyCode::Pause {
+        // Pause never sends a release, so make one up.
+        let (release, _) = state.keyboard.process(key, false);
+        input::report(state.device, release.into());
+    }
+    input::report(state.device, EventKind::Sync);
+    if leds_changed && super::write(state.port, SET_LEDS).is_ok() {
+        state.pending_leds = Some(state.keyboard.leds().bits());
+    }
+}
+
+/// Picks the scancode set to decode, preferring set 2 without translation.
+fn select_scancode_set(port: Port) -> Result<ScancodeSet, Ps2Error> {
+    if super::send(port, SCANCODE_SET)
+        .and_then(|_| super::send(port, 2))
+        .is_ok()
+    {
+        return Ok(ScancodeSet::Two);
+    }
+    // Translation only exists on the first port; a keyboard on the second one has to be
+    // running set 2 already.
+    if port == Port::First {
+        super::set_translation(true)?;
+        return Ok(ScancodeSet::One);
+    }
+    Ok(ScancodeSet::Two)
+}
+
+/// Sets up the keyboard on `port` and starts taking its interrupts.
+pub(super) fn initialize(port: Port) -> Result<(), Ps2Error> {
+    let set = select_scancode_set(port)?;
+    super::send(port, SET_LEDS)?;
+    super::send(port, 0)?;
+    super::enable_scanning(port)?;
+
+    *STATE.lock() = Some(State {
+        port,
+        device: input::register_device("ps2-keyboard", Capabilities::KEYS),
+        decoder: Decoder::new(set),
+        keyboard: Keyboard::new(),
+        pending_leds: None,
+    });
+    super::attach(port)?;
+    info!(
+        "ps2: keyboard on {:?} port using scancode set {:?}",
+        port, set
+    );
+    Ok(())
+}
@@ -0,0 +1,433 @@
+//! The 8042 PS/2 controller and the devices attached to its two ports.
+//!
+//! [`initialize`] runs the usual bring-up sequence: both ports are disabled, the controller
+//! tests itself and each port, and every port that passes has its device reset and
+//! identified. Devices report through IRQ 1 for the first port and IRQ 12 for the second,
+//! which is where the mouse is expected.
+
+use crate::{
+    info,
+    intrinsics::{inb, outb},
+    sync::SpinLock,
+    warn,
+};
+
+pub mod keyboard;
+pub mod mouse;
+
+const DATA: u16 = 0x60;
+/// Reads give the status register, writes send a controller command.
+const STATUS_COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// Set along with `STATUS_OUTPUT_FULL` when the byte came from the second port.
+const STATUS_SECOND_OUTPUT: u8 = 1 << 5;
+
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND: u8 = 0xA7;
+const ENABLE_SECOND: u8 = 0xA8;
+const TEST_SECOND: u8 = 0xA9;
+const SELF_TEST: u8 = 0xAA;
+const TEST_FIRST: u8 = 0xAB;
+const DISABLE_FIRST: u8 = 0xAD;
+const ENABLE_FIRST: u8 = 0xAE;
+const WRITE_SECOND: u8 = 0xD4;
+/// Pulses the CPU reset line, which is wired through the controller on PCs.
+const PULSE_RESET: u8 = 0xFE;
+
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_FIRST_CLOCK_OFF: u8 = 1 << 4;
+const CONFIG_SECOND_CLOCK_OFF: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+
+const DEVICE_RESET: u8 = 0xFF;
+const DEVICE_IDENTIFY: u8 = 0xF2;
+const DEVICE_ENABLE_SCANNING: u8 = 0xF4;
+const DEVICE_DISABLE_SCANNING: u8 = 0xF5;
+const DEVICE_RESET_PASSED: u8 = 0xAA;
+
+pub const ACK: u8 = 0xFA;
+pub const RESEND: u8 = 0xFE;
+
+/// How many times the status register is polled before giving up, roughly 100ms.
+const TIMEOUT: usize = 100_000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller or device did not respond in time.
+    Timeout,
+    /// The controller failed its self test.
+    SelfTest(u8),
+    /// The device answered a command with something other than an acknowledgement.
+    Unexpected(u8),
+    /// The port is missing or failed initialization.
+    NoDevice,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Port {
+    First,
+    Second,
+}
+
+impl Port {
+    /// The IRQ line the port raises when its device sends a byte.
+    pub const fn irq(&self) -> u8 {
+        match self {
+            Port::First => 1,
+            Port::Second => 12,
+        }
+    }
+
+    fn from_status(status: u8) -> Self {
+        if status & STATUS_SECOND_OUTPUT != 0 {
+            Port::Second
+        } else {
+            Port::First
+        }
+    }
+}
+
+/// What a device reported when identified.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// An AT keyboard, which sends no identification bytes.
+    AtKeyboard,
+    /// A standard mouse, or one with a scroll wheel or five buttons.
+    Mouse(u8),
+    /// An MF2 keyboard, with the second identification byte.
+    Keyboard(u8),
+    Unknown(u8, u8),
+}
+
+impl DeviceKind {
+    fn from_id(id: &[u8]) -> Self {
+        match *id {
+            [] => DeviceKind::AtKeyboard,
+            [id @ (0x00 | 0x03 | 0x04)] => DeviceKind::Mouse(id),
+            [0xAB, second] => DeviceKind::Keyboard(second),
+            [first] => DeviceKind::Unknown(first, 0),
+            [first, second, ..] => DeviceKind::Unknown(first, second),
+        }
+    }
+
+    pub const fn is_keyboard(&self) -> bool {
+        matches!(self, DeviceKind::AtKeyboard | DeviceKind::Keyboard(_))
+    }
+
+    pub const fn is_mouse(&self) -> bool {
+        matches!(self, DeviceKind::Mouse(_))
+    }
+}
+
+/// What was found on each port during initialization.
+static DEVICES: SpinLock<[Option<DeviceKind>; 2]> = SpinLock::new([None; 2]);
+
+/// The controller configuration byte as left by [`initialize`].
+static CONFIG: SpinLock<u8> = SpinLock::named("ps2 config", 0);
+
+fn status() -> u8 {
+    // SAFETY: Reading the status register has no side effects.
+    unsafe { inb(STATUS_COMMAND) }
+}
+
+fn wait_input_empty() -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Waits for a byte from the controller or a device and returns it.
+pub fn read() -> Result<u8, Ps2Error> {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            // SAFETY: The output buffer is full, so this reads the waiting byte.
+            return Ok(unsafe { inb(DATA) });
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Waits for a byte from the device on `port`, throwing away anything the other port sends
+/// in the meantime.
+pub fn read_from(port: Port) -> Result<u8, Ps2Error> {
+    for _ in 0..TIMEOUT {
+        let status = status();
+        if status & STATUS_OUTPUT_FULL != 0 {
+            // SAFETY: See `read`.
+            let byte = unsafe { inb(DATA) };
+            if Port::from_status(status) == port {
+                return Ok(byte);
+            }
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Returns the byte waiting in the output buffer along with the port it came from, if any.
+/// Used by interrupt handlers.
+pub fn read_data() -> Option<(Port, u8)> {
+    let status = status();
+    if status & STATUS_OUTPUT_FULL != 0 {
+        // SAFETY: See `read`.
+        Some((Port::from_status(status), unsafe { inb(DATA) }))
+    } else {
+        None
+    }
+}
+
+/// Throws away anything left in the output buffer.
+fn flush() {
+    for _ in 0..16 {
+        if read_data().is_none() {
+            break;
+        }
+    }
+}
+
+fn command(command: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    // SAFETY: Controller commands only affect the 8042 and its ports.
+    unsafe { outb(STATUS_COMMAND, command) };
+    Ok(())
+}
+
+fn command_with_data(cmd: u8, data: u8) -> Result<(), Ps2Error> {
+    command(cmd)?;
+    wait_input_empty()?;
+    // SAFETY: See `command`.
+    unsafe { outb(DATA, data) };
+    Ok(())
+}
+
+/// Resets the machine through the controller. Returns if the controller did not take the
+/// command or the reset line is not connected.
+pub fn pulse_reset() {
+    if command(PULSE_RESET).is_ok() {
+        // The reset takes a moment to come through.
+        for _ in 0..TIMEOUT {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Sends a byte to the device on `port` without waiting for its response.
+pub fn write(port: Port, byte: u8) -> Result<(), Ps2Error> {
+    if port == Port::Second {
+        command(WRITE_SECOND)?;
+    }
+    wait_input_empty()?;
+    // SAFETY: The byte goes to the device on `port`.
+    unsafe { outb(DATA, byte) };
+    Ok(())
+}
+
+/// Sends a command byte to the device on `port`, retrying on a resend request, and waits for
+/// the acknowledgement.
+///
+/// Only usable while the port's IRQ is not being handled, since the handler would otherwise
+/// swallow the response.
+pub fn send(port: Port, byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..3 {
+        write(port, byte)?;
+        match read_from(port)? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            other => return Err(Ps2Error::Unexpected(other)),
+        }
+    }
+    Err(Ps2Error::Unexpected(RESEND))
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    command(READ_CONFIG)?;
+    read()
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    command_with_data(WRITE_CONFIG, config)
+}
+
+/// Resets the device on `port` and works out what it is.
+fn identify(port: Port) -> Result<DeviceKind, Ps2Error> {
+    send(port, DEVICE_RESET)?;
+    match read_from(port)? {
+        DEVICE_RESET_PASSED => {}
+        other => return Err(Ps2Error::Unexpected(other)),
+    }
+    // Mice follow the reset result with their ID.
+    let _ = read_from(port);
+
+    send(port, DEVICE_DISABLE_SCANNING)?;
+    send(port, DEVICE_IDENTIFY)?;
+    let mut id = [0; 2];
+    let mut len = 0;
+    while len < id.len() {
+        match read_from(port) {
+            Ok(byte) => {
+                id[len] = byte;
+                len += 1;
+            }
+            Err(Ps2Error::Timeout) => break,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(DeviceKind::from_id(&id[..len]))
+}
+
+/// Returns the device found on `port`, if it passed initialization.
+pub fn device(port: Port) -> Option<DeviceKind> {
+    DEVICES.lock()[port as usize]
+}
+
+/// Turns scancode translation to set 1 on or off for the first port.
+pub fn set_translation(enabled: bool) -> Result<(), Ps2Error> {
+    let mut config = CONFIG.lock();
+    let new = if enabled {
+        *config | CONFIG_TRANSLATION
+    } else {
+        *config & !CONFIG_TRANSLATION
+    };
+    write_config(new)?;
+    *config = new;
+    Ok(())
+}
+
+/// Returns whether the controller translates scancodes of the first port to set 1.
+pub fn translation() -> bool {
+    *CONFIG.lock() & CONFIG_TRANSLATION != 0
+}
+
+/// Lets the device on `port` start sending data.
+pub fn enable_scanning(port: Port) -> Result<(), Ps2Error> {
+    send(port, DEVICE_ENABLE_SCANNING)
+}
+
+fn try_initialize() -> Result<(), Ps2Error> {
+    command(DISABLE_FIRST)?;
+    command(DISABLE_SECOND)?;
+    flush();
+
+    let mut config = read_config()?;
+    config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION);
+    write_config(config)?;
+
+    command(SELF_TEST)?;
+    match read()? {
+        SELF_TEST_PASSED => {}
+        result => return Err(Ps2Error::SelfTest(result)),
+    }
+    // Some controllers reset themselves during the self test.
+    write_config(config)?;
+
+    // The second clock only turns on when the controller has a second port.
+    let mut dual = false;
+    if config & CONFIG_SECOND_CLOCK_OFF != 0 {
+        command(ENABLE_SECOND)?;
+        dual = read_config()? & CONFIG_SECOND_CLOCK_OFF == 0;
+        command(DISABLE_SECOND)?;
+    }
+
+    let mut ports = [true, dual];
+    for (port, test) in [(Port::First, TEST_FIRST), (Port::Second, TEST_SECOND)] {
+        if !ports[port as usize] {
+            continue;
+        }
+        command(test)?;
+        match read()? {
+            0 => {}
+            result => {
+                warn!("ps2: {:?} port failed its test: {:#04x}", port, result);
+                ports[port as usize] = false;
+            }
+        }
+    }
+
+    if ports[0] {
+        command(ENABLE_FIRST)?;
+        config &= !CONFIG_FIRST_CLOCK_OFF;
+    }
+    if ports[1] {
+        command(ENABLE_SECOND)?;
+        config &= !CONFIG_SECOND_CLOCK_OFF;
+    }
+    write_config(config)?;
+    *CONFIG.lock() = config;
+
+    for port in [Port::First, Port::Second] {
+        if !ports[port as usize] {
+            continue;
+        }
+        match identify(port) {
+            Ok(kind) => {
+                info!("ps2: {:?} port: {:?}", port, kind);
+                DEVICES.lock()[port as usize] = Some(kind);
+            }
+            Err(Ps2Error::Timeout) => info!("ps2: {:?} port: no device", port),
+            Err(error) => warn!("ps2: {:?} port: {:?}", port, error),
+        }
+    }
+    Ok(())
+}
+
+/// Hands a byte that arrived through either IRQ to the driver of the port that sent it.
+///
+/// Both IRQs read from the same data register, so whichever handler runs first may pick up a
+/// byte meant for the other port.
+fn handle_irq() {
+    if let Some((port, byte)) = read_data() {
+        keyboard::receive(port, byte);
+        mouse::receive(port, byte);
+    }
+}
+
+/// Starts taking the IRQ of `port`, once its driver is ready for the bytes.
+fn attach(port: Port) -> Result<(), Ps2Error> {
+    crate::interrupts::register_irq(port.irq(), handle_irq).map_err(|_| Ps2Error::NoDevice)?;
+    enable_irq(port)
+}
+
+/// Enables the IRQ of `port` in the controller. The device has to be set up first.
+fn enable_irq(port: Port) -> Result<(), Ps2Error> {
+    let mut config = CONFIG.lock();
+    let new = *config
+        | match port {
+            Port::First => CONFIG_FIRST_IRQ,
+            Port::Second => CONFIG_SECOND_IRQ,
+        };
+    write_config(new)?;
+    *config = new;
+    Ok(())
+}
+
+/// Brings up the controller and the drivers of whatever is attached to it.
+pub fn initialize() {
+    if let Err(error) = try_initialize() {
+        warn!("ps2: controller unavailable: {:?}", error);
+        return;
+    }
+    if let Some(port) = [Port::First, Port::Second]
+        .into_iter()
+        .find(|&port| device(port).is_some_and(|kind| kind.is_keyboard()))
+    {
+        if let Err(error) = keyboard::initialize(port) {
+            warn!("ps2: keyboard initialization failed: {:?}", error);
+        }
+    }
+    if device(Port::Second).is_some_and(|kind| kind.is_mouse()) {
+        if let Err(error) = mouse::initialize(Port::Second) {
+            warn!("ps2: mouse initialization failed: {:?}", error);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! The driver for a mouse on the second PS/2 port.
+//!
+//! Every movement or button change arrives as a packet of three bytes, or four once the mouse
+//! has been switched into one of the IntelliMouse modes that report a scroll wheel and, on
+//! five-button mice, the two side buttons. Decoded packets are reported to the
+//! [`input`](crate::input) layer.
+
+use super::{DeviceKind, Port, Ps2Error};
+use crate::{
+    drivers::mouse::{Buttons, MouseButton},
+    info,
+    input::{self, Capabilities, DeviceId, EventKind, RelativeAxis},
+    sync::SpinLock,
+};
+
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const SET_DEFAULTS: u8 = 0xF6;
+const IDENTIFY: u8 = 0xF2;
+
+/// The ID of a mouse with a scroll wheel.
+const ID_WHEEL: u8 = 0x03;
+/// The ID of a mouse with a scroll wheel and five buttons.
+const ID_FIVE_BUTTONS: u8 = 0x04;
+
+const LEFT: u8 = 1 << 0;
+const RIGHT: u8 = 1 << 1;
+const MIDDLE: u8 = 1 << 2;
+/// Always set in the first byte of a packet, which lets the driver find packet boundaries.
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+const SIDE: u8 = 1 << 4;
+const EXTRA: u8 = 1 << 5;
+
+struct State {
+    port: Port,
+    device: DeviceId,
+    /// The ID the mouse ended up with, which decides the packet format.
+    id: u8,
+    packet: [u8; 4],
+    received: usize,
+    buttons: Buttons,
+}
+
+impl State {
+    fn packet_len(&self) -> usize {
+        if self.id == ID_WHEEL || self.id == ID_FIVE_BUTTONS {
+            4
+        } else {
+            3
+        }
+    }
+
+    /// Reports the events of a complete packet.
+    fn decode(&mut self) {
+        let [flags, x, y, extra] = self.packet;
+        if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+            return;
+        }
+
+        let mut buttons = Buttons::default();
+        buttons.set(MouseButton::Left, flags & LEFT != 0);
+        buttons.set(MouseButton::Right, flags & RIGHT != 0);
+        buttons.set(MouseButton::Middle, flags & MIDDLE != 0);
+        let wheel = match self.id {
+            ID_WHEEL => extra as i8,
+            ID_FIVE_BUTTONS => {
+                buttons.set(MouseButton::Side, extra & SIDE != 0);
+                buttons.set(MouseButton::Extra, extra & EXTRA != 0);
+                // The wheel movement is a 4-bit two's complement number.
+                ((extra << 4) as i8) >> 4
+            }
+            _ => 0,
+        };
+
+        // The movement is a 9-bit two's complement number with the sign kept in the flags.
+        let dx = x as i16 - if flags & X_SIGN != 0 { 0x100 } else { 0 };
+        let dy = y as i16 - if flags & Y_SIGN != 0 { 0x100 } else { 0 };
+        // The mouse counts upwards movement as positive, unlike the screen.
+        for (axis, value) in [
+            (RelativeAxis::X, dx as i32),
+            (RelativeAxis::Y, -dy as i32),
+            (RelativeAxis::Wheel, wheel as i32),
+        ] {
+            if value != 0 {
+                input::report(self.device, EventKind::Relative { axis, value });
+            }
+        }
+        for button in MouseButton::ALL {
+            let pressed = buttons.contains(button);
+            if pressed != self.buttons.contains(button) {
+                input::report(self.device, EventKind::Button { button, pressed });
+            }
+        }
+        self.buttons = buttons;
+        input::report(self.device, EventKind::Sync);
+    }
+}
+
+/// Only touched from the IRQ handler once the mouse is running.
+static STATE: SpinLock<Option<State>> = SpinLock::named("ps2 mouse", None);
+
+/// Returns the buttons currently held down.
+pub fn buttons() -> Buttons {
+    STATE
+        .lock()
+        .as_ref()
+        .map_or(Buttons::default(), |state| state.buttons)
+}
+
+/// Handles a byte that arrived from `port`, if it is the mouse's.
+pub(super) fn receive(port: Port, byte: u8) {
+    let mut state = STATE.lock();
+    let Some(state) = state.as_mut().filter(|state| state.port == port) else {
+        return;
+    };
+    // Resynchronise by dropping bytes until one looks like the start of a packet.
+    if state.received == 0 && byte & ALWAYS_ONE == 0 {
+        return;
+    }
+    state.packet[state.received] = byte;
+    state.received += 1;
+    if state.received == state.packet_len() {
+        state.received = 0;
+        state.decode();
+    }
+}
+
+fn set_sample_rate(port: Port, rate: u8) -> Result<(), Ps2Error> {
+    super::send(port, SET_SAMPLE_RATE)?;
+    super::send(port, rate)
+}
+
+fn identify(port: Port) -> Result<u8, Ps2Error> {
+    super::send(port, IDENTIFY)?;
+    super::read_from(port)
+}
+
+/// Unlocks the scroll wheel and then the extra buttons with the IntelliMouse sample rate
+/// sequences, returning the ID the mouse ends up with.
+fn enable_extensions(port: Port) -> Result<u8, Ps2Error> {
+    for rates in [[200, 100, 80], [200, 200, 80]] {
+        for rate in rates {
+            set_sample_rate(port, rate)?;
+        }
+        let id = identify(port)?;
+        if id != ID_WHEEL {
+            return Ok(id);
+        }
+    }
+    Ok(ID_WHEEL)
+}
+
+/// Sets up the mouse on `port` and starts taking its interrupts.
+pub(super) fn initialize(port: Port) -> Result<(), Ps2Error> {
+    if !super::device(port).is_some_and(|kind| kind.is_mouse()) {
+        return Err(Ps2Error::NoDevice);
+    }
+    super::send(port, SET_DEFAULTS)?;
+    let id = enable_extensions(port)?;
+    set_sample_rate(port, 100)?;
+    super::enable_scanning(port)?;
+
+    *STATE.lock() = Some(State {
+        port,
+        device: input::register_device("ps2-mouse", Capabilities::BUTTONS | Capabilities::RELATIVE),
+        id,
+        packet: [0; 4],
+        received: 0,
+        buttons: Buttons::default(),
+    });
+    super::DEVICES.lock()[port as usize] = Some(DeviceKind::Mouse(id));
+    super::attach(port)?;
+    info!("ps2: mouse on {:?} port with ID {:#04x}", port, id);
+    Ok(())
+}
@@ -0,0 +1,281 @@
+//! Virtio block devices, like the disks QEMU gives a guest for `-drive if=virtio`.
+//!
+//! Each request is a chain of three buffers in the device's only queue: a header saying what
+//! to do and where, the data, and a byte the device writes its verdict into. The device
+//! always counts in sectors of 512 bytes, whatever block size it prefers.
+
+use alloc::{format, string::String, sync::Arc};
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use super::{Buffer, Device, Virtqueue, VENDOR_ID};
+use crate::{
+    drivers::{
+        block::{self, BlockDevice, BlockError},
+        pci::{self, Match},
+        pit,
+    },
+    interrupts, pci_driver,
+    sync::SpinLock,
+    warn,
+};
+
+const DEVICE_ID_LEGACY: u16 = 0x1001;
+const DEVICE_ID_MODERN: u16 = 0x1042;
+
+const F_RO: u64 = 1 << 5;
+const F_BLK_SIZE: u64 = 1 << 6;
+const F_FLUSH: u64 = 1 << 9;
+
+// Fields of the device configuration.
+const CONFIG_CAPACITY: u16 = 0;
+const CONFIG_BLK_SIZE: u16 = 20;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_GET_ID: u32 = 8;
+
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+const VIRTIO_SECTOR_SIZE: usize = 512;
+/// How long a request may take while interrupts wake the driver, in ticks.
+const REQUEST_TIMEOUT: u32 = 5 * pit::HZ;
+/// How many times the queue is polled for a request while interrupts are off.
+const POLL_TIMEOUT: usize = 5_000_000;
+/// The length of the serial number `T_GET_ID` returns, padded with zeros if it is shorter.
+const ID_LEN: usize = 20;
+
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+pub struct Disk {
+    name: String,
+    device: Device,
+    queue: SpinLock<Virtqueue>,
+    use_irq: bool,
+    /// Set once a request timed out and the device was reset, which takes its queue away.
+    broken: AtomicBool,
+    sector_size: usize,
+    sector_count: u64,
+    description: String,
+}
+
+impl Disk {
+    /// Sends a request with an optional data buffer and waits for the device to finish it.
+    fn request(&self, kind: u32, sector: u64, data: Option<Buffer>) -> Result<(), BlockError> {
+        if self.broken.load(Ordering::Relaxed) {
+            return Err(BlockError::Timeout);
+        }
+        let header = RequestHeader {
+            kind,
+            reserved: 0,
+            sector,
+        };
+        let mut status = u8::MAX;
+        let header_buffer = Buffer::ToDevice(
+            &header as *const RequestHeader as usize,
+            mem::size_of::<RequestHeader>(),
+        );
+        let status_buffer = Buffer::FromDevice(&mut status as *mut u8 as usize, 1);
+        let mut buffers = [header_buffer, status_buffer, status_buffer];
+        let buffers = match data {
+            Some(data) => {
+                buffers[1] = data;
+                &buffers[..]
+            }
+            None => &buffers[..2],
+        };
+
+        let head = loop {
+            let mut queue = self.queue.lock();
+            if let Some(head) = queue.add(buffers) {
+                if queue.needs_notification() {
+                    self.device.notify(&queue);
+                }
+                break head;
+            }
+            // The queue is full of other requests, which finish or time out eventually.
+            drop(queue);
+            if self.broken.load(Ordering::Relaxed) {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        };
+
+        let sleep = self.use_irq && interrupts::are_enabled();
+        let start = pit::ticks();
+        let mut polls = 0;
+        loop {
+            if sleep {
+                interrupts::disable();
+            }
+            let finished = self.queue.lock().take(head).is_some();
+            if sleep {
+                if finished {
+                    interrupts::enable();
+                } else {
+                    interrupts::enable_and_wait();
+                }
+            }
+            if finished {
+                break;
+            }
+            let timed_out = if sleep {
+                pit::ticks().wrapping_sub(start) > REQUEST_TIMEOUT
+            } else {
+                polls += 1;
+                polls > POLL_TIMEOUT
+            };
+            if timed_out {
+                // The buffers are about to go away, so the device must stop using them.
+                warn!("virtio: {}: request timed out, giving up on it", self.name);
+                self.broken.store(true, Ordering::Relaxed);
+                if !self.device.reset() {
+                    self.device.fail();
+                }
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+
+        // SAFETY: The device is done with the status byte.
+        match unsafe { ptr::read_volatile(&status) } {
+            S_OK => Ok(()),
+            S_UNSUPP => Err(BlockError::Failed("request not supported")),
+            _ => Err(BlockError::Failed("I/O error")),
+        }
+    }
+
+    /// Converts a sector of this disk into the 512-byte sectors the device counts in.
+    fn device_sector(&self, sector: u64) -> u64 {
+        sector * (self.sector_size / VIRTIO_SECTOR_SIZE) as u64
+    }
+
+    /// Asks the device for its serial number.
+    fn serial(&self) -> Option<String> {
+        let mut id = [0u8; ID_LEN];
+        self.request(
+            T_GET_ID,
+            0,
+            Some(Buffer::FromDevice(id.as_mut_ptr() as usize, id.len())),
+        )
+        .ok()?;
+        let len = id.iter().position(|&byte| byte == 0).unwrap_or(id.len());
+        let serial = String::from_utf8_lossy(&id[..len]).trim().into();
+        Some(serial).filter(|serial: &String| !serial.is_empty())
+    }
+}
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.has_feature(F_RO)
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_transfer(self, sector, buffer.len())?;
+        let data = Buffer::FromDevice(buffer.as_mut_ptr() as usize, buffer.len());
+        self.request(T_IN, self.device_sector(sector), Some(data))
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_transfer(self, sector, buffer.len())?;
+        let data = Buffer::ToDevice(buffer.as_ptr() as usize, buffer.len());
+        self.request(T_OUT, self.device_sector(sector), Some(data))
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        // Without the flush feature, the device writes everything through.
+        if !self.device.has_feature(F_FLUSH) {
+            return Ok(());
+        }
+        self.request(T_FLUSH, 0, None)
+    }
+}
+
+fn probe(pci: &pci::Device) -> Result<(), ()> {
+    let device = Device::new(pci, F_RO | F_BLK_SIZE | F_FLUSH)
+        .map_err(|error| warn!("virtio-blk: {}: {}", pci.address, error))?;
+    let queue = match device.queue(0) {
+        Ok(queue) => queue,
+        Err(error) => {
+            warn!("virtio-blk: {}: {}", pci.address, error);
+            device.fail();
+            return Err(());
+        }
+    };
+    let use_irq = device.enable_interrupts(pci);
+    device.start();
+
+    let block_size = if device.has_feature(F_BLK_SIZE) {
+        device.config_u32(CONFIG_BLK_SIZE) as usize
+    } else {
+        VIRTIO_SECTOR_SIZE
+    };
+    // Block sizes that are not a whole number of the device's sectors are of no use.
+    let sector_size = if block_size >= VIRTIO_SECTOR_SIZE && block_size.is_power_of_two() {
+        block_size
+    } else {
+        VIRTIO_SECTOR_SIZE
+    };
+    let capacity = device.config_u64(CONFIG_CAPACITY);
+    let mut disk = Disk {
+        name: format!(
+            "vd{}",
+            (b'a' + DISKS.fetch_add(1, Ordering::Relaxed) as u8) as char
+        ),
+        device,
+        queue: SpinLock::new(queue),
+        use_irq,
+        broken: AtomicBool::new(false),
+        sector_size,
+        sector_count: capacity / (sector_size / VIRTIO_SECTOR_SIZE) as u64,
+        description: String::new(),
+    };
+    disk.description = match disk.serial() {
+        Some(serial) => format!("virtio disk {}", serial),
+        None => String::from("virtio disk"),
+    };
+    if block::register(Arc::new(disk)).is_err() {
+        warn!("virtio-blk: a block device with the same name already exists");
+    }
+    Ok(())
+}
+
+pci_driver!(
+    VIRTIO_BLK_DRIVER,
+    "virtio-blk",
+    &[
+        Match::id(VENDOR_ID, DEVICE_ID_LEGACY),
+        Match::id(VENDOR_ID, DEVICE_ID_MODERN)
+    ],
+    probe
+);
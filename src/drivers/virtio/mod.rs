@@ -0,0 +1,508 @@
+//! Virtio devices, which hypervisors offer in place of emulated hardware.
+//!
+//! Virtio devices sit on PCI with vendor ID 0x1AF4 and exchange requests with their driver
+//! through [`Virtqueue`]s in memory. How the driver reaches their registers depends on their
+//! age. Legacy devices keep them all in an I/O BAR, while modern ones describe where each
+//! group of them is in vendor-specific capabilities, which may point into any BAR.
+//! Transitional devices offer both and are driven the modern way whenever its registers are
+//! within reach.
+//!
+//! Bringing a device up is the same for every type: [`Device::new`] resets it and settles on
+//! the features both sides support, the driver sets up its queues with [`Device::queue`] and
+//! reads its configuration, and [`Device::start`] lets the device go.
+
+pub mod block;
+mod queue;
+
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+pub use queue::{Buffer, Virtqueue};
+
+use super::{
+    pci::{self, config, Bar},
+    pit,
+};
+use crate::{
+    info, interrupts,
+    intrinsics::{inb, inl, inw, outb, outl, outw},
+    warn,
+};
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+/// Offered by modern devices, which have to be driven the modern way once it is accepted.
+const F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+const STATUS_FAILED: u8 = 1 << 7;
+
+// Registers of the legacy interface, as offsets into its I/O BAR.
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+/// Where the device-specific configuration starts while MSI-X is off.
+const LEGACY_CONFIG: u16 = 0x14;
+/// The legacy interface takes the address of a queue as a page number.
+const LEGACY_QUEUE_SHIFT: u32 = 12;
+
+/// The ID of the vendor-specific capabilities modern devices describe their registers in.
+const CAPABILITY_VENDOR: u8 = 0x09;
+const CAPABILITY_COMMON: u8 = 1;
+const CAPABILITY_NOTIFY: u8 = 2;
+const CAPABILITY_ISR: u8 = 3;
+const CAPABILITY_DEVICE: u8 = 4;
+
+// Registers of the common configuration of modern devices.
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESCRIPTORS: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+/// The most entries a queue of a modern device is given, which can choose its queue sizes.
+const MAX_QUEUE_SIZE: u16 = 256;
+/// How long a device gets to reset, in ticks.
+const RESET_TIMEOUT: u32 = pit::HZ;
+/// How many times a reset is checked for when the timer is not running.
+const RESET_POLLS: usize = 1_000_000;
+/// How many times a configuration field is read again while the device keeps changing it.
+const CONFIG_RETRIES: usize = 16;
+
+/// The devices with an interrupt handler, whose ISR status it reads to acknowledge the
+/// interrupt. Legacy devices have theirs at an I/O port.
+const IRQ_SLOTS: usize = 4;
+static IRQ_ISRS: [AtomicUsize; IRQ_SLOTS] = [const { AtomicUsize::new(0) }; IRQ_SLOTS];
+static IRQ_LEGACY: [AtomicBool; IRQ_SLOTS] = [const { AtomicBool::new(false) }; IRQ_SLOTS];
+static IRQ_SLOTS_USED: AtomicUsize = AtomicUsize::new(0);
+
+fn handle_irq(slot: usize) {
+    let isr = IRQ_ISRS[slot].load(Ordering::Relaxed);
+    // SAFETY: Reading the ISR status acknowledges the interrupt.
+    unsafe {
+        if IRQ_LEGACY[slot].load(Ordering::Relaxed) {
+            inb(isr as u16);
+        } else {
+            ptr::read_volatile(isr as *const u8);
+        }
+    }
+}
+
+fn handle_irq_0() {
+    handle_irq(0);
+}
+
+fn handle_irq_1() {
+    handle_irq(1);
+}
+
+fn handle_irq_2() {
+    handle_irq(2);
+}
+
+fn handle_irq_3() {
+    handle_irq(3);
+}
+
+const IRQ_HANDLERS: [fn(); IRQ_SLOTS] = [handle_irq_0, handle_irq_1, handle_irq_2, handle_irq_3];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VirtioError {
+    /// Neither the legacy nor the modern registers can be reached.
+    NoRegisters,
+    /// The device would not take the features the driver settled on.
+    FeaturesRejected,
+    /// The device does not have the queue.
+    NoQueue(u16),
+    NoMemory,
+    /// The device never came back from being reset.
+    ResetTimeout,
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VirtioError::NoRegisters => f.write_str("registers out of reach"),
+            VirtioError::FeaturesRejected => f.write_str("features rejected"),
+            VirtioError::NoQueue(index) => write!(f, "no queue {}", index),
+            VirtioError::NoMemory => f.write_str("out of memory"),
+            VirtioError::ResetTimeout => f.write_str("does not reset"),
+        }
+    }
+}
+
+/// Where a device's registers are.
+#[derive(Copy, Clone, Debug)]
+enum Transport {
+    Legacy {
+        port: u16,
+    },
+    /// The physical addresses of the parts of a modern device's registers.
+    Modern {
+        common: usize,
+        notify: usize,
+        /// How far apart the notification registers of the queues are, in units of their
+        /// `queue_notify_off`.
+        notify_multiplier: usize,
+        isr: usize,
+        device: usize,
+    },
+}
+
+impl Transport {
+    /// Finds the registers of a modern device in its capabilities. Those behind a BAR above
+    /// 4 GiB are out of reach.
+    fn modern(pci: &pci::Device) -> Option<Transport> {
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        for capability in &pci.capabilities {
+            if capability.id != CAPABILITY_VENDOR {
+                continue;
+            }
+            let offset = u16::from(capability.offset);
+            let kind = config::read_u8(pci.address, offset + 3);
+            let bar = usize::from(config::read_u8(pci.address, offset + 4));
+            let Some(Some(Bar::Memory { address, .. })) = pci.bars.get(bar) else {
+                continue;
+            };
+            let Ok(address) = usize::try_from(address + u64::from(pci.read_config(offset + 8)))
+            else {
+                continue;
+            };
+            // The first capability of each kind is the one to use.
+            let slot = match kind {
+                CAPABILITY_COMMON => &mut common,
+                CAPABILITY_NOTIFY => {
+                    if notify.is_none() {
+                        notify_multiplier = pci.read_config(offset + 16) as usize;
+                    }
+                    &mut notify
+                }
+                CAPABILITY_ISR => &mut isr,
+                CAPABILITY_DEVICE => &mut device,
+                _ => continue,
+            };
+            slot.get_or_insert(address);
+        }
+        Some(Transport::Modern {
+            common: common?,
+            notify: notify?,
+            notify_multiplier,
+            isr: isr?,
+            // Devices without configuration have nothing to point at.
+            device: device.unwrap_or(0),
+        })
+    }
+
+    fn legacy(pci: &pci::Device) -> Option<Transport> {
+        match pci.bars[0] {
+            Some(Bar::Io { port, .. }) => Some(Transport::Legacy { port }),
+            _ => None,
+        }
+    }
+
+    fn read_common<T>(common: usize, offset: usize) -> T {
+        // SAFETY: The capability says the common configuration is there.
+        unsafe { ptr::read_volatile((common + offset) as *const T) }
+    }
+
+    fn write_common<T>(common: usize, offset: usize, value: T) {
+        // SAFETY: See `read_common`.
+        unsafe { ptr::write_volatile((common + offset) as *mut T, value) }
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            // SAFETY: The BAR belongs to the device.
+            Transport::Legacy { port } => unsafe { inb(port + LEGACY_STATUS) },
+            Transport::Modern { common, .. } => Self::read_common(common, COMMON_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            // SAFETY: See `status`.
+            Transport::Legacy { port } => unsafe { outb(port + LEGACY_STATUS, status) },
+            Transport::Modern { common, .. } => Self::write_common(common, COMMON_STATUS, status),
+        }
+    }
+
+    /// Writes 0 to the status and waits for the device to read it back as 0 once the reset is
+    /// done, for a second or, while the timer is not running, a bounded number of polls.
+    fn reset(&self) -> bool {
+        self.set_status(0);
+        let start = pit::ticks();
+        for _ in 0..RESET_POLLS {
+            if self.status() == 0 {
+                return true;
+            }
+            if pit::ticks().wrapping_sub(start) > RESET_TIMEOUT {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        self.status() == 0
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            // SAFETY: See `status`.
+            Transport::Legacy { port } => u64::from(unsafe { inl(port + LEGACY_DEVICE_FEATURES) }),
+            Transport::Modern { common, .. } => (0..2).fold(0, |features, half| {
+                Self::write_common(common, COMMON_DEVICE_FEATURE_SELECT, half as u32);
+                let bits: u32 = Self::read_common(common, COMMON_DEVICE_FEATURE);
+                features | u64::from(bits) << (32 * half)
+            }),
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match *self {
+            // SAFETY: See `status`.
+            Transport::Legacy { port } => unsafe {
+                outl(port + LEGACY_DRIVER_FEATURES, features as u32)
+            },
+            Transport::Modern { common, .. } => {
+                for half in 0..2 {
+                    Self::write_common(common, COMMON_DRIVER_FEATURE_SELECT, half as u32);
+                    let bits = (features >> (32 * half)) as u32;
+                    Self::write_common(common, COMMON_DRIVER_FEATURE, bits);
+                }
+            }
+        }
+    }
+}
+
+/// A virtio device that has been reset and agreed on its features with the driver.
+pub struct Device {
+    address: pci::Address,
+    transport: Transport,
+    features: u64,
+}
+
+impl Device {
+    /// Resets the device behind `pci` and accepts those of its features that are also in
+    /// `supported`.
+    pub fn new(pci: &pci::Device, supported: u64) -> Result<Device, VirtioError> {
+        let transport = Transport::modern(pci)
+            .or_else(|| Transport::legacy(pci))
+            .ok_or(VirtioError::NoRegisters)?;
+        pci.enable();
+        if !transport.reset() {
+            return Err(VirtioError::ResetTimeout);
+        }
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let modern = matches!(transport, Transport::Modern { .. });
+        let offered = transport.device_features();
+        let features = offered & (supported | if modern { F_VERSION_1 } else { 0 });
+        transport.set_driver_features(features);
+        let device = Device {
+            address: pci.address,
+            transport,
+            features,
+        };
+        if modern {
+            if features & F_VERSION_1 == 0 {
+                device.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+            device.add_status(STATUS_FEATURES_OK);
+            if transport.status() & STATUS_FEATURES_OK == 0 {
+                device.fail();
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        info!(
+            "virtio: {} is {}, features {:#x}",
+            pci.address,
+            if modern { "modern" } else { "legacy" },
+            features
+        );
+        Ok(device)
+    }
+
+    fn add_status(&self, status: u8) {
+        self.transport.set_status(self.transport.status() | status);
+    }
+
+    /// Resets the device, which stops it from touching its queues. Returns `false` if it does
+    /// not finish in time.
+    pub fn reset(&self) -> bool {
+        self.transport.reset()
+    }
+
+    /// Tells the device the driver has given up on it.
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    /// Lets the device go, once its queues are set up.
+    pub fn start(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    /// Sets up queue `index` and hands it to the device.
+    pub fn queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        match self.transport {
+            Transport::Legacy { port } => {
+                // SAFETY: See `Transport::status`.
+                let size = unsafe {
+                    outw(port + LEGACY_QUEUE_SELECT, index);
+                    inw(port + LEGACY_QUEUE_SIZE)
+                };
+                if size == 0 {
+                    return Err(VirtioError::NoQueue(index));
+                }
+                let queue = Virtqueue::new(index, size).ok_or(VirtioError::NoMemory)?;
+                let (descriptors, _, _) = queue.addresses();
+                // SAFETY: See `Transport::status`.
+                unsafe {
+                    outl(
+                        port + LEGACY_QUEUE_ADDRESS,
+                        (descriptors >> LEGACY_QUEUE_SHIFT) as u32,
+                    )
+                };
+                Ok(queue)
+            }
+            Transport::Modern { common, .. } => {
+                Transport::write_common(common, COMMON_QUEUE_SELECT, index);
+                let size: u16 = Transport::read_common(common, COMMON_QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::NoQueue(index));
+                }
+                let size = size.min(MAX_QUEUE_SIZE);
+                let queue = Virtqueue::new(index, size).ok_or(VirtioError::NoMemory)?;
+                let (descriptors, driver, device) = queue.addresses();
+                Transport::write_common(common, COMMON_QUEUE_SIZE, size);
+                for (offset, address) in [
+                    (COMMON_QUEUE_DESCRIPTORS, descriptors),
+                    (COMMON_QUEUE_DRIVER, driver),
+                    (COMMON_QUEUE_DEVICE, device),
+                ] {
+                    Transport::write_common(common, offset, address as u32);
+                    Transport::write_common(common, offset + 4, 0u32);
+                }
+                Transport::write_common(common, COMMON_QUEUE_ENABLE, 1u16);
+                Ok(queue)
+            }
+        }
+    }
+
+    /// Tells the device there are new requests in `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        match self.transport {
+            // SAFETY: See `Transport::status`.
+            Transport::Legacy { port } => unsafe {
+                outw(port + LEGACY_QUEUE_NOTIFY, queue.index())
+            },
+            Transport::Modern {
+                common,
+                notify,
+                notify_multiplier,
+                ..
+            } => {
+                Transport::write_common(common, COMMON_QUEUE_SELECT, queue.index());
+                let offset: u16 = Transport::read_common(common, COMMON_QUEUE_NOTIFY_OFF);
+                let register = notify + usize::from(offset) * notify_multiplier;
+                // SAFETY: The capability says the notification registers are there.
+                unsafe { ptr::write_volatile(register as *mut u16, queue.index()) };
+            }
+        }
+    }
+
+    /// Reads the double word at `offset` in the device-specific configuration.
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        match self.transport {
+            // SAFETY: See `Transport::status`.
+            Transport::Legacy { port } => unsafe { inl(port + LEGACY_CONFIG + offset) },
+            Transport::Modern { device, .. } => {
+                // SAFETY: The capability says the configuration is there.
+                unsafe { ptr::read_volatile((device + usize::from(offset)) as *const u32) }
+            }
+        }
+    }
+
+    /// Reads the quad word at `offset` in the device-specific configuration, in two halves
+    /// that are read again if the device changed it in between. A device that never stops
+    /// changing it gets the last value read.
+    pub fn config_u64(&self, offset: u16) -> u64 {
+        let mut value = 0;
+        for _ in 0..CONFIG_RETRIES {
+            let generation = self.generation();
+            value =
+                u64::from(self.config_u32(offset)) | u64::from(self.config_u32(offset + 4)) << 32;
+            if self.generation() == generation {
+                break;
+            }
+        }
+        value
+    }
+
+    /// Returns the configuration generation, which the device bumps when it changes the
+    /// configuration. Legacy devices do not have one.
+    fn generation(&self) -> u8 {
+        match self.transport {
+            Transport::Legacy { .. } => 0,
+            Transport::Modern { common, .. } => {
+                Transport::read_common(common, COMMON_CONFIG_GENERATION)
+            }
+        }
+    }
+
+    /// Installs a handler for the device's legacy interrupt. Returns `false` if it cannot be
+    /// used, in which case the driver has to poll its queues.
+    pub fn enable_interrupts(&self, pci: &pci::Device) -> bool {
+        let Some(irq) = pci.interrupt_line else {
+            return false;
+        };
+        // Devices are probed one at a time, so the slot is only claimed once the handler is
+        // installed, and a failure leaves it for the next device.
+        let slot = IRQ_SLOTS_USED.load(Ordering::Relaxed);
+        let usable = slot < IRQ_SLOTS && {
+            let (isr, legacy) = match self.transport {
+                Transport::Legacy { port } => (usize::from(port + LEGACY_ISR), true),
+                Transport::Modern { isr, .. } => (isr, false),
+            };
+            IRQ_ISRS[slot].store(isr, Ordering::Relaxed);
+            IRQ_LEGACY[slot].store(legacy, Ordering::Relaxed);
+            interrupts::register_irq(irq, IRQ_HANDLERS[slot]).is_ok()
+        };
+        if usable {
+            IRQ_SLOTS_USED.store(slot + 1, Ordering::Relaxed);
+            pci.set_interrupts(true);
+        } else {
+            // Nothing would acknowledge the device's interrupts, which would then hold a
+            // shared line asserted.
+            pci.set_interrupts(false);
+            warn!(
+                "virtio: {} cannot use IRQ {}, polling it",
+                self.address, irq
+            );
+        }
+        usable
+    }
+}
@@ -0,0 +1,224 @@
+//! Split virtqueues, through which a driver hands buffers to a device and gets them back.
+//!
+//! A virtqueue is three arrays in memory the device reads and writes on its own. The
+//! descriptor table lists buffers, chained into one request through their `next` fields. The
+//! driver offers a chain by putting its head into the available ring, and the device returns
+//! it through the used ring once it is done with it, along with how much it wrote.
+
+use alloc::{vec, vec::Vec};
+use core::{
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use crate::memory::{align_up, FRAME_ALLOCATOR, PAGE_SIZE};
+
+const DESCRIPTOR_SIZE: usize = 16;
+const USED_ELEMENT_SIZE: usize = 8;
+/// The legacy interface wants the used ring on the next page after the available ring.
+const USED_ALIGN: usize = PAGE_SIZE;
+
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+/// Set by the device in the used ring's flags when it does not need to be notified.
+const USED_NO_NOTIFY: u16 = 1 << 0;
+
+/// A buffer in a request, given by its physical address and length.
+#[derive(Copy, Clone, Debug)]
+pub enum Buffer {
+    /// A buffer the device reads from.
+    ToDevice(usize, usize),
+    /// A buffer the device writes to.
+    FromDevice(usize, usize),
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    /// The physical address of the descriptor table, which the rings follow.
+    memory: usize,
+    available: usize,
+    used: usize,
+    /// The first descriptor of the chain of free ones.
+    free: u16,
+    free_count: u16,
+    /// The index the next entry goes to in the available ring, wrapping around at 2^16.
+    next_available: u16,
+    /// The index of the first entry in the used ring that has not been looked at yet.
+    next_used: u16,
+    /// How many bytes the device wrote into each finished request that nobody has taken yet,
+    /// by the head of its chain.
+    finished: Vec<Option<u32>>,
+}
+
+// SAFETY: The queue's memory belongs to it alone and is only reached through `&mut self`.
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// How many frames a queue of `size` entries takes up.
+    fn frames(size: u16) -> usize {
+        let (_, _, end) = Self::layout(size);
+        end.div_ceil(PAGE_SIZE)
+    }
+
+    /// Returns where the available ring, the used ring and the end of the used ring are,
+    /// counted from the start of the descriptor table.
+    fn layout(size: u16) -> (usize, usize, usize) {
+        let size = usize::from(size);
+        let available = DESCRIPTOR_SIZE * size;
+        let used = align_up(available + 6 + 2 * size, USED_ALIGN);
+        (available, used, used + 6 + USED_ELEMENT_SIZE * size)
+    }
+
+    /// Allocates and clears the memory for queue `index` with `size` entries.
+    pub(super) fn new(index: u16, size: u16) -> Option<Virtqueue> {
+        let frames = Self::frames(size);
+        let memory = FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous(frames, PAGE_SIZE)?;
+        // SAFETY: The frames were just allocated for the queue.
+        unsafe { ptr::write_bytes(memory as *mut u8, 0, frames * PAGE_SIZE) };
+        let (available, used, _) = Self::layout(size);
+        let queue = Virtqueue {
+            index,
+            size,
+            memory,
+            available: memory + available,
+            used: memory + used,
+            free: 0,
+            free_count: size,
+            next_available: 0,
+            next_used: 0,
+            finished: vec![None; usize::from(size)],
+        };
+        // Chain every descriptor into the free list.
+        for descriptor in 0..size - 1 {
+            queue.write_descriptor(descriptor, 0, 0, DESCRIPTOR_NEXT, descriptor + 1);
+        }
+        Some(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Returns the physical addresses of the descriptor table, the available ring and the
+    /// used ring.
+    pub(super) fn addresses(&self) -> (usize, usize, usize) {
+        (self.memory, self.available, self.used)
+    }
+
+    fn descriptor(&self, descriptor: u16) -> *mut u8 {
+        (self.memory + usize::from(descriptor) * DESCRIPTOR_SIZE) as *mut u8
+    }
+
+    fn write_descriptor(&self, descriptor: u16, address: usize, len: u32, flags: u16, next: u16) {
+        let entry = self.descriptor(descriptor);
+        // SAFETY: The descriptor is in the queue's table and not in the device's hands.
+        unsafe {
+            (entry as *mut u64).write_volatile(address as u64);
+            (entry.add(8) as *mut u32).write_volatile(len);
+            (entry.add(12) as *mut u16).write_volatile(flags);
+            (entry.add(14) as *mut u16).write_volatile(next);
+        }
+    }
+
+    /// Reads the flags and `next` field of a descriptor.
+    fn read_link(&self, descriptor: u16) -> (u16, u16) {
+        let entry = self.descriptor(descriptor);
+        // SAFETY: See `write_descriptor`.
+        unsafe {
+            (
+                (entry.add(12) as *const u16).read_volatile(),
+                (entry.add(14) as *const u16).read_volatile(),
+            )
+        }
+    }
+
+    /// Offers a request made of `buffers` to the device, returning the head of its chain to
+    /// wait for it with. Fails if there are not enough free descriptors, in which case the
+    /// caller has to wait for some of its other requests to finish. The device has to be
+    /// notified afterwards.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || usize::from(self.free_count) < buffers.len() {
+            return None;
+        }
+        let head = self.free;
+        let mut descriptor = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let (address, len, flags) = match *buffer {
+                Buffer::ToDevice(address, len) => (address, len, 0),
+                Buffer::FromDevice(address, len) => (address, len, DESCRIPTOR_WRITE),
+            };
+            let (_, next) = self.read_link(descriptor);
+            let last = i + 1 == buffers.len();
+            let flags = if last { flags } else { flags | DESCRIPTOR_NEXT };
+            self.write_descriptor(descriptor, address, len as u32, flags, next);
+            if last {
+                self.free = next;
+            }
+            descriptor = next;
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = usize::from(self.next_available % self.size);
+        // SAFETY: The available ring is the queue's, and the device only reads the entries
+        // before its index.
+        unsafe {
+            ((self.available + 4 + 2 * slot) as *mut u16).write_volatile(head);
+            fence(Ordering::SeqCst);
+            self.next_available = self.next_available.wrapping_add(1);
+            ((self.available + 2) as *mut u16).write_volatile(self.next_available);
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Returns whether the device wants to hear about new requests.
+    pub fn needs_notification(&self) -> bool {
+        // SAFETY: The used ring's flags are written by the device and only read here.
+        unsafe { (self.used as *const u16).read_volatile() & USED_NO_NOTIFY == 0 }
+    }
+
+    /// Collects the requests the device has finished since the last call.
+    fn collect(&mut self) {
+        fence(Ordering::SeqCst);
+        // SAFETY: The used ring is written by the device and only read here.
+        let index = unsafe { ((self.used + 2) as *const u16).read_volatile() };
+        while self.next_used != index {
+            let slot = usize::from(self.next_used % self.size);
+            let element = self.used + 4 + USED_ELEMENT_SIZE * slot;
+            // SAFETY: See above.
+            let (head, len) = unsafe {
+                (
+                    (element as *const u32).read_volatile(),
+                    ((element + 4) as *const u32).read_volatile(),
+                )
+            };
+            if let Some(finished) = self.finished.get_mut(head as usize) {
+                *finished = Some(len);
+            }
+            self.next_used = self.next_used.wrapping_add(1);
+        }
+    }
+
+    /// Takes back the request whose chain starts at `head` if the device has finished it,
+    /// returning how many bytes the device wrote into it.
+    pub fn take(&mut self, head: u16) -> Option<u32> {
+        self.collect();
+        let len = self.finished[usize::from(head)].take()?;
+        // Put the chain back on the free list.
+        let mut descriptor = head;
+        loop {
+            self.free_count += 1;
+            let (flags, next) = self.read_link(descriptor);
+            if flags & DESCRIPTOR_NEXT == 0 {
+                self.write_descriptor(descriptor, 0, 0, DESCRIPTOR_NEXT, self.free);
+                break;
+            }
+            descriptor = next;
+        }
+        self.free = head;
+        Some(len)
+    }
+}
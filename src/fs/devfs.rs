@@ -0,0 +1,457 @@
+//! A filesystem of device nodes, mounted on `/dev`.
+//!
+//! Character devices are added with [`register`] as their drivers come up, and every block
+//! device and partition the block layer knows about shows up on its own, so the directory
+//! always lists what is there at the moment. Reads and writes go straight to the device:
+//! `/dev/tty0` is the screen and the keyboard, `/dev/hda1` is the bytes of a partition and
+//! `/dev/fb0` is the memory of the framebuffer.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    arch::{
+        asm,
+        x86::{__cpuid as cpuid, _rdtsc},
+    },
+};
+
+use super::{DirEntry, FileType, Filesystem, FsError, Inode, Metadata};
+use crate::{
+    drivers::{
+        block::{self, BlockDevice},
+        pit,
+    },
+    output::{self, serial, tty, tty::line_discipline},
+    sync::SpinLock,
+};
+
+const ROOT_INO: u64 = 1;
+/// Block devices are numbered from here, in the order the block layer lists them.
+const BLOCK_INO_BASE: u64 = 0x1_0000;
+
+/// A device that is read and written a byte at a time rather than in sectors.
+pub trait CharDevice: Send + Sync {
+    /// Reads from the device, returning how many bytes were read and 0 at its end. Devices
+    /// that are not addressable ignore `offset`.
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes to the device, returning how many bytes were taken.
+    fn write(&self, offset: u64, data: &[u8]) -> Result<usize, FsError>;
+
+    /// How many bytes the device holds, for the ones that are memory.
+    fn size(&self) -> u64 {
+        0
+    }
+}
+
+struct Registered {
+    name: String,
+    mode: u32,
+    device: Arc<dyn CharDevice>,
+}
+
+/// The character devices, by the order they were registered in, which gives their inodes.
+static DEVICES: SpinLock<Vec<Registered>> = SpinLock::named("char devices", Vec::new());
+
+/// Adds a character device called `name` to `/dev` with the permission bits `mode`.
+pub fn register(name: &str, mode: u32, device: Arc<dyn CharDevice>) -> Result<(), FsError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|registered| registered.name == name) || block::find(name).is_some() {
+        return Err(FsError::AlreadyExists);
+    }
+    devices.push(Registered {
+        name: name.to_string(),
+        mode: mode & 0o7777,
+        device,
+    });
+    Ok(())
+}
+
+/// Discards everything written to it and is always at its end.
+struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// Reads as an endless run of zeroes.
+struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write(&self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// Random bytes from RDRAND where the processor has it, and from a xorshift generator seeded
+/// with the time stamp counter otherwise. What is written is stirred into the generator.
+/// Neither is meant for keys.
+struct Random {
+    rdrand: bool,
+    state: SpinLock<u64>,
+}
+
+impl Random {
+    fn new() -> Self {
+        // SAFETY: Reading the time stamp counter has no side effects.
+        let seed = unsafe { _rdtsc() } ^ u64::from(pit::ticks()) << 32;
+        Random {
+            rdrand: cpuid(1).ecx & 1 << 30 != 0,
+            // Xorshift never leaves zero.
+            state: SpinLock::new(seed | 1),
+        }
+    }
+
+    /// Asks the processor for a random number, which it may fail to come up with in time.
+    fn rdrand() -> Option<u32> {
+        for _ in 0..10 {
+            let value: u32;
+            let ok: u8;
+            // SAFETY: Only called once CPUID said the processor has RDRAND, which touches
+            // nothing but the two registers.
+            unsafe {
+                asm!(
+                    "rdrand {}",
+                    "setc {}",
+                    out(reg) value,
+                    out(reg_byte) ok,
+                    options(nomem, nostack)
+                );
+            }
+            if ok != 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn next(&self) -> u32 {
+        if self.rdrand {
+            if let Some(value) = Self::rdrand() {
+                return value;
+            }
+        }
+        let mut state = self.state.lock();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state >> 32) as u32
+    }
+}
+
+impl CharDevice for Random {
+    fn read(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        for chunk in buffer.chunks_mut(4) {
+            chunk.copy_from_slice(&self.next().to_le_bytes()[..chunk.len()]);
+        }
+        Ok(buffer.len())
+    }
+
+    fn write(&self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut state = self.state.lock();
+        for &byte in data {
+            *state = (*state ^ u64::from(byte)).rotate_left(8) | 1;
+        }
+        Ok(data.len())
+    }
+}
+
+/// A console, read through the line discipline and written to `write`.
+///
+/// There is a single line discipline, which takes in the keyboard and the serial port alike,
+/// so `tty0` and `ttyS0` read the same input whichever of them it was typed on.
+struct Console {
+    write: fn(&[u8]),
+}
+
+impl CharDevice for Console {
+    fn read(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        line_discipline::read(buffer).map_err(|_| FsError::Io)
+    }
+
+    fn write(&self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        (self.write)(data);
+        Ok(data.len())
+    }
+}
+
+/// The memory of the framebuffer, which the bootloader left mapped.
+struct FrameBufferDevice(output::Framebuffer);
+
+impl FrameBufferDevice {
+    /// The part of the framebuffer a transfer of `len` bytes at `offset` covers.
+    fn span(&self, offset: u64, len: usize) -> (usize, usize) {
+        let size = self.0.size();
+        let start = usize::try_from(offset).map_or(size, |offset| offset.min(size));
+        (start, len.min(size - start))
+    }
+}
+
+impl CharDevice for FrameBufferDevice {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let (start, count) = self.span(offset, buffer.len());
+        // SAFETY: Paging is off and the framebuffer takes up `size` bytes from `address`, of
+        // which `span` keeps `start..start + count` within. The buffer is kernel memory, so
+        // the two do not overlap.
+        unsafe {
+            let source = (self.0.address + start) as *const u8;
+            core::ptr::copy_nonoverlapping(source, buffer.as_mut_ptr(), count);
+        }
+        Ok(count)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let (start, count) = self.span(offset, data.len());
+        if count == 0 && !data.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        // SAFETY: As for `read`, `start..start + count` lies within the framebuffer.
+        unsafe {
+            let target = (self.0.address + start) as *mut u8;
+            core::ptr::copy_nonoverlapping(data.as_ptr(), target, count);
+        }
+        Ok(count)
+    }
+
+    fn size(&self) -> u64 {
+        self.0.size() as u64
+    }
+}
+
+struct CharInode {
+    ino: u64,
+    mode: u32,
+    device: Arc<dyn CharDevice>,
+}
+
+impl Inode for CharInode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        Ok(Metadata {
+            ino: self.ino,
+            kind: FileType::CharDevice,
+            size: self.device.size(),
+            mode: self.mode,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        self.device.read(offset, buffer)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        self.device.write(offset, buffer)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct BlockInode {
+    ino: u64,
+    device: Arc<dyn BlockDevice>,
+}
+
+impl BlockInode {
+    fn size(&self) -> u64 {
+        self.device.sector_count() * self.device.sector_size() as u64
+    }
+}
+
+impl Inode for BlockInode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        Ok(Metadata {
+            ino: self.ino,
+            kind: FileType::BlockDevice,
+            size: self.size(),
+            mode: if self.device.is_read_only() {
+                0o440
+            } else {
+                0o660
+            },
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let count = self.size().saturating_sub(offset).min(buffer.len() as u64) as usize;
+        block::read_bytes(&*self.device, offset, &mut buffer[..count])?;
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let count = self.size().saturating_sub(offset).min(buffer.len() as u64) as usize;
+        if count == 0 && !buffer.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        block::write_bytes(&*self.device, offset, &buffer[..count])?;
+        Ok(count)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// The only directory, whose entries are worked out whenever it is looked at.
+struct RootInode;
+
+impl Inode for RootInode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        Ok(Metadata {
+            ino: ROOT_INO,
+            kind: FileType::Directory,
+            size: (DEVICES.lock().len() + block::devices().len()) as u64,
+            mode: 0o755,
+            nlink: 2,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let devices = DEVICES.lock();
+        if let Some((index, registered)) = devices
+            .iter()
+            .enumerate()
+            .find(|(_, registered)| registered.name == name)
+        {
+            return Ok(Arc::new(CharInode {
+                ino: ROOT_INO + 1 + index as u64,
+                mode: registered.mode,
+                device: registered.device.clone(),
+            }));
+        }
+        drop(devices);
+        block::devices()
+            .into_iter()
+            .enumerate()
+            .find(|(_, device)| device.name() == name)
+            .map(|(index, device)| {
+                Arc::new(BlockInode {
+                    ino: BLOCK_INO_BASE + index as u64,
+                    device,
+                }) as Arc<dyn Inode>
+            })
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut entries: Vec<DirEntry> = DEVICES
+            .lock()
+            .iter()
+            .enumerate()
+            .map(|(index, registered)| DirEntry {
+                name: registered.name.clone(),
+                ino: ROOT_INO + 1 + index as u64,
+                kind: FileType::CharDevice,
+            })
+            .collect();
+        entries.extend(
+            block::devices()
+                .iter()
+                .enumerate()
+                .map(|(index, device)| DirEntry {
+                    name: device.name().to_string(),
+                    ino: BLOCK_INO_BASE + index as u64,
+                    kind: FileType::BlockDevice,
+                }),
+        );
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn create(&self, _name: &str, _kind: FileType, _mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// The device filesystem. Every instance shows the same devices.
+pub struct DevFs {
+    root: Arc<RootInode>,
+}
+
+impl DevFs {
+    pub fn new() -> Self {
+        DevFs {
+            root: Arc::new(RootInode),
+        }
+    }
+}
+
+impl Default for DevFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Filesystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Registers the devices that are always there, the consoles and the framebuffer.
+pub fn initialize() {
+    let mut devices: Vec<(&str, u32, Arc<dyn CharDevice>)> = Vec::from([
+        ("null", 0o666, Arc::new(Null) as Arc<dyn CharDevice>),
+        ("zero", 0o666, Arc::new(Zero)),
+        ("random", 0o666, Arc::new(Random::new())),
+        (
+            "tty0",
+            0o620,
+            Arc::new(Console {
+                write: tty::write_bytes,
+            }),
+        ),
+    ]);
+    if serial::is_present() {
+        devices.push((
+            "ttyS0",
+            0o620,
+            Arc::new(Console {
+                write: serial::write_bytes,
+            }),
+        ));
+    }
+    if let Some(framebuffer) = output::framebuffer() {
+        devices.push(("fb0", 0o660, Arc::new(FrameBufferDevice(framebuffer))));
+    }
+    for (name, mode, device) in devices {
+        register(name, mode, device).expect("devfs: built-in device registered twice");
+    }
+}
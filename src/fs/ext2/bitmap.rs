@@ -0,0 +1,150 @@
+//! The bitmaps that record which blocks and inodes of each group are in use.
+//!
+//! Every allocation and release is written to the bitmap, the group descriptor and the
+//! superblock straight away, so that the volume is consistent whenever the cache is flushed.
+
+use super::{State, Volume};
+use crate::{fs::FsError, warn};
+
+/// Returns the first clear bit of `bitmap` from `start` up to `end`.
+fn find_clear(bitmap: &[u8], start: u32, end: u32) -> Option<u32> {
+    (start..end).find(|&bit| bitmap[bit as usize / 8] & (1 << (bit % 8)) == 0)
+}
+
+impl Volume {
+    /// How many blocks `group` has, which is fewer for the last one.
+    fn blocks_in_group(&self, group: usize) -> u32 {
+        let start = group as u32 * self.blocks_per_group;
+        (self.blocks_count - self.first_data_block - start).min(self.blocks_per_group)
+    }
+
+    /// The group a block belongs to.
+    pub(super) fn group_of_block(&self, block: u32) -> usize {
+        ((block - self.first_data_block) / self.blocks_per_group) as usize
+    }
+
+    /// The group an inode belongs to.
+    pub(super) fn group_of_inode(&self, ino: u32) -> usize {
+        ((ino - 1) / self.inodes_per_group) as usize
+    }
+
+    /// The first block of the group an inode belongs to, which is where its blocks should go.
+    pub(super) fn home_block(&self, ino: u32) -> u32 {
+        self.first_data_block + self.group_of_inode(ino) as u32 * self.blocks_per_group
+    }
+
+    /// Sets or clears a bit of the bitmap starting at `block`.
+    fn set_bit(&self, block: u32, bit: u32, value: bool) -> Result<(), FsError> {
+        let at = self.block_offset(block) + u64::from(bit / 8);
+        let mut byte = [0];
+        self.read_bytes(at, &mut byte)?;
+        let was = byte[0] & (1 << (bit % 8)) != 0;
+        if was == value {
+            warn!(
+                "ext2: {}: bit {} of bitmap block {} was already {}",
+                self.device.name(),
+                bit,
+                block,
+                if value { "set" } else { "clear" }
+            );
+            return Err(FsError::Io);
+        }
+        byte[0] ^= 1 << (bit % 8);
+        self.write_bytes(at, &byte)
+    }
+
+    /// Takes a free block, looking in the group of `goal` first.
+    pub(super) fn allocate_block(&self, state: &mut State, goal: u32) -> Result<u32, FsError> {
+        let count = state.groups.len();
+        let first = match goal {
+            0 => 0,
+            goal => self.group_of_block(goal).min(count - 1),
+        };
+        for group in (first..count).chain(0..first) {
+            if state.groups[group].free_blocks == 0 {
+                continue;
+            }
+            let bitmap = self.read_block(state.groups[group].block_bitmap)?;
+            let Some(bit) = find_clear(&bitmap, 0, self.blocks_in_group(group)) else {
+                continue;
+            };
+            self.set_bit(state.groups[group].block_bitmap, bit, true)?;
+            state.groups[group].free_blocks -= 1;
+            state.free_blocks = state.free_blocks.saturating_sub(1);
+            self.write_counts(state, group)?;
+            return Ok(self.first_data_block + group as u32 * self.blocks_per_group + bit);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    pub(super) fn free_block(&self, state: &mut State, block: u32) -> Result<(), FsError> {
+        if !self.is_valid_block(block) {
+            warn!("ext2: {}: freeing bad block {}", self.device.name(), block);
+            return Err(FsError::Io);
+        }
+        let group = self.group_of_block(block);
+        let bit = (block - self.first_data_block) % self.blocks_per_group;
+        self.set_bit(state.groups[group].block_bitmap, bit, false)?;
+        state.groups[group].free_blocks += 1;
+        state.free_blocks += 1;
+        self.write_counts(state, group)
+    }
+
+    /// Takes a free inode. Files go in the same group as their directory, so that they are
+    /// close to it, and directories in the group with the most free inodes, so that they
+    /// spread out over the volume.
+    pub(super) fn allocate_inode(
+        &self,
+        state: &mut State,
+        parent: u32,
+        directory: bool,
+    ) -> Result<u32, FsError> {
+        let count = state.groups.len();
+        let first = match directory {
+            true => (0..count)
+                .max_by_key(|&group| state.groups[group].free_inodes)
+                .unwrap_or(0),
+            false => self.group_of_inode(parent),
+        };
+        for group in (first..count).chain(0..first) {
+            if state.groups[group].free_inodes == 0 {
+                continue;
+            }
+            let base = group as u32 * self.inodes_per_group;
+            // Inodes are numbered from 1, and the first few are reserved.
+            let start = (self.first_ino - 1).saturating_sub(base);
+            let bitmap = self.read_block(state.groups[group].inode_bitmap)?;
+            let Some(bit) = find_clear(&bitmap, start, self.inodes_per_group) else {
+                continue;
+            };
+            self.set_bit(state.groups[group].inode_bitmap, bit, true)?;
+            let descriptor = &mut state.groups[group];
+            descriptor.free_inodes -= 1;
+            if directory {
+                descriptor.directories += 1;
+            }
+            state.free_inodes = state.free_inodes.saturating_sub(1);
+            self.write_counts(state, group)?;
+            return Ok(base + bit + 1);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    pub(super) fn free_inode(
+        &self,
+        state: &mut State,
+        ino: u32,
+        directory: bool,
+    ) -> Result<(), FsError> {
+        let group = self.group_of_inode(ino);
+        let bit = (ino - 1) % self.inodes_per_group;
+        self.set_bit(state.groups[group].inode_bitmap, bit, false)?;
+        let descriptor = &mut state.groups[group];
+        descriptor.free_inodes += 1;
+        if directory {
+            descriptor.directories = descriptor.directories.saturating_sub(1);
+        }
+        state.free_inodes += 1;
+        self.write_counts(state, group)
+    }
+}
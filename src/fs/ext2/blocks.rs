@@ -0,0 +1,485 @@
+//! Inodes as they are on the device, and the map from the blocks of a file to blocks of the
+//! volume.
+//!
+//! The first 12 blocks of a file are pointed at from the inode itself. The 13th pointer is to
+//! an indirect block full of pointers to the blocks after those, the 14th to a block of
+//! pointers to indirect blocks, and the 15th adds a third level. A pointer of 0 is a hole,
+//! which reads as zeroes.
+
+use alloc::vec::Vec;
+
+use super::{u16_at, u32_at, State, Volume, GOOD_OLD_INODE_SIZE};
+use crate::{
+    fs::{FileType, FsError},
+    warn,
+};
+
+/// How many bytes of each inode this driver knows the meaning of. The rest are left alone.
+pub(super) const INODE_SIZE: usize = GOOD_OLD_INODE_SIZE as usize;
+const DIRECT_BLOCKS: usize = 12;
+const BLOCK_POINTERS: usize = 15;
+/// Where the block pointers are, which is also where short symbolic links keep their target.
+const BLOCKS_OFFSET: usize = 40;
+/// Symbolic links shorter than this are kept in the inode instead of a block.
+pub(super) const FAST_SYMLINK_MAX: usize = BLOCK_POINTERS * 4;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFSOCK: u16 = 0xC000;
+const S_IFLNK: u16 = 0xA000;
+const S_IFREG: u16 = 0x8000;
+const S_IFBLK: u16 = 0x6000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFCHR: u16 = 0x2000;
+const S_IFIFO: u16 = 0x1000;
+
+/// The directory is indexed by a hash tree, which this driver does not keep up to date.
+pub(super) const INDEX_FLAG: u32 = 0x1000;
+
+/// The block of extended attributes starts with this, followed by a reference count.
+const XATTR_MAGIC: u32 = 0xEA02_0000;
+
+/// The bits of a mode that give the type of a file.
+pub(super) fn type_bits(kind: FileType) -> u16 {
+    match kind {
+        FileType::File => S_IFREG,
+        FileType::Directory => S_IFDIR,
+        FileType::Symlink => S_IFLNK,
+        FileType::CharDevice => S_IFCHR,
+        FileType::BlockDevice => S_IFBLK,
+        FileType::Fifo => S_IFIFO,
+        FileType::Socket => S_IFSOCK,
+    }
+}
+
+/// The part of an inode this driver knows about.
+#[derive(Clone)]
+pub(super) struct DiskInode([u8; INODE_SIZE]);
+
+impl DiskInode {
+    pub(super) fn new(mode: u16, links: u16) -> Self {
+        let mut inode = DiskInode([0; INODE_SIZE]);
+        inode.set_u16(0, mode);
+        inode.set_links(links);
+        inode
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        self.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub(super) fn mode(&self) -> u16 {
+        u16_at(&self.0, 0)
+    }
+
+    /// The type of the file, or `None` if the mode makes no sense.
+    pub(super) fn kind(&self) -> Option<FileType> {
+        match self.mode() & S_IFMT {
+            S_IFREG => Some(FileType::File),
+            S_IFDIR => Some(FileType::Directory),
+            S_IFLNK => Some(FileType::Symlink),
+            S_IFCHR => Some(FileType::CharDevice),
+            S_IFBLK => Some(FileType::BlockDevice),
+            S_IFIFO => Some(FileType::Fifo),
+            S_IFSOCK => Some(FileType::Socket),
+            _ => None,
+        }
+    }
+
+    pub(super) fn is_directory(&self) -> bool {
+        self.mode() & S_IFMT == S_IFDIR
+    }
+
+    pub(super) fn uid(&self) -> u32 {
+        u32::from(u16_at(&self.0, 2)) | u32::from(u16_at(&self.0, 120)) << 16
+    }
+
+    pub(super) fn gid(&self) -> u32 {
+        u32::from(u16_at(&self.0, 24)) | u32::from(u16_at(&self.0, 122)) << 16
+    }
+
+    /// The size of the file. Only regular files keep the upper half, the field being used
+    /// for something else by the others.
+    pub(super) fn size(&self) -> u64 {
+        let high = match self.mode() & S_IFMT {
+            S_IFREG => u64::from(u32_at(&self.0, 108)),
+            _ => 0,
+        };
+        u64::from(u32_at(&self.0, 4)) | high << 32
+    }
+
+    pub(super) fn set_size(&mut self, size: u64) {
+        self.set_u32(4, size as u32);
+        if self.mode() & S_IFMT == S_IFREG {
+            self.set_u32(108, (size >> 32) as u32);
+        }
+    }
+
+    pub(super) fn mtime(&self) -> u32 {
+        u32_at(&self.0, 16)
+    }
+
+    pub(super) fn links(&self) -> u16 {
+        u16_at(&self.0, 26)
+    }
+
+    pub(super) fn set_links(&mut self, links: u16) {
+        self.set_u16(26, links);
+    }
+
+    /// How many 512-byte units the blocks of the file take up, counting indirect blocks.
+    fn sectors(&self) -> u32 {
+        u32_at(&self.0, 28)
+    }
+
+    fn set_sectors(&mut self, sectors: u32) {
+        self.set_u32(28, sectors);
+    }
+
+    pub(super) fn flags(&self) -> u32 {
+        u32_at(&self.0, 32)
+    }
+
+    pub(super) fn set_flags(&mut self, flags: u32) {
+        self.set_u32(32, flags);
+    }
+
+    fn block(&self, slot: usize) -> u32 {
+        u32_at(&self.0, BLOCKS_OFFSET + slot * 4)
+    }
+
+    fn set_block(&mut self, slot: usize, block: u32) {
+        self.set_u32(BLOCKS_OFFSET + slot * 4, block);
+    }
+
+    /// The block of extended attributes, or 0.
+    fn xattr_block(&self) -> u32 {
+        u32_at(&self.0, 104)
+    }
+
+    /// The bytes where the block pointers would be, which hold the target of a short
+    /// symbolic link.
+    pub(super) fn inline_data(&self) -> &[u8] {
+        &self.0[BLOCKS_OFFSET..BLOCKS_OFFSET + FAST_SYMLINK_MAX]
+    }
+
+    pub(super) fn set_inline_data(&mut self, data: &[u8]) {
+        self.0[BLOCKS_OFFSET..BLOCKS_OFFSET + data.len()].copy_from_slice(data);
+    }
+}
+
+/// Reads the block pointers in an indirect block.
+fn pointers(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|pointer| u32::from_le_bytes(pointer.try_into().unwrap()))
+        .collect()
+}
+
+impl Volume {
+    /// Where an inode is on the device, in bytes.
+    fn inode_offset(&self, state: &State, ino: u32) -> Result<u64, FsError> {
+        if ino == 0 || ino > self.inodes_count {
+            warn!("ext2: {}: bad inode number {}", self.device.name(), ino);
+            return Err(FsError::Io);
+        }
+        let group = &state.groups[self.group_of_inode(ino)];
+        let index = (ino - 1) % self.inodes_per_group;
+        Ok(self.block_offset(group.inode_table) + u64::from(index) * u64::from(self.inode_size))
+    }
+
+    pub(super) fn read_inode(&self, state: &State, ino: u32) -> Result<DiskInode, FsError> {
+        let mut inode = DiskInode([0; INODE_SIZE]);
+        self.read_bytes(self.inode_offset(state, ino)?, &mut inode.0)?;
+        Ok(inode)
+    }
+
+    pub(super) fn write_inode(
+        &self,
+        state: &State,
+        ino: u32,
+        inode: &DiskInode,
+    ) -> Result<(), FsError> {
+        self.write_bytes(self.inode_offset(state, ino)?, &inode.0)
+    }
+
+    /// Fills a freshly allocated inode with zeroes, including the part this driver does not
+    /// know about, and writes `inode` over the start of it.
+    pub(super) fn init_inode(
+        &self,
+        state: &State,
+        ino: u32,
+        inode: &DiskInode,
+    ) -> Result<(), FsError> {
+        let offset = self.inode_offset(state, ino)?;
+        let mut bytes = alloc::vec![0; self.inode_size as usize];
+        bytes[..INODE_SIZE].copy_from_slice(&inode.0);
+        self.write_bytes(offset, &bytes)
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        u64::from(self.block_size / 4)
+    }
+
+    /// Works out where the pointer to block `index` of a file is: the slot in the inode,
+    /// followed by the index within each level of indirect block under it. Returns `None`
+    /// past the largest file the volume can hold.
+    fn path(&self, index: u64) -> Option<(usize, Vec<usize>)> {
+        let per = self.pointers_per_block();
+        if index < DIRECT_BLOCKS as u64 {
+            return Some((index as usize, Vec::new()));
+        }
+        let mut index = index - DIRECT_BLOCKS as u64;
+        let mut span = 1;
+        for depth in 1..=3 {
+            span *= per;
+            if index < span {
+                let indexes = (0..depth)
+                    .rev()
+                    .map(|level| (index / per.pow(level) % per) as usize)
+                    .collect();
+                return Some((DIRECT_BLOCKS + depth as usize - 1, indexes));
+            }
+            index -= span;
+        }
+        None
+    }
+
+    /// How many blocks the largest file can have.
+    pub(super) fn max_file_blocks(&self) -> u64 {
+        let per = self.pointers_per_block();
+        DIRECT_BLOCKS as u64 + per + per * per + per * per * per
+    }
+
+    fn read_pointer(&self, block: u32, index: usize) -> Result<u32, FsError> {
+        if !self.is_valid_block(block) {
+            warn!("ext2: {}: bad indirect block {}", self.device.name(), block);
+            return Err(FsError::Io);
+        }
+        let mut pointer = [0; 4];
+        self.read_bytes(self.block_offset(block) + index as u64 * 4, &mut pointer)?;
+        Ok(u32::from_le_bytes(pointer))
+    }
+
+    fn write_pointer(&self, block: u32, index: usize, pointer: u32) -> Result<(), FsError> {
+        self.write_bytes(
+            self.block_offset(block) + index as u64 * 4,
+            &pointer.to_le_bytes(),
+        )
+    }
+
+    /// Returns the block of the volume holding block `index` of a file, or 0 for a hole.
+    pub(super) fn block_of(&self, inode: &DiskInode, index: u64) -> Result<u32, FsError> {
+        let Some((slot, indexes)) = self.path(index) else {
+            return Ok(0);
+        };
+        let mut block = inode.block(slot);
+        for index in indexes {
+            if block == 0 {
+                break;
+            }
+            block = self.read_pointer(block, index)?;
+        }
+        if block != 0 && !self.is_valid_block(block) {
+            warn!("ext2: {}: bad block number {}", self.device.name(), block);
+            return Err(FsError::Io);
+        }
+        Ok(block)
+    }
+
+    /// Takes a block near `goal` for a file, counting it against the inode.
+    fn take_block(
+        &self,
+        state: &mut State,
+        inode: &mut DiskInode,
+        goal: u32,
+    ) -> Result<u32, FsError> {
+        let block = self.allocate_block(state, goal)?;
+        inode.set_sectors(inode.sectors() + self.sectors_per_block());
+        Ok(block)
+    }
+
+    /// Returns the block of the volume holding block `index` of a file, allocating it and
+    /// the indirect blocks leading to it if needed, along with whether it is new. New blocks
+    /// hold whatever was there before. `goal` is a block that the new ones should be near.
+    pub(super) fn map_block(
+        &self,
+        state: &mut State,
+        inode: &mut DiskInode,
+        index: u64,
+        goal: u32,
+    ) -> Result<(u32, bool), FsError> {
+        let (slot, indexes) = self.path(index).ok_or(FsError::NoSpace)?;
+        let mut fresh = false;
+        let mut block = inode.block(slot);
+        if block == 0 {
+            block = self.take_block(state, inode, goal)?;
+            inode.set_block(slot, block);
+            fresh = true;
+        }
+        let depth = indexes.len();
+        for (level, index) in indexes.into_iter().enumerate() {
+            if fresh {
+                // A new indirect block starts out pointing nowhere.
+                self.zero_block(block)?;
+            }
+            let mut next = self.read_pointer(block, index)?;
+            fresh = next == 0;
+            if fresh {
+                next = self.take_block(state, inode, block)?;
+                self.write_pointer(block, index, next)?;
+            } else if level + 1 < depth && !self.is_valid_block(next) {
+                return Err(FsError::Io);
+            }
+            block = next;
+        }
+        if !self.is_valid_block(block) {
+            warn!("ext2: {}: bad block number {}", self.device.name(), block);
+            return Err(FsError::Io);
+        }
+        Ok((block, fresh))
+    }
+
+    /// Frees `block` and, for an indirect block `depth` levels above the data, everything it
+    /// points to.
+    fn free_tree(
+        &self,
+        state: &mut State,
+        inode: &mut DiskInode,
+        block: u32,
+        depth: u32,
+    ) -> Result<(), FsError> {
+        if depth > 0 {
+            for pointer in pointers(&self.read_block(block)?) {
+                if pointer != 0 {
+                    self.free_tree(state, inode, pointer, depth - 1)?;
+                }
+            }
+        }
+        self.free_block(state, block)?;
+        inode.set_sectors(inode.sectors().saturating_sub(self.sectors_per_block()));
+        Ok(())
+    }
+
+    /// Frees what the indirect block `block`, `depth` levels above the data, points to from
+    /// its `from`th data block on.
+    fn free_from(
+        &self,
+        state: &mut State,
+        inode: &mut DiskInode,
+        block: u32,
+        depth: u32,
+        from: u64,
+    ) -> Result<(), FsError> {
+        let span = self.pointers_per_block().pow(depth - 1);
+        for (index, pointer) in pointers(&self.read_block(block)?).into_iter().enumerate() {
+            let start = index as u64 * span;
+            if pointer == 0 || start + span <= from {
+                continue;
+            }
+            if start >= from {
+                self.free_tree(state, inode, pointer, depth - 1)?;
+                self.write_pointer(block, index, 0)?;
+            } else {
+                self.free_from(state, inode, pointer, depth - 1, from - start)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Frees the blocks of a file from block `keep` on, along with the indirect blocks that
+    /// no longer point anywhere.
+    pub(super) fn free_blocks(
+        &self,
+        state: &mut State,
+        inode: &mut DiskInode,
+        keep: u64,
+    ) -> Result<(), FsError> {
+        for slot in (keep.min(DIRECT_BLOCKS as u64) as usize)..DIRECT_BLOCKS {
+            let block = inode.block(slot);
+            if block != 0 {
+                self.free_block(state, block)?;
+                inode.set_sectors(inode.sectors().saturating_sub(self.sectors_per_block()));
+                inode.set_block(slot, 0);
+            }
+        }
+        let mut base = DIRECT_BLOCKS as u64;
+        for depth in 1..=3 {
+            let slot = DIRECT_BLOCKS + depth as usize - 1;
+            let span = self.pointers_per_block().pow(depth);
+            let block = inode.block(slot);
+            if block != 0 {
+                if keep <= base {
+                    self.free_tree(state, inode, block, depth)?;
+                    inode.set_block(slot, 0);
+                } else if keep < base + span {
+                    self.free_from(state, inode, block, depth, keep - base)?;
+                }
+            }
+            base += span;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the target of a symbolic link is kept in the inode rather than in a
+    /// block.
+    pub(super) fn is_fast_symlink(&self, inode: &DiskInode) -> bool {
+        let xattr = match inode.xattr_block() {
+            0 => 0,
+            _ => self.sectors_per_block(),
+        };
+        inode.sectors() == xattr
+    }
+
+    /// Whether the block pointers of an inode point at blocks. Device files and short
+    /// symbolic links use them for other things.
+    fn has_blocks(&self, inode: &DiskInode) -> bool {
+        match inode.kind() {
+            Some(FileType::File | FileType::Directory) => true,
+            Some(FileType::Symlink) => !self.is_fast_symlink(inode),
+            _ => false,
+        }
+    }
+
+    /// Drops the reference of an inode to its block of extended attributes, freeing the
+    /// block if nothing else shares it.
+    fn release_xattr(&self, state: &mut State, inode: &DiskInode) -> Result<(), FsError> {
+        let block = inode.xattr_block();
+        if block == 0 {
+            return Ok(());
+        }
+        let header = self.read_block(block)?;
+        let references = u32_at(&header, 4);
+        if u32_at(&header, 0) != XATTR_MAGIC || references <= 1 {
+            self.free_block(state, block)
+        } else {
+            self.write_bytes(
+                self.block_offset(block) + 4,
+                &(references - 1).to_le_bytes(),
+            )
+        }
+    }
+
+    /// Frees a deleted inode and everything it owns.
+    pub(super) fn release(&self, state: &mut State, ino: u32) -> Result<(), FsError> {
+        let mut inode = self.read_inode(state, ino)?;
+        if inode.links() != 0 {
+            return Ok(());
+        }
+        let directory = inode.is_directory();
+        if self.has_blocks(&inode) {
+            self.free_blocks(state, &mut inode, 0)?;
+        }
+        self.release_xattr(state, &inode)?;
+        inode.set_sectors(0);
+        inode.set_size(0);
+        // A free inode with a type and no deletion time looks damaged to `e2fsck`, and there
+        // is no clock to set the time from.
+        inode.set_u16(0, 0);
+        self.write_inode(state, ino, &inode)?;
+        self.free_inode(state, ino, directory)
+    }
+}
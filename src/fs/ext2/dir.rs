@@ -0,0 +1,276 @@
+//! Directories, which are files made of variable-length records.
+//!
+//! Each record holds an inode number, its own length, the length of the name, the type of the
+//! file if the volume records it, and the name. The records of a block cover it completely:
+//! deleting one adds its length to the record before it, or clears its inode number if it is
+//! the first in its block, and a new record goes wherever one is longer than it needs to be.
+
+use alloc::{vec, vec::Vec};
+
+use super::{
+    blocks::{DiskInode, INDEX_FLAG},
+    u16_at, u32_at, State, Volume,
+};
+use crate::{
+    fs::{FileType, FsError},
+    warn,
+};
+
+const HEADER_SIZE: usize = 8;
+pub(super) const NAME_MAX: usize = 255;
+
+/// The type codes of directory records.
+pub(super) fn type_code(kind: FileType) -> u8 {
+    match kind {
+        FileType::File => 1,
+        FileType::Directory => 2,
+        FileType::CharDevice => 3,
+        FileType::BlockDevice => 4,
+        FileType::Fifo => 5,
+        FileType::Socket => 6,
+        FileType::Symlink => 7,
+    }
+}
+
+pub(super) fn kind_of_code(code: u8) -> Option<FileType> {
+    match code {
+        1 => Some(FileType::File),
+        2 => Some(FileType::Directory),
+        3 => Some(FileType::CharDevice),
+        4 => Some(FileType::BlockDevice),
+        5 => Some(FileType::Fifo),
+        6 => Some(FileType::Socket),
+        7 => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+/// How long a record for a name of `len` bytes has to be.
+fn record_size(len: usize) -> usize {
+    (HEADER_SIZE + len).next_multiple_of(4)
+}
+
+/// A record naming a file, and where it is.
+pub(super) struct Record {
+    pub(super) ino: u32,
+    /// The type code, if the volume records it, or 0.
+    pub(super) code: u8,
+    pub(super) name: Vec<u8>,
+    /// Where the record starts on the device, in bytes.
+    position: u64,
+    /// Where the record before it in the same block starts, if it is not the first.
+    previous: Option<u64>,
+}
+
+/// Fills in a record at `at` of `block`.
+fn put_record(block: &mut [u8], at: usize, len: usize, ino: u32, code: u8, name: &[u8]) {
+    block[at..at + 4].copy_from_slice(&ino.to_le_bytes());
+    block[at + 4..at + 6].copy_from_slice(&(len as u16).to_le_bytes());
+    block[at + 6] = name.len() as u8;
+    block[at + 7] = code;
+    block[at + HEADER_SIZE..at + HEADER_SIZE + name.len()].copy_from_slice(name);
+}
+
+impl Volume {
+    /// Calls `f` with each block of a directory, its number, and the records in it as
+    /// `(start, length)`, including the unused ones. Stops early when `f` returns `true`.
+    fn scan(
+        &self,
+        dir: &DiskInode,
+        mut f: impl FnMut(u32, &mut [u8], &[(usize, usize)]) -> Result<bool, FsError>,
+    ) -> Result<(), FsError> {
+        let block_size = self.block_size as usize;
+        for index in 0..dir.size() / u64::from(self.block_size) {
+            let block = self.block_of(dir, index)?;
+            if block == 0 {
+                warn!("ext2: {}: directory with a hole", self.device.name());
+                return Err(FsError::Io);
+            }
+            let mut bytes = self.read_block(block)?;
+            let mut records = Vec::new();
+            let mut at = 0;
+            while at < block_size {
+                if at + HEADER_SIZE > block_size {
+                    warn!(
+                        "ext2: {}: bad directory record in block {}",
+                        self.device.name(),
+                        block
+                    );
+                    return Err(FsError::Io);
+                }
+                let len = usize::from(u16_at(&bytes, at + 4));
+                let name_len = usize::from(bytes[at + 6]);
+                if len < HEADER_SIZE
+                    || !len.is_multiple_of(4)
+                    || at + len > block_size
+                    || record_size(name_len) > len
+                {
+                    warn!(
+                        "ext2: {}: bad directory record in block {}",
+                        self.device.name(),
+                        block
+                    );
+                    return Err(FsError::Io);
+                }
+                records.push((at, len));
+                at += len;
+            }
+            if f(block, &mut bytes, &records)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the records of a directory that name a file, including `.` and `..`.
+    pub(super) fn records(&self, dir: &DiskInode) -> Result<Vec<Record>, FsError> {
+        let mut found = Vec::new();
+        self.scan(dir, |block, bytes, records| {
+            let start = self.block_offset(block);
+            let mut previous = None;
+            for &(at, _) in records {
+                let ino = u32_at(bytes, at);
+                if ino != 0 {
+                    let name_len = usize::from(bytes[at + 6]);
+                    found.push(Record {
+                        ino,
+                        code: if self.filetype { bytes[at + 7] } else { 0 },
+                        name: bytes[at + HEADER_SIZE..at + HEADER_SIZE + name_len].to_vec(),
+                        position: start + at as u64,
+                        previous,
+                    });
+                }
+                previous = Some(start + at as u64);
+            }
+            Ok(false)
+        })?;
+        Ok(found)
+    }
+
+    pub(super) fn find(&self, dir: &DiskInode, name: &str) -> Result<Record, FsError> {
+        self.records(dir)?
+            .into_iter()
+            .find(|record| record.name == name.as_bytes())
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Returns whether a directory has nothing in it but `.` and `..`.
+    pub(super) fn is_empty_dir(&self, dir: &DiskInode) -> Result<bool, FsError> {
+        Ok(self
+            .records(dir)?
+            .iter()
+            .all(|record| record.name == b"." || record.name == b".."))
+    }
+
+    /// Adds a record naming inode `ino` to a directory, growing it by a block if no record
+    /// has the room to spare.
+    pub(super) fn add_record(
+        &self,
+        state: &mut State,
+        dir: &mut DiskInode,
+        name: &str,
+        ino: u32,
+        kind: FileType,
+    ) -> Result<(), FsError> {
+        let name = name.as_bytes();
+        let need = record_size(name.len());
+        let code = if self.filetype { type_code(kind) } else { 0 };
+        // An index would no longer agree with the records.
+        dir.set_flags(dir.flags() & !INDEX_FLAG);
+
+        let mut added = false;
+        self.scan(dir, |block, bytes, records| {
+            for &(at, len) in records {
+                let used = match u32_at(bytes, at) {
+                    0 => 0,
+                    _ => record_size(usize::from(bytes[at + 6])),
+                };
+                if len - used >= need {
+                    if used > 0 {
+                        bytes[at + 4..at + 6].copy_from_slice(&(used as u16).to_le_bytes());
+                    }
+                    put_record(bytes, at + used, len - used, ino, code, name);
+                    self.write_block(block, bytes)?;
+                    added = true;
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        })?;
+        if added {
+            return Ok(());
+        }
+
+        let index = dir.size() / u64::from(self.block_size);
+        let goal = match index {
+            0 => 0,
+            _ => self.block_of(dir, index - 1)?,
+        };
+        let (block, _) = self.map_block(state, dir, index, goal)?;
+        let mut bytes = vec![0; self.block_size as usize];
+        put_record(&mut bytes, 0, self.block_size as usize, ino, code, name);
+        self.write_block(block, &bytes)?;
+        dir.set_size(dir.size() + u64::from(self.block_size));
+        Ok(())
+    }
+
+    /// Removes a record from its directory.
+    pub(super) fn remove_record(&self, record: &Record) -> Result<(), FsError> {
+        match record.previous {
+            Some(previous) => {
+                let mut lengths = [0; 2];
+                self.read_bytes(previous + 4, &mut lengths)?;
+                let mut own = [0; 2];
+                self.read_bytes(record.position + 4, &mut own)?;
+                let merged = u16::from_le_bytes(lengths) + u16::from_le_bytes(own);
+                self.write_bytes(previous + 4, &merged.to_le_bytes())
+            }
+            None => self.write_bytes(record.position, &0u32.to_le_bytes()),
+        }
+    }
+
+    /// Points a record at another inode, keeping its name.
+    pub(super) fn retarget_record(
+        &self,
+        record: &Record,
+        ino: u32,
+        kind: FileType,
+    ) -> Result<(), FsError> {
+        self.write_bytes(record.position, &ino.to_le_bytes())?;
+        if self.filetype {
+            self.write_bytes(record.position + 7, &[type_code(kind)])?;
+        }
+        Ok(())
+    }
+
+    /// Gives a new directory its first block, holding `.` and `..`.
+    pub(super) fn init_directory(
+        &self,
+        state: &mut State,
+        dir: &mut DiskInode,
+        ino: u32,
+        parent: u32,
+        goal: u32,
+    ) -> Result<(), FsError> {
+        let (block, _) = self.map_block(state, dir, 0, goal)?;
+        let code = if self.filetype {
+            type_code(FileType::Directory)
+        } else {
+            0
+        };
+        let mut bytes = vec![0; self.block_size as usize];
+        let dot = record_size(1);
+        put_record(&mut bytes, 0, dot, ino, code, b".");
+        put_record(
+            &mut bytes,
+            dot,
+            self.block_size as usize - dot,
+            parent,
+            code,
+            b"..",
+        );
+        self.write_block(block, &bytes)?;
+        dir.set_size(u64::from(self.block_size));
+        Ok(())
+    }
+}
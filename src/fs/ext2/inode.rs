@@ -0,0 +1,655 @@
+//! The inodes of an ext2 volume, as the VFS sees them.
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+
+use super::{
+    blocks::{type_bits, DiskInode, FAST_SYMLINK_MAX},
+    dir::{self, Record, NAME_MAX},
+    State, Volume,
+};
+use crate::{
+    fs::{DirEntry, FileType, FsError, Inode, Metadata},
+    sync::Mutex,
+    warn,
+};
+
+/// How many inodes the table holds before the ones nothing uses any more are cleared out.
+const PRUNE_THRESHOLD: usize = 64;
+/// The most names a file can have, which is also the most subdirectories a directory can.
+const LINK_MAX: u16 = 32000;
+/// How large files can be without the large file feature.
+const SMALL_FILE_MAX: u64 = i32::MAX as u64;
+
+pub struct Ext2Inode {
+    volume: Arc<Volume>,
+    ino: u32,
+    /// Held across the disk I/O of reads and writes, so it has to be a sleeping lock.
+    disk: Mutex<DiskInode>,
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty()
+        || name.len() > NAME_MAX
+        || name.contains(['/', '\0'])
+        || name == "."
+        || name == ".."
+    {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Checks that an inode is a directory that has not been deleted.
+fn check_dir(disk: &DiskInode) -> Result<(), FsError> {
+    if !disk.is_directory() {
+        Err(FsError::NotADirectory)
+    } else if disk.links() == 0 {
+        Err(FsError::NotFound)
+    } else {
+        Ok(())
+    }
+}
+
+/// Removes one of the names of a file, or the only one of a directory, which has to be
+/// empty. Returns whether it was a directory and whether it has no names left.
+fn drop_link(volume: &Volume, disk: &mut DiskInode) -> Result<(bool, bool), FsError> {
+    if disk.is_directory() {
+        if !volume.is_empty_dir(disk)? {
+            return Err(FsError::NotEmpty);
+        }
+        disk.set_links(0);
+    } else {
+        disk.set_links(disk.links().saturating_sub(1));
+    }
+    Ok((disk.is_directory(), disk.links() == 0))
+}
+
+impl Ext2Inode {
+    /// Returns the inode numbered `ino`, reading it in if nothing uses it yet.
+    pub(super) fn get(
+        volume: &Arc<Volume>,
+        state: &mut State,
+        ino: u32,
+    ) -> Result<Arc<Self>, FsError> {
+        if let Some(inode) = state.inodes.get(&ino).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let disk = volume.read_inode(state, ino)?;
+        if disk.links() == 0 || disk.kind().is_none() {
+            warn!(
+                "ext2: {}: inode {} is not in use",
+                volume.device.name(),
+                ino
+            );
+            return Err(FsError::Io);
+        }
+        if state.inodes.len() >= PRUNE_THRESHOLD {
+            state.inodes.retain(|_, inode| inode.strong_count() > 0);
+        }
+        let inode = Arc::new(Ext2Inode {
+            volume: volume.clone(),
+            ino,
+            disk: Mutex::new(disk),
+        });
+        state.inodes.insert(ino, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    pub(super) fn is_directory(&self) -> bool {
+        self.disk.lock().is_directory()
+    }
+
+    /// Returns a copy of inode `ino`, as its `Ext2Inode` has it if something uses it.
+    fn load(volume: &Volume, state: &State, ino: u32) -> Result<DiskInode, FsError> {
+        match state.inodes.get(&ino).and_then(Weak::upgrade) {
+            Some(inode) => Ok(inode.disk.lock().clone()),
+            None => volume.read_inode(state, ino),
+        }
+    }
+
+    /// Changes inode `ino` with `f` and writes it back, going through its `Ext2Inode` if
+    /// something uses it so that the change is seen there. Also returns whether something
+    /// uses it.
+    fn update<R>(
+        volume: &Volume,
+        state: &mut State,
+        ino: u32,
+        f: impl FnOnce(&mut DiskInode) -> Result<R, FsError>,
+    ) -> Result<(R, bool), FsError> {
+        match state.inodes.get(&ino).and_then(Weak::upgrade) {
+            Some(inode) => {
+                let mut disk = inode.disk.lock();
+                let result = f(&mut disk)?;
+                volume.write_inode(state, ino, &disk)?;
+                Ok((result, true))
+            }
+            None => {
+                let mut disk = volume.read_inode(state, ino)?;
+                let result = f(&mut disk)?;
+                volume.write_inode(state, ino, &disk)?;
+                Ok((result, false))
+            }
+        }
+    }
+
+    fn store(&self, state: &State, disk: &DiskInode) -> Result<(), FsError> {
+        self.volume.write_inode(state, self.ino, disk)
+    }
+
+    /// Finds the ext2 inode behind a `dyn Inode` of the same volume.
+    fn downcast<'a>(&self, inode: &'a Arc<dyn Inode>) -> Result<&'a Ext2Inode, FsError> {
+        inode
+            .as_any()
+            .downcast_ref::<Ext2Inode>()
+            .filter(|other| Arc::ptr_eq(&other.volume, &self.volume))
+            .ok_or(FsError::CrossDevice)
+    }
+
+    /// The type of the file a record names, from the record if the volume keeps it there.
+    fn record_kind(&self, state: &State, record: &Record) -> Result<FileType, FsError> {
+        match dir::kind_of_code(record.code) {
+            Some(kind) => Ok(kind),
+            None => Self::load(&self.volume, state, record.ino)?
+                .kind()
+                .ok_or(FsError::Io),
+        }
+    }
+
+    /// The largest a file can grow.
+    fn max_size(&self) -> u64 {
+        let limit = self.volume.max_file_blocks() * u64::from(self.volume.block_size);
+        if self.volume.large_file {
+            limit
+        } else {
+            limit.min(SMALL_FILE_MAX)
+        }
+    }
+
+    /// Fills the bytes of a file from `start` up to `end` that share a block with the old end
+    /// of the file with zeroes. Those after that are in blocks that are not there yet.
+    fn zero_tail(&self, disk: &DiskInode, start: u64, end: u64) -> Result<(), FsError> {
+        let block_size = u64::from(self.volume.block_size);
+        let within = start % block_size;
+        if within == 0 {
+            return Ok(());
+        }
+        let block = self.volume.block_of(disk, start / block_size)?;
+        if block == 0 {
+            return Ok(());
+        }
+        let len = (block_size - within).min(end - start);
+        self.volume.write_bytes(
+            self.volume.block_offset(block) + within,
+            &vec![0; len as usize],
+        )
+    }
+
+    /// Writes `buffer` to a file at `offset`, allocating blocks as needed, and counts in
+    /// `done` how much got written.
+    fn write_pieces(
+        &self,
+        state: &mut State,
+        disk: &mut DiskInode,
+        offset: u64,
+        buffer: &[u8],
+        done: &mut usize,
+    ) -> Result<(), FsError> {
+        let block_size = u64::from(self.volume.block_size);
+        let mut goal = self.volume.home_block(self.ino);
+        while *done < buffer.len() {
+            let position = offset + *done as u64;
+            let within = position % block_size;
+            let count = ((block_size - within) as usize).min(buffer.len() - *done);
+            let (block, fresh) = self
+                .volume
+                .map_block(state, disk, position / block_size, goal)?;
+            if fresh && count < block_size as usize {
+                self.volume.zero_block(block)?;
+            }
+            self.volume.write_bytes(
+                self.volume.block_offset(block) + within,
+                &buffer[*done..*done + count],
+            )?;
+            goal = block;
+            *done += count;
+        }
+        Ok(())
+    }
+
+    /// Allocates an inode for a new child of this directory, lets `fill` set it up, and adds
+    /// it to the directory as `name`. If anything fails, what was allocated is given back.
+    fn add_child(
+        &self,
+        state: &mut State,
+        dir: &mut DiskInode,
+        name: &str,
+        mut disk: DiskInode,
+        fill: impl FnOnce(&mut State, u32, &mut DiskInode) -> Result<(), FsError>,
+    ) -> Result<Arc<Ext2Inode>, FsError> {
+        let kind = disk.kind().ok_or(FsError::InvalidArgument)?;
+        let directory = kind == FileType::Directory;
+        if directory && dir.links() >= LINK_MAX {
+            return Err(FsError::NoSpace);
+        }
+        let volume = &self.volume;
+        let ino = volume.allocate_inode(state, self.ino, directory)?;
+        let result = volume
+            .init_inode(state, ino, &disk)
+            .and_then(|()| fill(state, ino, &mut disk))
+            .and_then(|()| volume.write_inode(state, ino, &disk))
+            .and_then(|()| volume.add_record(state, dir, name, ino, kind));
+        if let Err(error) = result {
+            disk.set_links(0);
+            let released = volume
+                .write_inode(state, ino, &disk)
+                .and_then(|()| volume.release(state, ino));
+            if let Err(error) = released {
+                warn!(
+                    "ext2: {}: cannot free inode {}: {}",
+                    volume.device.name(),
+                    ino,
+                    error
+                );
+            }
+            self.store(state, dir)?;
+            return Err(error);
+        }
+        if directory {
+            dir.set_links(dir.links() + 1);
+        }
+        self.store(state, dir)?;
+        Self::get(volume, state, ino)
+    }
+
+    /// Adds `delta` to the link count of this directory.
+    fn adjust_links(&self, state: &State, delta: i32) -> Result<(), FsError> {
+        if delta == 0 {
+            return Ok(());
+        }
+        let mut disk = self.disk.lock();
+        let links = (i32::from(disk.links()) + delta).max(0);
+        disk.set_links(links as u16);
+        self.store(state, &disk)
+    }
+}
+
+impl Drop for Ext2Inode {
+    fn drop(&mut self) {
+        if self.disk.get_mut().links() == 0 {
+            // The state may be locked by whoever dropped the last reference.
+            self.volume.orphans.lock().push(self.ino);
+        }
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let disk = self.disk.lock();
+        Ok(Metadata {
+            ino: self.ino.into(),
+            kind: disk.kind().ok_or(FsError::Io)?,
+            size: disk.size(),
+            mode: u32::from(disk.mode() & 0o7777),
+            nlink: disk.links().into(),
+            uid: disk.uid(),
+            gid: disk.gid(),
+            mtime: disk.mtime().into(),
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let mut state = self.volume.lock();
+        let disk = self.disk.lock();
+        check_dir(&disk)?;
+        let record = self.volume.find(&disk, name)?;
+        drop(disk);
+        Ok(Ext2Inode::get(&self.volume, &mut state, record.ino)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let state = self.volume.lock();
+        let disk = self.disk.lock();
+        check_dir(&disk)?;
+        let records = self.volume.records(&disk)?;
+        drop(disk);
+        records
+            .into_iter()
+            .filter(|record| record.name != b"." && record.name != b"..")
+            .map(|record| {
+                Ok(DirEntry {
+                    name: String::from_utf8_lossy(&record.name).into_owned(),
+                    ino: record.ino.into(),
+                    kind: self.record_kind(&state, &record)?,
+                })
+            })
+            .collect()
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let _state = self.volume.lock();
+        let disk = self.disk.lock();
+        match disk.kind() {
+            Some(FileType::File) => {}
+            Some(FileType::Directory) => return Err(FsError::IsADirectory),
+            _ => return Err(FsError::InvalidArgument),
+        }
+        let block_size = u64::from(self.volume.block_size);
+        let count = disk.size().saturating_sub(offset).min(buffer.len() as u64) as usize;
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let piece = &mut buffer[done..count.min(done + (block_size - within) as usize)];
+            match self.volume.block_of(&disk, position / block_size)? {
+                0 => piece.fill(0),
+                block => self
+                    .volume
+                    .read_bytes(self.volume.block_offset(block) + within, piece)?,
+            }
+            done += piece.len();
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        self.volume.check_writable()?;
+        let mut state = self.volume.lock();
+        let mut disk = self.disk.lock();
+        match disk.kind() {
+            Some(FileType::File) => {}
+            Some(FileType::Directory) => return Err(FsError::IsADirectory),
+            _ => return Err(FsError::InvalidArgument),
+        }
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        offset
+            .checked_add(buffer.len() as u64)
+            .filter(|&end| end <= self.max_size())
+            .ok_or(FsError::NoSpace)?;
+        let size = disk.size();
+        if offset > size {
+            self.zero_tail(&disk, size, offset)?;
+        }
+        let mut done = 0;
+        let result = self.write_pieces(&mut state, &mut disk, offset, buffer, &mut done);
+        // Whatever made it is kept, along with the blocks taken for it.
+        if done > 0 {
+            disk.set_size(size.max(offset + done as u64));
+        }
+        self.store(&state, &disk)?;
+        result.map(|()| done)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        self.volume.check_writable()?;
+        let mut state = self.volume.lock();
+        let mut disk = self.disk.lock();
+        match disk.kind() {
+            Some(FileType::File) => {}
+            Some(FileType::Directory) => return Err(FsError::IsADirectory),
+            _ => return Err(FsError::InvalidArgument),
+        }
+        if size > self.max_size() {
+            return Err(FsError::NoSpace);
+        }
+        let old_size = disk.size();
+        let result = if size > old_size {
+            self.zero_tail(&disk, old_size, size)
+        } else {
+            let keep = size.div_ceil(u64::from(self.volume.block_size));
+            self.volume.free_blocks(&mut state, &mut disk, keep)
+        };
+        disk.set_size(size);
+        self.store(&state, &disk)?;
+        result
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        self.volume.check_writable()?;
+        check_name(name)?;
+        let links = match kind {
+            FileType::File | FileType::Fifo | FileType::Socket => 1,
+            FileType::Directory => 2,
+            // Device files need a device number and links a target.
+            _ => return Err(FsError::NotSupported),
+        };
+        let mut state = self.volume.lock();
+        let mut dir = self.disk.lock();
+        check_dir(&dir)?;
+        match self.volume.find(&dir, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+        let disk = DiskInode::new(type_bits(kind) | (mode & 0o7777) as u16, links);
+        let volume = &self.volume;
+        Ok(self.add_child(
+            &mut state,
+            &mut dir,
+            name,
+            disk,
+            |state, ino, disk| match kind {
+                FileType::Directory => {
+                    volume.init_directory(state, disk, ino, self.ino, volume.home_block(ino))
+                }
+                _ => Ok(()),
+            },
+        )?)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.volume.check_writable()?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut state = self.volume.lock();
+        let mut dir = self.disk.lock();
+        check_dir(&dir)?;
+        let volume = &self.volume;
+        let record = volume.find(&dir, name)?;
+        let ((directory, unused), live) = Self::update(volume, &mut state, record.ino, |disk| {
+            drop_link(volume, disk)
+        })?;
+        volume.remove_record(&record)?;
+        if directory {
+            let links = dir.links();
+            dir.set_links(links.saturating_sub(1));
+            self.store(&state, &dir)?;
+        }
+        // Something still using the file frees it when it lets go.
+        if unused && !live {
+            volume.release(&mut state, record.ino)?;
+        }
+        Ok(())
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        let _state = self.volume.lock();
+        let disk = self.disk.lock();
+        if disk.kind() != Some(FileType::Symlink) {
+            return Err(FsError::InvalidArgument);
+        }
+        let size = disk.size() as usize;
+        let target = if self.volume.is_fast_symlink(&disk) {
+            disk.inline_data().get(..size).ok_or(FsError::Io)?.to_vec()
+        } else {
+            if size > self.volume.block_size as usize {
+                return Err(FsError::Io);
+            }
+            let block = self.volume.block_of(&disk, 0)?;
+            if block == 0 {
+                return Err(FsError::Io);
+            }
+            let mut target = vec![0; size];
+            self.volume
+                .read_bytes(self.volume.block_offset(block), &mut target)?;
+            target
+        };
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.volume.check_writable()?;
+        check_name(name)?;
+        if target.is_empty() || target.len() >= self.volume.block_size as usize {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut state = self.volume.lock();
+        let mut dir = self.disk.lock();
+        check_dir(&dir)?;
+        match self.volume.find(&dir, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+        let disk = DiskInode::new(type_bits(FileType::Symlink) | 0o777, 1);
+        let volume = &self.volume;
+        Ok(
+            self.add_child(&mut state, &mut dir, name, disk, |state, ino, disk| {
+                if target.len() < FAST_SYMLINK_MAX {
+                    disk.set_inline_data(target.as_bytes());
+                } else {
+                    let (block, _) = volume.map_block(state, disk, 0, volume.home_block(ino))?;
+                    let mut bytes = vec![0; volume.block_size as usize];
+                    bytes[..target.len()].copy_from_slice(target.as_bytes());
+                    volume.write_block(block, &bytes)?;
+                }
+                disk.set_size(target.len() as u64);
+                Ok(())
+            })?,
+        )
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> Result<(), FsError> {
+        self.volume.check_writable()?;
+        check_name(name)?;
+        let other = self.downcast(inode)?;
+        if other.ino == self.ino {
+            return Err(FsError::IsADirectory);
+        }
+        let mut state = self.volume.lock();
+        let mut dir = self.disk.lock();
+        check_dir(&dir)?;
+        match self.volume.find(&dir, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(error) => return Err(error),
+        }
+        let mut target = other.disk.lock();
+        let kind = target.kind().ok_or(FsError::Io)?;
+        if kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if target.links() == 0 {
+            return Err(FsError::NotFound);
+        }
+        if target.links() >= LINK_MAX {
+            return Err(FsError::NoSpace);
+        }
+        let result = self
+            .volume
+            .add_record(&mut state, &mut dir, name, other.ino, kind);
+        self.store(&state, &dir)?;
+        result?;
+        let links = target.links();
+        target.set_links(links + 1);
+        other.store(&state, &target)
+    }
+
+    fn rename(&self, name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> Result<(), FsError> {
+        self.volume.check_writable()?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidArgument);
+        }
+        check_name(new_name)?;
+        let new_dir = self.downcast(new_dir)?;
+        let same = new_dir.ino == self.ino;
+        if same && name == new_name {
+            return Ok(());
+        }
+        let volume = &self.volume;
+        let mut state = volume.lock();
+        let source = {
+            let dir = self.disk.lock();
+            check_dir(&dir)?;
+            volume.find(&dir, name)?
+        };
+        let replaced = {
+            let dir = new_dir.disk.lock();
+            check_dir(&dir)?;
+            match volume.find(&dir, new_name) {
+                Ok(record) => Some(record),
+                Err(FsError::NotFound) => None,
+                Err(error) => return Err(error),
+            }
+        };
+        let kind = self.record_kind(&state, &source)?;
+        let moves_directory = kind == FileType::Directory && !same;
+
+        // Whatever `new_name` refers to is replaced, as long as the types are compatible.
+        let mut replaced_directory = false;
+        match &replaced {
+            Some(replaced) if replaced.ino == source.ino => return Ok(()),
+            Some(replaced) => {
+                match (
+                    self.record_kind(&state, replaced)? == FileType::Directory,
+                    kind == FileType::Directory,
+                ) {
+                    (true, false) => return Err(FsError::IsADirectory),
+                    (false, true) => return Err(FsError::NotADirectory),
+                    _ => {}
+                }
+                let ((directory, unused), live) =
+                    Self::update(volume, &mut state, replaced.ino, |disk| {
+                        drop_link(volume, disk)
+                    })?;
+                volume.retarget_record(replaced, source.ino, kind)?;
+                if unused && !live {
+                    volume.release(&mut state, replaced.ino)?;
+                }
+                replaced_directory = directory;
+            }
+            None => {
+                if moves_directory && new_dir.disk.lock().links() >= LINK_MAX {
+                    return Err(FsError::NoSpace);
+                }
+                let mut dir = new_dir.disk.lock();
+                let result = volume.add_record(&mut state, &mut dir, new_name, source.ino, kind);
+                new_dir.store(&state, &dir)?;
+                result?;
+            }
+        }
+
+        // Adding the new name may have moved the records around the old one.
+        let source = volume.find(&self.disk.lock(), name)?;
+        volume.remove_record(&source)?;
+
+        // A directory that moved has to point back at its new parent, and each `..` counts
+        // as a link to the directory it names.
+        if moves_directory {
+            Self::update(volume, &mut state, source.ino, |disk| {
+                let parent = volume.find(disk, "..")?;
+                volume.retarget_record(&parent, new_dir.ino, FileType::Directory)
+            })?;
+        }
+        let gained = i32::from(moves_directory) - i32::from(replaced_directory);
+        let lost = i32::from(moves_directory);
+        if same {
+            self.adjust_links(&state, gained - lost)
+        } else {
+            self.adjust_links(&state, -lost)?;
+            new_dir.adjust_links(&state, gained)
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,392 @@
+//! The second extended filesystem, as made by `mke2fs`.
+//!
+//! A volume is divided into groups of blocks, each with a bitmap of its free blocks, a bitmap
+//! of its free inodes and a table of inodes, all found through the group descriptors that
+//! follow the superblock. Inodes are numbered from 1, the root directory being inode 2, and
+//! point at the blocks of their file directly or through up to three levels of indirect
+//! blocks. A directory is a file made of variable-length records, each naming an inode.
+//!
+//! Volumes using a feature this driver does not know are refused, unless the feature only
+//! matters when writing, in which case they are mounted read-only. As with FAT, a file that
+//! is deleted while still open keeps its blocks until the last reference to it goes.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+mod bitmap;
+mod blocks;
+mod dir;
+mod inode;
+
+use inode::Ext2Inode;
+
+use super::{Filesystem, FsError, Inode};
+use crate::{
+    drivers::block::{self, BlockDevice},
+    info,
+    sync::{Mutex, MutexGuard, SpinLock},
+    warn,
+};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+/// Set in the state field when the volume was cleanly unmounted.
+const STATE_VALID: u16 = 1;
+
+/// Revision 0 volumes have fixed-size inodes and no feature flags.
+const GOOD_OLD_REV: u32 = 0;
+const GOOD_OLD_INODE_SIZE: u32 = 128;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+
+/// Directory records say what type of file they name.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+/// Only some groups keep a backup of the superblock, which only matters when resizing.
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+/// Regular files may be 2 GiB or larger.
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const RO_COMPAT_SUPPORTED: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+const ROOT_INO: u32 = 2;
+/// As on Linux, blocks may not be larger than a page.
+const MAX_BLOCK_SIZE: u32 = 4096;
+
+/// What is known about a block group, as its descriptor says.
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    directories: u16,
+}
+
+/// What changes as the volume is used, guarded by a single lock.
+struct State {
+    groups: Vec<Group>,
+    /// How many blocks and inodes are free, as the superblock records them.
+    free_blocks: u32,
+    free_inodes: u32,
+    /// The inodes in use, by number.
+    inodes: BTreeMap<u32, Weak<Ext2Inode>>,
+}
+
+/// A mounted ext2 volume and its geometry, as read from the superblock.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u32,
+    blocks_count: u32,
+    /// The block holding the superblock, which is where the first group starts.
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inodes_count: u32,
+    inode_size: u32,
+    /// The first inode that is not reserved for the filesystem itself.
+    first_ino: u32,
+    /// Whether directory records carry the type of the file they name.
+    filetype: bool,
+    large_file: bool,
+    read_only: bool,
+    state: Mutex<State>,
+    /// The deleted inodes whose last reference has gone, to be freed the next time the state
+    /// is locked.
+    orphans: SpinLock<Vec<u32>>,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Volume {
+    /// Locks the state, first freeing the deleted inodes nothing uses any more.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock();
+        let orphans = core::mem::take(&mut *self.orphans.lock());
+        for ino in orphans {
+            if let Err(error) = self.release(&mut state, ino) {
+                warn!(
+                    "ext2: {}: cannot free inode {}: {}",
+                    self.device.name(),
+                    ino,
+                    error
+                );
+            }
+        }
+        state
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.read_only {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads `buffer.len()` bytes starting at byte `offset` of the device.
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        Ok(block::read_bytes(&*self.device, offset, buffer)?)
+    }
+
+    /// Writes `data` starting at byte `offset` of the device.
+    fn write_bytes(&self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        Ok(block::write_bytes(&*self.device, offset, data)?)
+    }
+
+    /// Where a block starts on the device, in bytes.
+    fn block_offset(&self, block: u32) -> u64 {
+        u64::from(block) * u64::from(self.block_size)
+    }
+
+    fn is_valid_block(&self, block: u32) -> bool {
+        (self.first_data_block..self.blocks_count).contains(&block)
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, FsError> {
+        if !self.is_valid_block(block) {
+            warn!("ext2: {}: bad block number {}", self.device.name(), block);
+            return Err(FsError::Io);
+        }
+        let mut bytes = vec![0; self.block_size as usize];
+        self.read_bytes(self.block_offset(block), &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_block(&self, block: u32, bytes: &[u8]) -> Result<(), FsError> {
+        self.write_bytes(self.block_offset(block), bytes)
+    }
+
+    fn zero_block(&self, block: u32) -> Result<(), FsError> {
+        self.write_block(block, &vec![0; self.block_size as usize])
+    }
+
+    /// How many 512-byte units of an inode's block count one block makes.
+    fn sectors_per_block(&self) -> u32 {
+        self.block_size / 512
+    }
+
+    /// Writes the free counts of `group` to its descriptor and the totals to the superblock.
+    fn write_counts(&self, state: &State, group: usize) -> Result<(), FsError> {
+        let descriptor = &state.groups[group];
+        let mut counts = [0; 6];
+        counts[..2].copy_from_slice(&descriptor.free_blocks.to_le_bytes());
+        counts[2..4].copy_from_slice(&descriptor.free_inodes.to_le_bytes());
+        counts[4..].copy_from_slice(&descriptor.directories.to_le_bytes());
+        let table = self.block_offset(self.first_data_block + 1);
+        self.write_bytes(table + group as u64 * GROUP_DESCRIPTOR_SIZE + 12, &counts)?;
+
+        let mut totals = [0; 8];
+        totals[..4].copy_from_slice(&state.free_blocks.to_le_bytes());
+        totals[4..].copy_from_slice(&state.free_inodes.to_le_bytes());
+        self.write_bytes(SUPERBLOCK_OFFSET + 12, &totals)
+    }
+}
+
+/// Reads the superblock and group descriptors of `device`.
+fn read_volume(device: Arc<dyn BlockDevice>) -> Result<Volume, FsError> {
+    let size = device.sector_count() * device.sector_size() as u64;
+    if size < SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE as u64 {
+        return Err(FsError::InvalidArgument);
+    }
+    let mut superblock = vec![0; SUPERBLOCK_SIZE];
+    block::read_bytes(&*device, SUPERBLOCK_OFFSET, &mut superblock)?;
+    if u16_at(&superblock, 56) != MAGIC {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let inodes_count = u32_at(&superblock, 0);
+    let blocks_count = u32_at(&superblock, 4);
+    let first_data_block = u32_at(&superblock, 20);
+    let log_block_size = u32_at(&superblock, 24);
+    let blocks_per_group = u32_at(&superblock, 32);
+    let inodes_per_group = u32_at(&superblock, 40);
+    let state = u16_at(&superblock, 58);
+    let (inode_size, first_ino, incompat, ro_compat) = match u32_at(&superblock, 76) {
+        GOOD_OLD_REV => (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INO, 0, 0),
+        _ => (
+            u32::from(u16_at(&superblock, 88)),
+            u32_at(&superblock, 84),
+            u32_at(&superblock, 96),
+            u32_at(&superblock, 100),
+        ),
+    };
+    let name = device.name();
+
+    let block_size = 1024u32
+        .checked_shl(log_block_size)
+        .filter(|&size| size <= MAX_BLOCK_SIZE)
+        .ok_or(FsError::InvalidArgument)?;
+    let group_count = blocks_count
+        .saturating_sub(first_data_block)
+        .div_ceil(blocks_per_group.max(1));
+    let valid = blocks_per_group != 0
+        && blocks_per_group <= block_size * 8
+        && inodes_per_group != 0
+        && inodes_per_group <= block_size * 8
+        && group_count != 0
+        && u64::from(inodes_count) == u64::from(inodes_per_group) * u64::from(group_count)
+        && u64::from(blocks_count) * u64::from(block_size) <= size
+        && inode_size.is_power_of_two()
+        && (GOOD_OLD_INODE_SIZE..=block_size).contains(&inode_size)
+        && (ROOT_INO + 1..=inodes_count).contains(&first_ino);
+    if !valid {
+        warn!("ext2: {}: the superblock is inconsistent", name);
+        return Err(FsError::InvalidArgument);
+    }
+    if incompat & !INCOMPAT_SUPPORTED != 0 {
+        warn!(
+            "ext2: {}: unsupported features {:#x}",
+            name,
+            incompat & !INCOMPAT_SUPPORTED
+        );
+        return Err(FsError::NotSupported);
+    }
+    let mut read_only = device.is_read_only();
+    if ro_compat & !RO_COMPAT_SUPPORTED != 0 {
+        warn!(
+            "ext2: {}: features {:#x} can only be read, mounting read-only",
+            name,
+            ro_compat & !RO_COMPAT_SUPPORTED
+        );
+        read_only = true;
+    }
+    if state & STATE_VALID == 0 {
+        warn!("ext2: {}: was not cleanly unmounted, check it", name);
+    }
+
+    let mut table = vec![0; group_count as usize * GROUP_DESCRIPTOR_SIZE as usize];
+    block::read_bytes(
+        &*device,
+        u64::from(first_data_block + 1) * u64::from(block_size),
+        &mut table,
+    )?;
+    let groups: Vec<Group> = table
+        .chunks_exact(GROUP_DESCRIPTOR_SIZE as usize)
+        .map(|descriptor| Group {
+            block_bitmap: u32_at(descriptor, 0),
+            inode_bitmap: u32_at(descriptor, 4),
+            inode_table: u32_at(descriptor, 8),
+            free_blocks: u16_at(descriptor, 12),
+            free_inodes: u16_at(descriptor, 14),
+            directories: u16_at(descriptor, 16),
+        })
+        .collect();
+    let inode_table_blocks = (inodes_per_group * inode_size).div_ceil(block_size);
+    let in_range = |block: u32, count: u32| {
+        block >= first_data_block
+            && block
+                .checked_add(count)
+                .is_some_and(|end| end <= blocks_count)
+    };
+    if !groups.iter().all(|group| {
+        in_range(group.block_bitmap, 1)
+            && in_range(group.inode_bitmap, 1)
+            && in_range(group.inode_table, inode_table_blocks)
+    }) {
+        warn!("ext2: {}: a group descriptor is inconsistent", name);
+        return Err(FsError::InvalidArgument);
+    }
+
+    // The superblock is only brought up to date now and then, so the groups are trusted.
+    let free_blocks = groups
+        .iter()
+        .map(|group| u32::from(group.free_blocks))
+        .sum();
+    let free_inodes = groups
+        .iter()
+        .map(|group| u32::from(group.free_inodes))
+        .sum();
+    Ok(Volume {
+        device,
+        block_size,
+        blocks_count,
+        first_data_block,
+        blocks_per_group,
+        inodes_per_group,
+        inodes_count,
+        inode_size,
+        first_ino,
+        filetype: incompat & INCOMPAT_FILETYPE != 0,
+        large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+        read_only,
+        state: Mutex::named(
+            "ext2",
+            State {
+                groups,
+                free_blocks,
+                free_inodes,
+                inodes: BTreeMap::new(),
+            },
+        ),
+        orphans: SpinLock::new(Vec::new()),
+    })
+}
+
+/// A mounted ext2 volume.
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    root: Arc<Ext2Inode>,
+}
+
+impl Filesystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn read_only(&self) -> bool {
+        self.volume.read_only
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        // Everything is written as it changes, so the cache only has to be flushed.
+        drop(self.volume.lock());
+        self.volume.device.flush()?;
+        Ok(())
+    }
+
+    fn device(&self) -> Option<&Arc<dyn BlockDevice>> {
+        Some(&self.volume.device)
+    }
+}
+
+/// Opens the ext2 volume on `device`, failing with [`FsError::InvalidArgument`] if there is
+/// none.
+pub fn open(device: Arc<dyn BlockDevice>) -> Result<Arc<dyn Filesystem>, FsError> {
+    let volume = Arc::new(read_volume(device)?);
+    let mut state = volume.lock();
+    let root = Ext2Inode::get(&volume, &mut state, ROOT_INO)?;
+    if !root.is_directory() {
+        warn!(
+            "ext2: {}: the root is not a directory",
+            volume.device.name()
+        );
+        return Err(FsError::Io);
+    }
+    info!(
+        "ext2: {}: {} blocks of {} bytes, {} free, {} inodes, {} free{}",
+        volume.device.name(),
+        volume.blocks_count,
+        volume.block_size,
+        state.free_blocks,
+        volume.inodes_count,
+        state.free_inodes,
+        if volume.read_only { ", read-only" } else { "" }
+    );
+    drop(state);
+    Ok(Arc::new(Ext2Fs { volume, root }))
+}
@@ -0,0 +1,563 @@
+//! Directories, which are arrays of 32-byte entries.
+//!
+//! Each file has a short entry holding an 8.3 name in capitals along with everything else
+//! about the file. A name that does not fit 8.3 gets a made-up short name like `LONGFI~1.TXT`,
+//! and is stored in full in UTF-16 in the long entries that come right before the short one.
+//! Long entries are numbered backwards from the end of the name and carry a checksum of the
+//! short name, so that a long name left behind by software that knows nothing of them is not
+//! taken for the name of another file.
+
+use alloc::{format, string::String, vec, vec::Vec};
+
+use super::{u16_at, u32_at, FatType, State, Volume};
+use crate::fs::FsError;
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// The attributes that mark a long entry, which nothing else would have.
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// The first byte of an entry that has been deleted.
+const DELETED: u8 = 0xE5;
+/// Stands for a first byte of `DELETED` in the name of an entry that is not deleted.
+const KANJI_E5: u8 = 0x05;
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// How many UTF-16 units each long entry holds, and where they are in it.
+const LONG_ENTRY_UNITS: usize = 13;
+const LONG_ENTRY_OFFSETS: [usize; LONG_ENTRY_UNITS] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_UNITS: usize = 255;
+/// Flags of a short entry saying to show its name or its extension in lower case.
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+/// A directory can have no more entries than this.
+const MAX_ENTRIES: usize = 65536;
+
+/// 1980-01-01, the earliest date FAT can store. There is no clock to take dates from yet.
+const DEFAULT_DATE: u16 = 1 << 5 | 1;
+
+/// Characters a short name can have besides capitals and digits.
+const SHORT_NAME_SPECIALS: &[u8] = b"!#$%&'()-@^_`{}~";
+/// Characters no name can have, besides control characters.
+const FORBIDDEN: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// A short directory entry.
+#[derive(Copy, Clone)]
+pub struct RawEntry(pub [u8; ENTRY_SIZE]);
+
+impl RawEntry {
+    pub fn new(attributes: u8) -> RawEntry {
+        let mut bytes = [0; ENTRY_SIZE];
+        bytes[11] = attributes;
+        bytes[16..18].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+        bytes[18..20].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+        bytes[24..26].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+        RawEntry(bytes)
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.0[11]
+    }
+
+    pub fn set_attributes(&mut self, attributes: u8) {
+        self.0[11] = attributes;
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes() & ATTR_DIRECTORY != 0
+    }
+
+    /// The first cluster of the file, where the high half only counts on FAT32.
+    pub fn cluster(&self, kind: FatType) -> u32 {
+        let low = u32::from(u16_at(&self.0, 26));
+        match kind {
+            FatType::Fat32 => u32::from(u16_at(&self.0, 20)) << 16 | low,
+            _ => low,
+        }
+    }
+
+    pub fn set_cluster(&mut self, cluster: u32) {
+        self.0[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        self.0[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    }
+
+    pub fn size(&self) -> u32 {
+        u32_at(&self.0, 28)
+    }
+
+    pub fn set_size(&mut self, size: u32) {
+        self.0[28..32].copy_from_slice(&size.to_le_bytes());
+    }
+
+    /// When the file was last written, in seconds since the Unix epoch.
+    pub fn modified(&self) -> u64 {
+        let (time, date) = (u16_at(&self.0, 22), u16_at(&self.0, 24));
+        let (year, month, day) = (1980 + (date >> 9), (date >> 5) & 0xF, date & 0x1F);
+        if !(1..=12).contains(&month) || day == 0 {
+            return 0;
+        }
+        let seconds = u64::from(time >> 11) * 3600
+            + u64::from((time >> 5) & 0x3F) * 60
+            + u64::from(time & 0x1F) * 2;
+        days_since_epoch(year.into(), month.into(), day.into()) * 86400 + seconds
+    }
+
+    fn short_name(&self) -> [u8; 11] {
+        self.0[..11].try_into().unwrap()
+    }
+
+    fn set_short_name(&mut self, name: [u8; 11], case: u8) {
+        self.0[..11].copy_from_slice(&name);
+        self.0[12] = case;
+    }
+
+    /// The short name as it is shown, like `README.TXT`.
+    fn display_name(&self) -> String {
+        let mut name = self.short_name();
+        if name[0] == KANJI_E5 {
+            name[0] = DELETED;
+        }
+        let part = |bytes: &[u8], lower: bool| -> String {
+            let len = bytes
+                .iter()
+                .rposition(|&byte| byte != b' ')
+                .map_or(0, |i| i + 1);
+            bytes[..len]
+                .iter()
+                .map(|&byte| match lower {
+                    true => char::from(byte.to_ascii_lowercase()),
+                    false => char::from(byte),
+                })
+                .collect()
+        };
+        let base = part(&name[..8], self.0[12] & LOWER_CASE_BASE != 0);
+        let extension = part(&name[8..], self.0[12] & LOWER_CASE_EXTENSION != 0);
+        if extension.is_empty() {
+            base
+        } else {
+            format!("{}.{}", base, extension)
+        }
+    }
+}
+
+/// Counts the days from 1970-01-01 to a date of the Gregorian calendar.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // Years are counted from March, so that the leap day comes last.
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// A file found in a directory.
+pub struct Entry {
+    /// The long name, or the short one if there is none.
+    pub name: String,
+    pub raw: RawEntry,
+    /// Where the short entry is on the device, in bytes.
+    pub position: u64,
+    /// Where every entry of the file is, the long ones included.
+    pub slots: Vec<u64>,
+}
+
+fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// A long name being put together from its entries, which come last part first.
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    /// The number of the part expected next, counting down to 1.
+    next: u8,
+    slots: Vec<u64>,
+}
+
+impl LongName {
+    /// Takes in a long entry, returning `false` if it does not continue the name.
+    fn push(&mut self, position: u64, bytes: &[u8; ENTRY_SIZE]) -> bool {
+        let number = bytes[0] & 0x1F;
+        if number != self.next || number == 0 || bytes[13] != self.checksum {
+            return false;
+        }
+        let start = (usize::from(number) - 1) * LONG_ENTRY_UNITS;
+        for (i, &offset) in LONG_ENTRY_OFFSETS.iter().enumerate() {
+            self.units[start + i] = u16_at(bytes, offset);
+        }
+        self.next -= 1;
+        self.slots.push(position);
+        true
+    }
+
+    fn start(position: u64, bytes: &[u8; ENTRY_SIZE]) -> Option<LongName> {
+        let count = bytes[0] & 0x1F;
+        if count == 0 || usize::from(count) > MAX_NAME_UNITS.div_ceil(LONG_ENTRY_UNITS) {
+            return None;
+        }
+        let mut name = LongName {
+            units: vec![0; usize::from(count) * LONG_ENTRY_UNITS],
+            checksum: bytes[13],
+            next: count,
+            slots: Vec::new(),
+        };
+        name.push(position, bytes).then_some(name)
+    }
+
+    /// Returns the name if it is complete and belongs to `short`.
+    fn finish(self, short: &RawEntry) -> Option<(String, Vec<u64>)> {
+        if self.next != 0 || self.checksum != checksum(&short.short_name()) {
+            return None;
+        }
+        let len = self
+            .units
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(self.units.len());
+        Some((String::from_utf16_lossy(&self.units[..len]), self.slots))
+    }
+}
+
+/// Picks out the files among the raw entries of a directory, skipping `.`, `..` and the
+/// volume label.
+fn parse(slots: &[(u64, [u8; ENTRY_SIZE])]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long: Option<LongName> = None;
+    for &(position, bytes) in slots {
+        match bytes[0] {
+            0 => break,
+            DELETED => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+        if bytes[11] & 0x3F == ATTR_LONG_NAME {
+            long = if bytes[0] & LAST_LONG_ENTRY != 0 {
+                LongName::start(position, &bytes)
+            } else {
+                long.take()
+                    .and_then(|mut name| name.push(position, &bytes).then_some(name))
+            };
+            continue;
+        }
+
+        let raw = RawEntry(bytes);
+        let long = long.take().and_then(|name| name.finish(&raw));
+        if raw.attributes() & ATTR_VOLUME_ID != 0 || bytes[0] == b'.' {
+            continue;
+        }
+        let (name, mut slots) = long.unwrap_or_else(|| (raw.display_name(), Vec::new()));
+        slots.push(position);
+        entries.push(Entry {
+            name,
+            raw,
+            position,
+            slots,
+        });
+    }
+    entries
+}
+
+/// Compares names the way FAT does, without regard to case.
+pub fn same_name(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_uppercase)
+        .eq(b.chars().flat_map(char::to_uppercase))
+}
+
+/// Checks that `name` can be given to a file.
+pub fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > MAX_NAME_UNITS
+        || name.chars().any(|c| c < ' ' || FORBIDDEN.contains(&c))
+        // Other systems drop these, so the name would not come back the same.
+        || name.ends_with(['.', ' '])
+    {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+fn is_short_name_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || SHORT_NAME_SPECIALS.contains(&byte)
+}
+
+/// Returns the short entry name for `name` if it is a valid 8.3 name as it stands, apart from
+/// a base or extension that is all in lower case, along with the case flags that bring it
+/// back.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if !(1..=8).contains(&base.len())
+        || extension.len() > 3
+        || (name.contains('.') && extension.is_empty())
+    {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    for (part, range, flag) in [
+        (base, 0..8, LOWER_CASE_BASE),
+        (extension, 8..11, LOWER_CASE_EXTENSION),
+    ] {
+        let lower = part.bytes().any(|byte| byte.is_ascii_lowercase());
+        if lower && part.bytes().any(|byte| byte.is_ascii_uppercase()) {
+            return None;
+        }
+        if lower {
+            case |= flag;
+        }
+        for (i, byte) in part.bytes().enumerate() {
+            let byte = byte.to_ascii_uppercase();
+            if !is_short_name_char(byte) {
+                return None;
+            }
+            short[range.start + i] = byte;
+        }
+    }
+    Some((short, case))
+}
+
+/// Makes up a short name for `name` that `taken` says is not in use yet, like `LONGFI~1.TXT`.
+fn generated_short_name(
+    name: &str,
+    taken: impl Fn(&[u8; 11]) -> bool,
+) -> Result<[u8; 11], FsError> {
+    let convert = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match u8::try_from(c.to_ascii_uppercase()) {
+                Ok(byte) if is_short_name_char(byte) => byte,
+                _ => b'_',
+            })
+            .collect()
+    };
+    let name = name.trim_start_matches('.');
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) => (convert(&name[..dot]), convert(&name[dot + 1..])),
+        None => (convert(name), Vec::new()),
+    };
+    let base = if base.is_empty() { vec![b'_'] } else { base };
+
+    let mut short = [b' '; 11];
+    for (i, &byte) in extension.iter().take(3).enumerate() {
+        short[8 + i] = byte;
+    }
+    for number in 1..1_000_000 {
+        let tail = format!("~{}", number);
+        let kept = base.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..kept].copy_from_slice(&base[..kept]);
+        short[kept..kept + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken(&short) {
+            return Ok(short);
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+/// Builds the long entries that store `name`, last part first.
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_ENTRY_UNITS);
+    // The name ends with a zero unless it fills its last entry, and the rest is padding.
+    if units.len() < count * LONG_ENTRY_UNITS {
+        units.push(0);
+        units.resize(count * LONG_ENTRY_UNITS, 0xFFFF);
+    }
+    (1..=count)
+        .rev()
+        .map(|number| {
+            let mut bytes = [0; ENTRY_SIZE];
+            bytes[0] = number as u8;
+            if number == count {
+                bytes[0] |= LAST_LONG_ENTRY;
+            }
+            bytes[11] = ATTR_LONG_NAME;
+            bytes[13] = checksum;
+            let part = &units[(number - 1) * LONG_ENTRY_UNITS..][..LONG_ENTRY_UNITS];
+            for (&unit, &offset) in part.iter().zip(&LONG_ENTRY_OFFSETS) {
+                bytes[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            bytes
+        })
+        .collect()
+}
+
+fn is_free(bytes: &[u8; ENTRY_SIZE]) -> bool {
+    bytes[0] == 0 || bytes[0] == DELETED
+}
+
+impl Volume {
+    /// Reads every raw entry of the directory starting at `cluster`, or of the fixed root
+    /// directory if `cluster` is 0, along with where each is on the device.
+    fn read_slots(&self, cluster: u32) -> Result<Vec<(u64, [u8; ENTRY_SIZE])>, FsError> {
+        let regions = if cluster == 0 {
+            vec![(self.root_start, self.root_entries as usize * ENTRY_SIZE)]
+        } else {
+            self.chain(cluster)?
+                .into_iter()
+                .map(|cluster| (self.cluster_offset(cluster), self.cluster_size as usize))
+                .collect()
+        };
+        let mut slots = Vec::new();
+        for (start, len) in regions {
+            let mut bytes = vec![0; len];
+            self.read_bytes(start, &mut bytes)?;
+            for (i, entry) in bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+                slots.push((start + (i * ENTRY_SIZE) as u64, entry.try_into().unwrap()));
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Lists the files in the directory starting at `cluster`.
+    pub(super) fn entries(&self, cluster: u32) -> Result<Vec<Entry>, FsError> {
+        Ok(parse(&self.read_slots(cluster)?))
+    }
+
+    /// Finds the file called `name`, by its long or its short name.
+    pub(super) fn find(&self, cluster: u32, name: &str) -> Result<Entry, FsError> {
+        self.entries(cluster)?
+            .into_iter()
+            .find(|entry| {
+                same_name(&entry.name, name) || same_name(&entry.raw.display_name(), name)
+            })
+            .ok_or(FsError::NotFound)
+    }
+
+    pub(super) fn write_entry(&self, position: u64, raw: &RawEntry) -> Result<(), FsError> {
+        self.write_bytes(position, &raw.0)
+    }
+
+    /// Adds a file called `name` to the directory starting at `cluster`, with everything but
+    /// the short name, which is filled in, taken from `raw`. Returns where its short entry
+    /// went.
+    pub(super) fn add_entry(
+        &self,
+        state: &mut State,
+        cluster: u32,
+        name: &str,
+        raw: &mut RawEntry,
+    ) -> Result<u64, FsError> {
+        check_name(name)?;
+        let mut slots = self.read_slots(cluster)?;
+        let existing = parse(&slots);
+        if existing
+            .iter()
+            .any(|entry| same_name(&entry.name, name) || same_name(&entry.raw.display_name(), name))
+        {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let mut new = match exact_short_name(name) {
+            Some((short, case)) => {
+                raw.set_short_name(short, case);
+                Vec::new()
+            }
+            None => {
+                let short = generated_short_name(name, |short| {
+                    existing
+                        .iter()
+                        .any(|entry| entry.raw.short_name() == *short)
+                })?;
+                raw.set_short_name(short, 0);
+                long_entries(name, checksum(&short))
+            }
+        };
+        new.push(raw.0);
+
+        // Look for enough free entries in a row, growing the directory if there are none.
+        let mut run = 0;
+        let mut start = None;
+        for (i, (_, bytes)) in slots.iter().enumerate() {
+            run = if is_free(bytes) { run + 1 } else { 0 };
+            if run == new.len() {
+                start = Some(i + 1 - run);
+                break;
+            }
+        }
+        let start = match start {
+            Some(start) => start,
+            None if cluster == 0 => return Err(FsError::NoSpace),
+            None => {
+                let start = slots.len() - run;
+                let missing = new.len() - run;
+                let per_cluster = self.cluster_size as usize / ENTRY_SIZE;
+                if slots.len() + missing > MAX_ENTRIES {
+                    return Err(FsError::NoSpace);
+                }
+                let mut last = *self.chain(cluster)?.last().ok_or(FsError::Io)?;
+                for _ in 0..missing.div_ceil(per_cluster) {
+                    last = self.allocate(state, Some(last))?;
+                    self.zero_cluster(last)?;
+                    let offset = self.cluster_offset(last);
+                    slots.extend(
+                        (0..per_cluster)
+                            .map(|i| (offset + (i * ENTRY_SIZE) as u64, [0; ENTRY_SIZE])),
+                    );
+                }
+                start
+            }
+        };
+
+        let positions: Vec<u64> = slots[start..start + new.len()]
+            .iter()
+            .map(|&(position, _)| position)
+            .collect();
+        for (&position, bytes) in positions.iter().zip(&new) {
+            self.write_bytes(position, bytes)?;
+        }
+        Ok(*positions.last().unwrap())
+    }
+
+    /// Marks every entry of a file as deleted.
+    pub(super) fn remove_entry(&self, entry: &Entry) -> Result<(), FsError> {
+        for &position in &entry.slots {
+            self.write_bytes(position, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// Fills a newly allocated directory cluster with its `.` and `..` entries.
+    pub(super) fn init_directory(&self, cluster: u32, parent: u32) -> Result<(), FsError> {
+        self.zero_cluster(cluster)?;
+        let offset = self.cluster_offset(cluster);
+        for (i, (name, target)) in [(&b"."[..], cluster), (&b".."[..], parent)]
+            .into_iter()
+            .enumerate()
+        {
+            let mut raw = RawEntry::new(ATTR_DIRECTORY);
+            let mut short = [b' '; 11];
+            short[..name.len()].copy_from_slice(name);
+            raw.set_short_name(short, 0);
+            raw.set_cluster(target);
+            self.write_entry(offset + (i * ENTRY_SIZE) as u64, &raw)?;
+        }
+        Ok(())
+    }
+
+    /// Points the `..` entry of the directory starting at `cluster` at a new parent.
+    pub(super) fn set_parent(&self, cluster: u32, parent: u32) -> Result<(), FsError> {
+        let position = self.cluster_offset(cluster) + ENTRY_SIZE as u64;
+        let mut raw = RawEntry([0; ENTRY_SIZE]);
+        self.read_bytes(position, &mut raw.0)?;
+        if &raw.0[..2] != b".." {
+            return Err(FsError::Io);
+        }
+        raw.set_cluster(parent);
+        self.write_entry(position, &raw)
+    }
+}
@@ -0,0 +1,583 @@
+//! The inodes of a FAT volume, each standing for a directory entry.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+
+use super::{
+    dir::{self, Entry, RawEntry, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_READ_ONLY, ENTRY_SIZE},
+    State, Volume,
+};
+use crate::{
+    fs::{DirEntry, FileType, FsError, Inode, Metadata},
+    sync::Mutex,
+};
+
+/// How many inodes the table holds before the ones nothing uses any more are cleared out.
+const PRUNE_THRESHOLD: usize = 64;
+
+struct Node {
+    /// Where the short entry of the file is on the device, or `None` for the root directory.
+    position: Option<u64>,
+    /// The short entry as it is on the device.
+    raw: RawEntry,
+    /// Whether the file has been deleted while something still used it.
+    removed: bool,
+    /// The cluster last looked up, as its index in the chain and its number, so that going
+    /// through a file does not walk its chain from the start every time.
+    cursor: Option<(u32, u32)>,
+}
+
+pub struct FatInode {
+    volume: Arc<Volume>,
+    /// Held across the disk I/O of reads and writes, so it has to be a sleeping lock.
+    node: Mutex<Node>,
+}
+
+impl FatInode {
+    pub(super) fn root(volume: &Arc<Volume>) -> Arc<FatInode> {
+        let mut raw = RawEntry::new(ATTR_DIRECTORY);
+        raw.set_cluster(volume.root_cluster);
+        Arc::new(FatInode {
+            volume: volume.clone(),
+            node: Mutex::new(Node {
+                position: None,
+                raw,
+                removed: false,
+                cursor: None,
+            }),
+        })
+    }
+
+    /// Returns the inode of the file whose short entry is at `position`, making one up if
+    /// nothing uses it yet.
+    fn get(volume: &Arc<Volume>, state: &mut State, position: u64, raw: RawEntry) -> Arc<Self> {
+        if let Some(inode) = state.inodes.get(&position).and_then(Weak::upgrade) {
+            return inode;
+        }
+        if state.inodes.len() >= PRUNE_THRESHOLD {
+            state.inodes.retain(|_, inode| inode.strong_count() > 0);
+        }
+        let inode = Arc::new(FatInode {
+            volume: volume.clone(),
+            node: Mutex::new(Node {
+                position: Some(position),
+                raw,
+                removed: false,
+                cursor: None,
+            }),
+        });
+        state.inodes.insert(position, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Tells the inode of a file, if it has one, that its short entry is now `raw` at
+    /// `position`.
+    fn moved(state: &mut State, inode: Option<Arc<FatInode>>, position: u64, raw: RawEntry) {
+        if let Some(inode) = inode {
+            let mut node = inode.node.lock();
+            node.position = Some(position);
+            node.raw = raw;
+            drop(node);
+            state.inodes.insert(position, Arc::downgrade(&inode));
+        }
+    }
+
+    /// Finds the FAT inode behind a `dyn Inode` of the same volume.
+    fn downcast<'a>(&self, inode: &'a Arc<dyn Inode>) -> Result<&'a FatInode, FsError> {
+        inode
+            .as_any()
+            .downcast_ref::<FatInode>()
+            .filter(|other| Arc::ptr_eq(&other.volume, &self.volume))
+            .ok_or(FsError::CrossDevice)
+    }
+
+    fn first_cluster(&self, node: &Node) -> u32 {
+        node.raw.cluster(self.volume.kind)
+    }
+
+    /// Returns the first cluster of a directory, which is 0 for the fixed root directory of
+    /// FAT12 and FAT16.
+    fn dir_cluster(&self) -> Result<u32, FsError> {
+        let node = self.node.lock();
+        if !node.raw.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        let cluster = self.first_cluster(&node);
+        match node.position {
+            None => Ok(cluster),
+            Some(_) if self.volume.is_valid_cluster(cluster) => Ok(cluster),
+            Some(_) => Err(FsError::Io),
+        }
+    }
+
+    /// What the `..` entry of a child directory refers to, which is 0 for the root.
+    fn parent_reference(&self) -> Result<u32, FsError> {
+        let position = self.node.lock().position;
+        match position {
+            None => Ok(0),
+            Some(_) => self.dir_cluster(),
+        }
+    }
+
+    /// Returns the cluster at `index` in the chain of a file.
+    fn cluster_at(&self, node: &mut Node, index: u32) -> Result<u32, FsError> {
+        let (mut current, mut cluster) = match node.cursor {
+            Some((current, cluster)) if current <= index => (current, cluster),
+            _ => (0, self.first_cluster(node)),
+        };
+        if !self.volume.is_valid_cluster(cluster) {
+            return Err(FsError::Io);
+        }
+        while current < index {
+            cluster = self.volume.next_cluster(cluster)?.ok_or(FsError::Io)?;
+            current += 1;
+        }
+        node.cursor = Some((index, cluster));
+        Ok(cluster)
+    }
+
+    /// Calls `f` with where each piece of the byte range of a file that lies within one
+    /// cluster is on the device, and the range of the piece relative to `offset`.
+    fn for_each_piece(
+        &self,
+        node: &mut Node,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(u64, core::ops::Range<usize>) -> Result<(), FsError>,
+    ) -> Result<(), FsError> {
+        let cluster_size = u64::from(self.volume.cluster_size);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let cluster = self.cluster_at(node, (position / cluster_size) as u32)?;
+            let within = position % cluster_size;
+            let count = ((cluster_size - within) as usize).min(len - done);
+            f(
+                self.volume.cluster_offset(cluster) + within,
+                done..done + count,
+            )?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// How many clusters a file of `size` bytes takes up.
+    fn clusters_for(&self, size: u64) -> u32 {
+        size.div_ceil(u64::from(self.volume.cluster_size)) as u32
+    }
+
+    /// Makes sure a file has the clusters to grow to `size` bytes.
+    fn reserve(&self, state: &mut State, node: &mut Node, size: u32) -> Result<(), FsError> {
+        let have = match self.first_cluster(node) {
+            0 => 0,
+            _ => self.clusters_for(node.raw.size().into()).max(1),
+        };
+        let need = self.clusters_for(size.into());
+        let mut last = match have {
+            0 => None,
+            _ => Some(self.cluster_at(node, have - 1)?),
+        };
+        let mut added = None;
+        for _ in have..need {
+            match self.volume.allocate(state, last) {
+                Ok(cluster) => {
+                    added.get_or_insert(cluster);
+                    last = Some(cluster);
+                }
+                Err(error) => {
+                    // Give back what was taken, leaving the chain as it was.
+                    if let Some(added) = added {
+                        if have > 0 {
+                            let tail = self.cluster_at(node, have - 1)?;
+                            self.volume.end_chain(tail)?;
+                        }
+                        self.volume.free_chain(state, added)?;
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        if let Some(added) = added.filter(|_| have == 0) {
+            node.raw.set_cluster(added);
+            node.cursor = None;
+        }
+        Ok(())
+    }
+
+    /// Fills the bytes from `start` to `end` of a file with zeroes.
+    fn zero(&self, node: &mut Node, start: u32, end: u32) -> Result<(), FsError> {
+        let zeroes = vec![0; self.volume.cluster_size as usize];
+        let volume = &self.volume;
+        self.for_each_piece(node, start.into(), (end - start) as usize, |at, range| {
+            volume.write_bytes(at, &zeroes[..range.len()])
+        })
+    }
+
+    /// Shrinks a file to `size` bytes, freeing the clusters it no longer needs.
+    fn shrink(&self, state: &mut State, node: &mut Node, size: u32) -> Result<(), FsError> {
+        let first = self.first_cluster(node);
+        if first != 0 {
+            match self.clusters_for(size.into()) {
+                0 => {
+                    self.volume.free_chain(state, first)?;
+                    node.raw.set_cluster(0);
+                }
+                keep => {
+                    let tail = self.cluster_at(node, keep - 1)?;
+                    if let Some(next) = self.volume.next_cluster(tail)? {
+                        self.volume.end_chain(tail)?;
+                        self.volume.free_chain(state, next)?;
+                    }
+                }
+            }
+        }
+        node.cursor = None;
+        node.raw.set_size(size);
+        Ok(())
+    }
+
+    /// Writes the short entry of a file back to its directory, unless it has been deleted.
+    fn store(&self, node: &Node) -> Result<(), FsError> {
+        match node.position {
+            Some(position) if !node.removed => self.volume.write_entry(position, &node.raw),
+            _ => Ok(()),
+        }
+    }
+
+    /// Deletes a file from its directory. Its clusters are freed now, or once the last
+    /// reference to its inode goes if something still uses it.
+    fn remove(&self, state: &mut State, entry: &Entry) -> Result<(), FsError> {
+        self.volume.remove_entry(entry)?;
+        self.release(state, entry)
+    }
+
+    /// Lets go of a file whose entries have been removed, freeing its clusters unless
+    /// something still uses its inode.
+    fn release(&self, state: &mut State, entry: &Entry) -> Result<(), FsError> {
+        match state
+            .inodes
+            .remove(&entry.position)
+            .and_then(|inode| inode.upgrade())
+        {
+            Some(inode) => inode.node.lock().removed = true,
+            None => {
+                let first = entry.raw.cluster(self.volume.kind);
+                if first != 0 {
+                    self.volume.free_chain(state, first)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a file whose entries were removed back to the directory starting at `dir` under
+    /// its old name, telling its inode, taken out of the table, where it went.
+    fn put_back(
+        &self,
+        state: &mut State,
+        dir: u32,
+        entry: &Entry,
+        inode: Option<Arc<FatInode>>,
+    ) -> Result<(), FsError> {
+        let mut raw = entry.raw;
+        let position = self.volume.add_entry(state, dir, &entry.name, &mut raw)?;
+        FatInode::moved(state, inode, position, raw);
+        Ok(())
+    }
+
+    /// Returns whether the directory starting at `cluster` has nothing in it.
+    fn is_empty_dir(&self, cluster: u32) -> Result<bool, FsError> {
+        if !self.volume.is_valid_cluster(cluster) {
+            return Err(FsError::Io);
+        }
+        Ok(self.volume.entries(cluster)?.is_empty())
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let node = self.node.get_mut();
+        let first = node.raw.cluster(self.volume.kind);
+        if node.removed && first != 0 {
+            // The state may be locked by whoever dropped the last reference.
+            self.volume.orphans.lock().push(first);
+        }
+    }
+}
+
+fn kind_of(raw: &RawEntry) -> FileType {
+    if raw.is_directory() {
+        FileType::Directory
+    } else {
+        FileType::File
+    }
+}
+
+fn ino_of(position: u64) -> u64 {
+    position / ENTRY_SIZE as u64
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let node = self.node.lock();
+        let kind = kind_of(&node.raw);
+        let mut mode = match kind {
+            FileType::Directory => 0o755,
+            _ => 0o644,
+        };
+        if node.raw.attributes() & ATTR_READ_ONLY != 0 {
+            mode &= !0o222;
+        }
+        Ok(Metadata {
+            ino: node.position.map_or(1, ino_of),
+            kind,
+            size: match kind {
+                FileType::Directory => 0,
+                _ => node.raw.size().into(),
+            },
+            mode,
+            nlink: match kind {
+                FileType::Directory => 2,
+                _ => 1,
+            },
+            uid: 0,
+            gid: 0,
+            mtime: match node.position {
+                Some(_) => node.raw.modified(),
+                None => 0,
+            },
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let mut state = self.volume.lock();
+        let entry = self.volume.find(self.dir_cluster()?, name)?;
+        Ok(FatInode::get(
+            &self.volume,
+            &mut state,
+            entry.position,
+            entry.raw,
+        ))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let _state = self.volume.lock();
+        Ok(self
+            .volume
+            .entries(self.dir_cluster()?)?
+            .into_iter()
+            .map(|entry| DirEntry {
+                name: entry.name,
+                ino: ino_of(entry.position),
+                kind: kind_of(&entry.raw),
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let _state = self.volume.lock();
+        let mut node = self.node.lock();
+        if node.raw.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        let size = u64::from(node.raw.size());
+        let count = size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        let volume = &self.volume;
+        self.for_each_piece(&mut node, offset, count, |at, range| {
+            volume.read_bytes(at, &mut buffer[range])
+        })?;
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        self.volume.check_writable()?;
+        let mut state = self.volume.lock();
+        let mut node = self.node.lock();
+        if node.raw.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        // Files cannot be 4 GiB or larger.
+        let end = offset
+            .checked_add(buffer.len() as u64)
+            .and_then(|end| u32::try_from(end).ok())
+            .ok_or(FsError::NoSpace)?;
+        let size = node.raw.size();
+        if end > size {
+            self.reserve(&mut state, &mut node, end)?;
+            // Anything between the old end and `offset` reads back as zeroes.
+            if offset > u64::from(size) {
+                self.zero(&mut node, size, offset as u32)?;
+            }
+            node.raw.set_size(end);
+        }
+        let volume = &self.volume;
+        self.for_each_piece(&mut node, offset, buffer.len(), |at, range| {
+            volume.write_bytes(at, &buffer[range])
+        })?;
+        let attributes = node.raw.attributes();
+        node.raw.set_attributes(attributes | ATTR_ARCHIVE);
+        self.store(&node)?;
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        self.volume.check_writable()?;
+        let mut state = self.volume.lock();
+        let mut node = self.node.lock();
+        if node.raw.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        let size = u32::try_from(size).map_err(|_| FsError::NoSpace)?;
+        let old_size = node.raw.size();
+        if size > old_size {
+            self.reserve(&mut state, &mut node, size)?;
+            self.zero(&mut node, old_size, size)?;
+            node.raw.set_size(size);
+        } else {
+            self.shrink(&mut state, &mut node, size)?;
+        }
+        let attributes = node.raw.attributes();
+        node.raw.set_attributes(attributes | ATTR_ARCHIVE);
+        self.store(&node)
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        self.volume.check_writable()?;
+        let mut state = self.volume.lock();
+        let dir = self.dir_cluster()?;
+        dir::check_name(name)?;
+        let mut raw = match kind {
+            FileType::File => RawEntry::new(ATTR_ARCHIVE),
+            FileType::Directory => RawEntry::new(ATTR_DIRECTORY),
+            _ => return Err(FsError::NotSupported),
+        };
+        if mode & 0o222 == 0 {
+            raw.set_attributes(raw.attributes() | ATTR_READ_ONLY);
+        }
+
+        let cluster = match kind {
+            FileType::Directory => {
+                let cluster = self.volume.allocate(&mut state, None)?;
+                let initialized = self
+                    .parent_reference()
+                    .and_then(|parent| self.volume.init_directory(cluster, parent));
+                if let Err(error) = initialized {
+                    self.volume.free_chain(&mut state, cluster)?;
+                    return Err(error);
+                }
+                raw.set_cluster(cluster);
+                Some(cluster)
+            }
+            _ => None,
+        };
+        let position = match self.volume.add_entry(&mut state, dir, name, &mut raw) {
+            Ok(position) => position,
+            Err(error) => {
+                if let Some(cluster) = cluster {
+                    self.volume.free_chain(&mut state, cluster)?;
+                }
+                return Err(error);
+            }
+        };
+        Ok(FatInode::get(&self.volume, &mut state, position, raw))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.volume.check_writable()?;
+        let mut state = self.volume.lock();
+        let entry = self.volume.find(self.dir_cluster()?, name)?;
+        if entry.raw.is_directory() && !self.is_empty_dir(entry.raw.cluster(self.volume.kind))? {
+            return Err(FsError::NotEmpty);
+        }
+        self.remove(&mut state, &entry)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn link(&self, _name: &str, _inode: &Arc<dyn Inode>) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn rename(&self, name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> Result<(), FsError> {
+        self.volume.check_writable()?;
+        let new_dir = self.downcast(new_dir)?;
+        let mut state = self.volume.lock();
+        let (from, to) = (self.dir_cluster()?, new_dir.dir_cluster()?);
+        if from == to && name == new_name {
+            return Ok(());
+        }
+        dir::check_name(new_name)?;
+        let entry = self.volume.find(from, name)?;
+
+        // Whatever `new_name` refers to is replaced, as long as the types are compatible. It
+        // may also be the file itself, when only the case of its name changes.
+        let replaced = match self.volume.find(to, new_name) {
+            Ok(replaced) if replaced.position != entry.position => {
+                match (replaced.raw.is_directory(), entry.raw.is_directory()) {
+                    (true, true) => {
+                        if !self.is_empty_dir(replaced.raw.cluster(self.volume.kind))? {
+                            return Err(FsError::NotEmpty);
+                        }
+                    }
+                    (true, false) => return Err(FsError::IsADirectory),
+                    (false, true) => return Err(FsError::NotADirectory),
+                    (false, false) => {}
+                }
+                Some(replaced)
+            }
+            Ok(_) | Err(FsError::NotFound) => None,
+            Err(error) => return Err(error),
+        };
+
+        let inode = state
+            .inodes
+            .remove(&entry.position)
+            .and_then(|inode| inode.upgrade());
+        let mut raw = entry.raw;
+        self.volume.remove_entry(&entry)?;
+        // The replaced file only loses its name until the new one is in place, so that it can
+        // be put back if adding that fails.
+        let added = match &replaced {
+            Some(replaced) => self.volume.remove_entry(replaced),
+            None => Ok(()),
+        }
+        .and_then(|()| self.volume.add_entry(&mut state, to, new_name, &mut raw));
+        let position = match added {
+            Ok(position) => position,
+            Err(error) => {
+                // Put both files back where they were, which they still fit.
+                if let Some(replaced) = &replaced {
+                    let replaced_inode = state
+                        .inodes
+                        .remove(&replaced.position)
+                        .and_then(|inode| inode.upgrade());
+                    self.put_back(&mut state, to, replaced, replaced_inode)?;
+                }
+                self.put_back(&mut state, from, &entry, inode)?;
+                return Err(error);
+            }
+        };
+        if let Some(replaced) = &replaced {
+            self.release(&mut state, replaced)?;
+        }
+        if raw.is_directory() && from != to {
+            self.volume
+                .set_parent(raw.cluster(self.volume.kind), new_dir.parent_reference()?)?;
+        }
+        FatInode::moved(&mut state, inode, position, raw);
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
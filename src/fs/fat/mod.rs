@@ -0,0 +1,371 @@
+//! FAT12, FAT16 and FAT32 volumes, with VFAT long filenames.
+//!
+//! A volume is laid out as reserved sectors holding the boot sector (and on FAT32 the FSInfo
+//! sector), one or more copies of the file allocation table, on FAT12 and FAT16 a fixed-size
+//! root directory, and then the data area, which is divided into clusters. Each file and
+//! directory is a chain of clusters linked through the table.
+//!
+//! FAT has no inodes: everything there is to know about a file lives in its directory entry.
+//! The inodes handed to the VFS are therefore made up on the fly, one per directory entry,
+//! and are kept in a table so that every path to a file leads to the same inode. A file that
+//! is deleted while still open keeps its clusters until the last reference to it goes.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+
+mod dir;
+mod inode;
+mod table;
+
+use inode::FatInode;
+
+use super::{Filesystem, FsError, Inode};
+use crate::{
+    drivers::block::{self, BlockDevice},
+    info,
+    sync::{Mutex, MutexGuard, SpinLock},
+    warn,
+};
+
+const BOOT_JUMP: [u8; 2] = [0xEB, 0xE9];
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+/// What the FSInfo sector says when it does not know a value.
+const FSINFO_UNKNOWN: u32 = u32::MAX;
+/// Volumes with fewer clusters than these are FAT12 or FAT16, whatever they call themselves.
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65525;
+const FAT32_MAX_CLUSTERS: u32 = 0x0FFF_FFF5;
+/// The largest cluster there is, 64 KiB.
+const MAX_CLUSTER_SIZE: u32 = 64 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    const fn name(&self) -> &'static str {
+        match self {
+            FatType::Fat12 => "FAT12",
+            FatType::Fat16 => "FAT16",
+            FatType::Fat32 => "FAT32",
+        }
+    }
+}
+
+/// What changes as the volume is used, guarded by a single lock.
+struct State {
+    /// How many clusters are free.
+    free: u32,
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+    /// Whether the FSInfo sector is behind `free` and `next_free`.
+    fsinfo_dirty: bool,
+    /// The inodes in use, by the position of their directory entry.
+    inodes: BTreeMap<u64, Weak<FatInode>>,
+}
+
+/// A mounted FAT volume and its geometry, as read from the boot sector.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    kind: FatType,
+    cluster_size: u32,
+    /// Where the first copy of the table starts, in bytes.
+    fat_start: u64,
+    /// The size of each copy of the table, in bytes.
+    fat_size: u64,
+    fat_count: u32,
+    /// The only copy of the table in use, if the others are not kept in step with it.
+    active_fat: Option<u32>,
+    /// Where the fixed root directory of FAT12 and FAT16 starts, in bytes.
+    root_start: u64,
+    root_entries: u32,
+    /// The first cluster of the root directory on FAT32.
+    root_cluster: u32,
+    data_start: u64,
+    /// How many clusters the data area has. They are numbered from 2.
+    cluster_count: u32,
+    /// Where the FSInfo sector of FAT32 is, in bytes, if it has a valid one.
+    fsinfo: Option<u64>,
+    state: Mutex<State>,
+    /// The first clusters of deleted files whose last reference has gone, to be freed the
+    /// next time the state is locked.
+    orphans: SpinLock<Vec<u32>>,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Volume {
+    /// Locks the state, first freeing the clusters of deleted files nothing uses any more.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock();
+        let orphans = core::mem::take(&mut *self.orphans.lock());
+        for cluster in orphans {
+            if let Err(error) = self.free_chain(&mut state, cluster) {
+                warn!(
+                    "fat: {}: cannot free clusters: {}",
+                    self.device.name(),
+                    error
+                );
+            }
+        }
+        state
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.device.is_read_only() {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads `buffer.len()` bytes starting at byte `offset` of the device.
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        Ok(block::read_bytes(&*self.device, offset, buffer)?)
+    }
+
+    /// Writes `data` starting at byte `offset` of the device.
+    fn write_bytes(&self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        Ok(block::write_bytes(&*self.device, offset, data)?)
+    }
+
+    /// Where a cluster of the data area starts, in bytes.
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - 2) * u64::from(self.cluster_size)
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn zero_cluster(&self, cluster: u32) -> Result<(), FsError> {
+        self.write_bytes(
+            self.cluster_offset(cluster),
+            &vec![0; self.cluster_size as usize],
+        )
+    }
+
+    /// Records the free count and next free cluster in the FSInfo sector, if they changed.
+    fn write_fsinfo(&self, state: &mut State) -> Result<(), FsError> {
+        let Some(offset) = self.fsinfo.filter(|_| state.fsinfo_dirty) else {
+            return Ok(());
+        };
+        let mut values = [0; 8];
+        values[..4].copy_from_slice(&state.free.to_le_bytes());
+        values[4..].copy_from_slice(&state.next_free.to_le_bytes());
+        self.write_bytes(offset + FSINFO_FREE_COUNT as u64, &values)?;
+        state.fsinfo_dirty = false;
+        Ok(())
+    }
+}
+
+/// Reads the boot sector of `device` and works out the layout of the volume on it.
+fn read_volume(device: Arc<dyn BlockDevice>) -> Result<Volume, FsError> {
+    if device.sector_size() < 512 || device.sector_count() == 0 {
+        return Err(FsError::InvalidArgument);
+    }
+    let mut boot = vec![0; device.sector_size()];
+    device.read(0, &mut boot)?;
+
+    let bytes_per_sector = u32::from(u16_at(&boot, 11));
+    let sectors_per_cluster = u32::from(boot[13]);
+    let reserved = u32::from(u16_at(&boot, 14));
+    let fat_count = u32::from(boot[16]);
+    let root_entries = u32::from(u16_at(&boot, 17));
+    let fat_sectors = match u16_at(&boot, 22) {
+        0 => u32_at(&boot, 36),
+        sectors => u32::from(sectors),
+    };
+    let total_sectors = match u16_at(&boot, 19) {
+        0 => u32_at(&boot, 32),
+        sectors => u32::from(sectors),
+    };
+    if !BOOT_JUMP.contains(&boot[0])
+        || !(512..=4096).contains(&bytes_per_sector)
+        || !bytes_per_sector.is_power_of_two()
+        || !sectors_per_cluster.is_power_of_two()
+        || bytes_per_sector * sectors_per_cluster > MAX_CLUSTER_SIZE
+        || reserved == 0
+        || fat_count == 0
+        || fat_sectors == 0
+    {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let data_sector = u64::from(reserved)
+        + u64::from(fat_count) * u64::from(fat_sectors)
+        + u64::from(root_sectors);
+    if data_sector >= u64::from(total_sectors) {
+        return Err(FsError::InvalidArgument);
+    }
+    let cluster_count =
+        ((u64::from(total_sectors) - data_sector) / u64::from(sectors_per_cluster)) as u32;
+    let kind = match cluster_count {
+        count if count < FAT12_MAX_CLUSTERS => FatType::Fat12,
+        count if count < FAT16_MAX_CLUSTERS => FatType::Fat16,
+        count if count <= FAT32_MAX_CLUSTERS => FatType::Fat32,
+        _ => return Err(FsError::InvalidArgument),
+    };
+
+    let bytes_per_sector = u64::from(bytes_per_sector);
+    let volume_size = u64::from(total_sectors) * bytes_per_sector;
+    if volume_size > device.sector_count() * device.sector_size() as u64 {
+        warn!("fat: {}: volume is larger than the device", device.name());
+        return Err(FsError::InvalidArgument);
+    }
+    let fat_size = u64::from(fat_sectors) * bytes_per_sector;
+    let fat_bits = match kind {
+        FatType::Fat12 => 12,
+        FatType::Fat16 => 16,
+        FatType::Fat32 => 32,
+    };
+    if fat_size * 8 < (u64::from(cluster_count) + 2) * fat_bits {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let (active_fat, root_cluster, fsinfo_sector) = if kind == FatType::Fat32 {
+        let flags = u16_at(&boot, 40);
+        // The version has to be 0.0 and there is no fixed root directory on FAT32.
+        if u16_at(&boot, 42) != 0 || root_entries != 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let active = (flags & 0x80 != 0).then_some(u32::from(flags & 0x0F));
+        if active.is_some_and(|active| active >= fat_count) {
+            return Err(FsError::InvalidArgument);
+        }
+        (active, u32_at(&boot, 44), u32::from(u16_at(&boot, 48)))
+    } else {
+        (None, 0, 0)
+    };
+
+    let fat_start = u64::from(reserved) * bytes_per_sector;
+    let root_start = fat_start + u64::from(fat_count) * fat_size;
+    let mut volume = Volume {
+        device,
+        kind,
+        cluster_size: bytes_per_sector as u32 * sectors_per_cluster,
+        fat_start,
+        fat_size,
+        fat_count,
+        active_fat,
+        root_start,
+        root_entries,
+        root_cluster,
+        data_start: data_sector * bytes_per_sector,
+        cluster_count,
+        fsinfo: None,
+        state: Mutex::named(
+            "fat",
+            State {
+                free: 0,
+                next_free: 2,
+                fsinfo_dirty: false,
+                inodes: BTreeMap::new(),
+            },
+        ),
+        orphans: SpinLock::new(Vec::new()),
+    };
+    if kind == FatType::Fat32 && !volume.is_valid_cluster(root_cluster) {
+        return Err(FsError::InvalidArgument);
+    }
+
+    // The FSInfo sector only holds hints, so anything out of range in it is worked out anew.
+    let (mut free, mut next_free) = (FSINFO_UNKNOWN, FSINFO_UNKNOWN);
+    if (1..reserved).contains(&fsinfo_sector) {
+        let offset = u64::from(fsinfo_sector) * bytes_per_sector;
+        let mut fsinfo = [0; 512];
+        volume.read_bytes(offset, &mut fsinfo)?;
+        if u32_at(&fsinfo, 0) == FSINFO_LEAD_SIGNATURE
+            && u32_at(&fsinfo, 484) == FSINFO_STRUCT_SIGNATURE
+            && u32_at(&fsinfo, 508) == FSINFO_TRAIL_SIGNATURE
+        {
+            volume.fsinfo = Some(offset);
+            free = u32_at(&fsinfo, FSINFO_FREE_COUNT);
+            next_free = u32_at(&fsinfo, FSINFO_NEXT_FREE);
+        }
+    }
+    let mut fsinfo_dirty = false;
+    if free > cluster_count {
+        free = volume.count_free()?;
+        fsinfo_dirty = true;
+    }
+    if !volume.is_valid_cluster(next_free) {
+        next_free = 2;
+        fsinfo_dirty = true;
+    }
+    let state = volume.state.get_mut();
+    state.free = free;
+    state.next_free = next_free;
+    state.fsinfo_dirty = fsinfo_dirty;
+    Ok(volume)
+}
+
+/// A mounted FAT volume.
+pub struct FatFs {
+    volume: Arc<Volume>,
+    root: Arc<FatInode>,
+}
+
+impl Filesystem for FatFs {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn read_only(&self) -> bool {
+        self.volume.device.is_read_only()
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let mut state = self.volume.lock();
+        if !self.volume.device.is_read_only() {
+            self.volume.write_fsinfo(&mut state)?;
+        }
+        drop(state);
+        self.volume.device.flush()?;
+        Ok(())
+    }
+
+    fn device(&self) -> Option<&Arc<dyn BlockDevice>> {
+        Some(&self.volume.device)
+    }
+}
+
+/// Opens the FAT volume on `device`, failing with [`FsError::InvalidArgument`] if there is
+/// none.
+pub fn open(device: Arc<dyn BlockDevice>) -> Result<Arc<dyn Filesystem>, FsError> {
+    let volume = Arc::new(read_volume(device)?);
+    let root = FatInode::root(&volume);
+    let state = volume.lock();
+    info!(
+        "fat: {}: {} volume, {} clusters of {} bytes, {} free",
+        volume.device.name(),
+        volume.kind.name(),
+        volume.cluster_count,
+        volume.cluster_size,
+        state.free
+    );
+    drop(state);
+    Ok(Arc::new(FatFs { volume, root }))
+}
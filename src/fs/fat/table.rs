@@ -0,0 +1,226 @@
+//! The file allocation table, which chains the clusters of each file together.
+//!
+//! Each cluster has an entry in the table saying which cluster follows it in its file, that
+//! it is the last one, or that it is free. Entries are 12, 16 or 28 bits wide, the top 4 bits
+//! of a FAT32 entry being reserved. Every change is written to each copy of the table, unless
+//! the volume says only one of them is in use.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{FatType, State, Volume};
+use crate::{fs::FsError, warn};
+
+const FREE: u32 = 0;
+/// How many entries are read at a time when scanning the table.
+const SCAN_ENTRIES: u32 = 1024;
+
+impl Volume {
+    /// Where the entry of `cluster` is within a copy of the table, in bytes.
+    fn entry_offset(&self, cluster: u32) -> u64 {
+        let cluster = u64::from(cluster);
+        match self.kind {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    /// How many bytes have to be read to get at an entry.
+    fn entry_width(&self) -> usize {
+        match self.kind {
+            FatType::Fat12 | FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+
+    /// The value that marks the last cluster of a chain. Anything from `end_of_chain() & !7`
+    /// up means the same.
+    fn end_of_chain(&self) -> u32 {
+        match self.kind {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// The copy of the table that is read from.
+    fn read_copy(&self) -> u64 {
+        self.fat_start + u64::from(self.active_fat.unwrap_or(0)) * self.fat_size
+    }
+
+    /// Reads the entries of `count` clusters from `first` on.
+    fn read_entries(&self, first: u32, count: u32) -> Result<Vec<u32>, FsError> {
+        let start = self.entry_offset(first);
+        let end = self.entry_offset(first + count - 1) + self.entry_width() as u64;
+        let mut bytes = vec![0; (end - start) as usize];
+        self.read_bytes(self.read_copy() + start, &mut bytes)?;
+        Ok((first..first + count)
+            .map(|cluster| {
+                let at = (self.entry_offset(cluster) - start) as usize;
+                match self.kind {
+                    FatType::Fat12 => {
+                        let pair = u32::from(u16::from_le_bytes([bytes[at], bytes[at + 1]]));
+                        if cluster % 2 == 1 {
+                            pair >> 4
+                        } else {
+                            pair & 0xFFF
+                        }
+                    }
+                    FatType::Fat16 => u32::from(u16::from_le_bytes([bytes[at], bytes[at + 1]])),
+                    FatType::Fat32 => {
+                        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) & 0x0FFF_FFFF
+                    }
+                }
+            })
+            .collect())
+    }
+
+    fn entry(&self, cluster: u32) -> Result<u32, FsError> {
+        Ok(self.read_entries(cluster, 1)?[0])
+    }
+
+    /// Changes the entry of `cluster` in every copy of the table in use.
+    fn set_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let offset = self.entry_offset(cluster);
+        let copies = match self.active_fat {
+            Some(active) => active..active + 1,
+            None => 0..self.fat_count,
+        };
+        for copy in copies {
+            let at = self.fat_start + u64::from(copy) * self.fat_size + offset;
+            let mut bytes = [0; 4];
+            let bytes = &mut bytes[..self.entry_width()];
+            // The neighbours of a FAT12 entry and the reserved bits of a FAT32 one are kept.
+            let merged = match self.kind {
+                FatType::Fat12 => {
+                    self.read_bytes(at, bytes)?;
+                    let pair = u16::from_le_bytes([bytes[0], bytes[1]]);
+                    let value = value as u16 & 0xFFF;
+                    if cluster % 2 == 1 {
+                        u32::from(pair & 0x000F | value << 4)
+                    } else {
+                        u32::from(pair & 0xF000 | value)
+                    }
+                }
+                FatType::Fat16 => value & 0xFFFF,
+                FatType::Fat32 => {
+                    self.read_bytes(at, bytes)?;
+                    let old = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+                    old & 0xF000_0000 | value & 0x0FFF_FFFF
+                }
+            };
+            let width = bytes.len();
+            self.write_bytes(at, &merged.to_le_bytes()[..width])?;
+        }
+        Ok(())
+    }
+
+    /// Returns the cluster after `cluster` in its chain, or `None` if it is the last one.
+    pub(super) fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        match self.entry(cluster)? {
+            next if next >= self.end_of_chain() & !7 => Ok(None),
+            next if self.is_valid_cluster(next) => Ok(Some(next)),
+            next => {
+                warn!(
+                    "fat: {}: cluster {} is followed by {:#x}",
+                    self.device.name(),
+                    cluster,
+                    next
+                );
+                Err(FsError::Io)
+            }
+        }
+    }
+
+    /// Returns every cluster of the chain starting at `first`.
+    pub(super) fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        let mut cluster = Some(first).filter(|&first| first != 0);
+        while let Some(current) = cluster {
+            if !self.is_valid_cluster(current) || clusters.len() >= self.cluster_count as usize {
+                return Err(FsError::Io);
+            }
+            clusters.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(clusters)
+    }
+
+    /// Counts the free clusters by going through the whole table.
+    pub(super) fn count_free(&self) -> Result<u32, FsError> {
+        let mut free = 0;
+        let mut first = 2;
+        while first < self.cluster_count + 2 {
+            let count = SCAN_ENTRIES.min(self.cluster_count + 2 - first);
+            free += self
+                .read_entries(first, count)?
+                .into_iter()
+                .filter(|&entry| entry == FREE)
+                .count() as u32;
+            first += count;
+        }
+        Ok(free)
+    }
+
+    /// Finds a free cluster, looking from where the last one was found.
+    fn find_free(&self, state: &State) -> Result<Option<u32>, FsError> {
+        let end = self.cluster_count + 2;
+        for (mut first, last) in [(state.next_free, end), (2, state.next_free)] {
+            while first < last {
+                let count = SCAN_ENTRIES.min(last - first);
+                let entries = self.read_entries(first, count)?;
+                if let Some(index) = entries.iter().position(|&entry| entry == FREE) {
+                    return Ok(Some(first + index as u32));
+                }
+                first += count;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Takes a free cluster and makes it the last of its chain, appending it to `previous`
+    /// if given.
+    pub(super) fn allocate(
+        &self,
+        state: &mut State,
+        previous: Option<u32>,
+    ) -> Result<u32, FsError> {
+        if state.free == 0 {
+            return Err(FsError::NoSpace);
+        }
+        let Some(cluster) = self.find_free(state)? else {
+            // The free count was wrong.
+            state.free = 0;
+            state.fsinfo_dirty = true;
+            return Err(FsError::NoSpace);
+        };
+        self.set_entry(cluster, self.end_of_chain())?;
+        if let Some(previous) = previous {
+            self.set_entry(previous, cluster)?;
+        }
+        state.free -= 1;
+        state.next_free = if cluster + 1 < self.cluster_count + 2 {
+            cluster + 1
+        } else {
+            2
+        };
+        state.fsinfo_dirty = true;
+        Ok(cluster)
+    }
+
+    /// Makes `cluster` the last of its chain.
+    pub(super) fn end_chain(&self, cluster: u32) -> Result<(), FsError> {
+        self.set_entry(cluster, self.end_of_chain())
+    }
+
+    /// Frees every cluster of the chain starting at `first`.
+    pub(super) fn free_chain(&self, state: &mut State, first: u32) -> Result<(), FsError> {
+        for cluster in self.chain(first)? {
+            self.set_entry(cluster, FREE)?;
+            state.free += 1;
+            state.fsinfo_dirty = true;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+//! Open files and the descriptors that refer to them.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::ops::BitOr;
+
+use super::{
+    mount::{self, Mount},
+    FileType, FsError, Metadata,
+};
+use crate::sync::{Mutex, SpinLock};
+
+/// The most files that can be open at once.
+const MAX_OPEN_FILES: usize = 256;
+
+/// A handle to an open file.
+pub type Fd = usize;
+
+/// How a file is opened, combined with `|`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Create the file if it does not exist.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Fail if the file already exists, together with `CREATE`.
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(1 << 3);
+    /// Every write goes to the end of the file.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+    /// Fail unless the path names a directory.
+    pub const DIRECTORY: OpenFlags = OpenFlags(1 << 5);
+    /// Cut the file down to nothing when opening it for writing.
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 6);
+    pub const READ_WRITE: OpenFlags = OpenFlags(Self::READ.0 | Self::WRITE.0);
+
+    pub const fn contains(&self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+struct OpenFile {
+    dentry: mount::Dentry,
+    flags: OpenFlags,
+    /// Held across the read or write that moves it, so it has to be a sleeping lock.
+    offset: Mutex<u64>,
+}
+
+static FILES: SpinLock<Vec<Option<Arc<OpenFile>>>> = SpinLock::named("open files", Vec::new());
+
+fn get(fd: Fd) -> Result<Arc<OpenFile>, FsError> {
+    FILES
+        .lock()
+        .get(fd)
+        .and_then(Option::clone)
+        .ok_or(FsError::BadDescriptor)
+}
+
+/// Returns whether any open file lives on `mount`.
+pub(super) fn uses_mount(mount: &Arc<Mount>) -> bool {
+    FILES
+        .lock()
+        .iter()
+        .flatten()
+        .any(|file| Arc::ptr_eq(&file.dentry.mount, mount))
+}
+
+/// Opens the file at `path`, returning a descriptor for it.
+pub fn open(path: &str, flags: OpenFlags) -> Result<Fd, FsError> {
+    let dentry = match mount::resolve(path) {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(FsError::AlreadyExists)
+        }
+        Ok(dentry) => dentry,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = mount::resolve_parent(path)?;
+            let inode = parent.inode.create(&name, FileType::File, 0o644)?;
+            mount::Dentry {
+                path: super::path::join(&parent.path, &name),
+                inode,
+                mount: parent.mount,
+            }
+        }
+        Err(error) => return Err(error),
+    };
+
+    let kind = dentry.inode.metadata()?.kind;
+    if kind == FileType::Directory && flags.contains(OpenFlags::WRITE) {
+        return Err(FsError::IsADirectory);
+    }
+    if dentry.mount.fs.read_only() && flags.contains(OpenFlags::WRITE) {
+        return Err(FsError::ReadOnly);
+    }
+    if flags.contains(OpenFlags::TRUNCATE | OpenFlags::WRITE) && kind == FileType::File {
+        dentry.inode.truncate(0)?;
+    }
+    if kind != FileType::Directory && flags.contains(OpenFlags::DIRECTORY) {
+        return Err(FsError::NotADirectory);
+    }
+
+    let file = Arc::new(OpenFile {
+        dentry,
+        flags,
+        offset: Mutex::new(0),
+    });
+    let mut files = FILES.lock();
+    match files.iter().position(Option::is_none) {
+        Some(fd) => {
+            files[fd] = Some(file);
+            Ok(fd)
+        }
+        None if files.len() < MAX_OPEN_FILES => {
+            files.push(Some(file));
+            Ok(files.len() - 1)
+        }
+        None => Err(FsError::NoSpace),
+    }
+}
+
+/// Closes a descriptor. The file stays open as long as anything else still uses it.
+pub fn close(fd: Fd) -> Result<(), FsError> {
+    // The file is let go of once the table is unlocked, since that may be its last reference.
+    let file = FILES.lock().get_mut(fd).and_then(Option::take);
+    file.map(drop).ok_or(FsError::BadDescriptor)
+}
+
+/// Reads from the current position of `fd`, advancing it by the number of bytes read.
+pub fn read(fd: Fd, buffer: &mut [u8]) -> Result<usize, FsError> {
+    let file = get(fd)?;
+    if !file.flags.contains(OpenFlags::READ) {
+        return Err(FsError::PermissionDenied);
+    }
+    let mut offset = file.offset.lock();
+    let count = file.dentry.inode.read_at(*offset, buffer)?;
+    *offset += count as u64;
+    Ok(count)
+}
+
+/// Writes at the current position of `fd`, or at the end if it was opened with `APPEND`.
+pub fn write(fd: Fd, buffer: &[u8]) -> Result<usize, FsError> {
+    let file = get(fd)?;
+    if !file.flags.contains(OpenFlags::WRITE) {
+        return Err(FsError::PermissionDenied);
+    }
+    let mut offset = file.offset.lock();
+    if file.flags.contains(OpenFlags::APPEND) {
+        *offset = file.dentry.inode.metadata()?.size;
+    }
+    let count = file.dentry.inode.write_at(*offset, buffer)?;
+    *offset += count as u64;
+    Ok(count)
+}
+
+/// Moves the position of `fd`, returning the new offset from the start of the file.
+pub fn seek(fd: Fd, position: SeekFrom) -> Result<u64, FsError> {
+    let file = get(fd)?;
+    let mut offset = file.offset.lock();
+    let new = match position {
+        SeekFrom::Start(start) => Some(start),
+        SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+        SeekFrom::End(delta) => file.dentry.inode.metadata()?.size.checked_add_signed(delta),
+    };
+    *offset = new.ok_or(FsError::InvalidArgument)?;
+    Ok(*offset)
+}
+
+/// Grows or shrinks an open file to `size` bytes.
+pub fn ftruncate(fd: Fd, size: u64) -> Result<(), FsError> {
+    let file = get(fd)?;
+    if !file.flags.contains(OpenFlags::WRITE) {
+        return Err(FsError::PermissionDenied);
+    }
+    file.dentry.inode.truncate(size)
+}
+
+/// Returns the metadata of an open file.
+pub fn fstat(fd: Fd) -> Result<Metadata, FsError> {
+    get(fd)?.dentry.inode.metadata()
+}
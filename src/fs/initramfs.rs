@@ -0,0 +1,853 @@
+//! A read-only filesystem over an archive loaded as a boot module.
+//!
+//! Both POSIX USTAR archives (including GNU long names) and `newc` cpio archives are understood.
+//! Nothing is copied out of the archive: entries, names and file contents all borrow from the
+//! module, which stays mapped for the lifetime of the kernel.
+//!
+//! Directories do not need their own entries in the archive, any directory that appears in the
+//! path of another entry is listed as well.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{any::Any, fmt};
+
+use spin::Once;
+
+use super::{path, DirEntry, FileType, Filesystem, FsError, Inode};
+use crate::{boot_modules, info, warn};
+
+/// The name of the boot module the root archive is loaded from.
+pub const INITRD_MODULE: &str = "initrd";
+
+/// The deepest chain of symbolic links that is followed.
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// The longest path that can be built while following a relative symbolic link.
+const MAX_PATH: usize = 256;
+
+static INITRAMFS: Once<Archive> = Once::new();
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InitramfsError {
+    /// The data is neither a USTAR nor a `newc` cpio archive.
+    UnknownFormat,
+    /// A header or file extends past the end of the archive.
+    Truncated,
+    /// A USTAR header checksum does not match its contents.
+    BadChecksum,
+    /// A header field could not be parsed.
+    BadHeader,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    /// A chain of symbolic links was too deep or the resulting path too long.
+    TooManyLinks,
+}
+
+impl fmt::Display for InitramfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InitramfsError::UnknownFormat => "unknown archive format",
+            InitramfsError::Truncated => "archive is truncated",
+            InitramfsError::BadChecksum => "bad header checksum",
+            InitramfsError::BadHeader => "malformed header",
+            InitramfsError::NotFound => "no such file or directory",
+            InitramfsError::NotADirectory => "not a directory",
+            InitramfsError::IsADirectory => "is a directory",
+            InitramfsError::TooManyLinks => "too many levels of symbolic links",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ustar,
+    Newc,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// A USTAR hard link to another entry of the archive.
+    HardLink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    /// Anything else without contents, like sockets.
+    Special,
+}
+
+/// The metadata recorded for an entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: EntryKind,
+    pub size: usize,
+    /// The permission bits of the entry.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Seconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+/// A path as stored in the archive. USTAR splits long paths into a prefix and a name, so both
+/// halves are kept and compared piecewise.
+#[derive(Copy, Clone)]
+struct StoredPath {
+    prefix: &'static str,
+    name: &'static str,
+}
+
+impl StoredPath {
+    /// Iterates over the components of the path.
+    fn components(&self) -> impl Iterator<Item = &'static str> + Clone {
+        components(self.prefix).chain(components(self.name))
+    }
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> + Clone {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".")
+}
+
+/// A file, directory or link in the archive.
+#[derive(Copy, Clone)]
+pub struct Entry {
+    path: StoredPath,
+    metadata: Metadata,
+    data: &'static [u8],
+    link: &'static str,
+}
+
+impl Entry {
+    /// A directory that is only implied by the paths of other entries.
+    fn implied_directory(path: StoredPath) -> Self {
+        Entry {
+            path,
+            metadata: Metadata {
+                kind: EntryKind::Directory,
+                size: 0,
+                mode: 0o755,
+                uid: 0,
+                gid: 0,
+                mtime: 0,
+            },
+            data: &[],
+            link: "",
+        }
+    }
+
+    /// Returns the last component of the path.
+    pub fn name(&self) -> &'static str {
+        self.path.components().last().unwrap_or("")
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.metadata
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.metadata.kind
+    }
+
+    /// Returns the target of a symbolic or hard link.
+    pub fn link_target(&self) -> Option<&'static str> {
+        matches!(self.kind(), EntryKind::Symlink | EntryKind::HardLink).then_some(self.link)
+    }
+
+    /// Returns the contents of a regular file.
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+}
+
+/// Parses an octal USTAR field, which may be terminated by NUL or space.
+fn parse_octal(field: &[u8]) -> Result<u64, InitramfsError> {
+    let mut value = 0u64;
+    for &byte in field.iter().skip_while(|&&b| b == b' ') {
+        match byte {
+            b'0'..=b'7' => value = value * 8 + (byte - b'0') as u64,
+            b'\0' | b' ' => break,
+            _ => return Err(InitramfsError::BadHeader),
+        }
+    }
+    Ok(value)
+}
+
+/// Parses an eight digit hexadecimal cpio field.
+fn parse_hex(field: &[u8]) -> Result<u32, InitramfsError> {
+    let digits = core::str::from_utf8(field).map_err(|_| InitramfsError::BadHeader)?;
+    u32::from_str_radix(digits, 16).map_err(|_| InitramfsError::BadHeader)
+}
+
+/// Interprets a NUL padded field as a string.
+fn parse_str(field: &'static [u8]) -> Result<&'static str, InitramfsError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| InitramfsError::BadHeader)
+}
+
+const USTAR_BLOCK: usize = 512;
+const NEWC_HEADER: usize = 110;
+const NEWC_TRAILER: &str = "TRAILER!!!";
+
+/// Iterates over the entries of an archive in the order they are stored.
+#[derive(Clone)]
+pub struct Entries {
+    format: Format,
+    data: &'static [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl Entries {
+    fn next_ustar(&mut self) -> Result<Option<Entry>, InitramfsError> {
+        let mut long_name = None;
+        loop {
+            if self.offset >= self.data.len() {
+                return Ok(None);
+            }
+            let header = self
+                .data
+                .get(self.offset..self.offset + USTAR_BLOCK)
+                .ok_or(InitramfsError::Truncated)?;
+            // The archive ends with two zeroed blocks, one is enough to stop.
+            if header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+
+            let expected = parse_octal(&header[148..156])?;
+            let checksum: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+                .sum();
+            if checksum != expected {
+                return Err(InitramfsError::BadChecksum);
+            }
+
+            // A size too large to add up can only run past the end of the archive.
+            let size = usize::try_from(parse_octal(&header[124..136])?)
+                .map_err(|_| InitramfsError::Truncated)?;
+            let data_start = self.offset + USTAR_BLOCK;
+            let data_end = data_start
+                .checked_add(size)
+                .ok_or(InitramfsError::Truncated)?;
+            let data = self
+                .data
+                .get(data_start..data_end)
+                .ok_or(InitramfsError::Truncated)?;
+            self.offset = size
+                .checked_next_multiple_of(USTAR_BLOCK)
+                .and_then(|padded| data_start.checked_add(padded))
+                .ok_or(InitramfsError::Truncated)?;
+
+            let type_flag = header[156];
+            if type_flag == b'L' {
+                // A GNU long name applies to the header that follows it.
+                long_name = Some(parse_str(data)?);
+                continue;
+            }
+
+            let path = match long_name {
+                Some(name) => StoredPath { prefix: "", name },
+                None => StoredPath {
+                    // GNU headers say `ustar  ` and keep other things where POSIX has the
+                    // prefix.
+                    prefix: if &header[257..263] == b"ustar\0" {
+                        parse_str(&header[345..500])?
+                    } else {
+                        ""
+                    },
+                    name: parse_str(&header[0..100])?,
+                },
+            };
+            let kind = match type_flag {
+                b'0' | b'\0' | b'7' => EntryKind::File,
+                b'1' => EntryKind::HardLink,
+                b'2' => EntryKind::Symlink,
+                b'3' => EntryKind::CharDevice,
+                b'4' => EntryKind::BlockDevice,
+                b'5' => EntryKind::Directory,
+                b'6' => EntryKind::Fifo,
+                _ => EntryKind::Special,
+            };
+            return Ok(Some(Entry {
+                path,
+                metadata: Metadata {
+                    kind,
+                    size: if kind == EntryKind::File { size } else { 0 },
+                    mode: parse_octal(&header[100..108])? as u32 & 0o7777,
+                    uid: parse_octal(&header[108..116])? as u32,
+                    gid: parse_octal(&header[116..124])? as u32,
+                    mtime: parse_octal(&header[136..148])?,
+                },
+                data: if kind == EntryKind::File { data } else { &[] },
+                link: parse_str(&header[157..257])?,
+            }));
+        }
+    }
+
+    fn next_newc(&mut self) -> Result<Option<Entry>, InitramfsError> {
+        loop {
+            let header = self
+                .data
+                .get(self.offset..self.offset + NEWC_HEADER)
+                .ok_or(InitramfsError::Truncated)?;
+            if !matches!(&header[0..6], b"070701" | b"070702") {
+                return Err(InitramfsError::BadHeader);
+            }
+            let field = |index: usize| parse_hex(&header[6 + index * 8..14 + index * 8]);
+            let mode = field(1)?;
+            let size = field(6)? as usize;
+            let name_size = field(11)? as usize;
+
+            // Sizes too large to add up can only run past the end of the archive.
+            let name_start = self.offset + NEWC_HEADER;
+            let name_end = name_start
+                .checked_add(name_size)
+                .ok_or(InitramfsError::Truncated)?;
+            let name = self
+                .data
+                .get(name_start..name_end)
+                .ok_or(InitramfsError::Truncated)?;
+            let name = parse_str(name)?;
+            let data_start = name_end
+                .checked_next_multiple_of(4)
+                .ok_or(InitramfsError::Truncated)?;
+            let data_end = data_start
+                .checked_add(size)
+                .ok_or(InitramfsError::Truncated)?;
+            let data = self
+                .data
+                .get(data_start..data_end)
+                .ok_or(InitramfsError::Truncated)?;
+            self.offset = data_end
+                .checked_next_multiple_of(4)
+                .ok_or(InitramfsError::Truncated)?;
+
+            if name == NEWC_TRAILER {
+                return Ok(None);
+            }
+            // The root directory itself is recorded as `.`.
+            if components(name).next().is_none() {
+                continue;
+            }
+
+            let kind = match mode & 0o170000 {
+                0o100000 => EntryKind::File,
+                0o040000 => EntryKind::Directory,
+                0o120000 => EntryKind::Symlink,
+                0o020000 => EntryKind::CharDevice,
+                0o060000 => EntryKind::BlockDevice,
+                0o010000 => EntryKind::Fifo,
+                _ => EntryKind::Special,
+            };
+            let link = if kind == EntryKind::Symlink {
+                core::str::from_utf8(data).map_err(|_| InitramfsError::BadHeader)?
+            } else {
+                ""
+            };
+            return Ok(Some(Entry {
+                path: StoredPath { prefix: "", name },
+                metadata: Metadata {
+                    kind,
+                    size: if kind == EntryKind::File { size } else { 0 },
+                    mode: mode & 0o7777,
+                    uid: field(2)?,
+                    gid: field(3)?,
+                    mtime: field(5)? as u64,
+                },
+                data: if kind == EntryKind::File { data } else { &[] },
+                link,
+            }));
+        }
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Result<Entry, InitramfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = match self.format {
+            Format::Ustar => self.next_ustar(),
+            Format::Newc => self.next_newc(),
+        };
+        match result {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// A parsed archive.
+#[derive(Copy, Clone)]
+pub struct Archive {
+    format: Format,
+    data: &'static [u8],
+    len: usize,
+}
+
+impl Archive {
+    /// Detects the format of `data` and validates every header in it.
+    pub fn parse(data: &'static [u8]) -> Result<Self, InitramfsError> {
+        let format = if data.len() >= 6 && matches!(&data[0..6], b"070701" | b"070702") {
+            Format::Newc
+        } else if data.len() >= USTAR_BLOCK && &data[257..262] == b"ustar" {
+            Format::Ustar
+        } else {
+            return Err(InitramfsError::UnknownFormat);
+        };
+
+        let mut archive = Archive {
+            format,
+            data,
+            len: 0,
+        };
+        for entry in archive.raw_entries() {
+            entry?;
+            archive.len += 1;
+        }
+        Ok(archive)
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// The number of entries stored in the archive.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn raw_entries(&self) -> Entries {
+        Entries {
+            format: self.format,
+            data: self.data,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Iterates over every entry that is stored in the archive. Headers were validated by
+    /// [`Archive::parse`], so this never runs into a malformed one.
+    pub fn entries(&self) -> impl Iterator<Item = Entry> + Clone {
+        self.raw_entries().map_while(Result::ok)
+    }
+
+    /// Finds the entry whose path consists of `path`. Later entries override earlier ones,
+    /// just like extracting the archive would.
+    fn find<'a>(&self, path: impl Iterator<Item = &'a str> + Clone) -> Option<Entry> {
+        let found = self
+            .entries()
+            .filter(|entry| entry.path.components().eq(path.clone()))
+            .last();
+        if found.is_some() {
+            return found;
+        }
+
+        // The path might be a directory that only shows up in the paths of other entries.
+        let depth = path.clone().count();
+        self.entries()
+            .find(|entry| {
+                let parts = entry.path.components();
+                parts.clone().count() > depth && parts.take(depth).eq(path.clone())
+            })
+            .map(|entry| Entry::implied_directory(truncate_path(entry.path, depth)))
+    }
+
+    /// Looks up the entry at `path` without following symbolic links.
+    pub fn lookup(&self, path: &str) -> Result<Entry, InitramfsError> {
+        self.find(components(path)).ok_or(InitramfsError::NotFound)
+    }
+
+    /// Looks up `path`, following symbolic links and hard links until a file or directory is
+    /// found.
+    pub fn resolve(&self, path: &str) -> Result<Entry, InitramfsError> {
+        let mut buffer = [0u8; MAX_PATH];
+        let mut current = self.lookup(path)?;
+        for _ in 0..MAX_SYMLINK_DEPTH {
+            current = match current.kind() {
+                EntryKind::HardLink => self.lookup(current.link)?,
+                EntryKind::Symlink if current.link.starts_with('/') => {
+                    self.lookup(join(&mut buffer, components(current.link))?)?
+                }
+                EntryKind::Symlink => {
+                    // Relative targets are resolved against the directory holding the link.
+                    let parent = current.path.components();
+                    let depth = parent.clone().count().saturating_sub(1);
+                    let target = parent.take(depth).chain(components(current.link));
+                    self.lookup(join(&mut buffer, target)?)?
+                }
+                _ => return Ok(current),
+            };
+        }
+        Err(InitramfsError::TooManyLinks)
+    }
+
+    /// Lists the immediate children of the directory at `path`.
+    pub fn read_dir(&self, path: &str) -> Result<ReadDir, InitramfsError> {
+        let dir = self.resolve(path)?;
+        if dir.kind() != EntryKind::Directory {
+            return Err(InitramfsError::NotADirectory);
+        }
+        Ok(ReadDir {
+            archive: *self,
+            parent: dir.path,
+            depth: dir.path.components().count(),
+            entries: self.raw_entries(),
+            seen: 0,
+        })
+    }
+
+    /// Opens the file at `path` for reading.
+    pub fn open(&self, path: &str) -> Result<File, InitramfsError> {
+        let entry = self.resolve(path)?;
+        match entry.kind() {
+            EntryKind::Directory => Err(InitramfsError::IsADirectory),
+            _ => Ok(File { entry, offset: 0 }),
+        }
+    }
+}
+
+/// Cuts a stored path down to its first `depth` components.
+fn truncate_path(path: StoredPath, depth: usize) -> StoredPath {
+    let prefix_depth = components(path.prefix).count();
+    if depth <= prefix_depth {
+        StoredPath {
+            prefix: cut_after(path.prefix, depth),
+            name: "",
+        }
+    } else {
+        StoredPath {
+            prefix: path.prefix,
+            name: cut_after(path.name, depth - prefix_depth),
+        }
+    }
+}
+
+/// Returns the part of `path` that holds its first `count` components.
+fn cut_after(path: &'static str, count: usize) -> &'static str {
+    let mut seen = 0;
+    let mut end = 0;
+    let mut offset = 0;
+    for part in path.split('/') {
+        if seen == count {
+            break;
+        }
+        if !part.is_empty() && part != "." {
+            seen += 1;
+            end = offset + part.len();
+        }
+        offset += part.len() + 1;
+    }
+    &path[..end]
+}
+
+/// Writes `parts` into `buffer` as an absolute path, resolving `..` along the way.
+fn join<'a, 'b>(
+    buffer: &'a mut [u8; MAX_PATH],
+    parts: impl Iterator<Item = &'b str>,
+) -> Result<&'a str, InitramfsError> {
+    let mut len = 0;
+    for part in parts {
+        if part == ".." {
+            len = buffer[..len].iter().rposition(|&b| b == b'/').unwrap_or(0);
+            continue;
+        }
+        let end = len + part.len() + 1;
+        if end > MAX_PATH {
+            return Err(InitramfsError::TooManyLinks);
+        }
+        buffer[len] = b'/';
+        buffer[len + 1..end].copy_from_slice(part.as_bytes());
+        len = end;
+    }
+    Ok(core::str::from_utf8(&buffer[..len]).unwrap_or("/"))
+}
+
+/// Iterates over the children of a directory, including implied subdirectories.
+pub struct ReadDir {
+    archive: Archive,
+    parent: StoredPath,
+    depth: usize,
+    entries: Entries,
+    /// How many archive entries have been looked at, used to skip duplicate children.
+    seen: usize,
+}
+
+impl ReadDir {
+    /// Returns the name of the child of the listed directory that `entry` lives under.
+    fn child_of(&self, entry: &Entry) -> Option<&'static str> {
+        let mut parts = entry.path.components();
+        if !parts.by_ref().take(self.depth).eq(self.parent.components()) {
+            return None;
+        }
+        parts.next()
+    }
+}
+
+impl Iterator for ReadDir {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            let entry = self.entries.next()?.ok()?;
+            self.seen += 1;
+            let Some(child) = self.child_of(&entry) else {
+                continue;
+            };
+
+            // Only report a child the first time any entry beneath it shows up.
+            let reported = self
+                .archive
+                .entries()
+                .take(self.seen - 1)
+                .any(|other| self.child_of(&other) == Some(child));
+            if !reported {
+                let path = self.parent.components().chain(core::iter::once(child));
+                return self.archive.find(path);
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
+/// An open file in the archive with its own read position.
+#[derive(Copy, Clone)]
+pub struct File {
+    entry: Entry,
+    offset: usize,
+}
+
+impl File {
+    pub fn metadata(&self) -> Metadata {
+        self.entry.metadata
+    }
+
+    /// Copies as much of the file as fits into `buffer`, starting at the current position.
+    /// Returns the number of bytes read, which is zero at the end of the file.
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let remaining = &self.entry.data[self.offset.min(self.entry.data.len())..];
+        let count = remaining.len().min(buffer.len());
+        buffer[..count].copy_from_slice(&remaining[..count]);
+        self.offset += count;
+        count
+    }
+
+    /// Moves the read position, returning the new offset from the start of the file.
+    pub fn seek(&mut self, position: SeekFrom) -> usize {
+        let size = self.entry.data.len() as isize;
+        self.offset = match position {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(delta) => (self.offset as isize + delta).max(0) as usize,
+            SeekFrom::End(delta) => (size + delta).max(0) as usize,
+        };
+        self.offset
+    }
+
+    /// Returns the whole contents of the file without copying.
+    pub fn contents(&self) -> &'static [u8] {
+        self.entry.data
+    }
+}
+
+/// Parses the `initrd` boot module, if one was loaded.
+pub fn initialize() {
+    let Some(module) = boot_modules::find(INITRD_MODULE) else {
+        return;
+    };
+    match Archive::parse(module.data()) {
+        Ok(archive) => {
+            info!(
+                "initramfs: {} entries in {:?} archive",
+                archive.len(),
+                archive.format()
+            );
+            INITRAMFS.call_once(|| archive);
+        }
+        Err(error) => warn!("initramfs: cannot parse `{}`: {}", INITRD_MODULE, error),
+    }
+}
+
+/// Returns the archive loaded from the `initrd` module.
+pub fn get() -> Option<&'static Archive> {
+    INITRAMFS.get()
+}
+
+impl From<InitramfsError> for FsError {
+    fn from(error: InitramfsError) -> Self {
+        match error {
+            InitramfsError::NotFound => FsError::NotFound,
+            InitramfsError::NotADirectory => FsError::NotADirectory,
+            InitramfsError::IsADirectory => FsError::IsADirectory,
+            InitramfsError::TooManyLinks => FsError::TooManyLinks,
+            _ => FsError::Io,
+        }
+    }
+}
+
+/// Hashes a path into an inode number, since archives have no stable numbering of their own.
+fn inode_number(path: &str) -> u64 {
+    path::components(path).fold(0xcbf29ce484222325, |hash, component| {
+        component
+            .bytes()
+            .chain(core::iter::once(b'/'))
+            .fold(hash, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            })
+    })
+}
+
+/// An archive mounted into the VFS.
+pub struct InitramfsFs {
+    archive: Archive,
+}
+
+impl InitramfsFs {
+    pub fn new(archive: Archive) -> Self {
+        InitramfsFs { archive }
+    }
+
+    fn inode(&self, path: String) -> Result<Arc<dyn Inode>, FsError> {
+        let mut entry = self.archive.lookup(&path)?;
+        // Hard links share everything with their target but the name.
+        if entry.kind() == EntryKind::HardLink {
+            entry = self.archive.lookup(entry.link)?;
+        }
+        Ok(Arc::new(InitramfsInode {
+            archive: self.archive,
+            path,
+            entry,
+        }))
+    }
+}
+
+impl Filesystem for InitramfsFs {
+    fn name(&self) -> &'static str {
+        "initramfs"
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let root = Entry::implied_directory(StoredPath {
+            prefix: "",
+            name: "",
+        });
+        self.inode(String::from("/")).unwrap_or_else(|_| {
+            Arc::new(InitramfsInode {
+                archive: self.archive,
+                path: String::from("/"),
+                entry: root,
+            })
+        })
+    }
+}
+
+struct InitramfsInode {
+    archive: Archive,
+    path: String,
+    entry: Entry,
+}
+
+impl Inode for InitramfsInode {
+    fn metadata(&self) -> Result<super::Metadata, FsError> {
+        let metadata = self.entry.metadata();
+        Ok(super::Metadata {
+            ino: inode_number(&self.path),
+            kind: match metadata.kind {
+                EntryKind::File | EntryKind::HardLink | EntryKind::Special => FileType::File,
+                EntryKind::Directory => FileType::Directory,
+                EntryKind::Symlink => FileType::Symlink,
+                EntryKind::CharDevice => FileType::CharDevice,
+                EntryKind::BlockDevice => FileType::BlockDevice,
+                EntryKind::Fifo => FileType::Fifo,
+            },
+            size: match metadata.kind {
+                EntryKind::Symlink => self.entry.link.len() as u64,
+                _ => metadata.size as u64,
+            },
+            mode: metadata.mode,
+            nlink: 1,
+            uid: metadata.uid,
+            gid: metadata.gid,
+            mtime: metadata.mtime,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if self.entry.kind() != EntryKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        InitramfsFs::new(self.archive).inode(path::join(&self.path, name))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        if self.entry.kind() != EntryKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(self
+            .archive
+            .read_dir(&self.path)?
+            .map(|entry| {
+                let path = path::join(&self.path, entry.name());
+                let kind = InitramfsInode {
+                    archive: self.archive,
+                    path,
+                    entry,
+                }
+                .metadata()
+                .map_or(FileType::File, |metadata| metadata.kind);
+                DirEntry {
+                    name: String::from(entry.name()),
+                    ino: inode_number(&path::join(&self.path, entry.name())),
+                    kind,
+                }
+            })
+            .collect())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if self.entry.kind() == EntryKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let data = self.entry.data();
+        let start = usize::try_from(offset).map_or(data.len(), |offset| offset.min(data.len()));
+        let count = (data.len() - start).min(buffer.len());
+        buffer[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match self.entry.kind() {
+            EntryKind::Symlink => Ok(String::from(self.entry.link)),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,518 @@
+//! This module provides the virtual filesystem that every filesystem driver is mounted into.
+//!
+//! Drivers implement [`Filesystem`] and [`Inode`], and are attached to the single namespace
+//! with [`mount::mount`]. Paths are always absolute and are resolved through the mount table,
+//! with symbolic links followed along the way. Open files are referred to by a [`file::Fd`]
+//! handed out by [`file::open`]. Filesystems that live on a disk are mounted from their
+//! block device with [`mount_device`], which can also work out which filesystem it holds.
+//! At boot, the initramfs is mounted on `/` unless `root=` names a disk to use instead, and
+//! the device nodes of [`devfs`] on `/dev` and the kernel state of [`procfs`] on `/proc`.
+//! A `sync` task then writes back what the filesystems and disks have cached every few
+//! seconds.
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{any::Any, fmt, time::Duration};
+
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod file;
+pub mod initramfs;
+pub mod mount;
+pub mod path;
+pub mod procfs;
+pub mod tmpfs;
+
+use mount::{mount, mounts, resolve};
+
+use crate::{
+    boot_param,
+    cmdline::{ParamError, Value},
+    drivers::block::{self, BlockDevice, BlockError},
+    info, println,
+    shell::{absolute_path, complete_path, CommandError},
+    shell_command,
+    sync::SpinLock,
+    task::{self, Priority},
+    warn,
+};
+
+/// Opens the filesystem on a block device, failing with [`FsError::InvalidArgument`] if it
+/// holds some other kind.
+type DiskFilesystem = fn(Arc<dyn BlockDevice>) -> Result<Arc<dyn Filesystem>, FsError>;
+
+/// The filesystems that live on block devices, by the name `mount` knows them by, in the
+/// order they are tried.
+const DISK_FILESYSTEMS: &[(&str, DiskFilesystem)] = &[("ext2", ext2::open), ("vfat", fat::open)];
+
+/// How often the `sync` task writes back what is cached.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The block device to mount on `/` instead of the initramfs, as set by `root=`.
+static ROOT: SpinLock<Option<&'static str>> = SpinLock::new(None);
+/// The type of the filesystem on the root device, as set by `rootfstype=`.
+static ROOT_TYPE: SpinLock<Option<&'static str>> = SpinLock::new(None);
+
+fn set_root(value: Option<Value<'static>>) -> Result<(), ParamError> {
+    let device = value.ok_or(ParamError::MissingValue)?.as_str();
+    *ROOT.lock() = Some(device.strip_prefix("/dev/").unwrap_or(device));
+    Ok(())
+}
+
+fn set_root_type(value: Option<Value<'static>>) -> Result<(), ParamError> {
+    let kind = value.ok_or(ParamError::MissingValue)?.as_str();
+    if !DISK_FILESYSTEMS.iter().any(|(name, _)| *name == kind) {
+        return Err(ParamError::Invalid);
+    }
+    *ROOT_TYPE.lock() = Some(kind);
+    Ok(())
+}
+
+boot_param!(ROOT_PARAM, "root", set_root);
+boot_param!(ROOTFSTYPE_PARAM, "rootfstype", set_root_type);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    /// A directory still has entries in it.
+    NotEmpty,
+    ReadOnly,
+    /// The filesystem does not implement the operation.
+    NotSupported,
+    InvalidPath,
+    InvalidArgument,
+    BadDescriptor,
+    /// The file was not opened with the access the operation needs.
+    PermissionDenied,
+    /// A chain of symbolic links was too deep.
+    TooManyLinks,
+    /// Something is still mounted on or using the target.
+    Busy,
+    NoSpace,
+    /// The operation would have to move data between two filesystems.
+    CrossDevice,
+    Io,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::AlreadyExists => "file exists",
+            FsError::NotEmpty => "directory not empty",
+            FsError::ReadOnly => "read-only filesystem",
+            FsError::NotSupported => "operation not supported",
+            FsError::InvalidPath => "invalid path",
+            FsError::InvalidArgument => "invalid argument",
+            FsError::BadDescriptor => "bad file descriptor",
+            FsError::PermissionDenied => "permission denied",
+            FsError::TooManyLinks => "too many levels of symbolic links",
+            FsError::Busy => "device or resource busy",
+            FsError::NoSpace => "no space left on device",
+            FsError::CrossDevice => "cross-device link",
+            FsError::Io => "input/output error",
+        })
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> Self {
+        match error {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            _ => FsError::Io,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+}
+
+impl FileType {
+    /// The character `ls -l` shows for this type.
+    pub const fn symbol(&self) -> char {
+        match self {
+            FileType::File => '-',
+            FileType::Directory => 'd',
+            FileType::Symlink => 'l',
+            FileType::CharDevice => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Fifo => 'p',
+            FileType::Socket => 's',
+        }
+    }
+}
+
+/// What `stat` reports about an inode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    /// Unique within the filesystem the inode belongs to.
+    pub ino: u64,
+    pub kind: FileType,
+    pub size: u64,
+    /// The permission bits.
+    pub mode: u32,
+    /// The number of directory entries referring to the inode.
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Seconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+/// An entry returned by [`read_dir`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub kind: FileType,
+}
+
+/// A file, directory or link of a mounted filesystem.
+///
+/// Every operation has a default that fails the way a driver that does not support it should,
+/// so read-only filesystems only need to implement the lookups and reads.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata, FsError>;
+
+    /// Finds the child called `name` of a directory.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Lists the children of a directory, without `.` and `..`.
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Reads from the file at `offset`, returning how many bytes were read.
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+
+    /// Writes to the file at `offset`, growing it if needed.
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Creates a child called `name` of the given type in a directory.
+    fn create(&self, _name: &str, _kind: FileType, _mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Removes the child called `name` from a directory. Directories must be empty.
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Returns the target of a symbolic link.
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    /// Creates a symbolic link called `name` pointing at `target` in a directory.
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Grows or shrinks a file to `size` bytes, filling any new space with zeroes.
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Adds `inode`, which belongs to the same filesystem, to a directory as `name`.
+    fn link(&self, _name: &str, _inode: &Arc<dyn Inode>) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Moves the child `name` of a directory to `new_name` in `new_dir`, which belongs to the
+    /// same filesystem, replacing whatever was there.
+    fn rename(
+        &self,
+        _name: &str,
+        _new_dir: &Arc<dyn Inode>,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Lets a filesystem recognise its own inodes among `dyn Inode`s.
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// A mountable filesystem instance.
+pub trait Filesystem: Send + Sync {
+    /// The type of the filesystem, like `initramfs` or `tmpfs`.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Whether files on this filesystem can never be opened for writing.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Writes everything the filesystem keeps in memory back to its device.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    /// The block device the filesystem lives on, for the ones that live on a disk.
+    fn device(&self) -> Option<&Arc<dyn BlockDevice>> {
+        None
+    }
+}
+
+/// Returns the metadata of the file at `path`, following symbolic links.
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    resolve(path)?.inode.metadata()
+}
+
+/// Returns the metadata of the file at `path` without following a final symbolic link.
+pub fn lstat(path: &str) -> Result<Metadata, FsError> {
+    mount::resolve_no_follow(path)?.inode.metadata()
+}
+
+/// Lists the directory at `path`, including anything mounted directly beneath it.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let dir = resolve(path)?;
+    let mut entries = dir.inode.read_dir()?;
+    for mount in mounts() {
+        if path::parent(&mount.path) == Some(dir.path.as_str()) {
+            let name = path::file_name(&mount.path);
+            if !entries.iter().any(|entry| entry.name == name) {
+                entries.push(DirEntry {
+                    name: String::from(name),
+                    ino: mount.fs.root().metadata().map_or(0, |m| m.ino),
+                    kind: FileType::Directory,
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Creates a directory at `path`.
+pub fn mkdir(path: &str, mode: u32) -> Result<(), FsError> {
+    let (parent, name) = mount::resolve_parent(path)?;
+    parent.inode.create(&name, FileType::Directory, mode)?;
+    Ok(())
+}
+
+/// Removes the file or empty directory at `path`.
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let normalized = path::normalize(path)?;
+    if mounts().iter().any(|mount| mount.path == normalized) {
+        return Err(FsError::Busy);
+    }
+    let (parent, name) = mount::resolve_parent(&normalized)?;
+    parent.inode.unlink(&name)
+}
+
+/// Grows or shrinks the file at `path` to `size` bytes.
+pub fn truncate(path: &str, size: u64) -> Result<(), FsError> {
+    resolve(path)?.inode.truncate(size)
+}
+
+/// Creates a hard link at `new` to the file at `old`.
+pub fn link(old: &str, new: &str) -> Result<(), FsError> {
+    let source = mount::resolve_no_follow(old)?;
+    if source.inode.metadata()?.kind == FileType::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    let (parent, name) = mount::resolve_parent(new)?;
+    if !Arc::ptr_eq(&source.mount, &parent.mount) {
+        return Err(FsError::CrossDevice);
+    }
+    parent.inode.link(&name, &source.inode)
+}
+
+/// Creates a symbolic link at `path` that points at `target`.
+pub fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    let (parent, name) = mount::resolve_parent(path)?;
+    parent.inode.symlink(&name, target)?;
+    Ok(())
+}
+
+/// Moves the file or directory at `old` to `new`, replacing whatever `new` named.
+pub fn rename(old: &str, new: &str) -> Result<(), FsError> {
+    let old = path::normalize(old)?;
+    let new = path::normalize(new)?;
+    if mounts()
+        .iter()
+        .any(|mount| mount.path == old || mount.path == new)
+    {
+        return Err(FsError::Busy);
+    }
+    if old != new && path::starts_with(&new, &old) {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let (old_parent, old_name) = mount::resolve_parent(&old)?;
+    let (new_parent, new_name) = mount::resolve_parent(&new)?;
+    if !Arc::ptr_eq(&old_parent.mount, &new_parent.mount) {
+        return Err(FsError::CrossDevice);
+    }
+    old_parent
+        .inode
+        .rename(&old_name, &new_parent.inode, &new_name)
+}
+
+/// Returns the target of the symbolic link at `path`.
+pub fn read_link(path: &str) -> Result<String, FsError> {
+    mount::resolve_no_follow(path)?.inode.read_link()
+}
+
+/// Mounts the filesystem on the block device called `device` at `target`, returning the type
+/// of the filesystem. Without a `kind`, each type that can live on a disk is tried in turn.
+pub fn mount_device(
+    device: &str,
+    target: &str,
+    kind: Option<&str>,
+) -> Result<&'static str, FsError> {
+    let device = block::find(device).ok_or(FsError::NotFound)?;
+    // Checked again when the mount is added, this only saves reading the device.
+    if mount::is_device_mounted(&*device) {
+        return Err(FsError::Busy);
+    }
+    let candidates = DISK_FILESYSTEMS
+        .iter()
+        .filter(|(name, _)| kind.is_none_or(|kind| kind == *name));
+    let mut result = Err(match kind {
+        Some(_) => FsError::NotSupported,
+        None => FsError::InvalidArgument,
+    });
+    for (name, open) in candidates {
+        result = open(device.clone()).map(|fs| (*name, fs));
+        if !matches!(result, Err(FsError::InvalidArgument)) {
+            break;
+        }
+    }
+    let (name, fs) = result?;
+    mount(target, fs)?;
+    Ok(name)
+}
+
+/// Writes back what every mounted filesystem and every disk has cached.
+pub fn sync() -> Result<(), FsError> {
+    let mut result = Ok(());
+    for mount in mounts() {
+        result = result.and(mount.fs.sync());
+    }
+    result.and(block::sync().map_err(FsError::from))
+}
+
+/// Mounts the filesystems that are available at boot. Block devices have to be registered by
+/// then for `root=` to find them.
+pub fn initialize() {
+    initramfs::initialize();
+    if let Some(device) = *ROOT.lock() {
+        match mount_device(device, "/", *ROOT_TYPE.lock()) {
+            Ok(name) => info!("vfs: mounted {} from {} on /", name, device),
+            Err(error) => warn!("vfs: cannot mount {} on /: {}", device, error),
+        }
+    }
+    // The initramfs stands in for a root device that is missing or cannot be mounted.
+    if let Some(archive) = initramfs::get().filter(|_| mounts().is_empty()) {
+        match mount("/", Arc::new(initramfs::InitramfsFs::new(*archive))) {
+            Ok(()) => info!("vfs: mounted initramfs on /"),
+            Err(error) => warn!("vfs: cannot mount initramfs on /: {}", error),
+        }
+    }
+
+    // Without either the scratch space becomes the root instead.
+    let target = if mounts().is_empty() { "/" } else { "/tmp" };
+    match mount(target, Arc::new(tmpfs::TmpFs::new(tmpfs::default_limit()))) {
+        Ok(()) => info!("vfs: mounted tmpfs on {}", target),
+        Err(error) => warn!("vfs: cannot mount tmpfs on {}: {}", target, error),
+    }
+
+    devfs::initialize();
+    match mount("/dev", Arc::new(devfs::DevFs::new())) {
+        Ok(()) => info!("vfs: mounted devfs on /dev"),
+        Err(error) => warn!("vfs: cannot mount devfs on /dev: {}", error),
+    }
+    match mount("/proc", Arc::new(procfs::ProcFs::new())) {
+        Ok(()) => info!("vfs: mounted proc on /proc"),
+        Err(error) => warn!("vfs: cannot mount proc on /proc: {}", error),
+    }
+
+    // Writes only reach the disks when they are flushed, so losing power loses no more than
+    // the last few seconds of them.
+    let flusher = task::spawn("sync", Priority::Low, || loop {
+        task::sleep(SYNC_INTERVAL);
+        if let Err(error) = sync() {
+            warn!("vfs: cannot write back cached data: {}", error);
+        }
+    });
+    if flusher.is_err() {
+        warn!("vfs: no memory for the sync task, data is only written back by sync");
+    }
+}
+
+fn mount_command(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            for mount in mounts() {
+                println!("{:<16} {}", mount.path, mount.fs.name());
+            }
+            Ok(())
+        }
+        [device, target, kind @ ..] if kind.len() <= 1 => {
+            let target = absolute_path(target);
+            let name = mount_device(device, &target, kind.first().copied())?;
+            info!("vfs: mounted {} from {} on {}", name, device, target);
+            Ok(())
+        }
+        _ => Err(CommandError::Usage),
+    }
+}
+
+fn umount(args: &[&str]) -> Result<(), CommandError> {
+    let [target] = args else {
+        return Err(CommandError::Usage);
+    };
+    let fs = mount::unmount(&absolute_path(target))?;
+    fs.sync()?;
+    Ok(())
+}
+
+fn sync_command(_args: &[&str]) -> Result<(), CommandError> {
+    sync()?;
+    Ok(())
+}
+
+shell_command!(
+    MOUNT_COMMAND,
+    "mount",
+    "[<device> <path> [type]]",
+    "List mounts or mount the filesystem on a disk",
+    mount_command
+);
+shell_command!(
+    UMOUNT_COMMAND,
+    "umount",
+    "<path>",
+    "Unmount a filesystem",
+    umount,
+    complete = complete_path
+);
+shell_command!(
+    SYNC_COMMAND,
+    "sync",
+    "",
+    "Write cached data back to the disks",
+    sync_command
+);
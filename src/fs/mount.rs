@@ -0,0 +1,176 @@
+//! The mount table and path resolution across mounted filesystems.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use super::{file, path, FileType, Filesystem, FsError, Inode};
+use crate::{
+    drivers::block::{self, BlockDevice},
+    sync::RwLock,
+};
+
+/// The deepest chain of symbolic links that is followed while resolving a path.
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+static MOUNTS: RwLock<Vec<Arc<Mount>>> = RwLock::named("mounts", Vec::new());
+
+/// A filesystem attached to the namespace.
+pub struct Mount {
+    /// The normalized path the filesystem is mounted on.
+    pub path: String,
+    pub fs: Arc<dyn Filesystem>,
+}
+
+/// A resolved path: the inode it names, along with the mount it was found on.
+#[derive(Clone)]
+pub struct Dentry {
+    /// The normalized path the inode was reached through, after following symbolic links.
+    pub path: String,
+    pub inode: Arc<dyn Inode>,
+    pub mount: Arc<Mount>,
+}
+
+/// Returns every mounted filesystem, in the order they were mounted.
+pub fn mounts() -> Vec<Arc<Mount>> {
+    MOUNTS.read().clone()
+}
+
+/// Returns whether a filesystem in `mounts` lives on `device`, on a partition of it or on the
+/// disk it is a partition of.
+fn uses_device(mounts: &[Arc<Mount>], device: &dyn BlockDevice) -> bool {
+    mounts
+        .iter()
+        .filter_map(|mount| mount.fs.device())
+        .any(|other| block::overlap(&**other, device))
+}
+
+/// Returns whether `device` shares sectors with a device a filesystem is mounted from.
+pub(super) fn is_device_mounted(device: &dyn BlockDevice) -> bool {
+    uses_device(&MOUNTS.read(), device)
+}
+
+/// Attaches `fs` to the namespace at `target`.
+///
+/// Apart from `/`, the directory holding the mount point has to exist. The mount point itself
+/// does not, so filesystems can be mounted beneath read-only ones. A disk can only be mounted
+/// once, and not along with any of its partitions, since each mount keeps its own idea of
+/// what is free.
+pub fn mount(target: &str, fs: Arc<dyn Filesystem>) -> Result<(), FsError> {
+    let target = path::normalize(target)?;
+    if let Some(parent) = path::parent(&target) {
+        let parent = resolve(parent)?;
+        if parent.inode.metadata()?.kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        match parent.inode.lookup(path::file_name(&target)) {
+            Ok(inode) if inode.metadata()?.kind != FileType::Directory => {
+                return Err(FsError::NotADirectory)
+            }
+            _ => {}
+        }
+    }
+
+    // Checked under the same lock as the mount is added, so two mounts on the same path
+    // cannot both get through. Resolving the path above may sleep, so it cannot be.
+    let mut mounts = MOUNTS.write();
+    if mounts.iter().any(|mount| mount.path == target)
+        || fs
+            .device()
+            .is_some_and(|device| uses_device(&mounts, &**device))
+    {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Arc::new(Mount { path: target, fs }));
+    Ok(())
+}
+
+/// Detaches the filesystem mounted at `target`, as long as nothing beneath it is mounted or
+/// open.
+pub fn unmount(target: &str) -> Result<Arc<dyn Filesystem>, FsError> {
+    let target = path::normalize(target)?;
+    let mut mounts = MOUNTS.write();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == target)
+        .ok_or(FsError::InvalidArgument)?;
+    let nested = mounts
+        .iter()
+        .any(|mount| mount.path != target && path::starts_with(&mount.path, &target));
+    if nested || file::uses_mount(&mounts[index]) {
+        return Err(FsError::Busy);
+    }
+    Ok(mounts.remove(index).fs.clone())
+}
+
+fn walk(path: &str, follow_last: bool) -> Result<Dentry, FsError> {
+    let mut path = path::normalize(path)?;
+    let mut links = 0;
+
+    'resolve: loop {
+        let mounts = mounts();
+        let root = mounts
+            .iter()
+            .find(|mount| mount.path == "/")
+            .ok_or(FsError::NotFound)?;
+        let mut current = Dentry {
+            path: String::from("/"),
+            inode: root.fs.root(),
+            mount: root.clone(),
+        };
+
+        let components: Vec<&str> = path::components(&path).collect();
+        for (index, component) in components.iter().enumerate() {
+            let child_path = path::join(&current.path, component);
+            let (inode, mount) = match mounts.iter().find(|mount| mount.path == child_path) {
+                Some(mount) => (mount.fs.root(), mount.clone()),
+                None => (current.inode.lookup(component)?, current.mount.clone()),
+            };
+
+            let last = index + 1 == components.len();
+            if (follow_last || !last) && inode.metadata()?.kind == FileType::Symlink {
+                links += 1;
+                if links > MAX_SYMLINK_DEPTH {
+                    return Err(FsError::TooManyLinks);
+                }
+                let target = inode.read_link()?;
+                let base = if target.starts_with('/') {
+                    target
+                } else {
+                    path::join(&current.path, &target)
+                };
+                path = path::normalize(&path::join(&base, &components[index + 1..].join("/")))?;
+                continue 'resolve;
+            }
+
+            current = Dentry {
+                path: child_path,
+                inode,
+                mount,
+            };
+        }
+        return Ok(current);
+    }
+}
+
+/// Resolves `path` to the inode it names, following every symbolic link.
+pub fn resolve(path: &str) -> Result<Dentry, FsError> {
+    walk(path, true)
+}
+
+/// Resolves `path`, leaving a symbolic link in the last component alone.
+pub(super) fn resolve_no_follow(path: &str) -> Result<Dentry, FsError> {
+    walk(path, false)
+}
+
+/// Resolves the directory that holds `path`, returning it along with the final component.
+pub(super) fn resolve_parent(path: &str) -> Result<(Dentry, String), FsError> {
+    let path = path::normalize(path)?;
+    let parent = resolve(path::parent(&path).ok_or(FsError::InvalidPath)?)?;
+    if parent.inode.metadata()?.kind != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((parent, path::file_name(&path).to_string()))
+}
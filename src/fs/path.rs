@@ -0,0 +1,64 @@
+//! Helpers for absolute, `/` separated paths.
+
+use alloc::string::String;
+
+use super::FsError;
+
+/// Iterates over the components of a path, skipping empty ones and `.`.
+pub fn components(path: &str) -> impl Iterator<Item = &str> + Clone {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".")
+}
+
+/// Turns an absolute path into its canonical form, resolving `.` and `..` and removing
+/// duplicate and trailing slashes. `..` at the root stays at the root.
+pub fn normalize(path: &str) -> Result<String, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for component in components(path) {
+        if component == ".." {
+            let len = normalized.rfind('/').unwrap_or(0);
+            normalized.truncate(len);
+        } else {
+            normalized.push('/');
+            normalized.push_str(component);
+        }
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Appends `name` to the normalized path `dir`.
+pub fn join(dir: &str, name: &str) -> String {
+    let mut path = String::from(dir.trim_end_matches('/'));
+    path.push('/');
+    path.push_str(name.trim_start_matches('/'));
+    path
+}
+
+/// Returns the directory holding a normalized path, or `None` for the root.
+pub fn parent(path: &str) -> Option<&str> {
+    if path == "/" {
+        return None;
+    }
+    match path.rfind('/') {
+        Some(0) => Some("/"),
+        Some(index) => Some(&path[..index]),
+        None => None,
+    }
+}
+
+/// Returns the last component of a normalized path, which is empty for the root.
+pub fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or("")
+}
+
+/// Returns whether the normalized `path` is `dir` or lies beneath it.
+pub fn starts_with(path: &str, dir: &str) -> bool {
+    dir == "/"
+        || path == dir
+        || (path.starts_with(dir) && path.as_bytes().get(dir.len()) == Some(&b'/'))
+}
@@ -0,0 +1,310 @@
+//! A filesystem of kernel state, mounted on `/proc`.
+//!
+//! Nothing is stored: every file is text put together from the live state each time it is
+//! read, so its size is given as 0 and it has to be read until the end like a pipe. Each task
+//! has a directory named after its id.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{any::Any, fmt::Write};
+
+use super::{DirEntry, FileType, Filesystem, FsError, Inode, Metadata};
+use crate::{
+    cmdline, cpu,
+    drivers::pit,
+    interrupts,
+    memory::{self, heap, FRAME_ALLOCATOR, PAGE_SIZE},
+    task::{self, Task, TaskId},
+};
+
+const ROOT_INO: u64 = 1;
+/// Task directories are numbered from here, leaving room for the files in each.
+const TASK_INO_BASE: u64 = 0x1000;
+const TASK_INO_STRIDE: u64 = 0x10;
+
+/// Puts together the contents of a file.
+type Generator = fn() -> String;
+
+/// The files at the top, with what generates them.
+const FILES: &[(&str, Generator)] = &[
+    ("cmdline", cmdline),
+    ("cpuinfo", cpuinfo),
+    ("interrupts", interrupts),
+    ("meminfo", meminfo),
+    ("memmap", memmap),
+    ("uptime", uptime),
+];
+
+/// Puts together the contents of a file about a task.
+type TaskGenerator = fn(&Task) -> String;
+
+/// The files in the directory of every task.
+const TASK_FILES: &[(&str, TaskGenerator)] = &[("status", status)];
+
+fn cmdline() -> String {
+    format!("{}\n", cmdline::raw())
+}
+
+fn cpuinfo() -> String {
+    let info = cpu::identify();
+    let mut out = String::new();
+    writeln!(out, "vendor_id\t: {}", info.vendor).ok();
+    if let Some(brand) = &info.brand {
+        writeln!(out, "model name\t: {}", brand).ok();
+    }
+    writeln!(out, "cpu family\t: {}", info.family).ok();
+    writeln!(out, "model\t\t: {}", info.model).ok();
+    writeln!(out, "stepping\t: {}", info.stepping).ok();
+    writeln!(out, "flags\t\t: {}", info.features.join(" ")).ok();
+    out
+}
+
+fn interrupts() -> String {
+    let mut out = String::new();
+    for (vector, count) in interrupts::counts().into_iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        match interrupts::exception_name(vector) {
+            Some(name) => writeln!(out, "{:>3}: {:>10}  {}", vector, count, name),
+            None => writeln!(
+                out,
+                "{:>3}: {:>10}  IRQ {}",
+                vector,
+                count,
+                vector - interrupts::IRQ_BASE as usize
+            ),
+        }
+        .ok();
+    }
+    out
+}
+
+fn meminfo() -> String {
+    let frames = FRAME_ALLOCATOR.lock().stats();
+    let heap = heap::stats();
+    let mut out = String::new();
+    for (name, kib) in [
+        ("MemTotal", frames.total * PAGE_SIZE / 1024),
+        ("MemFree", frames.free * PAGE_SIZE / 1024),
+        ("HeapTotal", heap.size / 1024),
+        ("HeapUsed", heap.used / 1024),
+        ("HeapPeak", heap.peak / 1024),
+    ] {
+        writeln!(out, "{:<16}{:>8} kB", format!("{}:", name), kib).ok();
+    }
+    writeln!(out, "{:<16}{:>8}", "HeapAllocations:", heap.allocations).ok();
+    out
+}
+
+fn memmap() -> String {
+    let mut out = String::new();
+    for region in memory::memory_map() {
+        writeln!(
+            out,
+            "{:#011x}-{:#011x} {}",
+            region.start,
+            region.end.saturating_sub(1),
+            region.kind.name()
+        )
+        .ok();
+    }
+    out
+}
+
+fn uptime() -> String {
+    let uptime = pit::uptime();
+    format!("{}.{:02}\n", uptime.as_secs(), uptime.subsec_millis() / 10)
+}
+
+fn status(task: &Task) -> String {
+    format!(
+        "Name:\t{}\nState:\t{}\nPid:\t{}\nPriority:\t{}\n",
+        task.name(),
+        task.state(),
+        task.id(),
+        task.priority().name()
+    )
+}
+
+/// A file whose contents are generated when it is read.
+struct ProcFile<F> {
+    ino: u64,
+    generate: F,
+}
+
+impl<F: Fn() -> String + Send + Sync + 'static> Inode for ProcFile<F> {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        Ok(Metadata {
+            ino: self.ino,
+            kind: FileType::File,
+            size: 0,
+            mode: 0o444,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let contents = (self.generate)();
+        let data = contents.as_bytes();
+        let start = usize::try_from(offset).map_or(data.len(), |offset| offset.min(data.len()));
+        let count = (data.len() - start).min(buffer.len());
+        buffer[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn directory_metadata(ino: u64, size: usize) -> Metadata {
+    Metadata {
+        ino,
+        kind: FileType::Directory,
+        size: size as u64,
+        mode: 0o555,
+        nlink: 2,
+        uid: 0,
+        gid: 0,
+        mtime: 0,
+    }
+}
+
+/// The directory of a task, which is looked up again whenever one of its files is read.
+struct TaskDir {
+    id: TaskId,
+}
+
+impl TaskDir {
+    fn ino(&self) -> u64 {
+        TASK_INO_BASE + u64::from(self.id) * TASK_INO_STRIDE
+    }
+}
+
+impl Inode for TaskDir {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        Ok(directory_metadata(self.ino(), TASK_FILES.len()))
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let (index, &(_, generate)) = TASK_FILES
+            .iter()
+            .enumerate()
+            .find(|(_, (file, _))| *file == name)
+            .ok_or(FsError::NotFound)?;
+        let id = self.id;
+        Ok(Arc::new(ProcFile {
+            ino: self.ino() + 1 + index as u64,
+            generate: move || {
+                task::tasks()
+                    .iter()
+                    .find(|task| task.id() == id)
+                    .map_or_else(String::new, |task| generate(task))
+            },
+        }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(TASK_FILES
+            .iter()
+            .enumerate()
+            .map(|(index, (name, _))| DirEntry {
+                name: name.to_string(),
+                ino: self.ino() + 1 + index as u64,
+                kind: FileType::File,
+            })
+            .collect())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct RootDir;
+
+impl Inode for RootDir {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        Ok(directory_metadata(
+            ROOT_INO,
+            FILES.len() + task::tasks().len(),
+        ))
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if let Some((index, &(_, generate))) = FILES
+            .iter()
+            .enumerate()
+            .find(|(_, (file, _))| *file == name)
+        {
+            return Ok(Arc::new(ProcFile {
+                ino: ROOT_INO + 1 + index as u64,
+                generate,
+            }));
+        }
+        let id = name.parse::<TaskId>().map_err(|_| FsError::NotFound)?;
+        if !task::tasks().iter().any(|task| task.id() == id) {
+            return Err(FsError::NotFound);
+        }
+        Ok(Arc::new(TaskDir { id }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let files = FILES.iter().enumerate().map(|(index, (name, _))| DirEntry {
+            name: name.to_string(),
+            ino: ROOT_INO + 1 + index as u64,
+            kind: FileType::File,
+        });
+        let tasks = task::tasks().into_iter().map(|task| DirEntry {
+            name: task.id().to_string(),
+            ino: TaskDir { id: task.id() }.ino(),
+            kind: FileType::Directory,
+        });
+        Ok(files.chain(tasks).collect())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// The process filesystem. Every instance shows the same state.
+pub struct ProcFs {
+    root: Arc<RootDir>,
+}
+
+impl ProcFs {
+    pub fn new() -> Self {
+        ProcFs {
+            root: Arc::new(RootDir),
+        }
+    }
+}
+
+impl Default for ProcFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Filesystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
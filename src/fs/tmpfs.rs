@@ -0,0 +1,455 @@
+//! A writable filesystem that keeps everything on the kernel heap.
+//!
+//! Every byte of file data, every name and a fixed overhead per inode is charged against the
+//! limit the filesystem was created with, so a runaway writer gets [`FsError::NoSpace`] instead
+//! of exhausting the heap. Memory is given back once the last link to an inode and the last
+//! open file using it are gone.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    mem::size_of,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use super::{DirEntry, FileType, Filesystem, FsError, Inode, Metadata};
+use crate::{
+    drivers::pit,
+    memory::{FRAME_ALLOCATOR, PAGE_SIZE},
+    sync::RwLock,
+};
+
+/// What every inode costs on top of its contents.
+const INODE_OVERHEAD: usize = size_of::<TmpInode>() + size_of::<Arc<TmpInode>>();
+
+/// Returns the time to stamp modifications with.
+///
+/// There is no wall clock yet, so this counts seconds since boot rather than since the epoch.
+fn now() -> u64 {
+    pit::uptime().as_secs()
+}
+
+/// Returns half of the memory that is currently free, the usual default size of a tmpfs.
+pub fn default_limit() -> usize {
+    FRAME_ALLOCATOR.lock().stats().free * PAGE_SIZE / 2
+}
+
+/// Memory usage of a tmpfs instance, in bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Usage {
+    pub used: usize,
+    pub limit: usize,
+    pub inodes: usize,
+}
+
+struct Shared {
+    used: AtomicUsize,
+    limit: usize,
+    inodes: AtomicUsize,
+    next_ino: AtomicU32,
+}
+
+impl Shared {
+    /// Reserves `bytes` of the limit, failing if that would exceed it.
+    fn charge(&self, bytes: usize) -> Result<(), FsError> {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.limit)
+            })
+            .map(|_| ())
+            .map_err(|_| FsError::NoSpace)
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+enum Content {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+    Symlink(String),
+}
+
+impl Content {
+    /// The memory charged for the contents.
+    fn charge(&self) -> usize {
+        match self {
+            Content::File(data) => data.len(),
+            Content::Directory(children) => children.keys().map(String::len).sum(),
+            Content::Symlink(target) => target.len(),
+        }
+    }
+}
+
+struct Node {
+    content: Content,
+    mode: u32,
+    nlink: u32,
+    /// When the contents last changed, as returned by [`now`].
+    mtime: u64,
+}
+
+struct TmpInode {
+    ino: u64,
+    fs: Arc<Shared>,
+    /// Lets the inode hand out new references to itself, as when it is hard linked.
+    this: Weak<TmpInode>,
+    node: RwLock<Node>,
+}
+
+impl TmpInode {
+    /// Creates an inode, charging it against the limit of `fs`.
+    fn new(fs: &Arc<Shared>, content: Content, mode: u32) -> Result<Arc<Self>, FsError> {
+        fs.charge(INODE_OVERHEAD + content.charge())?;
+        Ok(Self::build(fs, content, mode))
+    }
+
+    /// Creates an inode whose memory has already been charged.
+    fn build(fs: &Arc<Shared>, content: Content, mode: u32) -> Arc<Self> {
+        fs.inodes.fetch_add(1, Ordering::Relaxed);
+        let nlink = if matches!(content, Content::Directory(_)) {
+            2
+        } else {
+            1
+        };
+        Arc::new_cyclic(|this| TmpInode {
+            ino: u64::from(fs.next_ino.fetch_add(1, Ordering::Relaxed)),
+            fs: fs.clone(),
+            this: this.clone(),
+            node: RwLock::new(Node {
+                content,
+                mode: mode & 0o7777,
+                nlink,
+                mtime: now(),
+            }),
+        })
+    }
+
+    /// Finds the tmpfs inode behind a `dyn Inode` of the same filesystem.
+    fn downcast<'a>(&self, inode: &'a Arc<dyn Inode>) -> Result<&'a TmpInode, FsError> {
+        inode
+            .as_any()
+            .downcast_ref::<TmpInode>()
+            .filter(|other| Arc::ptr_eq(&other.fs, &self.fs))
+            .ok_or(FsError::CrossDevice)
+    }
+
+    /// Returns whether `target` is this inode or lies somewhere beneath it.
+    fn contains(&self, target: &TmpInode) -> bool {
+        if core::ptr::eq(self, target) {
+            return true;
+        }
+        match &self.node.read().content {
+            Content::Directory(children) => children.values().any(|child| child.contains(target)),
+            _ => false,
+        }
+    }
+
+    /// Adds `child` as `name`, which must not exist yet.
+    fn insert(&self, name: &str, child: Arc<TmpInode>) -> Result<(), FsError> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsError::InvalidPath);
+        }
+        let child_is_dir = matches!(child.node.read().content, Content::Directory(_));
+        let mut node = self.node.write();
+        let node = &mut *node;
+        let Content::Directory(children) = &mut node.content else {
+            return Err(FsError::NotADirectory);
+        };
+        if children.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        self.fs.charge(name.len())?;
+        children.insert(name.to_string(), child);
+        // The child's `..` is another link to this directory.
+        if child_is_dir {
+            node.nlink += 1;
+        }
+        node.mtime = now();
+        Ok(())
+    }
+
+    /// Removes `name`, undoing what [`insert`](Self::insert) did to this directory.
+    fn remove(&self, name: &str) -> Option<Arc<TmpInode>> {
+        let mut node = self.node.write();
+        let node = &mut *node;
+        let Content::Directory(children) = &mut node.content else {
+            return None;
+        };
+        let child = children.remove(name)?;
+        if matches!(child.node.read().content, Content::Directory(_)) {
+            node.nlink -= 1;
+        }
+        node.mtime = now();
+        self.fs.release(name.len());
+        Some(child)
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        self.fs
+            .release(INODE_OVERHEAD + self.node.get_mut().content.charge());
+        self.fs.inodes.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn kind_of(content: &Content) -> FileType {
+    match content {
+        Content::File(_) => FileType::File,
+        Content::Directory(_) => FileType::Directory,
+        Content::Symlink(_) => FileType::Symlink,
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Result<Metadata, FsError> {
+        let node = self.node.read();
+        Ok(Metadata {
+            ino: self.ino,
+            kind: kind_of(&node.content),
+            size: match &node.content {
+                Content::File(data) => data.len() as u64,
+                Content::Directory(children) => children.len() as u64,
+                Content::Symlink(target) => target.len() as u64,
+            },
+            mode: node.mode,
+            nlink: node.nlink,
+            uid: 0,
+            gid: 0,
+            mtime: node.mtime,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match &self.node.read().content {
+            Content::Directory(children) => children
+                .get(name)
+                .map(|child| child.clone() as Arc<dyn Inode>)
+                .ok_or(FsError::NotFound),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        match &self.node.read().content {
+            Content::Directory(children) => Ok(children
+                .iter()
+                .map(|(name, child)| DirEntry {
+                    name: name.clone(),
+                    ino: child.ino,
+                    kind: kind_of(&child.node.read().content),
+                })
+                .collect()),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match &self.node.read().content {
+            Content::File(data) => {
+                let start =
+                    usize::try_from(offset).map_or(data.len(), |offset| offset.min(data.len()));
+                let count = (data.len() - start).min(buffer.len());
+                buffer[..count].copy_from_slice(&data[start..start + count]);
+                Ok(count)
+            }
+            Content::Directory(_) => Err(FsError::IsADirectory),
+            Content::Symlink(_) => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut node = self.node.write();
+        let node = &mut *node;
+        let data = match &mut node.content {
+            Content::File(data) => data,
+            Content::Directory(_) => return Err(FsError::IsADirectory),
+            Content::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+        let offset = usize::try_from(offset).map_err(|_| FsError::NoSpace)?;
+        let end = offset.checked_add(buffer.len()).ok_or(FsError::NoSpace)?;
+        if end > data.len() {
+            self.fs.charge(end - data.len())?;
+            if data.try_reserve(end - data.len()).is_err() {
+                self.fs.release(end - data.len());
+                return Err(FsError::NoSpace);
+            }
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(buffer);
+        node.mtime = now();
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut node = self.node.write();
+        let node = &mut *node;
+        let data = match &mut node.content {
+            Content::File(data) => data,
+            Content::Directory(_) => return Err(FsError::IsADirectory),
+            Content::Symlink(_) => return Err(FsError::InvalidArgument),
+        };
+        let size = usize::try_from(size).map_err(|_| FsError::NoSpace)?;
+        if size > data.len() {
+            self.fs.charge(size - data.len())?;
+            if data.try_reserve(size - data.len()).is_err() {
+                self.fs.release(size - data.len());
+                return Err(FsError::NoSpace);
+            }
+        } else {
+            self.fs.release(data.len() - size);
+        }
+        data.resize(size, 0);
+        data.shrink_to_fit();
+        node.mtime = now();
+        Ok(())
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u32) -> Result<Arc<dyn Inode>, FsError> {
+        let content = match kind {
+            FileType::File => Content::File(Vec::new()),
+            FileType::Directory => Content::Directory(BTreeMap::new()),
+            _ => return Err(FsError::NotSupported),
+        };
+        let child = TmpInode::new(&self.fs, content, mode)?;
+        self.insert(name, child.clone())?;
+        Ok(child)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let child = TmpInode::new(&self.fs, Content::Symlink(target.to_string()), 0o777)?;
+        self.insert(name, child.clone())?;
+        Ok(child)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        match &self.node.read().content {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut node = self.node.write();
+        let node = &mut *node;
+        let Content::Directory(children) = &mut node.content else {
+            return Err(FsError::NotADirectory);
+        };
+        let child = children.get(name).ok_or(FsError::NotFound)?;
+        if let Content::Directory(grandchildren) = &child.node.read().content {
+            if !grandchildren.is_empty() {
+                return Err(FsError::NotEmpty);
+            }
+            node.nlink -= 1;
+        }
+        let child = children.remove(name).ok_or(FsError::NotFound)?;
+        child.node.write().nlink -= 1;
+        node.mtime = now();
+        self.fs.release(name.len());
+        Ok(())
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> Result<(), FsError> {
+        let target = self.downcast(inode)?;
+        if matches!(target.node.read().content, Content::Directory(_)) {
+            return Err(FsError::IsADirectory);
+        }
+        let target = target.this.upgrade().ok_or(FsError::NotFound)?;
+        self.insert(name, target.clone())?;
+        target.node.write().nlink += 1;
+        Ok(())
+    }
+
+    fn rename(&self, name: &str, new_dir: &Arc<dyn Inode>, new_name: &str) -> Result<(), FsError> {
+        let new_dir = self.downcast(new_dir)?;
+        if core::ptr::eq(self, new_dir) && name == new_name {
+            return Ok(());
+        }
+
+        let child = match &self.node.read().content {
+            Content::Directory(children) => children.get(name).cloned().ok_or(FsError::NotFound)?,
+            _ => return Err(FsError::NotADirectory),
+        };
+        if child.contains(new_dir) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        // Whatever `new_name` refers to is replaced, as long as the types are compatible.
+        let replaced = match &new_dir.node.read().content {
+            Content::Directory(children) => children.get(new_name).cloned(),
+            _ => return Err(FsError::NotADirectory),
+        };
+        if let Some(replaced) = &replaced {
+            if Arc::ptr_eq(replaced, &child) {
+                return Ok(());
+            }
+            let child_is_dir = matches!(child.node.read().content, Content::Directory(_));
+            match (&replaced.node.read().content, child_is_dir) {
+                (Content::Directory(children), true) if !children.is_empty() => {
+                    return Err(FsError::NotEmpty)
+                }
+                (Content::Directory(_), false) => return Err(FsError::IsADirectory),
+                (Content::File(_) | Content::Symlink(_), true) => {
+                    return Err(FsError::NotADirectory)
+                }
+                _ => {}
+            }
+            new_dir.unlink(new_name)?;
+        }
+
+        new_dir.insert(new_name, child)?;
+        self.remove(name);
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A RAM-backed filesystem instance.
+pub struct TmpFs {
+    shared: Arc<Shared>,
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    /// Creates an empty filesystem that may use up to `limit` bytes of the heap.
+    pub fn new(limit: usize) -> Self {
+        let shared = Arc::new(Shared {
+            // The root directory is always there, even if it does not fit within the limit.
+            used: AtomicUsize::new(INODE_OVERHEAD),
+            limit,
+            inodes: AtomicUsize::new(0),
+            next_ino: AtomicU32::new(1),
+        });
+        let root = TmpInode::build(&shared, Content::Directory(BTreeMap::new()), 0o1777);
+        TmpFs { shared, root }
+    }
+
+    /// Returns how much of its limit the filesystem is using.
+    pub fn usage(&self) -> Usage {
+        Usage {
+            used: self.shared.used.load(Ordering::Relaxed),
+            limit: self.shared.limit,
+            inodes: self.shared.inodes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Filesystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
@@ -0,0 +1,259 @@
+//! The input layer that every input driver reports through.
+//!
+//! Drivers register a [`Device`] and [`report`] what happens on it as [`InputEvent`]s, usually
+//! from their interrupt handlers, ending each batch that belongs together with
+//! [`EventKind::Sync`]. Events reach consumers in two ways:
+//!
+//! - A [`Subscription`] gets its own queue of the events of one device or of all of them, to
+//!   be read at leisure. A slow reader only loses its own events.
+//! - A handler registered with [`register_handler`] sees every event as it is reported, in
+//!   interrupt context. The TTY uses this to turn key presses into typed characters.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    drivers::{
+        keyboard::{KeyCode, KeyEvent, Modifiers},
+        mouse::MouseButton,
+    },
+    ring_buffer::RingBuffer,
+    sync::{RwLock, Semaphore, SpinLock},
+};
+
+/// How many events a subscription holds before the oldest are dropped.
+const QUEUE_CAPACITY: usize = 256;
+/// The most handlers that can be registered.
+const MAX_HANDLERS: usize = 8;
+
+/// Identifies a registered device. Never reused.
+pub type DeviceId = usize;
+
+/// What kinds of events a device reports, combined with `|`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub const KEYS: Capabilities = Capabilities(1 << 0);
+    pub const BUTTONS: Capabilities = Capabilities(1 << 1);
+    pub const RELATIVE: Capabilities = Capabilities(1 << 2);
+    pub const ABSOLUTE: Capabilities = Capabilities(1 << 3);
+    pub const TEXT: Capabilities = Capabilities(1 << 4);
+
+    pub const fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeyState {
+    Released,
+    Pressed,
+    /// The key has been held long enough to repeat.
+    Repeated,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RelativeAxis {
+    X,
+    Y,
+    Wheel,
+    HorizontalWheel,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AbsoluteAxis {
+    X,
+    Y,
+    Pressure,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Key {
+        key: KeyCode,
+        state: KeyState,
+        /// The modifiers in effect after the event.
+        modifiers: Modifiers,
+        /// What the key types under the active keymap, for presses and repeats.
+        ch: Option<char>,
+    },
+    Button {
+        button: MouseButton,
+        pressed: bool,
+    },
+    Relative {
+        axis: RelativeAxis,
+        value: i32,
+    },
+    Absolute {
+        axis: AbsoluteAxis,
+        value: i32,
+    },
+    /// A character from a device that sends text rather than keys, like a serial console.
+    Text(char),
+    /// Ends a batch of events that happened at the same time, like one mouse packet.
+    Sync,
+}
+
+impl From<KeyEvent> for EventKind {
+    fn from(event: KeyEvent) -> Self {
+        EventKind::Key {
+            key: event.key,
+            state: match (event.pressed, event.repeat) {
+                (false, _) => KeyState::Released,
+                (true, false) => KeyState::Pressed,
+                (true, true) => KeyState::Repeated,
+            },
+            modifiers: event.modifiers,
+            ch: event.ch,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+    pub device: DeviceId,
+    pub kind: EventKind,
+}
+
+/// A registered input device.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Device {
+    pub id: DeviceId,
+    pub name: &'static str,
+    pub capabilities: Capabilities,
+}
+
+static DEVICES: RwLock<Vec<Device>> = RwLock::named("input devices", Vec::new());
+
+/// Adds an input device, returning the ID to report its events under.
+pub fn register_device(name: &'static str, capabilities: Capabilities) -> DeviceId {
+    let mut devices = DEVICES.write();
+    let id = devices.len();
+    devices.push(Device {
+        id,
+        name,
+        capabilities,
+    });
+    id
+}
+
+/// Returns every registered device.
+pub fn devices() -> Vec<Device> {
+    DEVICES.read().clone()
+}
+
+pub fn device(id: DeviceId) -> Option<Device> {
+    DEVICES.read().get(id).copied()
+}
+
+/// Runs in whatever context the event was reported from, usually an interrupt handler.
+pub type Handler = fn(&InputEvent);
+
+static HANDLERS: SpinLock<[Option<Handler>; MAX_HANDLERS]> = SpinLock::new([None; MAX_HANDLERS]);
+
+/// Has `handler` called for every event reported from now on.
+pub fn register_handler(handler: Handler) -> Result<(), ()> {
+    let mut handlers = HANDLERS.lock();
+    let slot = handlers.iter_mut().find(|slot| slot.is_none()).ok_or(())?;
+    *slot = Some(handler);
+    Ok(())
+}
+
+struct Subscriber {
+    /// The device whose events are queued, or `None` for all of them.
+    device: Option<DeviceId>,
+    queue: SpinLock<RingBuffer<InputEvent, QUEUE_CAPACITY>>,
+    /// Counts the events in the queue, for readers to wait on.
+    queued: Semaphore,
+    dropped: AtomicUsize,
+}
+
+static SUBSCRIBERS: SpinLock<Vec<Arc<Subscriber>>> =
+    SpinLock::named("input subscribers", Vec::new());
+
+/// A queue of input events. Events stop being queued once it is dropped.
+pub struct Subscription {
+    subscriber: Arc<Subscriber>,
+}
+
+impl Subscription {
+    /// Returns the oldest event that has not been read yet.
+    pub fn next(&self) -> Option<InputEvent> {
+        if !self.subscriber.queued.try_acquire() {
+            return None;
+        }
+        self.subscriber.queue.lock().pop()
+    }
+
+    /// Waits for the next event and returns it.
+    pub fn wait(&self) -> InputEvent {
+        self.subscriber.queued.acquire();
+        self.subscriber
+            .queue
+            .lock()
+            .pop()
+            .expect("input: event counted but not queued")
+    }
+
+    /// Returns how many events were lost because the queue was full, and resets the count.
+    pub fn take_dropped(&self) -> usize {
+        self.subscriber.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        SUBSCRIBERS
+            .lock()
+            .retain(|subscriber| !Arc::ptr_eq(subscriber, &self.subscriber));
+    }
+}
+
+/// Starts queueing the events of `device`, or of every device if it is `None`.
+pub fn subscribe(device: Option<DeviceId>) -> Subscription {
+    let subscriber = Arc::new(Subscriber {
+        device,
+        queue: SpinLock::new(RingBuffer::new()),
+        queued: Semaphore::new(0),
+        dropped: AtomicUsize::new(0),
+    });
+    SUBSCRIBERS.lock().push(subscriber.clone());
+    Subscription { subscriber }
+}
+
+/// Passes an event from `device` on to every handler and subscriber.
+///
+/// Safe to call from interrupt handlers: nothing here allocates.
+pub fn report(device: DeviceId, kind: EventKind) {
+    let event = InputEvent { device, kind };
+    let handlers = *HANDLERS.lock();
+    for handler in handlers.into_iter().flatten() {
+        handler(&event);
+    }
+
+    for subscriber in SUBSCRIBERS.lock().iter() {
+        if subscriber.device.is_some_and(|id| id != device) {
+            continue;
+        }
+        let mut queue = subscriber.queue.lock();
+        if queue.is_full() {
+            // The oldest event makes room, so the count stays the same.
+            subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+            queue.push_overwrite(event);
+        } else {
+            queue.push_overwrite(event);
+            drop(queue);
+            subscriber.queued.release();
+        }
+    }
+}
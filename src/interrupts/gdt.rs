@@ -0,0 +1,60 @@
+//! A flat global descriptor table.
+//!
+//! Multiboot leaves the GDTR pointing at whatever the bootloader used, which may not even be
+//! mapped any more, so the kernel installs its own before taking any interrupts.
+
+use core::{arch::asm, mem::size_of};
+
+/// The selector of the ring 0 code segment.
+pub const KERNEL_CODE: u16 = 0x08;
+/// The selector of the ring 0 data segment.
+pub const KERNEL_DATA: u16 = 0x10;
+
+/// Builds a segment descriptor covering the whole 4 GiB with 4 KiB granularity.
+const fn flat_descriptor(access: u8) -> u64 {
+    let limit_low = 0xFFFF;
+    let limit_high = 0xF << 48;
+    let flags = 0xC << 52; // 32-bit, 4 KiB granularity
+    limit_low | limit_high | flags | (access as u64) << 40
+}
+
+static GDT: [u64; 3] = [
+    0,
+    flat_descriptor(0x9A), // present, ring 0, executable, readable
+    flat_descriptor(0x92), // present, ring 0, writable
+];
+
+#[repr(C, packed)]
+struct Pointer {
+    limit: u16,
+    base: u32,
+}
+
+/// Loads the GDT and reloads every segment register from it.
+pub(super) fn load() {
+    let pointer = Pointer {
+        limit: (size_of::<[u64; 3]>() - 1) as u16,
+        base: GDT.as_ptr() as u32,
+    };
+    // SAFETY: The descriptors describe the same flat address space the bootloader left us in,
+    // so every address stays valid across the switch.
+    unsafe {
+        asm!(
+            "lgdt [{pointer}]",
+            "push {code}",
+            "lea {tmp}, [2f]",
+            "push {tmp}",
+            "retf",
+            "2:",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov fs, {data:x}",
+            "mov gs, {data:x}",
+            "mov ss, {data:x}",
+            pointer = in(reg) &pointer,
+            code = const KERNEL_CODE as u32,
+            data = in(reg) KERNEL_DATA as u32,
+            tmp = out(reg) _,
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! The interrupt descriptor table and the entry stubs it points at.
+//!
+//! Every vector the kernel handles gets a small stub that pushes a dummy error code where the
+//! CPU does not push one, followed by the vector number, so all of them can share a single
+//! path into [`dispatch`](super::dispatch) with the same [`InterruptFrame`](super::InterruptFrame)
+//! layout.
+
+use core::{arch::global_asm, mem::size_of};
+
+use super::gdt::KERNEL_CODE;
+
+/// The number of vectors with a stub: the CPU exceptions followed by the 16 PIC IRQs.
+pub const STUB_COUNT: usize = 48;
+
+global_asm! {r#"
+    .altmacro
+
+    .macro interrupt_stub vector
+    interrupt_stub_\vector:
+        .if (\vector == 8) || ((\vector >= 10) && (\vector <= 14)) || (\vector == 17) || (\vector == 21) || (\vector == 29) || (\vector == 30)
+        .else
+        push 0
+        .endif
+        push \vector
+        jmp interrupt_common
+    .endm
+
+    .macro interrupt_stub_address vector
+        .long interrupt_stub_\vector
+    .endm
+
+    .section .text
+    interrupt_common:
+        pushad
+        cld
+        push esp
+        call {dispatch}
+        add esp, 4
+        popad
+        add esp, 8
+        iretd
+
+    .set vector, 0
+    .rept {count}
+        interrupt_stub %vector
+        .set vector, vector + 1
+    .endr
+
+    .section .rodata.interrupt_stubs, "a"
+    .align 4
+    interrupt_stubs:
+    .set vector, 0
+    .rept {count}
+        interrupt_stub_address %vector
+        .set vector, vector + 1
+    .endr
+
+    .noaltmacro
+"#,
+    dispatch = sym super::dispatch,
+    count = const STUB_COUNT,
+}
+
+extern "C" {
+    static interrupt_stubs: [u32; STUB_COUNT];
+}
+
+/// A 32-bit interrupt gate, which clears IF on entry.
+const INTERRUPT_GATE: u8 = 0x8E;
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct Gate {
+    offset_low: u16,
+    selector: u16,
+    reserved: u8,
+    attributes: u8,
+    offset_high: u16,
+}
+
+impl Gate {
+    const MISSING: Gate = Gate {
+        offset_low: 0,
+        selector: 0,
+        reserved: 0,
+        attributes: 0,
+        offset_high: 0,
+    };
+
+    const fn new(handler: u32) -> Gate {
+        Gate {
+            offset_low: handler as u16,
+            selector: KERNEL_CODE,
+            reserved: 0,
+            attributes: INTERRUPT_GATE,
+            offset_high: (handler >> 16) as u16,
+        }
+    }
+}
+
+static mut IDT: [Gate; 256] = [Gate::MISSING; 256];
+
+#[repr(C, packed)]
+struct Pointer {
+    limit: u16,
+    base: u32,
+}
+
+/// Points every vector with a stub at it and loads the table.
+pub(super) fn load() {
+    // SAFETY: Interrupts are still disabled, so nothing can use the table while it is filled
+    // in, and it is never written again afterwards.
+    unsafe {
+        let idt = &mut *core::ptr::addr_of_mut!(IDT);
+        for (gate, &stub) in idt.iter_mut().zip(interrupt_stubs.iter()) {
+            *gate = Gate::new(stub);
+        }
+        let pointer = Pointer {
+            limit: (size_of::<[Gate; 256]>() - 1) as u16,
+            base: idt.as_ptr() as u32,
+        };
+        core::arch::asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack));
+    }
+}
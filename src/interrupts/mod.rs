@@ -0,0 +1,197 @@
+//! This module sets up the CPU and the 8259 PICs to deliver exceptions and hardware interrupts.
+//!
+//! Drivers attach to an IRQ line with [`register_irq`]. Their handlers run with interrupts
+//! disabled, so anything they share with the rest of the kernel has to be locked through
+//! [`without`], or the interrupted code could be holding the lock the handler spins on.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+mod gdt;
+mod idt;
+mod pic;
+
+pub use pic::{IRQ_BASE, IRQ_COUNT};
+
+use crate::{info, sync::SpinLock, task};
+
+/// The registers pushed by the entry stubs and the CPU, lowest address first.
+#[derive(Debug)]
+#[repr(C)]
+pub struct InterruptFrame {
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    /// The stack pointer from before `pushad`, which is not restored.
+    pub esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    pub vector: u32,
+    /// The error code pushed by the CPU, or zero for vectors without one.
+    pub error_code: u32,
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+}
+
+const EXCEPTIONS: [&str; 32] = [
+    "divide error",
+    "debug",
+    "non-maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid TSS",
+    "segment not present",
+    "stack-segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved",
+    "x87 floating-point exception",
+    "alignment check",
+    "machine check",
+    "SIMD floating-point exception",
+    "virtualization exception",
+    "control protection exception",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "hypervisor injection exception",
+    "VMM communication exception",
+    "security exception",
+    "reserved",
+];
+
+/// Runs in interrupt context when its IRQ fires, before the PIC is acknowledged.
+pub type IrqHandler = fn();
+
+static HANDLERS: SpinLock<[Option<IrqHandler>; IRQ_COUNT as usize]> =
+    SpinLock::named("irq handlers", [None; IRQ_COUNT as usize]);
+
+static COUNTS: [AtomicU32; idt::STUB_COUNT] = [const { AtomicU32::new(0) }; idt::STUB_COUNT];
+
+/// Called by the entry stubs for every interrupt.
+extern "C" fn dispatch(frame: &mut InterruptFrame) {
+    let vector = frame.vector as usize;
+    COUNTS[vector].fetch_add(1, Ordering::Relaxed);
+
+    if vector < EXCEPTIONS.len() {
+        panic!(
+            "cpu: {} (vector {}, error code {:#x}) at {:#010x}\n{:#x?}",
+            EXCEPTIONS[vector], vector, frame.error_code, frame.eip, frame
+        );
+    }
+
+    let irq = (vector - IRQ_BASE as usize) as u8;
+    if pic::is_spurious(irq) {
+        return;
+    }
+    let handler = HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+    pic::end_of_interrupt(irq);
+    // The interrupt may have woken a task that should run instead, or ended a time slice.
+    task::preempt();
+}
+
+/// Installs `handler` for `irq` and unmasks the line. Fails if the line is already taken.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), ()> {
+    assert!(irq < IRQ_COUNT);
+    let mut handlers = HANDLERS.lock();
+    if handlers[irq as usize].is_some() {
+        return Err(());
+    }
+    handlers[irq as usize] = Some(handler);
+    pic::unmask(irq);
+    Ok(())
+}
+
+/// Removes the handler of `irq` and masks the line again.
+pub fn unregister_irq(irq: u8) {
+    assert!(irq < IRQ_COUNT);
+    let mut handlers = HANDLERS.lock();
+    pic::mask(irq);
+    handlers[irq as usize] = None;
+}
+
+/// Returns how often `irq` has fired since boot.
+pub fn irq_count(irq: u8) -> u32 {
+    COUNTS[IRQ_BASE as usize + irq as usize].load(Ordering::Relaxed)
+}
+
+/// Returns how often each vector with a handler has been taken since boot.
+pub fn counts() -> [u32; idt::STUB_COUNT] {
+    core::array::from_fn(|vector| COUNTS[vector].load(Ordering::Relaxed))
+}
+
+/// Returns what the CPU calls exception `vector`, if it is one.
+pub fn exception_name(vector: usize) -> Option<&'static str> {
+    EXCEPTIONS.get(vector).copied()
+}
+
+/// Returns whether interrupts are currently enabled.
+pub fn are_enabled() -> bool {
+    let eflags: u32;
+    // SAFETY: Reading EFLAGS has no side effects.
+    unsafe { asm!("pushfd", "pop {}", out(reg) eflags, options(nomem, preserves_flags)) };
+    eflags & (1 << 9) != 0
+}
+
+/// Allows hardware interrupts to be delivered.
+pub fn enable() {
+    // SAFETY: The IDT is loaded by `initialize` before anything calls this.
+    unsafe { asm!("sti", options(nostack)) }
+}
+
+/// Stops hardware interrupts from being delivered.
+pub fn disable() {
+    // SAFETY: Masking interrupts cannot break memory safety.
+    unsafe { asm!("cli", options(nostack)) }
+}
+
+/// Runs `f` with interrupts disabled, restoring the previous state afterwards.
+pub fn without<T>(f: impl FnOnce() -> T) -> T {
+    let enabled = are_enabled();
+    disable();
+    let result = f();
+    if enabled {
+        enable();
+    }
+    result
+}
+
+/// Enables interrupts and sleeps until the next one arrives.
+///
+/// Called with interrupts disabled after checking for work, this cannot miss a wakeup: `sti`
+/// only takes effect after the following `hlt` has started waiting.
+pub fn enable_and_wait() {
+    // SAFETY: See `enable`.
+    unsafe { asm!("sti", "hlt", options(nostack)) }
+}
+
+/// Installs the GDT and IDT and remaps the PICs. Hardware interrupts stay disabled until
+/// [`enable`] is called.
+pub fn initialize() {
+    disable();
+    gdt::load();
+    idt::load();
+    pic::remap();
+    info!(
+        "interrupts: IDT loaded, IRQs 0-{} on vectors {:#x}-{:#x}",
+        IRQ_COUNT - 1,
+        IRQ_BASE,
+        IRQ_BASE + IRQ_COUNT - 1
+    );
+}
@@ -0,0 +1,105 @@
+//! The pair of cascaded 8259 programmable interrupt controllers.
+
+use crate::intrinsics::{inb, io_wait, outb};
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+const OCW3_READ_ISR: u8 = 0x0B;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// The IRQ line the slave controller is cascaded through.
+const CASCADE_IRQ: u8 = 2;
+
+/// The vector IRQ 0 is delivered on. The PICs power up overlapping the CPU exceptions.
+pub const IRQ_BASE: u8 = 0x20;
+/// The number of IRQ lines across both controllers.
+pub const IRQ_COUNT: u8 = 16;
+
+/// Moves the IRQs to [`IRQ_BASE`] and masks all of them apart from the cascade.
+pub(super) fn remap() {
+    // SAFETY: This is the standard initialization sequence of the 8259s.
+    unsafe {
+        outb(MASTER_COMMAND, ICW1_INIT | ICW1_ICW4);
+        io_wait();
+        outb(SLAVE_COMMAND, ICW1_INIT | ICW1_ICW4);
+        io_wait();
+        outb(MASTER_DATA, IRQ_BASE);
+        io_wait();
+        outb(SLAVE_DATA, IRQ_BASE + 8);
+        io_wait();
+        outb(MASTER_DATA, 1 << CASCADE_IRQ);
+        io_wait();
+        outb(SLAVE_DATA, CASCADE_IRQ);
+        io_wait();
+        outb(MASTER_DATA, ICW4_8086);
+        io_wait();
+        outb(SLAVE_DATA, ICW4_8086);
+        io_wait();
+
+        outb(MASTER_DATA, !(1 << CASCADE_IRQ));
+        outb(SLAVE_DATA, 0xFF);
+    }
+}
+
+fn data_port(irq: u8) -> (u16, u8) {
+    if irq < 8 {
+        (MASTER_DATA, irq)
+    } else {
+        (SLAVE_DATA, irq - 8)
+    }
+}
+
+/// Lets `irq` through to the CPU.
+pub(super) fn unmask(irq: u8) {
+    let (port, bit) = data_port(irq);
+    // SAFETY: Only the mask bit of `irq` changes.
+    unsafe { outb(port, inb(port) & !(1 << bit)) }
+}
+
+/// Stops `irq` from reaching the CPU.
+pub(super) fn mask(irq: u8) {
+    let (port, bit) = data_port(irq);
+    // SAFETY: Only the mask bit of `irq` changes.
+    unsafe { outb(port, inb(port) | 1 << bit) }
+}
+
+/// Returns the in-service register of both controllers, the slave in the high byte.
+fn in_service() -> u16 {
+    // SAFETY: Selecting the ISR for the next read has no other effect.
+    unsafe {
+        outb(MASTER_COMMAND, OCW3_READ_ISR);
+        outb(SLAVE_COMMAND, OCW3_READ_ISR);
+        (inb(SLAVE_COMMAND) as u16) << 8 | inb(MASTER_COMMAND) as u16
+    }
+}
+
+/// Returns whether `irq` was raised by noise on the line rather than a device.
+///
+/// A spurious interrupt still needs the master acknowledged when it came from the slave.
+pub(super) fn is_spurious(irq: u8) -> bool {
+    if (irq == 7 || irq == 15) && in_service() & 1 << irq == 0 {
+        if irq == 15 {
+            // SAFETY: The master did raise IRQ 2 for the cascade.
+            unsafe { outb(MASTER_COMMAND, END_OF_INTERRUPT) }
+        }
+        return true;
+    }
+    false
+}
+
+/// Acknowledges `irq` so the controllers deliver the next one.
+pub(super) fn end_of_interrupt(irq: u8) {
+    // SAFETY: `irq` is being serviced, so acknowledging it is expected.
+    unsafe {
+        if irq >= 8 {
+            outb(SLAVE_COMMAND, END_OF_INTERRUPT);
+        }
+        outb(MASTER_COMMAND, END_OF_INTERRUPT);
+    }
+}
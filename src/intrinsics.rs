@@ -13,3 +13,69 @@ pub fn halt_loop() -> ! {
         }
     }
 }
+
+/// Reads a byte from the given I/O port.
+///
+/// # Safety
+/// Port I/O can have arbitrary side effects on the hardware behind the port.
+#[inline]
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    core::arch::asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Writes a byte to the given I/O port.
+///
+/// # Safety
+/// Port I/O can have arbitrary side effects on the hardware behind the port.
+#[inline]
+pub unsafe fn outb(port: u16, value: u8) {
+    core::arch::asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Reads a word from the given I/O port.
+///
+/// # Safety
+/// Port I/O can have arbitrary side effects on the hardware behind the port.
+#[inline]
+pub unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    core::arch::asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Writes a word to the given I/O port.
+///
+/// # Safety
+/// Port I/O can have arbitrary side effects on the hardware behind the port.
+#[inline]
+pub unsafe fn outw(port: u16, value: u16) {
+    core::arch::asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Reads a double word from the given I/O port.
+///
+/// # Safety
+/// Port I/O can have arbitrary side effects on the hardware behind the port.
+#[inline]
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    core::arch::asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Writes a double word to the given I/O port.
+///
+/// # Safety
+/// Port I/O can have arbitrary side effects on the hardware behind the port.
+#[inline]
+pub unsafe fn outl(port: u16, value: u32) {
+    core::arch::asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Gives slow devices a moment to settle by writing to the unused POST port.
+pub fn io_wait() {
+    // SAFETY: Port 0x80 is only used for POST codes, writing to it has no effect.
+    unsafe { outb(0x80, 0) }
+}
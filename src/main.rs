@@ -4,13 +4,29 @@
 #![feature(used_with_arg)]
 #![feature(const_size_of_val)]
 
+extern crate alloc;
+
 use core::{arch::global_asm, panic::PanicInfo};
 
 use multiboot2::BootInformation;
 
+mod acpi;
+mod boot_modules;
+mod cmdline;
+mod cpu;
+mod drivers;
+mod fs;
+mod input;
+mod interrupts;
 mod intrinsics;
+mod memory;
 mod multiboot;
 mod output;
+mod power;
+mod ring_buffer;
+mod shell;
+mod sync;
+mod task;
 
 global_asm! {r#"
     .section .bss
@@ -29,6 +45,12 @@ pub fn panic(info: &PanicInfo) -> ! {
 /// This method is the portal through which our operating system is executed.
 /// It gets called by [`_start`], which sets up our stack and halt loop.
 pub fn kernel_main(boot_info: BootInformation) {
+    cmdline::initialize(&boot_info);
+    boot_modules::initialize(&boot_info);
+    memory::initialize(&boot_info);
+    memory::heap::initialize();
+    output::setup_serial();
+
     let framebuffer_info = boot_info.framebuffer_tag();
 
     if let Some(framebuffer_info) = framebuffer_info {
@@ -37,7 +59,19 @@ pub fn kernel_main(boot_info: BootInformation) {
     } else {
         output::setup_headless();
     }
-    println!("Hello, world!");
+    cmdline::report();
+    memory::report();
+    boot_modules::report();
+    acpi::initialize(&boot_info);
+    acpi::report();
+    interrupts::initialize();
+    task::initialize();
+    output::tty::line_discipline::initialize();
+    output::serial::initialize_input();
+    drivers::initialize();
+    interrupts::enable();
+    fs::initialize();
+    shell::run()
 }
 
 /// # Safety
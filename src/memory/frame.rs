@@ -0,0 +1,136 @@
+//! A bitmap allocator for physical frames.
+//!
+//! One bit covers each frame of the 32-bit physical address space. A set bit marks a free
+//! frame, so the zeroed bitmap in `.bss` starts out with nothing to give away until the memory
+//! map has been fed in.
+
+use super::{align_up, PAGE_SIZE};
+use crate::sync::SpinLock;
+
+/// The number of frames in the 4 GiB physical address space.
+const FRAME_COUNT: usize = 1 << 20;
+const WORD_BITS: usize = u32::BITS as usize;
+
+pub static FRAME_ALLOCATOR: SpinLock<FrameAllocator> = SpinLock::new(FrameAllocator::new());
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames of usable memory reported by the bootloader.
+    pub total: usize,
+    /// Frames that can currently be allocated.
+    pub free: usize,
+}
+
+pub struct FrameAllocator {
+    bitmap: [u32; FRAME_COUNT / WORD_BITS],
+    total: usize,
+    free: usize,
+    /// The word to start searching from, everything before it is known to be in use.
+    next: usize,
+}
+
+impl FrameAllocator {
+    const fn new() -> Self {
+        FrameAllocator {
+            bitmap: [0; FRAME_COUNT / WORD_BITS],
+            total: 0,
+            free: 0,
+            next: 0,
+        }
+    }
+
+    fn is_free_frame(&self, frame: usize) -> bool {
+        self.bitmap[frame / WORD_BITS] & (1 << (frame % WORD_BITS)) != 0
+    }
+
+    fn mark_free(&mut self, frame: usize) {
+        if !self.is_free_frame(frame) {
+            self.bitmap[frame / WORD_BITS] |= 1 << (frame % WORD_BITS);
+            self.free += 1;
+            self.next = self.next.min(frame / WORD_BITS);
+        }
+    }
+
+    fn mark_used(&mut self, frame: usize) {
+        if self.is_free_frame(frame) {
+            self.bitmap[frame / WORD_BITS] &= !(1 << (frame % WORD_BITS));
+            self.free -= 1;
+        }
+    }
+
+    /// Hands the frames that lie entirely within `start..end` to the allocator. Anything above
+    /// 4 GiB is out of reach and ignored.
+    pub fn add_region(&mut self, start: u64, end: u64) {
+        let page_size = PAGE_SIZE as u64;
+        let first = start.div_ceil(page_size).min(FRAME_COUNT as u64) as usize;
+        let last = (end / page_size).min(FRAME_COUNT as u64) as usize;
+        for frame in first..last {
+            if !self.is_free_frame(frame) {
+                self.total += 1;
+                self.mark_free(frame);
+            }
+        }
+    }
+
+    /// Takes every frame that overlaps `start..end` out of circulation.
+    pub fn reserve(&mut self, start: usize, end: usize) {
+        let first = start / PAGE_SIZE;
+        let last = end.div_ceil(PAGE_SIZE).min(FRAME_COUNT);
+        for frame in first..last {
+            self.mark_used(frame);
+        }
+    }
+
+    /// Returns whether the frame containing `addr` is free.
+    pub fn is_free(&self, addr: usize) -> bool {
+        self.is_free_frame(addr / PAGE_SIZE)
+    }
+
+    /// Allocates a single frame, returning its physical address.
+    pub fn allocate(&mut self) -> Option<usize> {
+        let word = (self.next..self.bitmap.len()).find(|&i| self.bitmap[i] != 0)?;
+        self.next = word;
+        let frame = word * WORD_BITS + self.bitmap[word].trailing_zeros() as usize;
+        self.mark_used(frame);
+        Some(frame * PAGE_SIZE)
+    }
+
+    /// Allocates `count` physically contiguous frames whose first frame is aligned to `align`
+    /// bytes, returning the physical address of the first one.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<usize> {
+        let step = (align.max(PAGE_SIZE) / PAGE_SIZE).max(1);
+        let mut first = align_up(self.next * WORD_BITS, step);
+        while first + count <= FRAME_COUNT {
+            match (first..first + count).find(|&frame| !self.is_free_frame(frame)) {
+                Some(used) => first = align_up(used + 1, step),
+                None => {
+                    for frame in first..first + count {
+                        self.mark_used(frame);
+                    }
+                    return Some(first * PAGE_SIZE);
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the frame at `addr` to the allocator.
+    pub fn deallocate(&mut self, addr: usize) {
+        debug_assert!(addr.is_multiple_of(PAGE_SIZE));
+        self.mark_free(addr / PAGE_SIZE);
+    }
+
+    /// Returns `count` contiguous frames starting at `addr` to the allocator.
+    pub fn deallocate_contiguous(&mut self, addr: usize, count: usize) {
+        for i in 0..count {
+            self.deallocate(addr + i * PAGE_SIZE);
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            free: self.free,
+        }
+    }
+}
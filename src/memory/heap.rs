@@ -0,0 +1,211 @@
+//! The kernel heap behind `alloc`.
+//!
+//! Free memory is kept in an address ordered list of blocks that is searched first fit and
+//! coalesced on every free. Whenever no block is large enough, the heap grows by taking
+//! contiguous frames from the [`FRAME_ALLOCATOR`](super::FRAME_ALLOCATOR).
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::{align_of, size_of},
+    ptr::{self, null_mut},
+};
+
+use super::{align_up, FRAME_ALLOCATOR, PAGE_SIZE};
+use crate::sync::SpinLock;
+
+/// The smallest amount the heap grows by at once.
+const GROW_SIZE: usize = 1024 * 1024;
+
+#[global_allocator]
+static HEAP: LockedHeap = LockedHeap(SpinLock::new(Heap::new()));
+
+/// Usage of the kernel heap, in bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Memory taken from the frame allocator for the heap.
+    pub size: usize,
+    /// Memory currently handed out, including rounding.
+    pub used: usize,
+    /// The most memory that has been handed out at once.
+    pub peak: usize,
+    /// The number of live allocations.
+    pub allocations: usize,
+}
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// Every block, free or allocated, is a multiple of this size so a free block always fits.
+const BLOCK_SIZE: usize = size_of::<FreeBlock>();
+const BLOCK_ALIGN: usize = align_of::<FreeBlock>();
+
+struct Heap {
+    head: *mut FreeBlock,
+    stats: HeapStats,
+}
+
+// SAFETY: The free list is only reached through the lock around the heap.
+unsafe impl Send for Heap {}
+
+impl Heap {
+    const fn new() -> Self {
+        Heap {
+            head: null_mut(),
+            stats: HeapStats {
+                size: 0,
+                used: 0,
+                peak: 0,
+                allocations: 0,
+            },
+        }
+    }
+
+    /// Rounds a layout up to what is actually carved out of a free block.
+    fn block_layout(layout: Layout) -> (usize, usize) {
+        let size = align_up(layout.size().max(BLOCK_SIZE), BLOCK_SIZE);
+        (size, layout.align().max(BLOCK_ALIGN))
+    }
+
+    /// Puts the block at `addr` back into the free list, merging it with its neighbours.
+    ///
+    /// # Safety
+    /// `addr..addr + size` must be unused memory owned by the heap.
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if !next.is_null() && addr + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    /// Carves `size` bytes aligned to `align` out of the first free block that can hold them.
+    unsafe fn take(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut current = self.head;
+        while !current.is_null() {
+            let start = current as usize;
+            let end = start + (*current).size;
+
+            // Any gap in front of the allocation has to be large enough to stay a free block.
+            let mut alloc_start = align_up(start, align);
+            if alloc_start != start && alloc_start - start < BLOCK_SIZE {
+                alloc_start = align_up(start + BLOCK_SIZE, align);
+            }
+            let alloc_end = alloc_start + size;
+            let fits = alloc_end <= end && (end - alloc_end == 0 || end - alloc_end >= BLOCK_SIZE);
+
+            if fits {
+                let next = (*current).next;
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if alloc_start > start {
+                    self.insert(start, alloc_start - start);
+                }
+                if end > alloc_end {
+                    self.insert(alloc_end, end - alloc_end);
+                }
+                return alloc_start as *mut u8;
+            }
+
+            prev = current;
+            current = (*current).next;
+        }
+        null_mut()
+    }
+
+    /// Takes enough frames to satisfy an allocation of `size` bytes aligned to `align`.
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        let bytes = align_up((size + align).max(GROW_SIZE), PAGE_SIZE);
+        let Some(addr) = FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous(bytes / PAGE_SIZE, PAGE_SIZE)
+        else {
+            return false;
+        };
+        self.stats.size += bytes;
+        // SAFETY: The frames were just allocated and are owned by the heap from now on.
+        unsafe { self.insert(addr, bytes) };
+        true
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::block_layout(layout);
+        let mut ptr = unsafe { self.take(size, align) };
+        if ptr.is_null() && self.grow(size, align) {
+            ptr = unsafe { self.take(size, align) };
+        }
+        if !ptr.is_null() {
+            self.stats.used += size;
+            self.stats.peak = self.stats.peak.max(self.stats.used);
+            self.stats.allocations += 1;
+        }
+        ptr
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::block_layout(layout);
+        self.insert(ptr as usize, size);
+        self.stats.used -= size;
+        self.stats.allocations -= 1;
+    }
+}
+
+// The lock disables interrupts, so neither an interrupt handler nor the scheduler, which
+// allocates with interrupts disabled, can find it held by a task they interrupted. It has no
+// name because lock debugging must not come back into the heap.
+struct LockedHeap(SpinLock<Heap>);
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().deallocate(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        // Blocks are rounded up, so small changes often still fit.
+        if Heap::block_layout(layout).0 == Heap::block_layout(new_layout).0 {
+            return ptr;
+        }
+        let new = self.alloc(new_layout);
+        if !new.is_null() {
+            ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new
+    }
+}
+
+/// Sets up the initial heap so early allocations do not have to wait for it to grow.
+pub fn initialize() {
+    HEAP.0.lock().grow(GROW_SIZE, PAGE_SIZE);
+}
+
+pub fn stats() -> HeapStats {
+    HEAP.0.lock().stats
+}
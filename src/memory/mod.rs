@@ -0,0 +1,156 @@
+//! This module tracks physical memory.
+//!
+//! Paging is left disabled by GRUB, so every physical address is also the address the kernel
+//! uses to reach it.
+
+use multiboot2::{BootInformation, MemoryAreaType};
+use spin::Once;
+
+pub mod frame;
+pub mod heap;
+
+pub use frame::FRAME_ALLOCATOR;
+
+use crate::info;
+
+/// The size of a physical frame.
+pub const PAGE_SIZE: usize = 4096;
+
+/// The most regions of the boot memory map that are kept around.
+const MAX_REGIONS: usize = 64;
+
+/// Rounds `addr` down to a multiple of `align`, which must be a power of two.
+pub const fn align_down(addr: usize, align: usize) -> usize {
+    addr & !(align - 1)
+}
+
+/// Rounds `addr` up to a multiple of `align`, which must be a power of two.
+pub const fn align_up(addr: usize, align: usize) -> usize {
+    align_down(addr + align - 1, align)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    Available,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Defective,
+}
+
+impl RegionKind {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            RegionKind::Available => "available",
+            RegionKind::Reserved => "reserved",
+            RegionKind::AcpiReclaimable => "ACPI reclaimable",
+            RegionKind::AcpiNvs => "ACPI NVS",
+            RegionKind::Defective => "defective",
+        }
+    }
+}
+
+/// An entry of the memory map handed over by the bootloader.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: RegionKind,
+}
+
+struct MemoryMap {
+    regions: [MemoryRegion; MAX_REGIONS],
+    len: usize,
+}
+
+static MEMORY_MAP: Once<MemoryMap> = Once::new();
+
+/// Returns the memory map the kernel was booted with.
+pub fn memory_map() -> &'static [MemoryRegion] {
+    MEMORY_MAP.get().map_or(&[], |map| &map.regions[..map.len])
+}
+
+/// Returns the physical range occupied by the kernel image, including its `.bss`.
+pub fn kernel_range() -> (usize, usize) {
+    extern "C" {
+        static __kernel_start: u8;
+        static __kernel_end: u8;
+    }
+
+    // Both symbols are defined by the linker script, only their addresses are meaningful.
+    (
+        core::ptr::addr_of!(__kernel_start) as usize,
+        core::ptr::addr_of!(__kernel_end) as usize,
+    )
+}
+
+/// Records the memory map and hands every available frame to the [`FRAME_ALLOCATOR`], except
+/// for the ones occupied by the kernel, the multiboot2 information and the boot modules.
+pub fn initialize(boot_info: &BootInformation) {
+    let map = MEMORY_MAP.call_once(|| {
+        let mut map = MemoryMap {
+            regions: [MemoryRegion {
+                start: 0,
+                end: 0,
+                kind: RegionKind::Reserved,
+            }; MAX_REGIONS],
+            len: 0,
+        };
+        let areas = boot_info
+            .memory_map_tag()
+            .into_iter()
+            .flat_map(|tag| tag.all_memory_areas());
+        for (slot, area) in map.regions.iter_mut().zip(areas) {
+            *slot = MemoryRegion {
+                start: area.start_address(),
+                end: area.end_address(),
+                kind: match area.typ() {
+                    MemoryAreaType::Available => RegionKind::Available,
+                    MemoryAreaType::Reserved => RegionKind::Reserved,
+                    MemoryAreaType::AcpiAvailable => RegionKind::AcpiReclaimable,
+                    MemoryAreaType::ReservedHibernate => RegionKind::AcpiNvs,
+                    MemoryAreaType::Defective => RegionKind::Defective,
+                },
+            };
+            map.len += 1;
+        }
+        map
+    });
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    for region in &map.regions[..map.len] {
+        if region.kind == RegionKind::Available {
+            allocator.add_region(region.start, region.end);
+        }
+    }
+
+    // The first MiB holds the IVT, BIOS data, VGA memory and option ROMs.
+    allocator.reserve(0, 0x100000);
+    let (kernel_start, kernel_end) = kernel_range();
+    allocator.reserve(kernel_start, kernel_end);
+    allocator.reserve(boot_info.start_address(), boot_info.end_address());
+    for module in boot_info.module_tags() {
+        allocator.reserve(
+            module.start_address() as usize,
+            module.end_address() as usize,
+        );
+    }
+}
+
+/// Logs the memory map and how much of it the frame allocator manages.
+pub fn report() {
+    for region in memory_map() {
+        info!(
+            "memory: [{:#011x}-{:#011x}] {}",
+            region.start,
+            region.end.saturating_sub(1),
+            region.kind.name()
+        );
+    }
+    let stats = FRAME_ALLOCATOR.lock().stats();
+    info!(
+        "memory: {} KiB free of {} KiB usable",
+        stats.free * PAGE_SIZE / 1024,
+        stats.total * PAGE_SIZE / 1024
+    );
+}
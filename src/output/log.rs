@@ -0,0 +1,123 @@
+//! Leveled kernel logging on top of [`print!`](crate::print).
+//!
+//! Messages above the level set by `loglevel=` are dropped. The level may be given by name
+//! (`error`, `warn`, `info`, `debug`, `trace`) or by number (`0` to `4`).
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::{
+    boot_param,
+    cmdline::{ParamError, Value},
+};
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl LogLevel {
+    const fn from_u8(level: u8) -> Option<Self> {
+        Some(match level {
+            0 => LogLevel::Error,
+            1 => LogLevel::Warn,
+            2 => LogLevel::Info,
+            3 => LogLevel::Debug,
+            4 => LogLevel::Trace,
+            _ => return None,
+        })
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "error" => LogLevel::Error,
+            "warn" => LogLevel::Warn,
+            "info" => LogLevel::Info,
+            "debug" => LogLevel::Debug,
+            "trace" => LogLevel::Trace,
+            _ => return None,
+        })
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Returns the most verbose level that is currently printed.
+pub fn level() -> LogLevel {
+    LogLevel::from_u8(LOG_LEVEL.load(Ordering::Relaxed)).unwrap_or(LogLevel::Info)
+}
+
+pub fn set_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level <= self::level()
+}
+
+fn set_loglevel(value: Option<Value<'static>>) -> Result<(), ParamError> {
+    let value = value.ok_or(ParamError::MissingValue)?;
+    let level = LogLevel::from_name(value.as_str())
+        .or_else(|| LogLevel::from_u8(value.parse().ok()?))
+        .ok_or(ParamError::Invalid)?;
+    set_level(level);
+    Ok(())
+}
+
+boot_param!(LOGLEVEL_PARAM, "loglevel", set_loglevel);
+
+#[doc(hidden)]
+pub fn _log(level: LogLevel, args: fmt::Arguments) {
+    if enabled(level) {
+        super::_print(format_args!("[{:>5}] {}\n", level.name(), args));
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::output::log::_log($level, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::output::log::LogLevel::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::output::log::LogLevel::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::output::log::LogLevel::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::output::log::LogLevel::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::output::log::LogLevel::Trace, $($arg)*));
+}
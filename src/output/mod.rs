@@ -1,12 +1,91 @@
 //! This module provides support for outputting data
 
+use core::sync::atomic::{AtomicU8, Ordering};
+
 use multiboot2::{FramebufferTag, FramebufferType};
 
 use self::tty::WRITER;
+use crate::{
+    boot_param,
+    cmdline::{ParamError, Value},
+    sync::SpinLock,
+};
 
+pub mod log;
+pub mod serial;
 pub mod tty;
 
+/// A set of consoles that kernel output is written to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Consoles(u8);
+
+impl Consoles {
+    pub const SERIAL: Consoles = Consoles(1 << 0);
+    pub const VGA: Consoles = Consoles(1 << 1);
+
+    pub const fn contains(&self, other: Consoles) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+static CONSOLES: AtomicU8 = AtomicU8::new(Consoles::VGA.0);
+
+/// Returns the consoles selected with `console=`.
+pub fn consoles() -> Consoles {
+    Consoles(CONSOLES.load(Ordering::Relaxed))
+}
+
+fn set_console(value: Option<Value<'static>>) -> Result<(), ParamError> {
+    let mut consoles = 0;
+    for name in value.ok_or(ParamError::MissingValue)?.list() {
+        consoles |= match name {
+            "serial" | "ttyS0" => Consoles::SERIAL.0,
+            "vga" | "tty0" => Consoles::VGA.0,
+            _ => return Err(ParamError::Invalid),
+        };
+    }
+    if consoles == 0 {
+        return Err(ParamError::Invalid);
+    }
+    CONSOLES.store(consoles, Ordering::Relaxed);
+    Ok(())
+}
+
+boot_param!(CONSOLE_PARAM, "console", set_console);
+
+/// Where the framebuffer the bootloader set up is, which is text for a VGA console.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub address: usize,
+    pub width: u32,
+    pub height: u32,
+    /// The number of bytes from one row to the next.
+    pub pitch: u32,
+    pub bpp: u8,
+}
+
+impl Framebuffer {
+    /// The size of the memory it takes up, in bytes.
+    pub const fn size(&self) -> usize {
+        self.pitch as usize * self.height as usize
+    }
+}
+
+static FRAMEBUFFER: SpinLock<Option<Framebuffer>> = SpinLock::new(None);
+
+/// Returns the framebuffer, if the bootloader described one.
+pub fn framebuffer() -> Option<Framebuffer> {
+    *FRAMEBUFFER.lock()
+}
+
 pub fn setup_visuals(framebuffer_info: &FramebufferTag) {
+    *FRAMEBUFFER.lock() = Some(Framebuffer {
+        address: framebuffer_info.address as usize,
+        width: framebuffer_info.width,
+        height: framebuffer_info.height,
+        pitch: framebuffer_info.pitch,
+        bpp: framebuffer_info.bpp,
+    });
     match &framebuffer_info.buffer_type {
         FramebufferType::Text => setup_vga(framebuffer_info),
         FramebufferType::RGB { red, green, blue } => {
@@ -21,6 +100,14 @@ pub fn setup_visuals(framebuffer_info: &FramebufferTag) {
     }
 }
 
+/// Brings up the serial console if it was selected with `console=serial`.
+pub fn setup_serial() {
+    if consoles().contains(Consoles::SERIAL) && serial::initialize().is_err() {
+        // Without a UART there is nothing to write to, so fall back to the screen.
+        CONSOLES.store(Consoles::VGA.0, Ordering::Relaxed);
+    }
+}
+
 pub fn setup_headless() {
     WRITER.initialize(0xb8000, 80, 25).unwrap();
 }
@@ -34,3 +121,14 @@ fn setup_vga(framebuffer_info: &FramebufferTag) {
         )
         .unwrap();
 }
+
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    let consoles = consoles();
+    if consoles.contains(Consoles::SERIAL) {
+        serial::_print(args);
+    }
+    if consoles.contains(Consoles::VGA) {
+        tty::_print(args);
+    }
+}
@@ -0,0 +1,153 @@
+//! A minimal driver for the 16550 UART found at COM1.
+//!
+//! Besides carrying the console output, the port can be an input device: once
+//! [`initialize_input`] has run, every byte received is reported as text.
+
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    info,
+    input::{self, Capabilities, EventKind},
+    interrupts,
+    intrinsics::{inb, outb},
+    sync::SpinLock,
+};
+
+/// The I/O port base of the first serial port.
+pub const COM1: u16 = 0x3F8;
+/// The IRQ line COM1 raises.
+const COM1_IRQ: u8 = 4;
+
+pub(super) static SERIAL: SpinLock<Option<SerialPort>> = SpinLock::named("serial", None);
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// Programs the UART for 38400 baud, 8 data bits, no parity and one stop bit, and checks
+    /// that it echoes a byte back in loopback mode.
+    ///
+    /// # Safety
+    /// `base` must be the I/O base of a 16550 compatible UART.
+    pub unsafe fn new(base: u16) -> Result<Self, ()> {
+        outb(base + 1, 0x00); // Disable interrupts
+        outb(base + 3, 0x80); // Enable DLAB to set the baud rate divisor
+        outb(base, 0x03); // Divisor low byte (38400 baud)
+        outb(base + 1, 0x00); // Divisor high byte
+        outb(base + 3, 0x03); // 8 bits, no parity, one stop bit
+        outb(base + 2, 0xC7); // Enable and clear FIFOs with a 14-byte threshold
+        outb(base + 4, 0x1E); // Loopback mode to test the chip
+
+        outb(base, 0xAE);
+        if inb(base) != 0xAE {
+            return Err(());
+        }
+
+        outb(base + 4, 0x0F); // Normal operation with IRQs, RTS and DTR set
+        Ok(SerialPort { base })
+    }
+
+    fn transmit_empty(&self) -> bool {
+        unsafe { inb(self.base + 5) & 0x20 != 0 }
+    }
+
+    /// Returns whether a received byte is waiting to be read.
+    pub fn received(&self) -> bool {
+        unsafe { inb(self.base + 5) & 0x01 != 0 }
+    }
+
+    /// Reads a byte if one has been received.
+    pub fn try_read(&mut self) -> Option<u8> {
+        if self.received() {
+            Some(unsafe { inb(self.base) })
+        } else {
+            None
+        }
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        while !self.transmit_empty() {
+            core::hint::spin_loop();
+        }
+        unsafe { outb(self.base, byte) }
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Brings up COM1, returning `Err` if no UART answers there.
+pub(super) fn initialize() -> Result<(), ()> {
+    let mut lock = SERIAL.lock();
+    if lock.is_none() {
+        lock.replace(unsafe { SerialPort::new(COM1)? });
+    }
+    Ok(())
+}
+
+/// Returns whether COM1 was brought up.
+pub fn is_present() -> bool {
+    SERIAL.lock().is_some()
+}
+
+/// Sends raw bytes out of COM1, as the `ttyS0` device does, turning newlines into CRLF.
+pub fn write_bytes(bytes: &[u8]) {
+    if let Some(port) = SERIAL.lock().as_mut() {
+        for &byte in bytes {
+            if byte == b'\n' {
+                port.send(b'\r');
+            }
+            port.send(byte);
+        }
+    }
+}
+
+/// The input device the received bytes are reported under, set once input is running.
+static DEVICE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Reads everything the UART has received. This never takes [`SERIAL`], which whoever was
+/// interrupted may be holding to print.
+fn handle_irq() {
+    let device = DEVICE.load(Ordering::Relaxed);
+    while unsafe { inb(COM1 + 5) } & 0x01 != 0 {
+        let byte = unsafe { inb(COM1) };
+        if device != usize::MAX && byte.is_ascii() {
+            input::report(device, EventKind::Text(byte as char));
+        }
+    }
+}
+
+/// Starts reporting what arrives on COM1 as input, if it was brought up as a console.
+pub fn initialize_input() {
+    if SERIAL.lock().is_none() {
+        return;
+    }
+    let device = input::register_device("serial", Capabilities::TEXT);
+    DEVICE.store(device, Ordering::Relaxed);
+    if interrupts::register_irq(COM1_IRQ, handle_irq).is_err() {
+        info!("serial: IRQ {} is taken, not reading input", COM1_IRQ);
+        return;
+    }
+    unsafe { outb(COM1 + 1, 0x01) } // Interrupt when data is received
+    info!("serial: reading input on COM1");
+}
+
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    if let Some(port) = SERIAL.lock().as_mut() {
+        port.write_fmt(args).ok();
+    }
+}
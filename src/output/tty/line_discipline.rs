@@ -0,0 +1,565 @@
+//! The line discipline between the input layer and whoever reads the console.
+//!
+//! Keys and the text of devices like the serial console arrive from the
+//! [`input`](crate::input) layer in interrupt context and are only queued there. They are
+//! interpreted when the console is read, which is also when they are echoed, so interrupt
+//! handlers never wait on the screen or the serial port.
+//!
+//! In [`Mode::Canonical`] input is gathered into lines that can be edited before they are
+//! handed over: backspace and delete, the cursor keys, Ctrl-A and Ctrl-E to jump to either end,
+//! Ctrl-U to erase the line, up and down to go through earlier lines, Ctrl-C to give up on the
+//! line and Ctrl-D on an empty line to signal the end of input. Tab completes the word before
+//! the cursor once a [`Completer`] is set. In [`Mode::Raw`] every [`Input`] is handed over as
+//! it comes.
+
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::{
+    drivers::keyboard::{KeyCode, Modifiers},
+    input::{self, EventKind, InputEvent, KeyState},
+    print,
+    ring_buffer::RingBuffer,
+    sync::{Mutex, SpinLock, WaitQueue},
+};
+
+/// The most inputs that can be waiting to be interpreted. Later ones are dropped.
+const CAPACITY: usize = 512;
+/// How many earlier lines are kept for going back to.
+const HISTORY_LENGTH: usize = 32;
+
+const CTRL_A: char = '\x01';
+const CTRL_C: char = '\x03';
+const CTRL_D: char = '\x04';
+const CTRL_E: char = '\x05';
+const BACKSPACE: char = '\x08';
+const CTRL_U: char = '\x15';
+const ESCAPE: char = '\x1b';
+const DELETE: char = '\x7f';
+
+/// A single piece of input: a typed character or one of the editing keys.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Delete,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Input is edited and read a line at a time.
+    Canonical,
+    /// Input is read as it comes, without any editing.
+    Raw,
+}
+
+/// Why [`read_line`] or [`read`] did not return a line.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReadError {
+    /// The line was abandoned with Ctrl-C.
+    Interrupted,
+    /// Ctrl-D was pressed on an empty line.
+    EndOfInput,
+}
+
+/// How far into an escape sequence from a terminal the text input is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Escape {
+    None,
+    Started,
+    /// Inside a control sequence, having seen the digit so far if there was one.
+    Sequence(Option<u8>),
+}
+
+/// The inputs that arrived but have not been interpreted yet.
+struct Pending {
+    inputs: RingBuffer<Input, CAPACITY>,
+    escape: Escape,
+}
+
+static PENDING: SpinLock<Pending> = SpinLock::new(Pending {
+    inputs: RingBuffer::new(),
+    escape: Escape::None,
+});
+/// The tasks waiting for something to be pending.
+static INPUT: WaitQueue = WaitQueue::new();
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Canonical as u8);
+static ECHO: AtomicBool = AtomicBool::new(true);
+
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        0 => Mode::Canonical,
+        _ => Mode::Raw,
+    }
+}
+
+/// Switches modes. A line that was being edited is thrown away.
+pub fn set_mode(mode: Mode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+    let mut editor = EDITOR.lock();
+    editor.line.clear();
+    editor.cursor = 0;
+    editor.browsing = None;
+}
+
+pub fn echo() -> bool {
+    ECHO.load(Ordering::Relaxed)
+}
+
+/// Sets whether what is typed is shown on the console.
+pub fn set_echo(echo: bool) {
+    ECHO.store(echo, Ordering::Relaxed);
+}
+
+impl Pending {
+    fn push(&mut self, input: Input) {
+        let _ = self.inputs.push(input);
+    }
+
+    /// Turns text into inputs, recognising the escape sequences terminals send for the
+    /// editing keys.
+    fn push_text(&mut self, ch: char) {
+        self.escape = match (self.escape, ch) {
+            (Escape::None, ESCAPE) => Escape::Started,
+            (Escape::None, ch) => {
+                self.push(Input::Char(match ch {
+                    // Terminals send a carriage return for enter.
+                    '\r' => '\n',
+                    DELETE => BACKSPACE,
+                    ch => ch,
+                }));
+                Escape::None
+            }
+            (Escape::Started, '[' | 'O') => Escape::Sequence(None),
+            (Escape::Started, ch) => {
+                self.push(Input::Char(ESCAPE));
+                self.escape = Escape::None;
+                return self.push_text(ch);
+            }
+            (Escape::Sequence(None), '0'..='9') => Escape::Sequence(Some(ch as u8 - b'0')),
+            (Escape::Sequence(digit), ch) => {
+                let input = match (digit, ch) {
+                    (None, 'A') => Some(Input::Up),
+                    (None, 'B') => Some(Input::Down),
+                    (None, 'C') => Some(Input::Right),
+                    (None, 'D') => Some(Input::Left),
+                    (None, 'H') | (Some(1 | 7), '~') => Some(Input::Home),
+                    (None, 'F') | (Some(4 | 8), '~') => Some(Input::End),
+                    (Some(3), '~') => Some(Input::Delete),
+                    _ => None,
+                };
+                if let Some(input) = input {
+                    self.push(input);
+                }
+                Escape::None
+            }
+        };
+    }
+}
+
+/// Returns the editing key `key` stands for, if any.
+fn editing_key(key: KeyCode, modifiers: Modifiers) -> Option<Input> {
+    // Without num lock the keypad acts as the navigation keys.
+    let keypad = !modifiers.contains(Modifiers::NUM_LOCK);
+    Some(match key {
+        KeyCode::Up => Input::Up,
+        KeyCode::Keypad8 if keypad => Input::Up,
+        KeyCode::Down => Input::Down,
+        KeyCode::Keypad2 if keypad => Input::Down,
+        KeyCode::Left => Input::Left,
+        KeyCode::Keypad4 if keypad => Input::Left,
+        KeyCode::Right => Input::Right,
+        KeyCode::Keypad6 if keypad => Input::Right,
+        KeyCode::Home => Input::Home,
+        KeyCode::Keypad7 if keypad => Input::Home,
+        KeyCode::End => Input::End,
+        KeyCode::Keypad1 if keypad => Input::End,
+        KeyCode::Delete => Input::Delete,
+        KeyCode::KeypadPeriod if keypad => Input::Delete,
+        _ => return None,
+    })
+}
+
+/// Queues what an input event types and wakes the readers. Runs in interrupt context, so it
+/// does nothing else.
+fn handle(event: &InputEvent) {
+    queue(event);
+    INPUT.wake_all();
+}
+
+fn queue(event: &InputEvent) {
+    let mut pending = PENDING.lock();
+    match event.kind {
+        EventKind::Key {
+            key,
+            state: KeyState::Pressed | KeyState::Repeated,
+            modifiers,
+            ch,
+        } => {
+            if let Some(input) = ch.map(Input::Char).or_else(|| editing_key(key, modifiers)) {
+                pending.push(input);
+            }
+        }
+        EventKind::Text(ch) => pending.push_text(ch),
+        _ => {}
+    }
+}
+
+/// Starts taking input from the input layer.
+pub fn initialize() {
+    input::register_handler(handle).expect("tty: no room for the input handler");
+}
+
+fn next_pending() -> Option<Input> {
+    PENDING.lock().inputs.pop()
+}
+
+/// Waits until something is pending. Returns straight away if anything already is.
+fn wait_for_input() {
+    INPUT.wait_until(|| !PENDING.lock().inputs.is_empty());
+}
+
+/// Given the line up to the cursor, returns the words the last word in it could be completed
+/// to. A completion ending in `/` is not followed by a space, so that it can be continued.
+pub type Completer = fn(&str) -> Vec<String>;
+
+/// The line being edited in canonical mode and the lines that came before it.
+struct Editor {
+    /// Shown again before the line when completion has to list the choices.
+    prompt: String,
+    completer: Option<Completer>,
+    line: Vec<char>,
+    /// Where in `line` the next character goes.
+    cursor: usize,
+    history: VecDeque<String>,
+    /// The entry of `history` being shown, with the line that was being typed before going
+    /// back kept aside.
+    browsing: Option<(usize, Vec<char>)>,
+}
+
+/// Held while tab completion reads directories, so it has to be a sleeping lock.
+static EDITOR: Mutex<Editor> = Mutex::new(Editor {
+    prompt: String::new(),
+    completer: None,
+    line: Vec::new(),
+    cursor: 0,
+    history: VecDeque::new(),
+    browsing: None,
+});
+
+/// Collects what an edit does to the screen so that it can be printed in one go.
+struct Echo(String);
+
+impl Echo {
+    fn new() -> Self {
+        Echo(String::new())
+    }
+
+    fn chars(&mut self, chars: &[char]) {
+        self.0.extend(chars);
+    }
+
+    fn back(&mut self, count: usize) {
+        self.0.extend(core::iter::repeat_n(BACKSPACE, count));
+    }
+
+    fn flush(self) {
+        if echo() && !self.0.is_empty() {
+            print!("{}", self.0);
+        }
+    }
+}
+
+impl Editor {
+    fn insert(&mut self, ch: char, echo: &mut Echo) {
+        self.line.insert(self.cursor, ch);
+        echo.chars(&self.line[self.cursor..]);
+        self.cursor += 1;
+        echo.back(self.line.len() - self.cursor);
+    }
+
+    /// Removes the character at `index`, which is at or just before the cursor.
+    fn remove(&mut self, index: usize, echo: &mut Echo) {
+        echo.back(self.cursor - index);
+        self.line.remove(index);
+        self.cursor = index;
+        echo.chars(&self.line[index..]);
+        echo.chars(&[' ']);
+        echo.back(self.line.len() - index + 1);
+    }
+
+    fn move_to(&mut self, cursor: usize, echo: &mut Echo) {
+        if cursor < self.cursor {
+            echo.back(self.cursor - cursor);
+        } else {
+            echo.chars(&self.line[self.cursor..cursor]);
+        }
+        self.cursor = cursor;
+    }
+
+    /// Swaps the whole line for `line`, leaving the cursor at its end.
+    fn replace(&mut self, line: Vec<char>, echo: &mut Echo) {
+        self.move_to(self.line.len(), echo);
+        for _ in 0..self.line.len() {
+            echo.0.push_str("\x08 \x08");
+        }
+        echo.chars(&line);
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    /// Goes `back` lines further back in the history, or forwards if it is negative.
+    fn browse(&mut self, back: isize, echo: &mut Echo) {
+        // Entry 0 is the newest.
+        let target = match &self.browsing {
+            Some((index, _)) => index.checked_add_signed(back),
+            None if back > 0 => Some(0),
+            None => return,
+        };
+        match target {
+            Some(index) if index < self.history.len() => {
+                let line = self.history[self.history.len() - 1 - index]
+                    .chars()
+                    .collect();
+                let saved = match self.browsing.take() {
+                    Some((_, saved)) => saved,
+                    None => self.line.clone(),
+                };
+                self.browsing = Some((index, saved));
+                self.replace(line, echo);
+            }
+            // Coming forwards past the newest entry brings back the unfinished line.
+            None if back < 0 => {
+                if let Some((_, saved)) = self.browsing.take() {
+                    self.replace(saved, echo);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Completes the word before the cursor as far as all the choices agree, and lists them if
+    /// that gets nowhere.
+    fn complete(&mut self, completer: Completer, echo: &mut Echo) {
+        let before: String = self.line[..self.cursor].iter().collect();
+        let word = &before[before.rfind(' ').map_or(0, |space| space + 1)..];
+        let candidates: Vec<String> = completer(&before)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .collect();
+        let Some(first) = candidates.first() else {
+            return;
+        };
+
+        let common = candidates[1..]
+            .iter()
+            .fold(first.as_str(), |common, candidate| {
+                let len = common
+                    .char_indices()
+                    .zip(candidate.chars())
+                    .find(|((_, a), b)| a != b)
+                    .map_or(common.len().min(candidate.len()), |((index, _), _)| index);
+                &common[..len]
+            });
+        let mut completion: Vec<char> = common[word.len()..].chars().collect();
+        if candidates.len() == 1 && !common.ends_with('/') {
+            completion.push(' ');
+        }
+        if !completion.is_empty() {
+            for ch in completion {
+                self.insert(ch, echo);
+            }
+        } else if candidates.len() > 1 {
+            echo.0.push('\n');
+            for candidate in &candidates {
+                echo.0.push_str(candidate);
+                echo.0.push_str("  ");
+            }
+            echo.0.push('\n');
+            echo.0.push_str(&self.prompt);
+            echo.chars(&self.line);
+            echo.back(self.line.len() - self.cursor);
+        }
+    }
+
+    /// Ends the line being edited and returns it.
+    fn finish(&mut self) -> String {
+        self.browsing = None;
+        self.cursor = 0;
+        let line: String = self.line.drain(..).collect();
+        if !line.is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_LENGTH {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+
+    /// Applies one input to the line, returning a result once the read is over.
+    fn process(&mut self, input: Input, echo: &mut Echo) -> Option<Result<String, ReadError>> {
+        match input {
+            Input::Char('\n') => {
+                self.move_to(self.line.len(), echo);
+                echo.chars(&['\n']);
+                return Some(Ok(self.finish()));
+            }
+            Input::Char(CTRL_C) => {
+                echo.0.push_str("^C\n");
+                self.line.clear();
+                self.finish();
+                return Some(Err(ReadError::Interrupted));
+            }
+            Input::Char(CTRL_D) if self.line.is_empty() => {
+                return Some(Err(ReadError::EndOfInput));
+            }
+            Input::Char(CTRL_U) => self.replace(Vec::new(), echo),
+            Input::Char(BACKSPACE | DELETE) if self.cursor > 0 => {
+                self.remove(self.cursor - 1, echo)
+            }
+            Input::Delete if self.cursor < self.line.len() => self.remove(self.cursor, echo),
+            Input::Left if self.cursor > 0 => self.move_to(self.cursor - 1, echo),
+            Input::Right if self.cursor < self.line.len() => self.move_to(self.cursor + 1, echo),
+            Input::Home | Input::Char(CTRL_A) => self.move_to(0, echo),
+            Input::End | Input::Char(CTRL_E) => self.move_to(self.line.len(), echo),
+            Input::Up => self.browse(1, echo),
+            Input::Down => self.browse(-1, echo),
+            Input::Char('\t') => match self.completer {
+                Some(completer) => self.complete(completer, echo),
+                None => self.insert('\t', echo),
+            },
+            // Other control characters have no place in a line.
+            Input::Char(ch) if !ch.is_control() => self.insert(ch, echo),
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Interprets whatever is pending and returns the line if it was finished.
+///
+/// Only for [`Mode::Canonical`]: returns `None` in raw mode.
+pub fn try_read_line() -> Option<Result<String, ReadError>> {
+    if mode() != Mode::Canonical {
+        return None;
+    }
+    let mut editor = EDITOR.lock();
+    let mut echo = Echo::new();
+    let mut result = None;
+    while let Some(input) = next_pending() {
+        result = editor.process(input, &mut echo);
+        if result.is_some() {
+            break;
+        }
+    }
+    drop(editor);
+    echo.flush();
+    result
+}
+
+/// Waits for a line to be finished and returns it without its newline.
+pub fn read_line() -> Result<String, ReadError> {
+    loop {
+        if let Some(result) = try_read_line() {
+            return result;
+        }
+        wait_for_input();
+    }
+}
+
+/// Shows `prompt` and waits for a line like [`read_line`], showing the prompt again whenever
+/// the line has to be redrawn.
+pub fn prompt(prompt: &str) -> Result<String, ReadError> {
+    EDITOR.lock().prompt = String::from(prompt);
+    print!("{}", prompt);
+    let result = read_line();
+    EDITOR.lock().prompt.clear();
+    result
+}
+
+/// Sets what completes words when tab is pressed, or turns completion off with `None`.
+pub fn set_completer(completer: Option<Completer>) {
+    EDITOR.lock().completer = completer;
+}
+
+/// Returns the next input as it is, if there is one. Printable characters are echoed.
+pub fn try_read_input() -> Option<Input> {
+    let input = next_pending()?;
+    if let Input::Char(ch) = input {
+        if echo() && (ch == '\n' || !ch.is_control()) {
+            print!("{}", ch);
+        }
+    }
+    Some(input)
+}
+
+/// Waits for the next input and returns it as it is. Meant for [`Mode::Raw`].
+pub fn read_input() -> Input {
+    loop {
+        if let Some(input) = try_read_input() {
+            return input;
+        }
+        wait_for_input();
+    }
+}
+
+/// What was read of the last line but did not fit in the caller's buffer.
+static UNREAD: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+/// Reads console input into `buffer` the way a file is read, returning how many bytes were
+/// read and 0 at the end of input. In [`Mode::Canonical`] this is a line at a time, ending
+/// with its newline, and in [`Mode::Raw`] the characters of a single input.
+pub fn read(buffer: &mut [u8]) -> Result<usize, ReadError> {
+    if buffer.is_empty() {
+        return Ok(0);
+    }
+    let mut unread = UNREAD.lock();
+    if unread.is_empty() {
+        match mode() {
+            Mode::Canonical => match read_line() {
+                Ok(line) => unread.extend(line.bytes().chain(Some(b'\n'))),
+                Err(ReadError::EndOfInput) => return Ok(0),
+                Err(error) => return Err(error),
+            },
+            // The editing keys have no bytes of their own, so they are skipped.
+            Mode::Raw => loop {
+                if let Input::Char(ch) = read_input() {
+                    let mut bytes = [0; 4];
+                    unread.extend(ch.encode_utf8(&mut bytes).bytes());
+                    break;
+                }
+            },
+        }
+    }
+    let count = unread.len().min(buffer.len());
+    for (byte, value) in buffer.iter_mut().zip(unread.drain(..count)) {
+        *byte = value;
+    }
+    Ok(count)
+}
+
+/// Returns the earlier lines, oldest first.
+pub fn history() -> Vec<String> {
+    EDITOR.lock().history.iter().cloned().collect()
+}
+
+/// Throws away everything typed so far, including a line being edited.
+pub fn flush() {
+    PENDING.lock().inputs.clear();
+    let mut editor = EDITOR.lock();
+    if !editor.line.is_empty() {
+        let mut echo = Echo::new();
+        editor.replace(Vec::new(), &mut echo);
+        drop(editor);
+        echo.flush();
+    } else {
+        editor.browsing = None;
+    }
+}
@@ -1,13 +1,15 @@
 use core::fmt::Write;
 
-use spin::mutex::SpinMutex;
 use voladdress::{Safe, VolAddress};
 
+pub mod line_discipline;
 mod vga;
 
 use vga::{VGAEntry, VGAEntryColor, DEFAULT_VGA_COLOR};
 
-pub(super) static WRITER: Writer = Writer(SpinMutex::new(None));
+use crate::sync::SpinLock;
+
+pub(super) static WRITER: Writer = Writer(SpinLock::named("tty", None));
 
 unsafe impl Sync for Writer {}
 
@@ -26,12 +28,12 @@ impl Buffer {
         }
     }
     fn index(&self, idx: (usize, usize)) -> VolAddress<VGAEntry, Safe, Safe> {
-        assert!(idx.0 < self.width && idx.1 < self.height);
+        assert!(idx.0 < self.height && idx.1 < self.width);
         unsafe { VolAddress::new(self.data.add(idx.0 * self.width + idx.1) as usize) }
     }
 }
 
-pub struct Writer(SpinMutex<Option<WriterInner>>);
+pub struct Writer(SpinLock<Option<WriterInner>>);
 
 impl Writer {
     pub(super) fn initialize(
@@ -90,12 +92,24 @@ impl WriterInner {
         self.cursor.1 = 0;
     }
 
+    /// Moves the cursor back a cell, onto the end of the previous line if need be.
+    fn back(&mut self) {
+        if self.cursor.1 > 0 {
+            self.cursor.1 -= 1;
+        } else if self.cursor.0 > 0 {
+            self.cursor.0 -= 1;
+            self.cursor.1 = self.width - 1;
+        }
+    }
+
     pub fn add_char(&mut self, char: char) {
         if char == '\n' {
             self.new_line();
             self.carriage_return();
         } else if char == '\r' {
             self.carriage_return();
+        } else if char == '\x08' {
+            self.back();
         } else if char.is_ascii() {
             self.buffer.index(self.cursor).write(VGAEntry {
                 byte: char as u8,
@@ -139,8 +153,7 @@ macro_rules! print {
 macro_rules! println {
     () => ($crate::print!("\n"));
     ($($arg:tt)*) => {
-        $crate::output::tty::_print(format_args!($($arg)*));
-        $crate::output::tty::_print(format_args!("\n"));
+        $crate::output::_print(format_args!("{}\n", format_args!($($arg)*)))
     };
 }
 
@@ -155,3 +168,28 @@ pub fn _print(args: core::fmt::Arguments) {
         }
     }
 }
+
+/// Writes raw bytes to the screen, as the `tty0` device does. They are taken as UTF-8, and
+/// every invalid sequence shows as a single replacement character.
+pub fn write_bytes(mut bytes: &[u8]) {
+    let mut lock = WRITER.0.lock();
+    let Some(writer) = lock.as_mut() else {
+        return;
+    };
+    while !bytes.is_empty() {
+        match core::str::from_utf8(bytes) {
+            Ok(text) => {
+                writer.add_str(text);
+                break;
+            }
+            Err(error) => {
+                let (valid, rest) = bytes.split_at(error.valid_up_to());
+                // SAFETY: `from_utf8` checked everything up to here.
+                writer.add_str(unsafe { core::str::from_utf8_unchecked(valid) });
+                writer.add_char(char::REPLACEMENT_CHARACTER);
+                // A sequence cut off at the end is dropped along with the rest.
+                bytes = &rest[error.error_len().unwrap_or(rest.len())..];
+            }
+        }
+    }
+}
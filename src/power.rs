@@ -0,0 +1,160 @@
+//! Turning the machine off and restarting it.
+//!
+//! Each way of doing so is tried in turn until one works. ACPI comes first, through the sleep
+//! state S5 or the reset register (see [`acpi::pm`]), and the older ways are kept for machines
+//! without it.
+
+use core::fmt;
+
+use crate::{
+    acpi,
+    drivers::ps2,
+    info, interrupts,
+    intrinsics::{halt_loop, io_wait, outw},
+    warn,
+};
+
+/// The ports and values emulators power off on without ACPI: QEMU, then Bochs and older QEMU,
+/// then VirtualBox.
+const EMULATOR_SHUTDOWN: [(u16, u16); 3] = [(0x604, 0x2000), (0xB004, 0x2000), (0x4004, 0x3400)];
+/// How many microseconds to give an emulator to notice it was told to power off.
+const EMULATOR_TIMEOUT: usize = 100_000;
+
+/// The sleep state the machine is off in.
+const S5: u8 = 5;
+
+/// Why a way of turning the machine off or restarting it did not work.
+pub type Error = &'static str;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RebootMethod {
+    /// The reset register in the FADT.
+    Acpi,
+    /// Pulsing the reset line of the 8042.
+    Keyboard,
+    /// Faulting with no IDT loaded, which cannot fail.
+    TripleFault,
+}
+
+impl RebootMethod {
+    /// Every method, in the order they are tried.
+    pub const ALL: [RebootMethod; 3] = [
+        RebootMethod::Acpi,
+        RebootMethod::Keyboard,
+        RebootMethod::TripleFault,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RebootMethod::Acpi => "acpi",
+            RebootMethod::Keyboard => "8042",
+            RebootMethod::TripleFault => "triple",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShutdownMethod {
+    /// Entering S5 through the PM1 control registers.
+    Acpi,
+    /// The ports emulators power off on.
+    Emulator,
+}
+
+impl ShutdownMethod {
+    /// Every method, in the order they are tried.
+    pub const ALL: [ShutdownMethod; 2] = [ShutdownMethod::Acpi, ShutdownMethod::Emulator];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ShutdownMethod::Acpi => "acpi",
+            ShutdownMethod::Emulator => "emulator",
+        }
+    }
+}
+
+impl fmt::Display for RebootMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for ShutdownMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+fn triple_fault() -> ! {
+    // With an empty IDT any interrupt turns into a triple fault, which resets the CPU.
+    #[repr(C, packed)]
+    struct Pointer {
+        limit: u16,
+        base: u32,
+    }
+    let pointer = Pointer { limit: 0, base: 0 };
+    // SAFETY: The machine is meant to go down here.
+    unsafe {
+        core::arch::asm!("lidt [{}]", "int3", in(reg) &pointer, options(readonly, nostack));
+    }
+    halt_loop()
+}
+
+/// Restarts the machine one particular way, returning only if that did not work. Interrupts
+/// are left disabled either way.
+pub fn reboot_with(method: RebootMethod) -> Result<(), Error> {
+    interrupts::disable();
+    match method {
+        RebootMethod::Acpi => acpi::pm::reset(),
+        RebootMethod::Keyboard => {
+            if acpi::fadt().is_some_and(|fadt| !fadt.has_8042()) {
+                return Err("there is no 8042");
+            }
+            ps2::pulse_reset();
+            Err("the 8042 did not reset the machine")
+        }
+        RebootMethod::TripleFault => triple_fault(),
+    }
+}
+
+/// Turns the machine off one particular way, returning only if that did not work. Interrupts
+/// are left disabled either way.
+pub fn shutdown_with(method: ShutdownMethod) -> Result<(), Error> {
+    interrupts::disable();
+    match method {
+        ShutdownMethod::Acpi => acpi::pm::enter_sleep_state(S5),
+        ShutdownMethod::Emulator => {
+            for (port, value) in EMULATOR_SHUTDOWN {
+                // SAFETY: The machine is going down anyway, whatever sits behind these ports.
+                unsafe { outw(port, value) };
+            }
+            for _ in 0..EMULATOR_TIMEOUT {
+                io_wait();
+            }
+            Err("not running in a known emulator")
+        }
+    }
+}
+
+/// Restarts the machine, trying every way of doing so in turn.
+pub fn reboot() -> ! {
+    info!("power: rebooting");
+    for method in RebootMethod::ALL {
+        if let Err(error) = reboot_with(method) {
+            warn!("power: {} reboot failed: {}", method, error);
+        }
+    }
+    triple_fault()
+}
+
+/// Turns the machine off if it can, and halts it otherwise.
+pub fn shutdown() -> ! {
+    info!("power: shutting down");
+    for method in ShutdownMethod::ALL {
+        if let Err(error) = shutdown_with(method) {
+            warn!("power: {} shutdown failed: {}", method, error);
+        }
+    }
+    info!("power: it is now safe to turn off the machine");
+    halt_loop()
+}
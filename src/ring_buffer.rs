@@ -0,0 +1,90 @@
+//! A fixed-capacity FIFO that never allocates, for queues filled from interrupt handlers.
+
+pub struct RingBuffer<T: Copy, const N: usize> {
+    items: [Option<T>; N],
+    /// The index of the oldest item.
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            items: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub const fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Appends `item`, handing it back if the buffer is full.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        self.items[(self.head + self.len) % N] = Some(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Appends `item`, dropping the oldest one to make room if needed.
+    pub fn push_overwrite(&mut self, item: T) {
+        if self.is_full() {
+            self.pop();
+        }
+        let _ = self.push(item);
+    }
+
+    /// Removes and returns the oldest item.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        item
+    }
+
+    /// Removes and returns the newest item.
+    pub fn pop_back(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.len -= 1;
+        self.items[(self.head + self.len) % N].take()
+    }
+
+    /// Returns the newest item.
+    pub fn back(&self) -> Option<T> {
+        self.len
+            .checked_sub(1)
+            .and_then(|last| self.items[(self.head + last) % N])
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    /// Iterates from the oldest item to the newest.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.len).filter_map(move |index| self.items[(self.head + index) % N])
+    }
+}
+
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
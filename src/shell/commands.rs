@@ -0,0 +1,342 @@
+//! The commands every kernel has, whatever drivers it was built with.
+
+use alloc::{string::String, vec::Vec};
+
+use super::{absolute_path, commands, complete_path, hexdump, parse_number, CommandError};
+use crate::{
+    cpu,
+    drivers::pit,
+    fs::{
+        self,
+        file::{self, OpenFlags},
+        FileType,
+    },
+    interrupts,
+    memory::{self, heap, FRAME_ALLOCATOR, PAGE_SIZE},
+    power::{self, RebootMethod, ShutdownMethod},
+    print, println, shell_command,
+};
+
+/// How many bytes `peek` shows when not told otherwise.
+const PEEK_DEFAULT: usize = 64;
+/// The most bytes `peek` dumps at once.
+const PEEK_MAX: usize = 4096;
+
+fn help(_args: &[&str]) -> Result<(), CommandError> {
+    let mut commands: Vec<_> = commands().iter().collect();
+    commands.sort_by_key(|command| command.name);
+    for command in commands {
+        let synopsis = [command.name, command.usage].join(" ");
+        println!("{:<24} {}", synopsis.trim_end(), command.description);
+    }
+    Ok(())
+}
+
+fn mem(_args: &[&str]) -> Result<(), CommandError> {
+    for region in memory::memory_map() {
+        println!(
+            "[{:#011x}-{:#011x}] {}",
+            region.start,
+            region.end.saturating_sub(1),
+            region.kind.name()
+        );
+    }
+    let frames = FRAME_ALLOCATOR.lock().stats();
+    println!(
+        "frames: {} KiB free of {} KiB",
+        frames.free * PAGE_SIZE / 1024,
+        frames.total * PAGE_SIZE / 1024
+    );
+    let heap = heap::stats();
+    println!(
+        "heap:   {} KiB used of {} KiB, peak {} KiB, {} allocations",
+        heap.used / 1024,
+        heap.size / 1024,
+        heap.peak / 1024,
+        heap.allocations
+    );
+    Ok(())
+}
+
+fn cpu(_args: &[&str]) -> Result<(), CommandError> {
+    let info = cpu::identify();
+    println!("vendor:   {}", info.vendor);
+    if let Some(brand) = &info.brand {
+        println!("model:    {}", brand);
+    }
+    println!(
+        "family {:#x}, model {:#x}, stepping {}",
+        info.family, info.model, info.stepping
+    );
+    println!("features: {}", info.features.join(" "));
+    Ok(())
+}
+
+fn uptime(_args: &[&str]) -> Result<(), CommandError> {
+    let uptime = pit::uptime();
+    let seconds = uptime.as_secs();
+    println!(
+        "up {}:{:02}:{:02}.{:02} ({} ticks)",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        uptime.subsec_millis() / 10,
+        pit::ticks()
+    );
+    Ok(())
+}
+
+fn irqs(_args: &[&str]) -> Result<(), CommandError> {
+    for (vector, count) in interrupts::counts().into_iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        match interrupts::exception_name(vector) {
+            Some(name) => println!("{:>3}  {:<32} {}", vector, name, count),
+            None => println!(
+                "{:>3}  IRQ {:<28} {}",
+                vector,
+                vector - interrupts::IRQ_BASE as usize,
+                count
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Reads a byte of physical memory. Paging is off, so physical addresses are used as they are.
+fn read_physical(address: usize) -> u8 {
+    let value: u8;
+    // SAFETY: Reading memory has no side effects, apart from on memory-mapped devices, which
+    // is what the user asked for.
+    unsafe {
+        core::arch::asm!("mov {}, byte ptr [{}]", out(reg_byte) value, in(reg) address, options(readonly, nostack));
+    }
+    value
+}
+
+fn write_physical(address: usize, value: u8) {
+    // SAFETY: None whatsoever, the user asked for it.
+    unsafe {
+        core::arch::asm!("mov byte ptr [{}], {}", in(reg) address, in(reg_byte) value, options(nostack));
+    }
+}
+
+fn peek(args: &[&str]) -> Result<(), CommandError> {
+    let (address, len) = match args {
+        [address] => (parse_number(address)?, PEEK_DEFAULT),
+        [address, len] => (parse_number(address)?, parse_number(len)?),
+        _ => return Err(CommandError::Usage),
+    };
+    if len > PEEK_MAX {
+        return Err(CommandError::Failed("too many bytes at once"));
+    }
+    let end = address
+        .checked_add(len)
+        .ok_or(CommandError::Failed("address out of range"))?;
+    let bytes: Vec<u8> = (address..end).map(read_physical).collect();
+    hexdump(address as u64, &bytes);
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> Result<(), CommandError> {
+    let [address, values @ ..] = args else {
+        return Err(CommandError::Usage);
+    };
+    if values.is_empty() {
+        return Err(CommandError::Usage);
+    }
+    let address = parse_number(address)?;
+    let bytes = values
+        .iter()
+        .map(|value| {
+            let number = parse_number(value)?;
+            u8::try_from(number).map_err(|_| CommandError::InvalidNumber(String::from(*value)))
+        })
+        .collect::<Result<Vec<u8>, CommandError>>()?;
+    // Checked before anything is written, so that a poke is never cut short.
+    if address.checked_add(bytes.len() - 1).is_none() {
+        return Err(CommandError::Failed("address out of range"));
+    }
+    for (offset, byte) in bytes.into_iter().enumerate() {
+        write_physical(address + offset, byte);
+    }
+    Ok(())
+}
+
+fn ls(args: &[&str]) -> Result<(), CommandError> {
+    let path = match args {
+        [] => String::from("/"),
+        [path] => absolute_path(path),
+        _ => return Err(CommandError::Usage),
+    };
+    let mut entries = fs::read_dir(&path)?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        let full = fs::path::join(&path, &entry.name);
+        let size = fs::lstat(&full).map_or(0, |metadata| metadata.size);
+        print!("{} {:>10} {}", entry.kind.symbol(), size, entry.name);
+        if entry.kind == FileType::Symlink {
+            if let Ok(target) = fs::read_link(&full) {
+                print!(" -> {}", target);
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn cat(args: &[&str]) -> Result<(), CommandError> {
+    if args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+    for path in args {
+        let fd = file::open(&absolute_path(path), OpenFlags::READ)?;
+        // Printed as it is read, since devices like `/dev/zero` never end. A character cut
+        // in two by a read is kept back until the rest of it arrives.
+        let mut buffer = [0; 512];
+        let mut kept = 0;
+        let result = loop {
+            match file::read(fd, &mut buffer[kept..]) {
+                Ok(0) => {
+                    print!("{}", String::from_utf8_lossy(&buffer[..kept]));
+                    break Ok(());
+                }
+                Ok(read) => {
+                    let end = kept + read;
+                    let complete = match core::str::from_utf8(&buffer[..end]) {
+                        Err(error) if error.error_len().is_none() => error.valid_up_to(),
+                        _ => end,
+                    };
+                    print!("{}", String::from_utf8_lossy(&buffer[..complete]));
+                    buffer.copy_within(complete..end, 0);
+                    kept = end - complete;
+                }
+                Err(error) => break Err(error),
+            }
+        };
+        file::close(fd)?;
+        result?;
+    }
+    Ok(())
+}
+
+fn reboot(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => power::reboot(),
+        [name] => {
+            let method = RebootMethod::ALL
+                .into_iter()
+                .find(|method| method.name() == *name)
+                .ok_or(CommandError::Usage)?;
+            let result = power::reboot_with(method);
+            interrupts::enable();
+            result.map_err(CommandError::Failed)
+        }
+        _ => Err(CommandError::Usage),
+    }
+}
+
+fn complete_reboot(prefix: &str) -> Vec<String> {
+    RebootMethod::ALL
+        .into_iter()
+        .map(RebootMethod::name)
+        .filter(|name| name.starts_with(prefix))
+        .map(String::from)
+        .collect()
+}
+
+fn shutdown(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => power::shutdown(),
+        [name] => {
+            let method = ShutdownMethod::ALL
+                .into_iter()
+                .find(|method| method.name() == *name)
+                .ok_or(CommandError::Usage)?;
+            let result = power::shutdown_with(method);
+            interrupts::enable();
+            result.map_err(CommandError::Failed)
+        }
+        _ => Err(CommandError::Usage),
+    }
+}
+
+fn complete_shutdown(prefix: &str) -> Vec<String> {
+    ShutdownMethod::ALL
+        .into_iter()
+        .map(ShutdownMethod::name)
+        .filter(|name| name.starts_with(prefix))
+        .map(String::from)
+        .collect()
+}
+
+shell_command!(HELP_COMMAND, "help", "", "List the commands", help);
+shell_command!(
+    MEM_COMMAND,
+    "mem",
+    "",
+    "Show the memory map and allocator usage",
+    mem
+);
+shell_command!(CPU_COMMAND, "cpu", "", "Show what the processor is", cpu);
+shell_command!(
+    UPTIME_COMMAND,
+    "uptime",
+    "",
+    "Show the time since boot",
+    uptime
+);
+shell_command!(
+    IRQS_COMMAND,
+    "irqs",
+    "",
+    "Show how often each interrupt was taken",
+    irqs
+);
+shell_command!(
+    PEEK_COMMAND,
+    "peek",
+    "<address> [length]",
+    "Dump physical memory",
+    peek
+);
+shell_command!(
+    POKE_COMMAND,
+    "poke",
+    "<address> <byte>...",
+    "Write bytes to physical memory",
+    poke
+);
+shell_command!(
+    LS_COMMAND,
+    "ls",
+    "[path]",
+    "List a directory",
+    ls,
+    complete = complete_path
+);
+shell_command!(
+    CAT_COMMAND,
+    "cat",
+    "<path>...",
+    "Print files",
+    cat,
+    complete = complete_path
+);
+shell_command!(
+    REBOOT_COMMAND,
+    "reboot",
+    "[acpi|8042|triple]",
+    "Restart the machine, only one way if told which",
+    reboot,
+    complete = complete_reboot
+);
+shell_command!(
+    SHUTDOWN_COMMAND,
+    "shutdown",
+    "[acpi|emulator]",
+    "Turn the machine off, only one way if told which",
+    shutdown,
+    complete = complete_shutdown
+);
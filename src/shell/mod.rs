@@ -0,0 +1,227 @@
+//! This module provides the kernel monitor that runs on the console once the kernel is up.
+//!
+//! Each line is split into whitespace separated words, the first of which names the command.
+//! Commands are registered with [`shell_command!`](crate::shell_command), which places a
+//! [`Command`] in the `.shell_commands` link section, so any subsystem can add its own next to
+//! the code it drives. Tab completes command names, and the arguments of commands that bring a
+//! completer along.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+
+mod commands;
+
+use crate::{
+    fs::{self, FileType, FsError},
+    output::tty::line_discipline::{self, ReadError},
+    print, println,
+};
+
+const PROMPT: &str = "> ";
+
+/// A command the shell can run.
+pub struct Command {
+    pub name: &'static str,
+    /// The arguments the command takes, as shown by `help`.
+    pub usage: &'static str,
+    pub description: &'static str,
+    /// Invoked with the words that followed the name.
+    pub handler: fn(&[&str]) -> Result<(), CommandError>,
+    /// Given the argument being typed, returns what it could be completed to.
+    pub complete: Option<fn(&str) -> Vec<String>>,
+}
+
+/// Registers a [`Command`](crate::shell::Command) under the given static name.
+///
+/// ```ignore
+/// fn uptime(args: &[&str]) -> Result<(), CommandError> { ... }
+/// shell_command!(UPTIME_COMMAND, "uptime", "", "Show the time since boot", uptime);
+/// shell_command!(CAT_COMMAND, "cat", "<path>...", "Print files", cat, complete = complete_path);
+/// ```
+#[macro_export]
+macro_rules! shell_command {
+    ($ident:ident, $name:literal, $usage:literal, $description:literal, $handler:expr) => {
+        $crate::shell_command!(@ $ident, $name, $usage, $description, $handler, None);
+    };
+    ($ident:ident, $name:literal, $usage:literal, $description:literal, $handler:expr,
+     complete = $complete:expr) => {
+        $crate::shell_command!(@ $ident, $name, $usage, $description, $handler, Some($complete));
+    };
+    (@ $ident:ident, $name:literal, $usage:literal, $description:literal, $handler:expr,
+     $complete:expr) => {
+        #[used(linker)]
+        #[link_section = ".shell_commands"]
+        static $ident: $crate::shell::Command = $crate::shell::Command {
+            name: $name,
+            usage: $usage,
+            description: $description,
+            handler: $handler,
+            complete: $complete,
+        };
+    };
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    /// The arguments did not make sense, so the usage is shown.
+    Usage,
+    /// An argument that should have been a number was not.
+    InvalidNumber(String),
+    Fs(FsError),
+    Failed(&'static str),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Usage => f.write_str("invalid arguments"),
+            CommandError::InvalidNumber(word) => write!(f, "not a number: {}", word),
+            CommandError::Fs(error) => error.fmt(f),
+            CommandError::Failed(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<FsError> for CommandError {
+    fn from(error: FsError) -> Self {
+        CommandError::Fs(error)
+    }
+}
+
+/// Returns every registered command.
+pub fn commands() -> &'static [Command] {
+    extern "C" {
+        static __shell_commands_start: u8;
+        static __shell_commands_end: u8;
+    }
+
+    // SAFETY: The linker script gathers every `Command` emitted by `shell_command!` between
+    // these two symbols.
+    unsafe {
+        let start = core::ptr::addr_of!(__shell_commands_start) as *const Command;
+        let end = core::ptr::addr_of!(__shell_commands_end) as *const Command;
+        let len = (end as usize - start as usize) / core::mem::size_of::<Command>();
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+pub fn lookup(name: &str) -> Option<&'static Command> {
+    commands().iter().find(|command| command.name == name)
+}
+
+/// Parses a number given in decimal or, with a `0x` prefix, in hexadecimal.
+pub fn parse_number(word: &str) -> Result<usize, CommandError> {
+    let parsed = match word.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    parsed.map_err(|_| CommandError::InvalidNumber(word.to_string()))
+}
+
+/// Prints `bytes` 16 to a line, in hex and as ASCII, with each line labelled with its offset
+/// counting from `start`.
+pub fn hexdump(start: u64, bytes: &[u8]) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        print!("{:08x} ", start + i as u64 * 16);
+        for byte in line {
+            print!(" {:02x}", byte);
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!("{:width$}  {}", "", ascii, width = (16 - line.len()) * 3);
+    }
+}
+
+/// Turns a path typed at the shell into an absolute one. There is no working directory, so
+/// relative paths start at the root.
+pub fn absolute_path(path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+/// Completes a path, adding a `/` to directories.
+pub fn complete_path(word: &str) -> Vec<String> {
+    let (directory, prefix) = match word.rfind('/') {
+        Some(slash) => word.split_at(slash + 1),
+        None => ("", word),
+    };
+    let Ok(entries) = fs::read_dir(&absolute_path(directory)) else {
+        return Vec::new();
+    };
+    entries
+        .into_iter()
+        .filter(|entry| entry.name != "." && entry.name != "..")
+        .filter(|entry| entry.name.starts_with(prefix))
+        .map(|entry| {
+            let slash = if entry.kind == FileType::Directory {
+                "/"
+            } else {
+                ""
+            };
+            format!("{}{}{}", directory, entry.name, slash)
+        })
+        .collect()
+}
+
+/// Completes command names, or hands the argument over to the command being typed.
+fn complete(line: &str) -> Vec<String> {
+    let mut words = line.split(' ');
+    let first = words.next().unwrap_or("");
+    match words.next_back() {
+        None => commands()
+            .iter()
+            .filter(|command| command.name.starts_with(first))
+            .map(|command| command.name.to_string())
+            .collect(),
+        Some(word) => match lookup(first).and_then(|command| command.complete) {
+            Some(complete) => complete(word),
+            None => Vec::new(),
+        },
+    }
+}
+
+/// Runs a single line of input.
+pub fn execute(line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = words.split_first() else {
+        return;
+    };
+    let Some(command) = lookup(name) else {
+        println!("{}: command not found", name);
+        return;
+    };
+    match (command.handler)(args) {
+        Ok(()) => {}
+        Err(CommandError::Usage) => println!("usage: {} {}", command.name, command.usage),
+        Err(error) => println!("{}: {}", command.name, error),
+    }
+}
+
+/// Reads and runs commands on the console forever.
+pub fn run() -> ! {
+    line_discipline::set_completer(Some(complete));
+    println!("Type `help` for a list of commands.");
+    loop {
+        match line_discipline::prompt(PROMPT) {
+            Ok(line) => execute(&line),
+            Err(ReadError::Interrupted) => {}
+            // There is nothing to leave to, so just start a new line.
+            Err(ReadError::EndOfInput) => println!(),
+        }
+    }
+}
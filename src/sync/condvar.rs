@@ -0,0 +1,60 @@
+//! Condition variables, for waiting on something guarded by a [`Mutex`].
+
+use super::{
+    mutex::{Mutex, MutexGuard},
+    spin::{restore_interrupts, save_interrupts},
+    wait_queue::WaitQueue,
+};
+
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Lets go of the lock and sleeps until notified, then takes the lock again. The task may
+    /// also wake up without having been notified, so the condition has to be checked again.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex: &'a Mutex<T> = guard.mutex();
+        // The lock is let go with interrupts disabled, so that a notification sent by whoever
+        // takes it next cannot arrive before the task is waiting.
+        let interrupts = save_interrupts();
+        drop(guard);
+        self.waiters.sleep();
+        restore_interrupts(interrupts);
+        mutex.lock()
+    }
+
+    /// Waits until `condition` returns `false` for the data the lock guards.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes one waiting task.
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    /// Wakes every waiting task.
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,252 @@
+//! Checks that locks are always taken in the same order, when booted with `lockdep`.
+//!
+//! Locks made with a name belong to the class of that name. Whenever one is taken while the
+//! task holds others, the order is recorded, and taking two classes in both orders, which can
+//! deadlock once two tasks or a task and an interrupt do it at the same time, is reported the
+//! first time it happens. Taking a class the task already holds would deadlock straight away,
+//! so that panics instead. Spin locks also report when they have been waiting suspiciously
+//! long.
+//!
+//! Locks without a name are not checked, and neither are the heap and the scheduler, which
+//! the checks themselves rely on. Nothing here allocates.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use spin::mutex::SpinMutex;
+
+use crate::{
+    boot_param,
+    cmdline::{ParamError, Value},
+    interrupts,
+    task::{self, TaskId},
+    warn,
+};
+
+/// The most lock classes that are told apart. Later ones are not checked.
+const MAX_CLASSES: usize = 64;
+/// The most tasks that can be holding checked locks at once.
+const MAX_TASKS: usize = 32;
+/// The most checked locks one task can hold at once.
+const MAX_HELD: usize = 16;
+/// How many times a spin lock spins before it is reported as stuck.
+pub(super) const SPIN_LIMIT: u32 = 1 << 26;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The task printing a report, whose locks are not checked meanwhile: printing takes locks
+/// that may be the very ones the report is about.
+static REPORTING: AtomicU32 = AtomicU32::new(NOBODY);
+const NOBODY: TaskId = TaskId::MAX;
+
+fn set_lockdep(value: Option<Value<'static>>) -> Result<(), ParamError> {
+    let enabled = match value {
+        None => true,
+        Some(value) => value.as_bool()?,
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+boot_param!(LOCKDEP_PARAM, "lockdep", set_lockdep);
+
+/// Returns whether lock debugging was turned on with `lockdep`.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn checking() -> bool {
+    enabled() && REPORTING.load(Ordering::Relaxed) != task::current_id()
+}
+
+fn report(print: impl FnOnce()) {
+    REPORTING.store(task::current_id(), Ordering::Relaxed);
+    print();
+    REPORTING.store(NOBODY, Ordering::Relaxed);
+}
+
+/// The locks a task is holding, innermost last.
+#[derive(Copy, Clone)]
+struct Held {
+    task: TaskId,
+    classes: [u8; MAX_HELD],
+    len: usize,
+}
+
+struct Graph {
+    classes: [&'static str; MAX_CLASSES],
+    class_count: usize,
+    /// Bit `j` of `after[i]` is set once class `j` has been taken while holding class `i`.
+    after: [u64; MAX_CLASSES],
+    /// Bit `j` of `reported[i]` is set once taking `i` and `j` in both orders was reported.
+    reported: [u64; MAX_CLASSES],
+    held: [Option<Held>; MAX_TASKS],
+}
+
+static GRAPH: SpinMutex<Graph> = SpinMutex::new(Graph {
+    classes: [""; MAX_CLASSES],
+    class_count: 0,
+    after: [0; MAX_CLASSES],
+    reported: [0; MAX_CLASSES],
+    held: [None; MAX_TASKS],
+});
+
+/// What went wrong, worked out with the graph locked and reported once it is not.
+enum Problem {
+    Recursive(&'static str),
+    Inversion(&'static str, &'static str),
+    Full,
+}
+
+impl Graph {
+    fn class(&mut self, name: &'static str) -> Option<usize> {
+        if let Some(index) = self.classes[..self.class_count]
+            .iter()
+            .position(|&class| class == name)
+        {
+            return Some(index);
+        }
+        if self.class_count == MAX_CLASSES {
+            return None;
+        }
+        self.classes[self.class_count] = name;
+        self.class_count += 1;
+        Some(self.class_count - 1)
+    }
+
+    fn held(&mut self, task: TaskId) -> Option<&mut Held> {
+        let slot = self
+            .held
+            .iter()
+            .position(|held| held.is_some_and(|held| held.task == task))
+            .or_else(|| self.held.iter().position(Option::is_none))?;
+        Some(self.held[slot].get_or_insert(Held {
+            task,
+            classes: [0; MAX_HELD],
+            len: 0,
+        }))
+    }
+
+    /// Returns whether `to` has ever been taken after `from`, directly or through others.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut seen = 1u64 << from;
+        let mut frontier = 1u64 << from;
+        while frontier != 0 {
+            let mut next = 0;
+            for class in 0..self.class_count {
+                if frontier & 1 << class != 0 {
+                    next |= self.after[class];
+                }
+            }
+            if next & 1 << to != 0 {
+                return true;
+            }
+            frontier = next & !seen;
+            seen |= next;
+        }
+        false
+    }
+
+    fn acquire(&mut self, name: &'static str) -> Option<Problem> {
+        let class = self.class(name)?;
+        let Some(held) = self.held(task::current_id()).copied() else {
+            return Some(Problem::Full);
+        };
+        let holding = &held.classes[..held.len];
+        if holding.contains(&(class as u8)) {
+            return Some(Problem::Recursive(name));
+        }
+        let mut problem = None;
+        for &outer in holding {
+            let outer = usize::from(outer);
+            if self.after[outer] & 1 << class != 0 {
+                continue;
+            }
+            if self.reaches(class, outer) && self.reported[outer] & 1 << class == 0 {
+                self.reported[outer] |= 1 << class;
+                self.reported[class] |= 1 << outer;
+                problem = Some(Problem::Inversion(self.classes[outer], name));
+            }
+            self.after[outer] |= 1 << class;
+        }
+        if held.len == MAX_HELD {
+            return Some(Problem::Full);
+        }
+        let held = self.held(task::current_id()).unwrap();
+        held.classes[held.len] = class as u8;
+        held.len += 1;
+        problem
+    }
+
+    fn release(&mut self, name: &'static str) {
+        let Some(class) = self.classes[..self.class_count]
+            .iter()
+            .position(|&class| class == name)
+        else {
+            return;
+        };
+        let task = task::current_id();
+        let Some(slot) = self
+            .held
+            .iter()
+            .position(|held| held.is_some_and(|held| held.task == task))
+        else {
+            return;
+        };
+        let held = self.held[slot].as_mut().unwrap();
+        // Locks need not be released in the order they were taken.
+        if let Some(at) = held.classes[..held.len]
+            .iter()
+            .rposition(|&other| usize::from(other) == class)
+        {
+            held.classes.copy_within(at + 1..held.len, at);
+            held.len -= 1;
+        }
+        if held.len == 0 {
+            self.held[slot] = None;
+        }
+    }
+}
+
+/// Records that the current task is about to take the lock `name`.
+pub(super) fn acquire(name: Option<&'static str>) {
+    let Some(name) = name.filter(|_| checking()) else {
+        return;
+    };
+    match interrupts::without(|| GRAPH.lock().acquire(name)) {
+        None => {}
+        Some(Problem::Recursive(name)) => {
+            // The panic message is printed, which would come straight back here.
+            ENABLED.store(false, Ordering::Relaxed);
+            panic!("lockdep: {} taken while already held", name)
+        }
+        Some(Problem::Inversion(outer, inner)) => report(|| {
+            warn!(
+                "lockdep: {} taken while holding {}, but elsewhere the other way round",
+                inner, outer
+            )
+        }),
+        Some(Problem::Full) => {
+            ENABLED.store(false, Ordering::Relaxed);
+            warn!("lockdep: too many locks held, giving up");
+        }
+    }
+}
+
+/// Records that the current task has let go of the lock `name`.
+pub(super) fn release(name: Option<&'static str>) {
+    if let Some(name) = name.filter(|_| checking()) {
+        interrupts::without(|| GRAPH.lock().release(name));
+    }
+}
+
+/// Reports a spin lock that has been spinning for [`SPIN_LIMIT`] rounds.
+pub(super) fn stuck(name: Option<&'static str>) {
+    if checking() {
+        report(|| {
+            warn!(
+                "lockdep: task {} still waiting for {}",
+                task::current_id(),
+                name.unwrap_or("an unnamed lock")
+            )
+        });
+    }
+}
@@ -0,0 +1,30 @@
+//! This module provides the locks and other ways for tasks to wait for each other.
+//!
+//! The spin locks, [`SpinLock`], [`TicketLock`] and [`RwLock`], disable interrupts while they
+//! are held, so they can be shared with interrupt handlers but must not be held for long or
+//! across anything that blocks. [`Mutex`], [`Semaphore`] and [`Condvar`] put the waiting task
+//! to sleep on a [`WaitQueue`] instead, so only tasks can wait on them, though interrupt
+//! handlers can still wake them up.
+//!
+//! Locks made with `named` can be checked for the order they are taken in by booting with
+//! `lockdep`, see [`lockdep`]. A [`Semaphore`] has no name: it is usually released by some
+//! other task or an interrupt handler than the one that acquired it, so nobody holds it the
+//! way a lock is held. A [`Condvar`] has no lock of its own, and the [`Mutex`] it is used with
+//! is checked as usual.
+
+mod condvar;
+pub mod lockdep;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod spin;
+mod wait_queue;
+
+// Nothing in the kernel waits on a condition yet.
+#[allow(unused_imports)]
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::RwLock;
+pub use semaphore::Semaphore;
+pub use spin::{SpinLock, TicketLock};
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,158 @@
+//! A lock that puts the tasks waiting for it to sleep.
+//!
+//! Unlike the spin locks, a [`Mutex`] leaves interrupts alone and can be held across anything
+//! that blocks, such as disk I/O or [`task::sleep`](crate::task::sleep). It must never be
+//! taken from an interrupt handler.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use super::{lockdep, wait_queue::WaitQueue};
+use crate::task::{self, TaskId};
+
+/// Stored as the owner while nobody holds the lock.
+const NO_OWNER: TaskId = TaskId::MAX;
+
+pub struct Mutex<T: ?Sized> {
+    name: Option<&'static str>,
+    locked: AtomicBool,
+    owner: AtomicU32,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Creates a lock that lock debugging does not check.
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            name: None,
+            locked: AtomicBool::new(false),
+            owner: AtomicU32::new(NO_OWNER),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Creates a lock that lock debugging knows as `name`.
+    pub const fn named(name: &'static str, value: T) -> Self {
+        Mutex {
+            name: Some(name),
+            locked: AtomicBool::new(false),
+            owner: AtomicU32::new(NO_OWNER),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    fn try_take(&self) -> bool {
+        let taken = self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        if taken {
+            self.owner.store(task::current_id(), Ordering::Relaxed);
+        }
+        taken
+    }
+
+    /// Takes the lock, sleeping for as long as another task holds it.
+    ///
+    /// # Panics
+    ///
+    /// If the current task already holds it.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        assert!(
+            !self.is_owned_by_current(),
+            "mutex: {} locked twice by task {}",
+            self.name.unwrap_or("lock"),
+            task::current_id()
+        );
+        lockdep::acquire(self.name);
+        self.waiters.wait_until(|| self.try_take());
+        MutexGuard { lock: self }
+    }
+
+    /// Takes the lock if nobody holds it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.try_take() {
+            lockdep::acquire(self.name);
+            Some(MutexGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    fn is_owned_by_current(&self) -> bool {
+        self.is_locked() && self.owner.load(Ordering::Relaxed) == task::current_id()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn unlock(&self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+        lockdep::release(self.name);
+        self.waiters.wake_one();
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    lock: &'a Mutex<T>,
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// Returns the lock the guard holds, for [`Condvar`](super::Condvar) to take it again.
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.lock
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The guard holds the lock.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard holds the lock.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
@@ -0,0 +1,183 @@
+//! A reader-writer spin lock that keeps interrupts disabled while it is held.
+//!
+//! Any number of readers can hold the lock at once, or a single writer. A writer that is
+//! waiting keeps new readers out, so that a steady stream of them cannot starve it.
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use super::{
+    lockdep,
+    spin::{restore_interrupts, save_interrupts, spin_until},
+};
+
+const WRITER: u32 = 1 << 31;
+/// Set while a writer waits for the readers to leave.
+const WRITER_WAITING: u32 = 1 << 30;
+const READERS: u32 = WRITER_WAITING - 1;
+
+pub struct RwLock<T: ?Sized> {
+    name: Option<&'static str>,
+    /// The number of readers, with the bits above.
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Creates a lock that lock debugging does not check.
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            name: None,
+            state: AtomicU32::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Creates a lock that lock debugging knows as `name`.
+    pub const fn named(name: &'static str, value: T) -> Self {
+        RwLock {
+            name: Some(name),
+            state: AtomicU32::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn try_take_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        state & (WRITER | WRITER_WAITING) == 0
+            && state & READERS != READERS
+            && self
+                .state
+                .compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn try_take_write(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        state & (WRITER | READERS) == 0
+            && self
+                .state
+                .compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let interrupts = save_interrupts();
+        lockdep::acquire(self.name);
+        spin_until(self.name, || self.try_take_read());
+        RwLockReadGuard {
+            lock: self,
+            interrupts,
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let interrupts = save_interrupts();
+        lockdep::acquire(self.name);
+        spin_until(self.name, || {
+            let taken = self.try_take_write();
+            if !taken {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+            taken
+        });
+        RwLockWriteGuard {
+            lock: self,
+            interrupts,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let interrupts = save_interrupts();
+        if self.try_take_read() {
+            lockdep::acquire(self.name);
+            Some(RwLockReadGuard {
+                lock: self,
+                interrupts,
+            })
+        } else {
+            restore_interrupts(interrupts);
+            None
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let interrupts = save_interrupts();
+        if self.try_take_write() {
+            lockdep::acquire(self.name);
+            Some(RwLockWriteGuard {
+                lock: self,
+                interrupts,
+            })
+        } else {
+            restore_interrupts(interrupts);
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    /// Whether interrupts were enabled before the lock was taken.
+    interrupts: bool,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The guard holds the lock for reading, so nobody writes.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        lockdep::release(self.lock.name);
+        restore_interrupts(self.interrupts);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    /// Whether interrupts were enabled before the lock was taken.
+    interrupts: bool,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The guard holds the lock for writing.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard holds the lock for writing.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // A writer still waiting sets its bit again.
+        self.lock.state.store(0, Ordering::Release);
+        lockdep::release(self.lock.name);
+        restore_interrupts(self.interrupts);
+    }
+}
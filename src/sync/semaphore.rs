@@ -0,0 +1,44 @@
+//! A counting semaphore that puts the tasks waiting on it to sleep.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::wait_queue::WaitQueue;
+
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes one from the count, sleeping until there is one to take.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Takes one from the count if it is not zero, returning whether it was.
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Adds one to the count and wakes a task waiting for it. Can be called from an interrupt
+    /// handler.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,254 @@
+//! Spin locks that keep interrupts disabled while they are held.
+//!
+//! With a single processor, a spin lock can only be contended by an interrupt handler that
+//! interrupted its holder, which then spins forever. Disabling interrupts for as long as the
+//! lock is held rules that out, and it also keeps the holder from being preempted. The state
+//! interrupts were in is restored when the guard goes, so guards have to be dropped in the
+//! reverse order they were taken in.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use super::lockdep::{self, SPIN_LIMIT};
+use crate::interrupts;
+
+/// Disables interrupts, returning whether they were enabled.
+pub(super) fn save_interrupts() -> bool {
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    enabled
+}
+
+pub(super) fn restore_interrupts(enabled: bool) {
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+/// Spins until `acquired` returns `true`, reporting to [`lockdep`] if that takes too long.
+pub(super) fn spin_until(name: Option<&'static str>, mut acquired: impl FnMut() -> bool) {
+    let mut spins = 0u32;
+    while !acquired() {
+        core::hint::spin_loop();
+        spins = spins.wrapping_add(1);
+        if spins == SPIN_LIMIT {
+            lockdep::stuck(name);
+        }
+    }
+}
+
+/// A spin lock that disables interrupts while it is held.
+pub struct SpinLock<T: ?Sized> {
+    name: Option<&'static str>,
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    /// Creates a lock that lock debugging does not check.
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            name: None,
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Creates a lock that lock debugging knows as `name`.
+    pub const fn named(name: &'static str, value: T) -> Self {
+        SpinLock {
+            name: Some(name),
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    fn try_take(&self) -> bool {
+        self.locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts = save_interrupts();
+        lockdep::acquire(self.name);
+        spin_until(self.name, || self.try_take());
+        SpinLockGuard {
+            lock: self,
+            interrupts,
+        }
+    }
+
+    /// Takes the lock if nobody holds it.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts = save_interrupts();
+        if self.try_take() {
+            lockdep::acquire(self.name);
+            Some(SpinLockGuard {
+                lock: self,
+                interrupts,
+            })
+        } else {
+            restore_interrupts(interrupts);
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("SpinLock").field("data", &&*guard).finish(),
+            None => f.write_str("SpinLock { <locked> }"),
+        }
+    }
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    /// Whether interrupts were enabled before the lock was taken.
+    interrupts: bool,
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The guard holds the lock.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard holds the lock.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        lockdep::release(self.lock.name);
+        restore_interrupts(self.interrupts);
+    }
+}
+
+/// A spin lock handed out in the order it was asked for, which disables interrupts while it
+/// is held.
+pub struct TicketLock<T: ?Sized> {
+    name: Option<&'static str>,
+    next: AtomicU32,
+    serving: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    /// Creates a lock that lock debugging does not check.
+    pub const fn new(value: T) -> Self {
+        TicketLock {
+            name: None,
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Creates a lock that lock debugging knows as `name`.
+    pub const fn named(name: &'static str, value: T) -> Self {
+        TicketLock {
+            name: Some(name),
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> TicketLock<T> {
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        let interrupts = save_interrupts();
+        lockdep::acquire(self.name);
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        spin_until(self.name, || self.serving.load(Ordering::Acquire) == ticket);
+        TicketLockGuard {
+            lock: self,
+            interrupts,
+        }
+    }
+
+    /// Takes the lock if nobody holds it or is waiting for it.
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let interrupts = save_interrupts();
+        let serving = self.serving.load(Ordering::Acquire);
+        if self
+            .next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            lockdep::acquire(self.name);
+            Some(TicketLockGuard {
+                lock: self,
+                interrupts,
+            })
+        } else {
+            restore_interrupts(interrupts);
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+}
+
+pub struct TicketLockGuard<'a, T: ?Sized> {
+    lock: &'a TicketLock<T>,
+    /// Whether interrupts were enabled before the lock was taken.
+    interrupts: bool,
+}
+
+impl<T: ?Sized> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The guard holds the lock.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard holds the lock.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.serving.fetch_add(1, Ordering::Release);
+        lockdep::release(self.lock.name);
+        restore_interrupts(self.interrupts);
+    }
+}
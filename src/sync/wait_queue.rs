@@ -0,0 +1,82 @@
+//! Tasks waiting for something to happen.
+//!
+//! A task checks its condition and, if it does not hold yet, joins the queue and blocks, all
+//! with interrupts disabled, so a wakeup from an interrupt handler cannot slip in between and
+//! be lost. Whoever changes what the condition depends on wakes the queue afterwards. Woken
+//! tasks check again, since someone else may have got there first.
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::spin::{restore_interrupts, save_interrupts, SpinLock};
+use crate::{
+    interrupts,
+    task::{self, Task},
+};
+
+pub struct WaitQueue {
+    waiters: SpinLock<VecDeque<Arc<Task>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: SpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current task until something wakes the queue. Interrupts must be disabled,
+    /// from before whatever the task is waiting for was found not to have happened yet.
+    ///
+    /// Before the scheduler runs there is nothing else to switch to, so this halts until the
+    /// next interrupt instead, and the caller has to cope with waking up for no reason.
+    pub fn sleep(&self) {
+        debug_assert!(!interrupts::are_enabled());
+        if !task::is_running() {
+            interrupts::enable_and_wait();
+            interrupts::disable();
+            return;
+        }
+        self.waiters.lock().push_back(task::current());
+        task::block();
+    }
+
+    /// Waits until `condition` returns `true`, checking it with interrupts disabled.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        let interrupts = save_interrupts();
+        while !condition() {
+            self.sleep();
+        }
+        restore_interrupts(interrupts);
+    }
+
+    /// Wakes the task that has waited longest, returning whether there was one.
+    pub fn wake_one(&self) -> bool {
+        match self.waiters.lock().pop_front() {
+            Some(waiter) => {
+                task::wake(&waiter);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting task, returning how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let count = waiters.len();
+        for waiter in waiters {
+            task::wake(&waiter);
+        }
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,151 @@
+//! Saving and restoring what a task was doing: its registers, its stack and the FPU.
+//!
+//! A task that is not running has its callee-saved registers pushed on its own stack, above
+//! the address to carry on from, so switching tasks comes down to swapping stack pointers. The
+//! other registers have already been saved by the compiler around the call to [`switch`]. The
+//! x87 and SSE state goes with `fxsave` where the processor has it, and `fnsave` otherwise.
+
+use core::{
+    arch::{asm, global_asm, x86::__cpuid as cpuid},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Once;
+
+const CR0_MP: u32 = 1 << 1;
+const CR0_EM: u32 = 1 << 2;
+const CR0_TS: u32 = 1 << 3;
+const CR0_NE: u32 = 1 << 5;
+const CR4_OSFXSR: u32 = 1 << 9;
+const CR4_OSXMMEXCPT: u32 = 1 << 10;
+
+/// Room for an `fxsave` image, which is more than `fnsave` needs.
+#[derive(Clone)]
+#[repr(C, align(16))]
+pub(super) struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// The state of a freshly initialized FPU, for a new task to start from.
+    pub(super) fn initial() -> Self {
+        INITIAL_FPU.get().cloned().unwrap_or(FpuState([0; 512]))
+    }
+}
+
+/// Whether the processor has `fxsave`. Read by [`switch`] as a byte.
+static FXSR: AtomicBool = AtomicBool::new(false);
+static INITIAL_FPU: Once<FpuState> = Once::new();
+
+global_asm! {r#"
+    .section .text
+    task_switch:
+        mov eax, [esp + 4]
+        mov edx, [esp + 8]
+        mov ecx, [esp + 12]
+        cmp byte ptr [{fxsr}], 0
+        je 1f
+        fxsave [ecx]
+        mov ecx, [esp + 16]
+        fxrstor [ecx]
+        jmp 2f
+    1:
+        fnsave [ecx]
+        mov ecx, [esp + 16]
+        frstor [ecx]
+    2:
+        push ebp
+        push ebx
+        push esi
+        push edi
+        mov [eax], esp
+        mov esp, edx
+        pop edi
+        pop esi
+        pop ebx
+        pop ebp
+        ret
+"#,
+    fxsr = sym FXSR,
+}
+
+extern "C" {
+    fn task_switch(
+        save_sp: *mut usize,
+        load_sp: usize,
+        save_fpu: *mut FpuState,
+        load_fpu: *const FpuState,
+    );
+}
+
+/// Saves the current registers and FPU state and carries on with the ones saved before,
+/// returning once something switches back.
+///
+/// # Safety
+/// Interrupts must be disabled, and `load_sp` and `load_fpu` must have been saved by an
+/// earlier switch or made by [`initial_stack`] and [`FpuState::initial`], on a stack that is
+/// still there.
+pub(super) unsafe fn switch(
+    save_sp: *mut usize,
+    load_sp: usize,
+    save_fpu: *mut FpuState,
+    load_fpu: *const FpuState,
+) {
+    task_switch(save_sp, load_sp, save_fpu, load_fpu);
+}
+
+/// Lays out the top of a new stack so that switching to it calls `entry` as if from a
+/// function that never returns, and returns the stack pointer to switch to.
+///
+/// # Safety
+/// `top` must be the 16-byte aligned end of memory that is free to use as a stack.
+pub(super) unsafe fn initial_stack(top: usize, entry: extern "C" fn() -> !) -> usize {
+    let top = top as *mut usize;
+    // Where `entry` would find the return address of a call, keeping the stack aligned the
+    // way the compiler expects on entry.
+    top.sub(1).write(0);
+    top.sub(2).write(entry as usize);
+    // EBP, EBX, ESI and EDI, in the order they are popped.
+    for slot in 3..=6 {
+        top.sub(slot).write(0);
+    }
+    top.sub(6) as usize
+}
+
+/// Turns on the FPU, and SSE where there is `fxsave`, and records the state new tasks start
+/// with.
+pub(super) fn initialize() {
+    let leaf1 = cpuid(1);
+    let fxsr = leaf1.edx & 1 << 24 != 0;
+    let sse = leaf1.edx & 1 << 25 != 0;
+    FXSR.store(fxsr, Ordering::Relaxed);
+    // SAFETY: The FPU is not in use yet, and the kernel runs with a flat address space that
+    // none of these bits affect.
+    unsafe {
+        let mut cr0: u32;
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        cr0 = (cr0 & !(CR0_EM | CR0_TS)) | CR0_MP | CR0_NE;
+        asm!("mov cr0, {}", in(reg) cr0, options(nomem, nostack, preserves_flags));
+        if fxsr {
+            let mut cr4: u32;
+            asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+            cr4 |= CR4_OSFXSR;
+            if sse {
+                cr4 |= CR4_OSXMMEXCPT;
+            }
+            asm!("mov cr4, {}", in(reg) cr4, options(nomem, nostack, preserves_flags));
+        }
+        asm!("fninit", options(nomem, nostack));
+    }
+    INITIAL_FPU.call_once(|| {
+        let mut state = FpuState([0; 512]);
+        // SAFETY: The image is large and aligned enough for either instruction.
+        unsafe {
+            if fxsr {
+                asm!("fxsave [{}]", in(reg) state.0.as_mut_ptr(), options(nostack));
+            } else {
+                // This also initializes the FPU again, which it just was.
+                asm!("fnsave [{}]", in(reg) state.0.as_mut_ptr(), options(nostack));
+            }
+        }
+        state
+    });
+}
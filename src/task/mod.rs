@@ -0,0 +1,388 @@
+//! This module provides kernel threads, which are called tasks.
+//!
+//! A task is started with [`spawn`] and runs a closure on its own stack, with its own saved
+//! registers and FPU state, until the closure returns. Tasks are preempted by the timer and
+//! scheduled by priority, round robin among equals. They can [`yield_now`], [`sleep`] for a
+//! while and wait for each other to finish through their [`JoinHandle`]. Whatever runs before
+//! [`initialize`], and the shell after it, is the `kernel` task.
+//!
+//! Code that waits for something else to happen parks its task with [`block`] and is woken
+//! with [`wake`] by whoever made it happen.
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+    time::Duration,
+};
+
+mod context;
+mod scheduler;
+
+pub use scheduler::{preempt, tick};
+
+use self::{
+    context::FpuState,
+    scheduler::{schedule, SCHEDULER},
+};
+use crate::{
+    drivers::pit,
+    info, interrupts,
+    memory::{FRAME_ALLOCATOR, PAGE_SIZE},
+    println,
+    shell::CommandError,
+    shell_command,
+    sync::{SpinLock, WaitQueue},
+};
+
+/// The size of the stack of every task but the first.
+const STACK_PAGES: usize = 4;
+
+pub type TaskId = u32;
+
+/// How urgently a task wants the processor. A ready task always runs before any of a lower
+/// priority.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum State {
+    /// Waiting in a run queue.
+    Ready,
+    Running,
+    /// Waiting for a tick, see [`sleep`].
+    Sleeping,
+    /// Waiting for [`wake`].
+    Blocked,
+    Exited,
+}
+
+impl State {
+    const fn from_u8(value: u8) -> State {
+        match value {
+            0 => State::Ready,
+            1 => State::Running,
+            2 => State::Sleeping,
+            3 => State::Blocked,
+            _ => State::Exited,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            State::Ready => "ready",
+            State::Running => "running",
+            State::Sleeping => "sleeping",
+            State::Blocked => "blocked",
+            State::Exited => "exited",
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Where a task that is not running left off.
+struct Context {
+    sp: usize,
+    fpu: FpuState,
+}
+
+/// Frames taken from the frame allocator for a stack.
+struct Stack {
+    base: usize,
+}
+
+impl Stack {
+    fn allocate() -> Option<Stack> {
+        FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous(STACK_PAGES, PAGE_SIZE)
+            .map(|base| Stack { base })
+    }
+
+    fn top(&self) -> usize {
+        self.base + STACK_PAGES * PAGE_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        FRAME_ALLOCATOR
+            .lock()
+            .deallocate_contiguous(self.base, STACK_PAGES);
+    }
+}
+
+type Entry = Box<dyn FnOnce() + Send>;
+
+pub struct Task {
+    id: TaskId,
+    name: String,
+    priority: Priority,
+    /// Atomic, so that the timer interrupt never waits for whoever is looking at it.
+    state: AtomicU8,
+    /// The tasks waiting in [`JoinHandle::join`] for this one to exit.
+    joiners: WaitQueue,
+    /// What the task runs, until it starts.
+    entry: SpinLock<Option<Entry>>,
+    /// `None` for the first task, which runs on the boot stack.
+    stack: SpinLock<Option<Stack>>,
+    /// Only touched by the scheduler while switching, with interrupts disabled.
+    context: UnsafeCell<Context>,
+}
+
+// SAFETY: `context` is only used by `scheduler::schedule`, which runs with interrupts
+// disabled on the only processor.
+unsafe impl Sync for Task {}
+
+impl Task {
+    fn new(name: &str, priority: Priority, entry: Option<Entry>, stack: Option<Stack>) -> Self {
+        static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+        let sp = match &stack {
+            // SAFETY: The stack was just allocated and nothing else uses it.
+            Some(stack) => unsafe { context::initial_stack(stack.top(), task_entry) },
+            // Filled in when the task is first switched away from.
+            None => 0,
+        };
+        Task {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            name: name.to_string(),
+            priority,
+            state: AtomicU8::new(State::Ready as u8),
+            joiners: WaitQueue::new(),
+            entry: SpinLock::new(entry),
+            stack: SpinLock::new(stack),
+            context: UnsafeCell::new(Context {
+                sp,
+                fpu: FpuState::initial(),
+            }),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn state(&self) -> State {
+        State::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    fn free_stack(&self) {
+        self.stack.lock().take();
+    }
+}
+
+/// Where every task but the first starts, with interrupts still disabled from the switch.
+extern "C" fn task_entry() -> ! {
+    let entry = SCHEDULER.lock().current().entry.lock().take();
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+
+/// Lets the owner of a task wait for it to finish. Dropping the handle leaves the task
+/// running.
+pub struct JoinHandle {
+    task: Arc<Task>,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> TaskId {
+        self.task.id
+    }
+
+    /// Waits for the task to return.
+    pub fn join(self) {
+        self.task
+            .joiners
+            .wait_until(|| self.task.state() == State::Exited);
+    }
+}
+
+/// Starts running `f` in a new task, which gets the processor once nothing of a higher
+/// priority is ready. Fails if there is no memory for its stack.
+pub fn spawn(
+    name: &str,
+    priority: Priority,
+    f: impl FnOnce() + Send + 'static,
+) -> Result<JoinHandle, ()> {
+    scheduler::reap();
+    let stack = Stack::allocate().ok_or(())?;
+    let task = Arc::new(Task::new(name, priority, Some(Box::new(f)), Some(stack)));
+    interrupts::without(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.tasks.push(task.clone());
+        scheduler.make_ready(task.clone());
+    });
+    Ok(JoinHandle { task })
+}
+
+/// Returns the task that is running.
+pub fn current() -> Arc<Task> {
+    interrupts::without(|| SCHEDULER.lock().current().clone())
+}
+
+/// Returns the id of the task that is running, without taking any locks. Before the
+/// scheduler runs this is the id the `kernel` task gets.
+pub fn current_id() -> TaskId {
+    scheduler::CURRENT_ID.load(Ordering::Relaxed)
+}
+
+/// Returns whether tasks are being scheduled yet.
+pub fn is_running() -> bool {
+    interrupts::without(|| SCHEDULER.lock().current.is_some())
+}
+
+/// Lets the other ready tasks of the same priority run first.
+pub fn yield_now() {
+    scheduler::yield_current();
+}
+
+/// Waits for at least `duration`, rounded up to the next tick. Before the scheduler runs this
+/// halts until enough ticks have gone by.
+pub fn sleep(duration: Duration) {
+    let ticks = (duration.as_millis() * u128::from(pit::HZ)).div_ceil(1000);
+    let ticks = ticks.min(u128::from(u32::MAX / 2)) as u32;
+    let until = pit::ticks().wrapping_add(ticks);
+    if !is_running() {
+        // Waiting leaves interrupts enabled, so they are put back the way the caller had them.
+        interrupts::without(|| {
+            while (pit::ticks().wrapping_sub(until) as i32) < 0 {
+                interrupts::enable_and_wait();
+                interrupts::disable();
+            }
+        });
+        return;
+    }
+    interrupts::without(|| {
+        SCHEDULER.lock().sleep_until(until);
+        schedule();
+    });
+}
+
+/// Parks the current task until something calls [`wake`] for it.
+///
+/// Interrupts must be disabled from before the task made itself known to whoever wakes it,
+/// so that the wakeup cannot come in between and be missed.
+pub fn block() {
+    assert!(
+        !interrupts::are_enabled(),
+        "task: blocking with interrupts enabled"
+    );
+    current().set_state(State::Blocked);
+    schedule();
+}
+
+/// Makes a task that [`block`]ed ready to run again. Does nothing to tasks in any other
+/// state.
+pub fn wake(task: &Arc<Task>) {
+    interrupts::without(|| {
+        if task.state() == State::Blocked {
+            SCHEDULER.lock().make_ready(task.clone());
+        }
+    });
+}
+
+/// Ends the current task, waking whoever is joining it.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let task = current();
+    task.set_state(State::Exited);
+    SCHEDULER.lock().retire(&task);
+    task.joiners.wake_all();
+    drop(task);
+    schedule();
+    unreachable!("task: an exited task was scheduled");
+}
+
+/// Returns every task that has not exited, in the order they were started.
+pub fn tasks() -> Vec<Arc<Task>> {
+    interrupts::without(|| SCHEDULER.lock().tasks.clone())
+}
+
+/// Turns what is running into the `kernel` task, starts the idle task and turns on the FPU.
+/// Tasks are preempted once the timer runs.
+pub fn initialize() {
+    context::initialize();
+    let kernel = Arc::new(Task::new("kernel", Priority::Normal, None, None));
+    kernel.set_state(State::Running);
+    let idle = Task::new(
+        "idle",
+        Priority::Low,
+        Some(Box::new(|| loop {
+            scheduler::reap();
+            interrupts::disable();
+            interrupts::enable_and_wait();
+        })),
+        Some(Stack::allocate().expect("task: no memory for the idle stack")),
+    );
+    let idle = Arc::new(idle);
+    interrupts::without(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.tasks.push(kernel.clone());
+        scheduler.tasks.push(idle.clone());
+        scheduler.current = Some(kernel);
+        scheduler.idle = Some(idle);
+    });
+    info!(
+        "task: scheduling with {} KiB stacks",
+        STACK_PAGES * PAGE_SIZE / 1024
+    );
+}
+
+fn ps(_args: &[&str]) -> Result<(), CommandError> {
+    println!("{:>4}  {:<8} {:<9} NAME", "ID", "PRIORITY", "STATE");
+    for task in tasks() {
+        println!(
+            "{:>4}  {:<8} {:<9} {}",
+            task.id,
+            task.priority.name(),
+            task.state(),
+            task.name
+        );
+    }
+    Ok(())
+}
+
+shell_command!(PS_COMMAND, "ps", "", "List the tasks", ps);
@@ -0,0 +1,200 @@
+//! The run queues, and picking which task runs next.
+//!
+//! Every ready task waits in the queue of its priority, and the highest non-empty queue is
+//! served first, round robin. A running task keeps the processor until it blocks, sleeps or
+//! yields, until its time slice runs out while another task of the same or a higher priority
+//! is waiting, or until a task of a higher priority wakes up. The switch itself happens on
+//! the way out of the interrupt that asked for it, in [`preempt`]. When nothing is ready the
+//! idle task halts the processor.
+//!
+//! Apart from [`reap`], everything here runs with interrupts disabled.
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use super::{context, Priority, State, Task};
+use crate::{drivers::pit, interrupts, sync::SpinLock};
+
+/// How many ticks a task runs for before others of its priority get a turn.
+const TIME_SLICE: u32 = 5;
+
+pub(super) struct Scheduler {
+    pub(super) current: Option<Arc<Task>>,
+    pub(super) idle: Option<Arc<Task>>,
+    ready: [VecDeque<Arc<Task>>; Priority::COUNT],
+    /// The tasks waiting for a tick, with the tick to wake them at.
+    sleeping: Vec<(u32, Arc<Task>)>,
+    /// Every task that has not exited, so that blocked ones can be found to wake.
+    pub(super) tasks: Vec<Arc<Task>>,
+    /// Tasks that have exited, whose stacks go the next time the idle task runs or a task is
+    /// spawned.
+    dead: Vec<Arc<Task>>,
+    /// The ticks left of the time slice of the current task.
+    slice: u32,
+}
+
+/// Not checked by lock debugging, which goes by the current task: that changes while the
+/// scheduler is locked.
+pub(super) static SCHEDULER: SpinLock<Scheduler> = SpinLock::new(Scheduler {
+    current: None,
+    idle: None,
+    ready: [const { VecDeque::new() }; Priority::COUNT],
+    sleeping: Vec::new(),
+    tasks: Vec::new(),
+    dead: Vec::new(),
+    slice: TIME_SLICE,
+});
+
+/// Set when the current task should give way at the next chance.
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+/// The id of the current task, for code that cannot take [`SCHEDULER`] to find out.
+pub(super) static CURRENT_ID: AtomicU32 = AtomicU32::new(0);
+
+impl Scheduler {
+    pub(super) fn current(&self) -> &Arc<Task> {
+        self.current.as_ref().expect("task: scheduler not running")
+    }
+
+    /// Queues a task to run. The idle task never waits in a queue, it runs when they are all
+    /// empty.
+    pub(super) fn make_ready(&mut self, task: Arc<Task>) {
+        task.set_state(State::Ready);
+        if self
+            .idle
+            .as_ref()
+            .is_some_and(|idle| Arc::ptr_eq(idle, &task))
+        {
+            return;
+        }
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|current| task.priority > current.priority || self.is_idle(current))
+        {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+        self.ready[task.priority as usize].push_back(task);
+    }
+
+    fn is_idle(&self, task: &Arc<Task>) -> bool {
+        self.idle
+            .as_ref()
+            .is_some_and(|idle| Arc::ptr_eq(idle, task))
+    }
+
+    /// Puts the current task to sleep until `tick`.
+    pub(super) fn sleep_until(&mut self, tick: u32) {
+        let current = self.current().clone();
+        current.set_state(State::Sleeping);
+        self.sleeping.push((tick, current));
+    }
+
+    /// Takes a task that exited off the books. Its stack goes later, see [`reap`].
+    pub(super) fn retire(&mut self, task: &Arc<Task>) {
+        self.tasks.retain(|other| !Arc::ptr_eq(other, task));
+        self.dead.push(task.clone());
+    }
+
+    fn next(&mut self) -> Arc<Task> {
+        self.ready
+            .iter_mut()
+            .rev()
+            .find_map(VecDeque::pop_front)
+            .or_else(|| self.idle.clone())
+            .expect("task: nothing to run")
+    }
+
+    /// Whether a task is waiting that should get the processor when the current one's time
+    /// is up.
+    fn has_contender(&self) -> bool {
+        let current = self.current();
+        let first = if self.is_idle(current) {
+            0
+        } else {
+            current.priority as usize
+        };
+        self.ready[first..].iter().any(|queue| !queue.is_empty())
+    }
+}
+
+/// Switches to the task that should run next. The current task must have been given its new
+/// state, and queued if it is still ready, by the caller.
+pub(super) fn schedule() {
+    debug_assert!(!interrupts::are_enabled());
+    let mut scheduler = SCHEDULER.lock();
+    let next = scheduler.next();
+    let previous = scheduler.current.replace(next.clone()).unwrap();
+    next.set_state(State::Running);
+    CURRENT_ID.store(next.id, Ordering::Relaxed);
+    scheduler.slice = TIME_SLICE;
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+    if Arc::ptr_eq(&previous, &next) {
+        return;
+    }
+    // Both stay alive in the scheduler across the switch: in `tasks` or `dead`, and in
+    // `current`.
+    let save = previous.context.get();
+    let load = next.context.get();
+    drop(scheduler);
+    drop(previous);
+    drop(next);
+    // SAFETY: Interrupts are disabled, and what is loaded was saved by the switch that took
+    // that task off the processor, or set up for it when it was spawned.
+    unsafe {
+        context::switch(&mut (*save).sp, (*load).sp, &mut (*save).fpu, &(*load).fpu);
+    }
+}
+
+/// Frees the stacks of the tasks that have exited, none of which can be running by now.
+/// This is kept out of [`schedule`] so that interrupts are not held off while it happens.
+pub(super) fn reap() {
+    let dead = interrupts::without(|| core::mem::take(&mut SCHEDULER.lock().dead));
+    for task in dead {
+        task.free_stack();
+    }
+}
+
+/// Gives way to another task if one is due. Called on the way out of every interrupt.
+pub fn preempt() {
+    if NEED_RESCHED.load(Ordering::Relaxed) {
+        yield_current();
+    }
+}
+
+/// Puts the current task at the back of its queue and runs whatever is next.
+pub(super) fn yield_current() {
+    interrupts::without(|| {
+        {
+            let mut scheduler = SCHEDULER.lock();
+            let Some(current) = scheduler.current.clone() else {
+                return;
+            };
+            scheduler.make_ready(current);
+        }
+        schedule();
+    });
+}
+
+/// Wakes the tasks whose sleep is over and counts down the time slice. Called from the timer
+/// interrupt.
+pub fn tick() {
+    let mut scheduler = SCHEDULER.lock();
+    if scheduler.current.is_none() {
+        return;
+    }
+    let now = pit::ticks();
+    let mut index = 0;
+    while index < scheduler.sleeping.len() {
+        // The tick count wraps, so what matters is which side of `now` the wakeup is on.
+        if now.wrapping_sub(scheduler.sleeping[index].0) as i32 >= 0 {
+            let (_, task) = scheduler.sleeping.swap_remove(index);
+            scheduler.make_ready(task);
+        } else {
+            index += 1;
+        }
+    }
+    scheduler.slice = scheduler.slice.saturating_sub(1);
+    if scheduler.slice == 0 && scheduler.has_contender() {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}